@@ -215,6 +215,9 @@ impl Peer {
             Message::GetBlocks(_get_blocks) => {}
             Message::GetData(_data) => {}
             Message::Block(_block) => {}
+            Message::CompactBlock(_block) => {}
+            Message::GetBlockTxn(_get_block_txn) => {}
+            Message::BlockTxn(_block_txn) => {}
             Message::NotFound(_not_found) => {}
             Message::Unparsed(name, message) => {
                 // Support for alert messages has been removed from bitcoin core in March 2016.
@@ -0,0 +1,573 @@
+//!
+//! Reader and writer for bitcoin-core style blk*.dat files
+//!
+//! Each file contains a sequence of blocks, each prefixed with the network magic and the length
+//! of the block. Blocks are stored in the order they were received, so a block can come before
+//! its parent; handling that is left to the caller.
+//!
+//! The `Importer` keeps track of its position and can persist it in a checkpoint file, such that
+//! an import can be resumed after a crash. A position should only be committed once all blocks
+//! before it are safely stored. As checkpoints are only written periodically, some blocks may be
+//! read again after resuming; the caller must accept blocks it already has.
+//!
+//! ```no_run
+//! let mut importer = blkfile::Importer::with_checkpoint(
+//!     "/home/user/.bitcoin/blocks", "import.checkpoint").unwrap();
+//!
+//! while let Some(block) = importer.next_block().unwrap() {
+//!     // store block.data
+//!     importer.commit(block.end).unwrap();
+//! }
+//! importer.flush().unwrap();
+//! ```
+//!
+//! The `Exporter` writes blocks in the same format, starting a new file when the current one
+//! would exceed the maximum file size.
+
+extern crate byteorder;
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+
+
+/// Magic number stored at the start of each block
+pub const MAGIC: u32 = 0xD9B4BEF9;
+
+/// Number of commits after which the checkpoint is written
+const DEFAULT_CHECKPOINT_INTERVAL: usize = 1000;
+
+/// Maximum size of a blk file as used by bitcoin-core
+pub const MAX_BLK_FILE_SIZE: u64 = 0x800_0000;
+
+/// The maximum size of a block in a blk file; the maximum block weight plus some slack
+/// for the header and the transaction count
+pub const MAX_BLOCK_SIZE: u32 = 4_000_000 + 1000;
+
+
+/// Returns the name of the blk file with the given number
+pub fn blk_file_name(file_number: u32) -> String {
+    format!("blk{:05}.dat", file_number)
+}
+
+
+/// A position in the blk files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BlkPosition {
+    pub file_number: u32,
+    pub file_offset: u64
+}
+
+/// A block as read from a blk file
+#[derive(Debug)]
+pub struct BlkBlock {
+    pub data:  Vec<u8>,
+
+    /// The position of the magic number of this block
+    pub start: BlkPosition,
+
+    /// The position after this block
+    pub end:   BlkPosition
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ImportProgress {
+    pub position:    BlkPosition,
+
+    /// Number of blocks read since the importer was opened
+    pub blocks:      u64,
+
+    /// Bytes before the current position, and the total size of the blk files
+    /// as found when the importer was opened
+    pub bytes_done:  u64,
+    pub bytes_total: u64
+}
+
+/// Error of an import; either reading the blk files or adding a block failed
+#[derive(Debug)]
+pub enum ImportError<E> {
+    Io(io::Error),
+    Block(E)
+}
+
+impl<E> From<io::Error> for ImportError<E> {
+    fn from(err: io::Error) -> ImportError<E> {
+        ImportError::Io(err)
+    }
+}
+
+
+/// Sequential reader of blk files
+pub struct Importer {
+    dir:                 PathBuf,
+    reader:              Option<BufReader<File>>,
+    position:            BlkPosition,
+
+    checkpoint_file:     Option<PathBuf>,
+    checkpoint:          BlkPosition,
+    checkpoint_interval: usize,
+    uncommitted:         usize,
+
+    file_sizes:          Vec<u64>,
+    blocks:              u64
+}
+
+impl Importer {
+
+    /// Opens the blk files in `dir`, starting at the first file
+    pub fn new<P: AsRef<Path>>(dir: P) -> Importer {
+
+        let dir = dir.as_ref().to_path_buf();
+
+        // gather the file sizes for progress reporting
+        let file_sizes = (0..)
+            .map(|n| fs::metadata(dir.join(blk_file_name(n))))
+            .take_while(|m| m.is_ok())
+            .map(|m| m.unwrap().len())
+            .collect();
+
+        Importer {
+            dir,
+            reader:              None,
+            position:            BlkPosition::default(),
+            checkpoint_file:     None,
+            checkpoint:          BlkPosition::default(),
+            checkpoint_interval: DEFAULT_CHECKPOINT_INTERVAL,
+            uncommitted:         0,
+            file_sizes,
+            blocks:              0
+        }
+    }
+
+    /// Opens the blk files in `dir`, resuming from the position stored in `checkpoint_file`
+    /// if it exists. Committed positions are stored in this file.
+    pub fn with_checkpoint<P: AsRef<Path>, Q: AsRef<Path>>(dir: P, checkpoint_file: Q)
+        -> io::Result<Importer>
+    {
+        let mut importer = Importer::new(dir);
+        let checkpoint_file = checkpoint_file.as_ref().to_path_buf();
+
+        if checkpoint_file.exists() {
+            importer.checkpoint = read_checkpoint(&checkpoint_file)?;
+            importer.position   = importer.checkpoint;
+        }
+        importer.checkpoint_file = Some(checkpoint_file);
+        Ok(importer)
+    }
+
+    /// Sets the number of commits after which the checkpoint is written
+    pub fn set_checkpoint_interval(&mut self, interval: usize) {
+        self.checkpoint_interval = interval;
+    }
+
+    /// The position of the next block to read
+    pub fn position(&self) -> BlkPosition {
+        self.position
+    }
+
+    /// The last committed position
+    pub fn checkpoint(&self) -> BlkPosition {
+        self.checkpoint
+    }
+
+    /// Continues reading at `position`, which must be the start of a block or the start of a file
+    pub fn seek(&mut self, position: BlkPosition) {
+        self.reader   = None;
+        self.position = position;
+    }
+
+    pub fn progress(&self) -> ImportProgress {
+
+        let file_number = self.position.file_number as usize;
+        let bytes_done  = self.file_sizes.iter().take(file_number).sum::<u64>()
+            + self.position.file_offset;
+
+        ImportProgress {
+            position:    self.position,
+            blocks:      self.blocks,
+            bytes_done,
+            bytes_total: self.file_sizes.iter().sum()
+        }
+    }
+
+    /// Reads the next block
+    ///
+    /// Returns None if no more blocks are available. More blocks may come in later, as the
+    /// position is kept; a truncated block at the end of the last file is not skipped.
+    pub fn next_block(&mut self) -> io::Result<Option<BlkBlock>> {
+
+        loop {
+            if self.reader.is_none() {
+                let name = self.dir.join(blk_file_name(self.position.file_number));
+                if !name.exists() {
+                    return Ok(None);
+                }
+                let mut rdr = BufReader::new(File::open(name)?);
+                rdr.seek(SeekFrom::Start(self.position.file_offset))?;
+                self.reader = Some(rdr);
+            }
+
+            let result = read_block(self.reader.as_mut().unwrap(), self.position.file_offset)?;
+
+            if let Some((start_offset, data)) = result {
+
+                let start = BlkPosition {
+                    file_number: self.position.file_number,
+                    file_offset: start_offset
+                };
+                self.position.file_offset = start_offset + 8 + data.len() as u64;
+                self.blocks += 1;
+
+                return Ok(Some(BlkBlock {
+                    data,
+                    start,
+                    end:   self.position
+                }));
+            }
+
+            // end of this file; we reopen next time to see appended data
+            self.reader = None;
+
+            let next_file = self.dir.join(blk_file_name(self.position.file_number + 1));
+            if !next_file.exists() {
+                return Ok(None);
+            }
+            self.position = BlkPosition {
+                file_number: self.position.file_number + 1,
+                file_offset: 0
+            };
+        }
+    }
+
+    /// Marks all blocks before `position` as stored
+    ///
+    /// The checkpoint is written every `checkpoint_interval` commits
+    pub fn commit(&mut self, position: BlkPosition) -> io::Result<()> {
+
+        self.checkpoint   = position;
+        self.uncommitted += 1;
+
+        if self.uncommitted >= self.checkpoint_interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the last committed position to the checkpoint file
+    pub fn flush(&mut self) -> io::Result<()> {
+
+        self.uncommitted = 0;
+
+        if let Some(ref checkpoint_file) = self.checkpoint_file {
+            write_checkpoint(checkpoint_file, self.checkpoint)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Sequential writer of blk files
+pub struct Exporter {
+    dir:           PathBuf,
+    writer:        Option<io::BufWriter<File>>,
+    position:      BlkPosition,
+    max_file_size: u64
+}
+
+impl Exporter {
+
+    /// Creates an exporter that writes to `dir`, starting with the first file
+    ///
+    /// Existing blk files in `dir` are overwritten
+    pub fn new<P: AsRef<Path>>(dir: P) -> io::Result<Exporter> {
+
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        Ok(Exporter {
+            dir,
+            writer:        None,
+            position:      BlkPosition::default(),
+            max_file_size: MAX_BLK_FILE_SIZE
+        })
+    }
+
+    /// Sets the size after which a new file is started
+    pub fn set_max_file_size(&mut self, max_file_size: u64) {
+        self.max_file_size = max_file_size;
+    }
+
+    /// The position of the next block to write
+    pub fn position(&self) -> BlkPosition {
+        self.position
+    }
+
+    /// Appends the block and returns its position
+    ///
+    /// A block that doesn't fit in the current file is written to the next one, unless the
+    /// current file is empty
+    pub fn write_block(&mut self, block: &[u8]) -> io::Result<BlkPosition> {
+
+        let size = 8 + block.len() as u64;
+
+        if self.position.file_offset > 0 && self.position.file_offset + size > self.max_file_size {
+            self.finish_file()?;
+            self.position = BlkPosition {
+                file_number: self.position.file_number + 1,
+                file_offset: 0
+            };
+        }
+
+        if self.writer.is_none() {
+            let file = File::create(self.dir.join(blk_file_name(self.position.file_number)))?;
+            self.writer = Some(io::BufWriter::new(file));
+        }
+
+        let start = self.position;
+        {
+            let writer = self.writer.as_mut().unwrap();
+            writer.write_u32::<LittleEndian>(MAGIC)?;
+            writer.write_u32::<LittleEndian>(block.len() as u32)?;
+            writer.write_all(block)?;
+        }
+        self.position.file_offset += size;
+
+        Ok(start)
+    }
+
+    /// Flushes and syncs the current file
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.finish_file()
+    }
+
+    fn finish_file(&mut self) -> io::Result<()> {
+
+        if let Some(writer) = self.writer.take() {
+            let file = writer.into_inner().map_err(|err| err.into_error())?;
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Reads a block from a blk_file as used by bitcoin-core and various other implementations
+///
+/// Returns the offset of the block's magic number and the block, or None at the end of the file
+fn read_block(rdr: &mut dyn Read, mut offset: u64) -> io::Result<Option<(u64, Vec<u8>)>> {
+
+    loop {
+        match rdr.read_u32::<LittleEndian>() {
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+
+            // Core preallocates the files with zeros, and zeros have also been seen
+            // before a magic number (bitcrust-1 at block 451327, blk000760 pos 54391594)
+            Ok(0)     => offset += 4,
+            Ok(MAGIC) => break,
+            Ok(_)     => return Err(io::Error::new(io::ErrorKind::InvalidData, "Incorrect magic number"))
+        }
+    }
+
+    let length = match rdr.read_u32::<LittleEndian>() {
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        other => other?
+    };
+
+    // the length is read from the file, so we check it before allocating
+    if length > MAX_BLOCK_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Block length exceeds the maximum block size"));
+    }
+
+    let mut buffer = vec![0; length as usize];
+    match rdr.read_exact(&mut buffer) {
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+        Ok(_)  => Ok(Some((offset, buffer)))
+    }
+}
+
+fn read_checkpoint(path: &Path) -> io::Result<BlkPosition> {
+
+    let mut content = String::new();
+    File::open(path)?.read_to_string(&mut content)?;
+
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid checkpoint file");
+    let mut parts = content.split_whitespace();
+
+    let file_number = parts.next().and_then(|s| s.parse().ok()).ok_or_else(invalid)?;
+    let file_offset = parts.next().and_then(|s| s.parse().ok()).ok_or_else(invalid)?;
+
+    Ok(BlkPosition { file_number, file_offset })
+}
+
+// The checkpoint is written to a temporary file and renamed, so a crash
+// never leaves a partially written checkpoint
+fn write_checkpoint(path: &Path, position: BlkPosition) -> io::Result<()> {
+
+    let tmp = path.with_extension("tmp");
+    {
+        let mut file = File::create(&tmp)?;
+        writeln!(file, "{} {}", position.file_number, position.file_offset)?;
+        file.sync_all()?;
+    }
+    fs::rename(tmp, path)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("blkfile-{}-{}", name, ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_blk_file(dir: &Path, file_number: u32, blocks: &[Vec<u8>], padding: usize) {
+        let mut file = File::create(dir.join(blk_file_name(file_number))).unwrap();
+        for block in blocks {
+            file.write_u32::<LittleEndian>(MAGIC).unwrap();
+            file.write_u32::<LittleEndian>(block.len() as u32).unwrap();
+            file.write_all(block).unwrap();
+        }
+        file.write_all(&vec![0; padding]).unwrap();
+    }
+
+    #[test]
+    fn test_read_files() {
+        let dir = test_dir("read");
+        write_blk_file(&dir, 0, &[vec![1; 10], vec![2; 20]], 16);
+        write_blk_file(&dir, 1, &[vec![3; 30]], 0);
+
+        let mut importer = Importer::new(&dir);
+        assert_eq!(importer.progress().bytes_total, 2 * 8 + 30 + 16 + 8 + 30);
+
+        let b1 = importer.next_block().unwrap().unwrap();
+        assert_eq!(b1.data, vec![1; 10]);
+        assert_eq!(b1.start, BlkPosition { file_number: 0, file_offset: 0 });
+        assert_eq!(b1.end,   BlkPosition { file_number: 0, file_offset: 18 });
+
+        let b2 = importer.next_block().unwrap().unwrap();
+        assert_eq!(b2.data, vec![2; 20]);
+
+        let b3 = importer.next_block().unwrap().unwrap();
+        assert_eq!(b3.data, vec![3; 30]);
+        assert_eq!(b3.start, BlkPosition { file_number: 1, file_offset: 0 });
+
+        assert!(importer.next_block().unwrap().is_none());
+        assert_eq!(importer.progress().blocks, 3);
+        assert_eq!(importer.progress().bytes_done, importer.progress().bytes_total);
+
+        // appended blocks are found on the next call
+        write_blk_file(&dir, 1, &[vec![3; 30], vec![4; 40]], 0);
+        assert_eq!(importer.next_block().unwrap().unwrap().data, vec![4; 40]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_seek() {
+        let dir = test_dir("seek");
+        write_blk_file(&dir, 0, &[vec![1; 10]], 0);
+        write_blk_file(&dir, 1, &[vec![2; 20], vec![3; 30]], 0);
+
+        let mut importer = Importer::new(&dir);
+        importer.seek(BlkPosition { file_number: 1, file_offset: 0 });
+        assert_eq!(importer.next_block().unwrap().unwrap().data, vec![2; 20]);
+
+        importer.seek(BlkPosition { file_number: 0, file_offset: 0 });
+        assert_eq!(importer.next_block().unwrap().unwrap().data, vec![1; 10]);
+        assert_eq!(importer.next_block().unwrap().unwrap().data, vec![2; 20]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_incorrect_magic() {
+        let dir = test_dir("magic");
+        fs::write(dir.join(blk_file_name(0)), [1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+
+        let mut importer = Importer::new(&dir);
+        assert_eq!(importer.next_block().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_length_too_large() {
+        let dir = test_dir("length");
+        {
+            let mut file = File::create(dir.join(blk_file_name(0))).unwrap();
+            file.write_u32::<LittleEndian>(MAGIC).unwrap();
+            file.write_u32::<LittleEndian>(0xFFFF_FFFF).unwrap();
+        }
+
+        let mut importer = Importer::new(&dir);
+        assert_eq!(importer.next_block().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_resume() {
+        let dir = test_dir("resume");
+        let checkpoint = dir.join("checkpoint");
+        write_blk_file(&dir, 0, &[vec![1; 10], vec![2; 20], vec![3; 30]], 0);
+
+        {
+            let mut importer = Importer::with_checkpoint(&dir, &checkpoint).unwrap();
+            importer.set_checkpoint_interval(2);
+
+            let b1 = importer.next_block().unwrap().unwrap();
+            importer.commit(b1.end).unwrap();
+            assert!(!checkpoint.exists());
+
+            let b2 = importer.next_block().unwrap().unwrap();
+            importer.commit(b2.end).unwrap();
+
+            // crash after reading the third block
+            let _ = importer.next_block().unwrap().unwrap();
+        }
+
+        let mut importer = Importer::with_checkpoint(&dir, &checkpoint).unwrap();
+        assert_eq!(importer.position(), BlkPosition { file_number: 0, file_offset: 8 + 10 + 8 + 20 });
+        assert_eq!(importer.next_block().unwrap().unwrap().data, vec![3; 30]);
+        assert!(importer.next_block().unwrap().is_none());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_export_rollover() {
+        let dir = test_dir("export");
+        let blocks = vec![vec![1; 10], vec![2; 20], vec![3; 30], vec![4; 100]];
+
+        let mut exporter = Exporter::new(&dir).unwrap();
+        exporter.set_max_file_size(60);
+
+        let positions: Vec<_> = blocks.iter().map(|b| exporter.write_block(b).unwrap()).collect();
+        exporter.finish().unwrap();
+
+        // the third block doesn't fit after the first two; the fourth exceeds the maximum on its own
+        assert_eq!(positions, vec![
+            BlkPosition { file_number: 0, file_offset: 0 },
+            BlkPosition { file_number: 0, file_offset: 18 },
+            BlkPosition { file_number: 1, file_offset: 0 },
+            BlkPosition { file_number: 2, file_offset: 0 }]);
+
+        let mut importer = Importer::new(&dir);
+        for block in blocks {
+            assert_eq!(importer.next_block().unwrap().unwrap().data, block);
+        }
+        assert!(importer.next_block().unwrap().is_none());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
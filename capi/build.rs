@@ -0,0 +1,40 @@
+extern crate cbindgen;
+
+use std::env;
+use std::path::Path;
+
+// Generates the header of the C interface into OUT_DIR
+//
+// The header in include/ is only updated if BITCRUST_UPDATE_HEADER is set. A failure of cbindgen
+// doesn't fail the build, as the library itself doesn't need the header.
+fn main() {
+
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out = env::var("OUT_DIR").unwrap();
+    let src = Path::new(&dir).join("../src/capi.rs");
+
+    println!("cargo:rerun-if-changed={}", src.display());
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed=BITCRUST_UPDATE_HEADER");
+
+    let header = cbindgen::Config::from_file(Path::new(&dir).join("cbindgen.toml"))
+        .map_err(|err| err.to_string())
+        .and_then(|config| cbindgen::Builder::new()
+            .with_config(config)
+            .with_src(&src)
+            .generate()
+            .map_err(|err| err.to_string()));
+
+    let header = match header {
+        Ok(header) => header,
+        Err(err)   => {
+            println!("cargo:warning=Unable to generate the C header: {}", err);
+            return;
+        }
+    };
+
+    header.write_to_file(Path::new(&out).join("bitcrust.h"));
+    if env::var_os("BITCRUST_UPDATE_HEADER").is_some() {
+        header.write_to_file(Path::new(&dir).join("../include/bitcrust.h"));
+    }
+}
@@ -0,0 +1,9 @@
+//! Shared library with the C interface to bitcrust-db
+//!
+//! The functions are defined in `bitcrust_lib::capi`; this crate only builds them as a cdylib,
+//! such that building the library itself doesn't require libbitcoinconsensus to link.
+
+
+extern crate bitcrust_lib;
+
+pub use bitcrust_lib::capi::*;
@@ -0,0 +1,12 @@
+#![no_main]
+
+use bitcrust_lib::block::Block;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(block) = Block::new(data) {
+        for tx in block.txs.iter() {
+            let _ = tx.verify_syntax();
+        }
+    }
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use bitcrust_lib::buffer::{Buffer, Parse};
+use bitcrust_lib::transaction::Transaction;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(tx) = Transaction::parse(&mut Buffer::new(data)) {
+        let _ = tx.verify_syntax();
+        let _ = tx.get_relative_locks();
+    }
+});
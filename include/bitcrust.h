@@ -0,0 +1,142 @@
+#ifndef BITCRUST_H
+#define BITCRUST_H
+
+/* Generated by cbindgen from src/capi.rs; do not edit */
+
+#include <stddef.h>
+#include <stdint.h>
+
+/**
+ * Result code of the bitcrust functions
+ */
+typedef enum bitcrust_result {
+  BITCRUST_RESULT_OK = 0,
+  /**
+   * A required pointer argument is NULL
+   */
+  BITCRUST_RESULT_NULL_POINTER = 1,
+  /**
+   * The block or transaction could not be parsed
+   */
+  BITCRUST_RESULT_PARSE_ERROR = 2,
+  /**
+   * The block or transaction violates a consensus rule
+   */
+  BITCRUST_RESULT_INVALID = 3,
+  /**
+   * The requested block or transaction is not stored
+   */
+  BITCRUST_RESULT_NOT_FOUND = 4,
+  /**
+   * The output buffer is too small; the required size is written to `out_len`
+   */
+  BITCRUST_RESULT_BUFFER_TOO_SMALL = 5,
+  /**
+   * An internal error occurred
+   */
+  BITCRUST_RESULT_PANIC = 6,
+  /**
+   * The block or transaction is stored, but its transactions have been pruned
+   */
+  BITCRUST_RESULT_PRUNED = 7,
+} bitcrust_result;
+
+/**
+ * An opened store
+ */
+typedef struct bitcrust_store bitcrust_store;
+
+/**
+ * Opens the store in the directory `path`, creating it if needed
+ *
+ * Returns NULL if the store cannot be opened; the store must be closed with `bitcrust_close`
+ *
+ * # Safety
+ *
+ * `path` must be NULL or point to a NUL-terminated string.
+ */
+struct bitcrust_store *bitcrust_open(const char *path);
+
+/**
+ * Closes a store opened with `bitcrust_open`; does nothing if `store` is NULL
+ *
+ * # Safety
+ *
+ * `store` must be NULL or a store returned by `bitcrust_open` that is not yet closed. It must
+ * not be used after this call.
+ */
+void bitcrust_close(struct bitcrust_store *store);
+
+/**
+ * Validates and stores a raw block
+ *
+ * # Safety
+ *
+ * `store` must be NULL or an open store that no other thread uses during the call. `data`
+ * must be NULL or point to `len` readable bytes.
+ */
+enum bitcrust_result bitcrust_add_block(struct bitcrust_store *store,
+                                        const uint8_t *data,
+                                        size_t len);
+
+/**
+ * Validates and stores a raw transaction
+ *
+ * # Safety
+ *
+ * `store` and `data` are as for `bitcrust_add_block`: an open store used by a single thread,
+ * and NULL or `len` readable bytes.
+ */
+enum bitcrust_result bitcrust_add_transaction(struct bitcrust_store *store,
+                                              const uint8_t *data,
+                                              size_t len);
+
+/**
+ * Copies the raw transaction with the 32-byte `hash` into `buf`
+ *
+ * The size of the transaction is written to `out_len`. If `buf` is NULL or smaller than the
+ * transaction, `BITCRUST_RESULT_BUFFER_TOO_SMALL` is returned and nothing is copied.
+ *
+ * # Safety
+ *
+ * `store` must be NULL or an open store that no other thread uses during the call. `hash`
+ * must be NULL or point to 32 readable bytes, `buf` must be NULL or point to `buf_len`
+ * writable bytes, and `out_len` must be NULL or point to a writable `size_t`.
+ */
+enum bitcrust_result bitcrust_get_transaction(struct bitcrust_store *store,
+                                              const uint8_t *hash,
+                                              uint8_t *buf,
+                                              size_t buf_len,
+                                              size_t *out_len);
+
+/**
+ * Copies the raw block with the 32-byte `hash` into `buf`
+ *
+ * Only connected blocks are found. The buffer is handled as in `bitcrust_get_transaction`.
+ *
+ * # Safety
+ *
+ * The pointers must be valid as for `bitcrust_get_transaction`.
+ */
+enum bitcrust_result bitcrust_get_block(struct bitcrust_store *store,
+                                        const uint8_t *hash,
+                                        uint8_t *buf,
+                                        size_t buf_len,
+                                        size_t *out_len);
+
+/**
+ * Writes the 32-byte hash and the height of the connected tip with the most work
+ *
+ * Returns `BITCRUST_RESULT_NOT_FOUND` if no block is stored
+ *
+ * # Safety
+ *
+ * `store` must be NULL or an open store that no other thread uses during the call.
+ * `hash_out` must be NULL or point to 32 writable bytes, and `height_out` must be NULL or point
+ * to a writable `uint64_t`.
+ */
+enum bitcrust_result bitcrust_get_best_block(struct bitcrust_store *store,
+                                             uint8_t *hash_out,
+                                             uint64_t *height_out);
+
+#endif /* BITCRUST_H */
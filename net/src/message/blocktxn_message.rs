@@ -0,0 +1,48 @@
+use {Encode, VarInt};
+use super::TransactionMessage;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_implements_types_required_for_protocol() {
+        let m =  BlockTxnMessage::default();
+        assert_eq!(m.name(), "blocktxn");
+        assert_eq!(m.len(), 36);
+    }
+}
+
+///
+/// https://github.com/bitcoin/bips/blob/master/bip-0152.mediawiki
+///
+/// The response to a getblocktxn; contains the requested transactions
+/// in the order of the requested indexes.
+#[derive(Debug, Default, Encode, PartialEq)]
+pub struct BlockTxnMessage {
+    pub block_hash: [u8; 32],
+    #[count]
+    pub transactions: Vec<TransactionMessage>,
+}
+
+impl BlockTxnMessage {
+    #[inline]
+    pub fn len(&self) -> usize {
+        32 + 4 + self.transactions.iter().map(|t| t.len()).sum::<usize>()
+    }
+
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        "blocktxn"
+    }
+
+    pub fn new(block_hash: &[u8], transactions: Vec<TransactionMessage>) -> BlockTxnMessage {
+        debug_assert!(block_hash.len() == 32);
+        let mut a: [u8; 32] = Default::default();
+        a.copy_from_slice(&block_hash);
+        BlockTxnMessage {
+            block_hash: a,
+            transactions: transactions,
+        }
+    }
+}
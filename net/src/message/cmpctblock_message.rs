@@ -0,0 +1,156 @@
+use std::io;
+
+use Encode;
+use VarInt;
+use super::TransactionMessage;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_implements_types_required_for_protocol() {
+        let m =  CmpctBlockMessage::default();
+        assert_eq!(m.name(), "cmpctblock");
+        assert_eq!(m.len(), 96);
+    }
+
+    #[test]
+    fn it_encodes_short_ids_as_six_bytes() {
+        let m = CmpctBlockMessage {
+            short_ids: vec![0x0000_0605_0403_0201],
+            ..Default::default()
+        };
+        let mut encoded = vec![];
+        let _ = m.encode(&mut encoded);
+        assert_eq!(&encoded[88..96], &[1, 1, 2, 3, 4, 5, 6, 0]);
+    }
+
+    #[test]
+    fn it_encodes_prefilled_indexes_differentially() {
+        let m = CmpctBlockMessage {
+            prefilled_transactions: vec![
+                PrefilledTransaction { index: 0, transaction: TransactionMessage::default() },
+                PrefilledTransaction { index: 3, transaction: TransactionMessage::default() },
+            ],
+            ..Default::default()
+        };
+        let mut encoded = vec![];
+        let _ = m.encode(&mut encoded);
+        // header, nonce, no short ids, two prefilled transactions of 10 bytes
+        assert_eq!(encoded[89], 2);
+        assert_eq!(encoded[90], 0);
+        assert_eq!(encoded[90 + 11], 2);
+    }
+
+    #[test]
+    fn it_rejects_unsorted_prefilled_indexes() {
+        let m = CmpctBlockMessage {
+            prefilled_transactions: vec![
+                PrefilledTransaction { index: 3, transaction: TransactionMessage::default() },
+                PrefilledTransaction { index: 3, transaction: TransactionMessage::default() },
+            ],
+            ..Default::default()
+        };
+        let mut encoded = vec![];
+        let err = m.encode(&mut encoded).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
+
+///
+/// https://github.com/bitcoin/bips/blob/master/bip-0152.mediawiki
+///
+/// A compact block contains the header, the 6-byte short transaction ids
+/// of all non-prefilled transactions and a list of prefilled transactions
+/// (normally only the coinbase).
+///
+/// The indexes of the prefilled transactions are absolute here; on the wire
+/// they are differentially encoded.
+#[derive(Debug, Default, PartialEq)]
+pub struct CmpctBlockMessage {
+    pub version: i32,
+    pub previous_block: [u8; 32],
+    pub merkle_root: [u8; 32],
+    pub timestamp: u32,
+    pub bits: u32,
+    pub nonce: u32,
+    pub short_id_nonce: u64,
+    /// Only the lower 48 bits of each short id are used
+    pub short_ids: Vec<u64>,
+    pub prefilled_transactions: Vec<PrefilledTransaction>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PrefilledTransaction {
+    pub index: u64,
+    pub transaction: TransactionMessage,
+}
+
+impl CmpctBlockMessage {
+    #[inline]
+    pub fn len(&self) -> usize {
+        80usize + // header
+        8usize + // short id nonce
+        4usize + // count of short ids
+        6 * self.short_ids.len() + // short ids
+        4usize + // count of prefilled transactions
+        self.prefilled_transactions.iter().map(|p| 4 + p.transaction.len()).sum::<usize>() // prefilled transactions
+    }
+
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        "cmpctblock"
+    }
+
+    pub fn new(version: i32, prev_block: &[u8], merkle_root: &[u8], timestamp: u32,
+               bits: u32, nonce: u32, short_id_nonce: u64, short_ids: Vec<u64>,
+               prefilled_transactions: Vec<PrefilledTransaction>) -> CmpctBlockMessage {
+        debug_assert!(prev_block.len() == 32);
+        let mut a: [u8; 32] = Default::default();
+        a.copy_from_slice(&prev_block);
+        debug_assert!(merkle_root.len() == 32);
+        let mut b: [u8; 32] = Default::default();
+        b.copy_from_slice(&merkle_root);
+        CmpctBlockMessage {
+            version: version,
+            previous_block: a,
+            merkle_root: b,
+            timestamp: timestamp,
+            bits: bits,
+            nonce: nonce,
+            short_id_nonce: short_id_nonce,
+            short_ids: short_ids,
+            prefilled_transactions: prefilled_transactions,
+        }
+    }
+}
+
+impl Encode for CmpctBlockMessage {
+    fn encode(&self, mut buff: &mut Vec<u8>) -> Result<(), io::Error> {
+        self.version.encode(&mut buff)?;
+        self.previous_block.encode(&mut buff)?;
+        self.merkle_root.encode(&mut buff)?;
+        self.timestamp.encode(&mut buff)?;
+        self.bits.encode(&mut buff)?;
+        self.nonce.encode(&mut buff)?;
+        self.short_id_nonce.encode(&mut buff)?;
+
+        VarInt::new(self.short_ids.len() as u64).encode(&mut buff)?;
+        for short_id in self.short_ids.iter() {
+            (*short_id as u32).encode(&mut buff)?;
+            ((*short_id >> 32) as u16).encode(&mut buff)?;
+        }
+
+        VarInt::new(self.prefilled_transactions.len() as u64).encode(&mut buff)?;
+        let mut next_index = 0;
+        for prefilled in self.prefilled_transactions.iter() {
+            let diff = prefilled.index.checked_sub(next_index).ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidInput, "Prefilled transaction indexes must be ascending and unique"))?;
+            VarInt::new(diff).encode(&mut buff)?;
+            prefilled.transaction.encode(&mut buff)?;
+            next_index = prefilled.index + 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+use std::io;
+
+use {Encode, VarInt};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_implements_types_required_for_protocol() {
+        let m =  GetBlockTxnMessage::default();
+        assert_eq!(m.name(), "getblocktxn");
+        assert_eq!(m.len(), 36);
+    }
+
+    #[test]
+    fn it_encodes_indexes_differentially() {
+        let m = GetBlockTxnMessage { block_hash: [0; 32], indexes: vec![1, 2, 5] };
+        let mut encoded = vec![];
+        let _ = m.encode(&mut encoded);
+        assert_eq!(&encoded[32..], &[3, 1, 0, 2]);
+    }
+
+    #[test]
+    fn it_rejects_unsorted_indexes() {
+        let m = GetBlockTxnMessage { block_hash: [0; 32], indexes: vec![2, 1] };
+        let mut encoded = vec![];
+        let err = m.encode(&mut encoded).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
+
+///
+/// https://github.com/bitcoin/bips/blob/master/bip-0152.mediawiki
+///
+/// Requests the transactions of a compact block that could not be
+/// reconstructed from the mempool.
+///
+/// The indexes are absolute here; on the wire they are differentially encoded.
+#[derive(Debug, Default, PartialEq)]
+pub struct GetBlockTxnMessage {
+    pub block_hash: [u8; 32],
+    pub indexes: Vec<u64>,
+}
+
+impl GetBlockTxnMessage {
+    #[inline]
+    pub fn len(&self) -> usize {
+        32 + 4 + (4 * self.indexes.len())
+    }
+
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        "getblocktxn"
+    }
+
+    pub fn new(block_hash: &[u8], indexes: Vec<u64>) -> GetBlockTxnMessage {
+        debug_assert!(block_hash.len() == 32);
+        let mut a: [u8; 32] = Default::default();
+        a.copy_from_slice(&block_hash);
+        GetBlockTxnMessage {
+            block_hash: a,
+            indexes: indexes,
+        }
+    }
+}
+
+impl Encode for GetBlockTxnMessage {
+    fn encode(&self, mut buff: &mut Vec<u8>) -> Result<(), io::Error> {
+        self.block_hash.encode(&mut buff)?;
+        VarInt::new(self.indexes.len() as u64).encode(&mut buff)?;
+        let mut next_index = 0;
+        for index in self.indexes.iter() {
+            let diff = index.checked_sub(next_index).ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidInput, "Transaction indexes must be ascending and unique"))?;
+            VarInt::new(diff).encode(&mut buff)?;
+            next_index = index + 1;
+        }
+        Ok(())
+    }
+}
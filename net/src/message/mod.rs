@@ -4,8 +4,11 @@ use Encode;
 mod version_message;
 mod addr_message;
 mod block_message;
+mod blocktxn_message;
+mod cmpctblock_message;
 mod getdata_message;
 mod getblocks_message;
+mod getblocktxn_message;
 mod getheaders_message;
 mod header_message;
 mod inv_message;
@@ -18,8 +21,11 @@ mod bitcrust;
 pub use self::version_message::VersionMessage;
 pub use self::addr_message::AddrMessage;
 pub use self::block_message::BlockMessage;
+pub use self::blocktxn_message::BlockTxnMessage;
+pub use self::cmpctblock_message::{CmpctBlockMessage, PrefilledTransaction};
 pub use self::getdata_message::GetdataMessage;
 pub use self::getblocks_message::GetblocksMessage;
+pub use self::getblocktxn_message::GetBlockTxnMessage;
 pub use self::getheaders_message::GetheadersMessage;
 pub use self::inv_message::InvMessage;
 pub use self::header_message::HeaderMessage;
@@ -177,6 +183,9 @@ pub enum Message {
     SendHeaders,
     Block(BlockMessage),
     SendCompact(SendCmpctMessage),
+    CompactBlock(CmpctBlockMessage),
+    GetBlockTxn(GetBlockTxnMessage),
+    BlockTxn(BlockTxnMessage),
     GetAddr,
     GetData(GetdataMessage),
     GetBlocks(GetblocksMessage),
@@ -287,6 +296,9 @@ impl Message {
             Message::Inv(ref inv) => packet!(testnet, inv),
             Message::Header(ref headers) => packet!(testnet, headers),
             Message::SendCompact(ref message) => packet!(testnet, message),
+            Message::CompactBlock(ref block) => packet!(testnet, block),
+            Message::GetBlockTxn(ref msg) => packet!(testnet, msg),
+            Message::BlockTxn(ref msg) => packet!(testnet, msg),
             Message::Tx(ref tx) => packet!(testnet, tx),
             Message::NotFound(ref notfound) => packet!(testnet, notfound),
             // Unparsed messages
@@ -6,9 +6,10 @@ use sha2::{Sha256, Digest};
 
 use message::Message;
 use message::{
-    AddrMessage, AuthenticatedBitcrustMessage, BlockMessage, GetdataMessage,
-    GetblocksMessage, GetheadersMessage, HeaderMessage, InvMessage, SendCmpctMessage,
-    TransactionMessage, VersionMessage, NotfoundMessage};
+    AddrMessage, AuthenticatedBitcrustMessage, BlockMessage, BlockTxnMessage, CmpctBlockMessage,
+    GetBlockTxnMessage, GetdataMessage, GetblocksMessage, GetheadersMessage, HeaderMessage,
+    InvMessage, PrefilledTransaction, SendCmpctMessage, TransactionMessage, VersionMessage,
+    NotfoundMessage};
 use inventory_vector::InventoryVector;
 use transactions::{Outpoint, TransactionInput, TransactionOutput, Witness};
 use {BlockHeader, VarInt};
@@ -142,6 +143,9 @@ pub fn message<'a>(i: &'a [u8], name: &String) -> IResult<&'a [u8], Message> {
                 "inv" => inv(raw_message.body),
                 "tx" => transaction(raw_message.body),
                 "block" => block(raw_message.body),
+                "cmpctblock" => cmpct_block(raw_message.body),
+                "getblocktxn" => getblocktxn(raw_message.body),
+                "blocktxn" => blocktxn(raw_message.body),
                 "notfound" => notfound(raw_message.body),
                 // Bitcrust Specific Messages
                 "bcr_pcr" => bitcrust_peer_count_request(raw_message.body),
@@ -265,6 +269,94 @@ named!(block <Message>,
     )
 );
 
+named!(cmpct_block <Message>,
+    do_parse!(
+        version: le_i32 >>
+        prev_block: take!(32) >>
+        merkle_root: take!(32) >>
+        timestamp: le_u32 >>
+        bits: le_u32 >>
+        nonce: le_u32 >>
+        short_id_nonce: le_u64 >>
+        short_id_count: compact_size >>
+        short_ids: count!(short_id, short_id_count as usize) >>
+        prefilled_count: compact_size >>
+        prefilled: count!(prefilled_transaction, prefilled_count as usize) >>
+        (
+            Message::CompactBlock(CmpctBlockMessage::new(
+                version,
+                prev_block,
+                merkle_root,
+                timestamp,
+                bits,
+                nonce,
+                short_id_nonce,
+                short_ids,
+                absolute_prefilled(prefilled),
+            ))
+        )
+    )
+);
+
+// short ids are 6 bytes little endian
+named!(short_id <u64>,
+  do_parse!(
+    low: le_u32 >>
+    high: le_u16 >>
+    ((high as u64) << 32 | low as u64)
+));
+
+named!(prefilled_transaction <PrefilledTransaction>,
+  do_parse!(
+    index: compact_size >>
+    transaction: transaction_message >>
+    (
+        PrefilledTransaction {
+            index: index,
+            transaction: transaction,
+        }
+    )
+));
+
+named!(getblocktxn <Message>,
+  do_parse!(
+    block_hash: take!(32) >>
+    count: compact_size >>
+    indexes: count!(compact_size, count as usize) >>
+    (
+        Message::GetBlockTxn(GetBlockTxnMessage::new(block_hash, absolute_indexes(indexes)))
+    )
+));
+
+named!(blocktxn <Message>,
+  do_parse!(
+    block_hash: take!(32) >>
+    count: compact_size >>
+    transactions: count!(transaction_message, count as usize) >>
+    (
+        Message::BlockTxn(BlockTxnMessage::new(block_hash, transactions))
+    )
+));
+
+// BIP152 encodes each index as the difference with the previous index plus one
+fn absolute_indexes(mut indexes: Vec<u64>) -> Vec<u64> {
+    let mut next_index = 0u64;
+    for index in indexes.iter_mut() {
+        *index = index.saturating_add(next_index);
+        next_index = index.saturating_add(1);
+    }
+    indexes
+}
+
+fn absolute_prefilled(mut prefilled: Vec<PrefilledTransaction>) -> Vec<PrefilledTransaction> {
+    let mut next_index = 0u64;
+    for p in prefilled.iter_mut() {
+        p.index = p.index.saturating_add(next_index);
+        next_index = p.index.saturating_add(1);
+    }
+    prefilled
+}
+
 named!(transaction_message <TransactionMessage> ,
     do_parse!(
         version: le_i32 >>
@@ -1130,6 +1222,44 @@ mod parse_tests {
             assert_eq!(input_message, parsed);
         }
 
+        #[test]
+        fn it_parses_cmpctblock() {
+            let input_message = Message::CompactBlock(CmpctBlockMessage {
+                short_id_nonce: 0x0102030405060708,
+                short_ids: vec![0x0000_FFFF_FFFF_FFFF, 1, 0x0000_1234_5678_9ABC],
+                prefilled_transactions: vec![
+                    PrefilledTransaction { index: 0, transaction: TransactionMessage::default() },
+                    PrefilledTransaction { index: 4, transaction: TransactionMessage::default() },
+                ],
+                ..Default::default()
+            });
+            let encoded = input_message.encode(false);
+            let parsed = message(&encoded, &"".to_string()).unwrap().1;
+            assert_eq!(input_message, parsed);
+        }
+
+        #[test]
+        fn it_parses_getblocktxn() {
+            let input_message = Message::GetBlockTxn(GetBlockTxnMessage {
+                block_hash: [1; 32],
+                indexes: vec![1, 2, 7, 300],
+            });
+            let encoded = input_message.encode(false);
+            let parsed = message(&encoded, &"".to_string()).unwrap().1;
+            assert_eq!(input_message, parsed);
+        }
+
+        #[test]
+        fn it_parses_blocktxn() {
+            let input_message = Message::BlockTxn(BlockTxnMessage {
+                block_hash: [1; 32],
+                transactions: vec![TransactionMessage::default()],
+            });
+            let encoded = input_message.encode(false);
+            let parsed = message(&encoded, &"".to_string()).unwrap().1;
+            assert_eq!(input_message, parsed);
+        }
+
         #[test]
         fn it_parses_getdata() {
             let input_message = Message::GetData(GetdataMessage{inventory: vec![InventoryVector::default()]});
@@ -10,6 +10,40 @@ use config;
 use store;
 use store::Store;
 use block_add;
+use block::BlockError;
+use scheduler::BlockProgress;
+use export;
+use recovery;
+use fsck;
+use utxo;
+use store::best_chain;
+use store::script_index;
+use store::spent_by;
+use header_add;
+use buffer::*;
+use hash::*;
+use transaction::{Transaction, TransactionError, TransactionOk};
+use store::HashIndexGuard;
+
+use std::io;
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
+
+pub use blkfile::{Importer, ImportError, ImportProgress, Exporter};
+pub use export::ExportError;
+pub use header_add::HeaderError;
+pub use recovery::RecoveryReport;
+pub use fsck::{CheckReport, Inconsistency};
+pub use store::prune::{PrunePolicy, PruneReport, Pruner, DEFAULT_PRUNE_DEPTH};
+pub use store::Pruned;
+pub use store::tips::{Tip, TipStatus};
+pub use store::gc::{GcReport, DEFAULT_GC_DEPTH};
+pub use ::snapshot::SnapshotReport;
+pub use utxo::{Utxo, UtxoIter, UtxoReader, UtxoStats};
+pub use store::best_chain::BestChainIter;
+pub use store::script_index::{ScriptOutput, ScriptSpend};
+pub use store::spent_by::SpentBy;
 
 
 
@@ -40,14 +74,309 @@ pub fn add_block(store: &mut store::Store, buffer: &[u8]) {
     block_add::add_block(store, buffer)
 }
 
-pub fn add_transaction(_: &[u8]) {
+/// Validates and stores a block, returning the error if it is invalid
+pub fn validate_block(store: &mut Store, buffer: &[u8]) -> Result<(), BlockError> {
+    block_add::validate_block(store, buffer, &BlockProgress::new())
+}
+
+/// Imports the blocks from bitcoin-core blk*.dat files
+///
+/// Blocks that come in before their parent are stored behind a guard and connected when the
+/// parent arrives, so the checkpoint can be advanced after each block.
+///
+/// `progress` is called after each block; returns the number of blocks imported
+pub fn import_blk_files<F>(store: &mut Store, importer: &mut Importer, mut progress: F)
+    -> Result<u64, ImportError<BlockError>>
+
+    where F: FnMut(&ImportProgress)
+{
+    let mut count = 0;
+
+    while let Some(block) = importer.next_block()? {
+
+        block_add::validate_block(store, &block.data, &BlockProgress::new())
+            .map_err(ImportError::Block)?;
+
+        count += 1;
+        importer.commit(block.end)?;
+        progress(&importer.progress());
+    }
+
+    importer.flush()?;
+    Ok(count)
+}
+
+/// Exports the blocks of the best chain with a height in `heights` to blk*.dat files
+///
+/// Returns the number of blocks written
+pub fn export_blk_files(store: &mut Store, exporter: &mut Exporter, heights: Range<u64>)
+    -> Result<u64, ExportError>
+{
+    match store.tips.get_most_work_tip() {
+        None      => Ok(0),
+        Some(tip) => export::export_chain(store, exporter, tip.block_hash.as_ref(), heights)
+    }
+}
+
+/// Validates and stores a block header without its transactions
+///
+/// The header must be added after its previous header
+pub fn add_header(store: &mut Store, header: &[u8]) -> Result<(), HeaderError> {
+    header_add::add_header(store, header)
+}
+
+/// Returns the hashes of up to `max` blocks of the best header chain that are not yet stored,
+/// lowest first
+pub fn get_missing_bodies(store: &mut Store, max: usize) -> Vec<[u8; 32]> {
+    header_add::get_missing_bodies(store, max)
+        .iter()
+        .map(|hash| *hash.as_ref().0)
+        .collect()
+}
+
+/// Returns the hash of the block at the given height in the best chain, like `getblockhash`
+pub fn get_block_hash(store: &mut Store, height: u64) -> Option<[u8; 32]> {
+    store.best_chain.get_hash(height).map(|hash| *hash.as_ref().0)
+}
+
+/// Returns the height of a connected block, which need not be in the best chain
+pub fn get_block_height(store: &mut Store, hash: &[u8; 32]) -> Option<u64> {
+    best_chain::get_height(store, Hash32(hash))
+}
+
+/// Returns the heights and hashes of the blocks of the best chain, from the given height up to
+/// the tip
+pub fn get_best_chain(store: &mut Store, from: u64) -> BestChainIter {
+    store.best_chain.iter_from(from)
+}
+
+/// Returns the tips of all chains with their status, most work first, like `getchaintips`
+pub fn get_chain_tips(store: &mut Store) -> Vec<Tip> {
+    store.tips.get_all()
+}
+
+/// Opens the store at the given directory, creating it if needed
+///
+/// Data left by an interrupted write is repaired before the store is returned, unless another
+/// store is writing at that moment
+pub fn open(path: &str) -> Store {
+    open_config(config::Config::new(path))
+}
+
+/// Opens the store like `open`, keeping the index of outputs and inputs by script
+///
+/// Blocks stored before the index was enabled are added with `build_script_index`
+pub fn open_with_script_index(path: &str) -> Store {
+    open_config(config::Config::new(path).with_script_index(true))
+}
+
+/// Opens the store like `open`, keeping the index of the input spending each output
+///
+/// Only spends in blocks connected while the index is enabled are found
+pub fn open_with_spent_by_index(path: &str) -> Store {
+    open_config(config::Config::new(path).with_spent_by_index(true))
+}
+
+/// Opens the store like `open`, keeping the MuHash of the unspent outputs as of each block
+///
+/// Without it, `get_utxo_hash` returns None
+pub fn open_with_utxo_hash(path: &str) -> Store {
+    open_config(config::Config::new(path).with_utxo_hash(true))
+}
+
+/// Opens an existing store at the given directory for reading only
+///
+/// Nothing is written to the store; blocks added by other processes are seen. Operations that
+/// write panic
+pub fn open_read_only(path: &str) -> io::Result<Store> {
+    Store::open_read_only(&config::Config::new(path))
+}
+
+fn open_config(cfg: config::Config) -> Store {
+
+    let mut store = Store::new(&cfg);
+
+    match recover(&mut store) {
+        Some(ref report) if !report.is_clean() =>
+            warn!(store.logger, "Store recovered"; "report" => format!("{:?}", report)),
+        Some(_) => {},
+        None    => info!(store.logger, "Recovery skipped; the store is being written")
+    }
+    best_chain::update(&mut store);
+    store
+}
+
+/// Checks the tails of the store and repairs what was left by an interrupted write
+///
+/// This is done by `open`. Returns None without repairing if another store is writing, as its
+/// partly written data can't be told apart from that of a crash
+pub fn recover(store: &mut Store) -> Option<RecoveryReport> {
+
+    let _lock = ::snapshot::try_lock_exclusive(store)?;
+    Some(recovery::recover(store))
+}
+
+/// Walks the entire store and checks that the spend-tree, the indexes and the stored
+/// transactions and headers are consistent
+pub fn check(store: &mut Store) -> CheckReport {
+    fsck::check(store)
+}
+
+/// Checks the store at the given directory as it is on disk
+///
+/// Unlike `open`, this does not repair interrupted writes first. The store must not be in use
+pub fn check_path(path: &str) -> CheckReport {
+
+    let mut store = Store::new(&config::Config::new(path));
+    fsck::check(&mut store)
+}
+
+/// Removes the transactions that are fully spent deeper than `policy.depth` in the best chain
+/// from the tx-index
+///
+/// This can be called while blocks are added with other stores; use a `Pruner` to run it
+/// in the background
+pub fn prune_tx_index(store: &mut Store, policy: &PrunePolicy) -> PruneReport {
+    store::prune::prune_tx_index(store, policy)
+}
+
+/// Prunes the tx-index as `prune_tx_index`, and removes files of the transaction store
+/// while it exceeds the disk budget of the policy
+///
+/// Transactions in removed files can no longer be read; blocks containing them return `Pruned`
+pub fn prune(store: &mut Store, policy: &PrunePolicy) -> PruneReport {
+    store::prune::prune(store, policy)
+}
+
+/// Removes forks and orphan blocks that are more than `depth` blocks behind the best tip
+///
+/// Their blocks can no longer be found, and transactions that are only in these blocks are
+/// removed from the tx-index. Other stores can't write while this runs
+pub fn collect_garbage(store: &mut Store, depth: u64) -> GcReport {
+    store::gc::collect(store, depth)
+}
+
+/// Writes a consistent copy of the store to the directory `target` while it is in use
+///
+/// Writers are paused while the indexes are copied, but not while the transactions are copied.
+/// The snapshot can be opened with `open`
+pub fn snapshot(store: &mut Store, target: &str) -> io::Result<SnapshotReport> {
+    ::snapshot::snapshot(store, Path::new(target))
+}
+
+/// Returns the unspent outputs as of the block with the given hash, in the order of the chain
+///
+/// Returns None if the block is not stored and connected. This scans the spend-tree before
+/// the first output is returned
+pub fn get_utxos<'a>(store: &'a mut Store, hash: &[u8; 32]) -> Option<UtxoIter<'a>> {
+
+    export::get_block(store, Hash32(hash)).map(move |block| UtxoIter::new(store, block))
+}
+
+/// Returns the totals of the unspent outputs as of the block with the given hash, like
+/// `gettxoutsetinfo`
+pub fn get_utxo_stats(store: &mut Store, hash: &[u8; 32]) -> Option<UtxoStats> {
+
+    export::get_block(store, Hash32(hash)).map(|block| utxo::get_stats(store, block))
+}
+
+/// Returns the MuHash of the unspent outputs as of the block with the given hash, which is kept
+/// up to date as blocks are connected
+///
+/// This is the `muhash` of `gettxoutsetinfo` in reverse byte order. Returns None if the block
+/// is not connected, or was connected before the store kept these hashes; see
+/// `open_with_utxo_hash`
+pub fn get_utxo_hash(store: &mut Store, hash: &[u8; 32]) -> Option<[u8; 32]> {
+
+    export::get_block(store, Hash32(hash)).and_then(|block| utxo::get_muhash(store, block))
+}
+
+/// Writes the unspent outputs as of the block with the given hash to a file, which can be
+/// read with `UtxoReader`
+pub fn export_utxo_set(store: &mut Store, hash: &[u8; 32], path: &str) -> Result<UtxoStats, ExportError> {
+
+    let block      = export::get_block(store, Hash32(hash)).ok_or(ExportError::UnknownBlock)?;
+    let mut writer = io::BufWriter::new(::std::fs::File::create(path)?);
+
+    let stats = utxo::export(store, block, &mut writer)?;
+    writer.flush()?;
+    Ok(stats)
+}
+
+/// Returns the outputs of the best chain paying to the script, with the inputs spending them
+///
+/// Returns None if the store was not opened with the script index
+pub fn get_script_outputs(store: &mut Store, script: &[u8]) -> Option<Vec<ScriptOutput>> {
+    script_index::get_outputs(store, script)
+}
+
+/// Adds the blocks stored before the script index was enabled; returns the number of blocks added
+///
+/// This can be interrupted and continues where it stopped. Once built, all stores adding
+/// blocks must be opened with the index
+pub fn build_script_index(store: &mut Store) -> io::Result<usize> {
+
+    let _lock = ::snapshot::lock_writes(store);
+    script_index::build(store)
+}
+
+/// Returns the transaction and input of the best chain that spend output `index` of the
+/// transaction with the given hash, and the block containing them
+///
+/// Returns None if the output is not spent, or the store was not opened with the spent-by index
+pub fn get_spent_by(store: &mut Store, txid: &[u8; 32], index: u32) -> Option<SpentBy> {
+    spent_by::get_spent_by(store, Hash32(txid), index)
+}
+
+/// Validates and stores a transaction that is not (yet) in a block
+///
+/// Inputs spending unknown transactions are accepted; their scripts are verified when the
+/// spent transaction comes in
+pub fn add_transaction(store: &mut Store, buffer: &[u8]) -> Result<(), TransactionError> {
 
+    let tx   = Transaction::parse(&mut Buffer::new(buffer))?;
+    let hash = Hash32Buf::double_sha256(tx.to_raw());
+
+    let _lock = ::snapshot::lock_writes(store);
+    let result = tx.verify_and_store(&mut store.tx_index, &mut store.transactions, false, hash.as_ref())?;
+
+    // compact blocks are reconstructed from the transactions that are not in a block
+    if let TransactionOk::VerifiedAndStored { ptr, .. } = result {
+        store.mempool.add(hash.as_ref(), ptr);
+    }
+    Ok(())
 }
 
+/// Returns the raw transaction with the given hash
+///
+/// Returns `Err(Pruned)` if the file of the transaction has been pruned
+pub fn get_transaction(store: &mut Store, hash: &[u8; 32]) -> Result<Option<Vec<u8>>, Pruned> {
 
+    let ptr = store.tx_index.get(Hash32(hash))
+        .into_iter()
+        .find(|ptr| !ptr.is_guard());
 
-pub fn get_block(_: [u8; 32]) {
+    match ptr {
+        None      => Ok(None),
+        Some(ptr) => store.transactions.read(ptr).map(Some)
+    }
+}
+
+/// Returns the raw block with the given hash if it is stored and connected
+///
+/// Returns `Err(Pruned)` if transactions of the block have been pruned
+pub fn get_block(store: &mut Store, hash: &[u8; 32]) -> Result<Option<Vec<u8>>, Pruned> {
 
+    match export::get_block(store, Hash32(hash)) {
+        None        => Ok(None),
+        Some(block) => export::read_block(store, block).map(Some)
+    }
+}
+
+/// Returns the hash and height of the connected tip with the most work
+pub fn get_best_block(store: &mut Store) -> Option<([u8; 32], u64)> {
+
+    store.tips.get_most_work_tip().map(|tip| (*tip.block_hash.as_ref().0, tip.height))
 }
 
 
@@ -77,4 +406,136 @@ mod tests {
         add_block(&mut store, slice);
 
     }
+
+    #[test]
+    fn test_recover_while_writing() {
+
+        let cfg        = test_cfg!();
+        let path       = cfg.root.to_str().unwrap();
+        let mut writer = open(path);
+
+        // the block header that is being written is not discarded
+        let lock = ::snapshot::lock_writes(&writer);
+        let _    = writer.block_headers.write(&[0; 80]);
+
+        let mut store = open(path);
+        assert_eq!(recover(&mut store), None);
+
+        drop(lock);
+        assert!(recover(&mut store).unwrap().block_header);
+    }
+
+    #[test]
+    fn test_import_blk_files() {
+        use std::fs::File;
+        use std::io::Write;
+        use hash::Hash32Buf;
+        use store::HashIndexGuard;
+
+        tx_builder!(bld);
+
+        let genesis = genesis!();
+        let block1  = blk!(prev = genesis; tx!(bld; coinbase => a));
+        let block2  = blk!(prev = block1;  tx!(bld; coinbase => b));
+
+        let cfg = test_cfg!();
+        let mut store = Store::new(&cfg);
+
+        let dir = cfg.root.join("blocks");
+        ::std::fs::create_dir_all(&dir).unwrap();
+        {
+            let mut file = File::create(dir.join("blk00000.dat")).unwrap();
+            for block in &[&genesis, &block2, &block1] {
+                file.write_all(&[0xf9, 0xbe, 0xb4, 0xd9]).unwrap();
+                file.write_all(&(block.len() as u32).to_le_bytes()).unwrap();
+                file.write_all(block).unwrap();
+            }
+        }
+
+        let checkpoint = cfg.root.join("import-checkpoint");
+        let mut importer = Importer::with_checkpoint(&dir, &checkpoint).unwrap();
+        let mut reported = Vec::new();
+
+        let count = import_blk_files(&mut store, &mut importer, |p| reported.push(p.blocks)).unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(reported, vec![1, 2, 3]);
+
+        for block in &[&genesis, &block1, &block2] {
+            let hash = Hash32Buf::double_sha256(&block[0..80]);
+            assert!(store.block_index.get(hash.as_ref()).iter().any(|ptr| !ptr.is_guard()));
+        }
+
+        // resuming from the checkpoint skips the imported blocks
+        let mut importer = Importer::with_checkpoint(&dir, &checkpoint).unwrap();
+        assert_eq!(import_blk_files(&mut store, &mut importer, |_| {}).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_export_blk_files() {
+        use hash::Hash32Buf;
+        use store::HashIndexGuard;
+
+        tx_builder!(bld);
+
+        // a chain where the last block spends the coinbase of block 1, and a shorter fork
+        let mut blocks = vec![genesis!()];
+        blocks.push(blk!(prev = blocks[0]; tx!(bld; coinbase => b;11)));
+        for n in 0..99 {
+            let mut coinbase = tx!(bld; coinbase => a);
+            coinbase[42..46].copy_from_slice(&[n as u8, 0, 0xFF, 0]);
+            let block = blk!(prev = blocks[blocks.len() - 1]; coinbase);
+            blocks.push(block);
+        }
+        let last = blk!(prev = blocks[blocks.len() - 1];
+            tx!(bld; coinbase => f;12),
+            tx!(bld; b => c,e)
+        );
+        blocks.push(last);
+        let fork = blk!(prev = blocks[1]; tx!(bld; coinbase => g;13));
+
+        let cfg = test_cfg!();
+        let mut store = Store::new(&cfg);
+        for block in blocks.iter() {
+            add_block(&mut store, block);
+        }
+        add_block(&mut store, &fork);
+
+        let dir = cfg.root.join("export");
+        let mut exporter = Exporter::new(&dir).unwrap();
+        exporter.set_max_file_size(2000);
+        assert_eq!(export_blk_files(&mut store, &mut exporter, 0..u64::MAX).unwrap(), 102);
+        assert!(exporter.position().file_number > 0);
+
+        let mut importer = Importer::new(&dir);
+        for block in blocks.iter() {
+            assert_eq!(&importer.next_block().unwrap().unwrap().data, block);
+        }
+        assert!(importer.next_block().unwrap().is_none());
+
+        // the export can be imported in a new store
+        let mut other = Store::new(&test_cfg!());
+        assert_eq!(import_blk_files(&mut other, &mut Importer::new(&dir), |_| {}).unwrap(), 102);
+        let hash = Hash32Buf::double_sha256(&blocks[101][0..80]);
+        assert!(other.block_index.get(hash.as_ref()).iter().any(|ptr| !ptr.is_guard()));
+
+        // export a height range
+        let dir = cfg.root.join("export-range");
+        let mut exporter = Exporter::new(&dir).unwrap();
+        assert_eq!(export_blk_files(&mut store, &mut exporter, 100..200).unwrap(), 2);
+
+        let mut importer = Importer::new(&dir);
+        assert_eq!(importer.next_block().unwrap().unwrap().data, blocks[100]);
+        assert_eq!(importer.next_block().unwrap().unwrap().data, blocks[101]);
+
+        // and the chain of the fork, which leaves the best chain after block 1
+        let dir = cfg.root.join("export-fork");
+        let mut exporter = Exporter::new(&dir).unwrap();
+        let fork_hash = Hash32Buf::double_sha256(&fork[0..80]);
+        assert_eq!(export::export_chain(&mut store, &mut exporter, fork_hash.as_ref(), 1..10).unwrap(), 2);
+
+        let mut importer = Importer::new(&dir);
+        assert_eq!(importer.next_block().unwrap().unwrap().data, blocks[1]);
+        assert_eq!(importer.next_block().unwrap().unwrap().data, fork);
+        assert!(importer.next_block().unwrap().is_none());
+    }
 }
@@ -19,7 +19,7 @@ use transaction::{Transaction, TransactionError};
 const MAX_BLOCK_SIZE: usize =  1_000_000;
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum BlockError {
     NoTransanctions,
     FirstNotCoinbase,
@@ -29,18 +29,21 @@ pub enum BlockError {
 
     IncorrectMerkleRoot,
 
-    UnexpectedEndOfBuffer,
+    ParseError(ParseError),
+
+    /// Validation was cancelled before the block was stored in the spend-tree
+    Cancelled,
 
 
     SpendingError(SpendingError),
     TransactionError(TransactionError)
 }
 
-impl convert::From<EndOfBufferError> for BlockError {
+impl convert::From<ParseError> for BlockError {
 
-    fn from(_: EndOfBufferError) -> BlockError {
+    fn from(err: ParseError) -> BlockError {
 
-        BlockError::UnexpectedEndOfBuffer
+        BlockError::ParseError(err)
     }
 
 }
@@ -88,7 +91,7 @@ pub struct BlockHeader<'a> {
     version:     u32,
     pub prev_hash:   Hash32<'a>,    // TODO should not be pub
     merkle_root: Hash32<'a>,
-    time:        u32,
+    pub time:    u32,
     bits:        u32,
     nonce:       u32,
 
@@ -101,14 +104,14 @@ impl<'a> Block<'a> {
     /// Parses the block from a raw blob
     ///
     /// The transactions will not be parsed yet, and simply stored as a slice
-    pub fn new(raw: &'a [u8]) -> Result<Block<'a>, EndOfBufferError> {
+    pub fn new(raw: &'a [u8]) -> Result<Block<'a>, ParseError> {
         let mut buf = Buffer::new(raw);
 
-        Ok(Block {
+        buf.parse_structure("block", |buf| Ok(Block {
             raw: raw,
-            header: BlockHeader::parse(&mut buf)?,
-            txs: Vec::parse(&mut buf)?
-        })
+            header: BlockHeader::parse(buf)?,
+            txs: Vec::parse(buf)?
+        }))
     }
 
 
@@ -137,12 +140,14 @@ impl<'a> Block<'a> {
 
 impl<'a> Parse<'a> for BlockHeader<'a> {
 
+    const MIN_SIZE: usize = 80;
+
     /// Parses the block-header
-    fn parse(buffer: &mut Buffer<'a>) -> Result<BlockHeader<'a>, EndOfBufferError> {
+    fn parse(buffer: &mut Buffer<'a>) -> Result<BlockHeader<'a>, ParseError> {
 
         let org_buffer = *buffer;
 
-        Ok(BlockHeader {
+        buffer.parse_structure("block-header", |buffer| Ok(BlockHeader {
             version:     u32::parse(buffer)?,
             prev_hash:   Hash32::parse(buffer)?,
             merkle_root: Hash32::parse(buffer)?,
@@ -151,7 +156,15 @@ impl<'a> Parse<'a> for BlockHeader<'a> {
             nonce:       u32::parse(buffer)?,
 
             raw:         buffer.consumed_since(org_buffer).inner
-        })
+        }))
+    }
+}
+
+impl<'a> BlockHeader<'a> {
+
+    /// The target in compact format
+    pub fn bits(&self) -> u32 {
+        self.bits
     }
 }
 
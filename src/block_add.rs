@@ -76,11 +76,20 @@ use store::Store;
 use transaction;
 use transaction::TransactionStats;
 use merkle_tree;
+use finality;
+use snapshot;
+use pow;
+use utxo;
 use block::*;
 use store::Record;
 use store::BlockPtr;
 use store::HashIndexGuard;
 use store::tips;
+use store::best_chain;
+use store::mempool;
+use store::script_index;
+use store::spent_by;
+use scheduler::BlockProgress;
 
 type BlockResult<T> = Result<T, BlockError>;
 
@@ -89,7 +98,7 @@ const PARALLEL_HASHING_THRESHOLD: usize = 10;
 
 
 /// Returns true if the given hash is the hash of a genesis block
-fn is_genesis_block(hash: Hash32) -> bool {
+pub fn is_genesis_block(hash: Hash32) -> bool {
     const HASH_GENESIS: &'static str =
         "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
 
@@ -98,6 +107,34 @@ fn is_genesis_block(hash: Hash32) -> bool {
     genesis.as_ref() == hash
 }
 
+// Verifies the lock-times of the transactions in a block that was just connected to `previous`
+//
+// Like script validation, this is skipped on initial sync
+fn verify_finality(store: &mut Store, previous: BlockPtr, block: BlockPtr) -> BlockResult<()> {
+
+    if !store.initial_sync {
+        let height = get_height(store, previous) + 1;
+        finality::verify_block_finality(store, block, height)?;
+    }
+    Ok(())
+}
+
+// Links a block to its previous block in the spend-tree and verifies its inputs and lock-times
+//
+// If the block turns out to be invalid, the link is removed again so that it isn't seen as part
+// of a chain
+fn link_block(store: &mut Store, previous: BlockPtr, block: BlockPtr) -> BlockResult<()> {
+
+    let result = store.spend_tree.connect_block(&mut store.spend_index, &store.logger, previous, block)
+        .map_err(BlockError::from)
+        .and_then(|_| verify_finality(store, previous, block));
+
+    if result.is_err() {
+        store.spend_tree.disconnect_block(block);
+    }
+    result
+}
+
 // Connects two blocks (A,B) in the spend-tree and then stores the hash of B in the hash-index
 // Connecting the blocks will verify double-spends
 //
@@ -133,8 +170,9 @@ fn connect_block(
 
     // connect this block if not genesis...
     if let Some(previous_block) = previous_block {
-        store.spend_tree.connect_block( &mut store.spend_index, & store.logger, previous_block, this_block) ?;
+        link_block(store, previous_block, this_block)?;
     }
+    block_connected(store, this_block, this_block_hash.as_buf());
 
     // The to_do list contains blocks that are connected to their previous but not yet added to the
     // block-index. Start with the one we just connected;
@@ -193,7 +231,8 @@ fn connect_block(
                 ptr
             );
 
-            store.spend_tree.connect_block(&mut store.spend_index, &store.logger, conn.block, ptr)?;
+            link_block(store, conn.block, ptr)?;
+            block_connected(store, ptr, hash);
 
 
             todo.push(Connection {
@@ -212,6 +251,111 @@ fn connect_block(
 
 
 
+// Returns the header of a block in the spend-tree
+fn get_header(store: &mut Store, block: BlockPtr) -> Vec<u8> {
+
+    let header_ptr = store.spend_tree.get_block_header_ptr(block);
+    store.block_headers.read(header_ptr).to_vec()
+}
+
+fn get_work(header: &[u8]) -> u128 {
+
+    let mut bits = [0; 4];
+    bits.copy_from_slice(&header[72..76]);
+    pow::work_from_compact(u32::from_le_bytes(bits))
+}
+
+// Returns the height and chain work of a connected block
+//
+// These are taken from a tip or the header-index if they have the block; otherwise we walk
+// back until one does, or until genesis
+fn get_height_and_work(store: &mut Store, block: BlockPtr) -> (u64, u128) {
+
+    let mut block  = block;
+    let mut blocks = 0;
+    let mut work   = 0u128;
+    loop {
+        let header = get_header(store, block);
+        let hash   = Hash32Buf::double_sha256(&header);
+
+        let known = store.tips.get(hash.as_ref())
+            .filter(|tip| tip.status != tips::TipStatus::OrphanHeaders)
+            .map(|tip| (tip.height, tip.work))
+            .or_else(|| store.headers.get(hash.as_ref()).map(|rec| (rec.height, rec.work())));
+
+        if let Some((height, known_work)) = known {
+            return (height + blocks, known_work.saturating_add(work));
+        }
+
+        work = work.saturating_add(get_work(&header));
+        match store.spend_tree.get_previous_block(block) {
+            Some(previous) => { block = previous; blocks += 1; },
+            None           => return (blocks, work)
+        }
+    }
+}
+
+// Returns the height of a connected block
+//
+// This is stored in the height index when the block is connected; blocks connected before the
+// index was kept are found from the tips or the header-index
+fn get_height(store: &mut Store, block: BlockPtr) -> u64 {
+
+    let hash = store.get_block_hash(block);
+    match store.best_chain.get_height(hash.as_ref()) {
+        Some(height) => height,
+        None         => get_height_and_work(store, block).0
+    }
+}
+
+// Updates the MuHash, the mempool, the tips, the best chain and the optional indexes for a block
+// that was just connected
+fn block_connected(store: &mut Store, block: BlockPtr, block_hash: Hash32Buf) {
+
+    utxo::connect_block(store, block, block_hash.as_ref());
+    mempool::connect_block(store, block);
+    let height = add_connected_tip(store, block, block_hash);
+    best_chain::connect_block(store, block, block_hash.as_ref(), height);
+    script_index::index_block(store, block, height);
+    spent_by::index_block(store, block, height);
+}
+
+// Replaces the tip of the previous block by one for this block, which was just connected;
+// returns its height
+fn add_connected_tip(store: &mut Store, block: BlockPtr, block_hash: Hash32Buf) -> u64 {
+
+    let header   = get_header(store, block);
+    let previous = Hash32Buf::from_slice(&header[4..36]);
+
+    let (height, work) = match store.spend_tree.get_previous_block(block) {
+        Some(previous) => {
+            let (height, work) = get_height_and_work(store, previous);
+            (height + 1, work.saturating_add(get_work(&header)))
+        },
+        None => (0, get_work(&header))
+    };
+
+    store.tips.add_tip(&tips::Tip::new(block_hash, previous, height, work));
+    height
+}
+
+// Adds a tip for a block that waits for its previous block; if that is an orphan tip too,
+// this block replaces it
+fn add_orphan_tip(store: &mut Store, block: &Block, block_hash: Hash32Buf) {
+
+    let previous = block.header.prev_hash.as_buf();
+    let work     = pow::work_from_compact(block.header.bits());
+
+    let tip = match store.tips.get(previous.as_ref()) {
+        Some(ref prev) if prev.status == tips::TipStatus::OrphanHeaders =>
+            tips::Tip::new_orphan(block_hash, previous, prev.height + 1, prev.work.saturating_add(work)),
+
+        _ => tips::Tip::new_orphan(block_hash, previous, 0, work)
+    };
+    store.tips.add_tip(&tip);
+}
+
+
 /// Returns true if the block is already stored
 fn block_exists(store: & mut Store, block_hash: Hash32) -> bool {
     let ptr = store.block_index.get(block_hash);
@@ -227,15 +371,16 @@ fn block_exists(store: & mut Store, block_hash: Hash32) -> bool {
 ///
 /// Returns a list fileptrs to the transactions
 ///
-fn verify_and_store_transactions(store: &mut Store, block: &Block) -> BlockResult<Vec<Record>> {
+/// Progress is reported per transaction, and cancellation is checked before each transaction
+fn verify_and_store_transactions(store: &mut Store, block: &Block, progress: &BlockProgress) -> BlockResult<Vec<Record>> {
 
     let timer = ::std::time::Instant::now();
 
     // We use chunked parallelization because otherwise we need to clone() the stores on each
     // iteration
     // The main procedure here is to hash and call verify_and_store for each transaction
-    let chunks: Vec<_> =
-        block.txs.par_chunks(PARALLEL_HASHING_THRESHOLD).map(|chunk_tx| {
+    let chunks: Vec<BlockResult<_>> =
+        block.txs.par_chunks(PARALLEL_HASHING_THRESHOLD).map(|chunk_tx| -> BlockResult<_> {
 
         let len = chunk_tx.len();
         let p0  = Instant::now();
@@ -251,6 +396,10 @@ fn verify_and_store_transactions(store: &mut Store, block: &Block) -> BlockResul
 
         for tx in chunk_tx {
 
+            if progress.is_cancelled() {
+                return Err(BlockError::Cancelled);
+            }
+
             let p1  = Instant::now();
 
             let hash = Hash32Buf::double_sha256(tx.to_raw());
@@ -258,7 +407,7 @@ fn verify_and_store_transactions(store: &mut Store, block: &Block) -> BlockResul
 
             let p2  = Instant::now();
 
-            let res = tx.verify_and_store(tx_index, tx_store, store.initial_sync, hash.as_ref()).unwrap();
+            let res = tx.verify_and_store(tx_index, tx_store, store.initial_sync, hash.as_ref())?;
 
             // AlreadyExists and VerifiedAndStored are both ok here;
             // Extract the TxPtr and the stats
@@ -267,17 +416,25 @@ fn verify_and_store_transactions(store: &mut Store, block: &Block) -> BlockResul
                 transaction::TransactionOk::AlreadyExists     {ptr } => (ptr, Default::default())
             };
 
-            records.push(Record::new_transaction(ptr));
+            records.push(if tx.is_coinbase() {
+                Record::new_coinbase(ptr)
+            } else {
+                Record::new_transaction(ptr)
+            });
             for rec in tx.get_output_records(tx_index) {
                 records.push(rec);
             }
 
             chunk_stats = chunk_stats + stats;
             chunk_stats.hashing = chunk_stats.hashing + (p2 - p1);
+
+            progress.add_transaction(tx.txs_in.len());
         }
-        (chunk_stats, (hashes, records))
+        Ok((chunk_stats, (hashes, records)))
     }).collect();
 
+    let chunks = chunks.into_iter().collect::<BlockResult<Vec<_>>>()?;
+
 
     let p3 = Instant::now();
 
@@ -296,7 +453,7 @@ fn verify_and_store_transactions(store: &mut Store, block: &Block) -> BlockResul
 
     // check merkle roots
     let calculated_merkle_root = merkle_tree::get_merkle_root(hashes);
-    block.verify_merkle_root(calculated_merkle_root.as_ref()).unwrap();
+    block.verify_merkle_root(calculated_merkle_root.as_ref())?;
     stats.merkle = Instant::now() - p3;
 
     let elapsed : usize = timer.elapsed().as_secs() as usize * 1000 +
@@ -312,18 +469,66 @@ fn verify_and_store_transactions(store: &mut Store, block: &Block) -> BlockResul
 }
 
 
+/// Verifies the transactions of the block and writes it to the store
+///
+/// The block is stored in the spend-tree, but not yet connected or added to the block-index
+pub fn store_block(store: &mut Store, block: &Block, block_hash: Hash32, progress: &BlockProgress)
+    -> BlockResult<BlockPtr>
+{
+    block.verify_block_size()?;
+    progress.set_tx_count(block.txs.len());
+
+    // check and store the transactions in block_content and check the merkle_root
+    let spend_tree_ptrs = verify_and_store_transactions(store, block, progress)?;
+
+    if progress.is_cancelled() {
+        return Err(BlockError::Cancelled);
+    }
+
+    // store the blockheader in block_content
+    let block_header_ptr = store.block_headers.write( block.header.to_raw());
+
+    // we also store the txcount, although we only use it for a reindex benchmark
+    let _ = store.block_headers.write_fixed( &block.txs.len());
+
+    // store the block in the spend_tree
+
+    let block_ptr       = store.spend_tree.store_block(block_header_ptr, spend_tree_ptrs);
+
+    // the block is no longer missing for header-first sync
+    store.headers.set_has_body(block_hash);
+
+    Ok(block_ptr)
+}
+
+
 /// Validates and stores a block;
 ///
-/// For now; this panics on invalids; but all underlying functions
-/// propagate problems to jere
+/// For now; this panics on invalids; use validate_block to handle these
 pub fn add_block(store: &mut Store, buffer: &[u8]) {
 
+    validate_block(store, buffer, &BlockProgress::new()).unwrap()
+}
+
+/// Validates and stores a block, reporting to `progress`
+///
+/// The validation can be cancelled through `progress` until the transactions are verified;
+/// after that the block is stored in the spend-tree and connected.
+pub fn validate_block(store: &mut Store, buffer: &[u8], progress: &BlockProgress) -> BlockResult<()> {
+
+    let _lock = snapshot::lock_writes(store);
+    validate_block_locked(store, buffer, progress)
+}
+
+/// Validates and stores a block like `validate_block`, for a caller that already holds a lock
+/// on the writes of the store
+pub fn validate_block_locked(store: &mut Store, buffer: &[u8], progress: &BlockProgress) -> BlockResult<()> {
 
     let block_logger = slog::Logger::new(&store.logger, o!());
     info!(block_logger, "add_block - start");
 
     // parse & hash block
-    let block      = Block::new(buffer) .unwrap();
+    let block      = Block::new(buffer)?;
     let block_hash = Hash32Buf::double_sha256( block.header.to_raw());
 
     info!(block_logger, "add_block - hashed"; "hash" => format!("{:?}", block_hash));
@@ -331,23 +536,10 @@ pub fn add_block(store: &mut Store, buffer: &[u8]) {
     // already done?
     if block_exists(store, block_hash.as_ref()) {
         info!(store.logger, "add_block - Block already exists");
-        return;
+        return Ok(());
     }
 
-    block.verify_block_size().unwrap();
-
-    // check and store the transactions in block_content and check the merkle_root
-    let spend_tree_ptrs = verify_and_store_transactions(store, &block).unwrap();
-
-    // store the blockheader in block_content
-    let block_header_ptr = store.block_headers.write( &block.header.to_raw());
-
-    // we also store the txcount, although we only use it for a reindex benchmark
-    let _ = store.block_headers.write_fixed( &block.txs.len());
-
-    // store the block in the spend_tree
-
-    let block_ptr       = store.spend_tree.store_block(block_header_ptr, spend_tree_ptrs);
+    let block_ptr = store_block(store, &block, block_hash.as_ref(), progress)?;
 
 
     if is_genesis_block(block_hash.as_ref()) {
@@ -356,7 +548,7 @@ pub fn add_block(store: &mut Store, buffer: &[u8]) {
 
         // there is None previous block, but we call connect_block anyway as this will also
         // connect to next blocks if they are already in
-        connect_block(store, block_hash.as_ref(), None, block_ptr).unwrap();
+        connect_block(store, block_hash.as_ref(), None, block_ptr)?;
     }
     else {
 
@@ -371,19 +563,20 @@ pub fn add_block(store: &mut Store, buffer: &[u8]) {
         // if it is in, we will connect
         if let Some(previous_block) = previous_block {
 
-            connect_block(store, block_hash.as_ref(), Some(previous_block), block_ptr).unwrap();
+            connect_block(store, block_hash.as_ref(), Some(previous_block), block_ptr)?;
+        }
+        else {
+            add_orphan_tip(store, &block, block_hash);
         }
-
     }
 
-    tips::add_tip(&store.tips, block_hash, None, 0, 0);
-
 
     // TODO verify amounts
     // TODO verify PoW
     // TODO verify header-syntax
 
     info!(block_logger, "add_block - done");
+    Ok(())
 }
 
 
@@ -393,6 +586,7 @@ mod tests {
 
     use store;
     use super::*;
+    use test_chain::TestChain;
 
 
     #[test]
@@ -415,58 +609,184 @@ mod tests {
     }
 
 
+    // Creates a chain of 103 blocks where block 101 spends the matured coinbase of block 1,
+    // and block 102 spends an output of that transaction
+    fn create_spending_chain() -> Vec<Vec<u8>> {
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let block1    = chain.extend(genesis, 1);
+        let base      = chain.extend(block1, 99);
+        let coinbase  = chain.block(block1).coinbase.clone().unwrap();
+        let value     = coinbase.output(0).value;
+
+        let tx1       = chain.spend(&[coinbase.output(0)], &[value / 2, value / 2]);
+        let tx2       = chain.spend(&[tx1.output(0)], &[value / 2]);
+        let block101  = chain.add_block(base, vec![tx1]);
+        let tip       = chain.add_block(block101, vec![tx2]);
+
+        chain.chain(tip).into_iter().map(|block| block.raw.clone()).collect()
+    }
+
     #[test]
     fn test_block_simple() {
 
         let mut store = store::Store::new(& test_cfg!());
 
-        tx_builder!(bld);
+        for block in create_spending_chain() {
+            add_block(&mut store, &block);
+        }
+    }
+
+    #[test]
+    fn test_blocks_reorder() {
+
+        let mut store = store::Store::new(& test_cfg!());
+
+        // genesis, the last two blocks in reverse, the coinbase-only blocks and block 1
+        let blocks = create_spending_chain();
+        add_block(&mut store, &blocks[0]);
+        add_block(&mut store, &blocks[102]);
+        add_block(&mut store, &blocks[101]);
+        for block in &blocks[2..101] {
+            add_block(&mut store, block);
+        }
+        add_block(&mut store, &blocks[1]);
+    }
+
+    #[test]
+    fn test_tips() {
+
+        let mut store = store::Store::new(& test_cfg!());
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let first     = chain.extend(genesis, 1);
+        let main      = chain.extend(first, 2);
+        let fork      = chain.extend(first, 1);
+        let orphans   = chain.extend(main, 2);
 
-        let block0 = genesis!();
+        for block in chain.chain(main) {
+            add_block(&mut store, &block.raw);
+        }
+        add_block(&mut store, &chain.block(fork).raw);
+
+        // the last block comes in before its previous
+        let blocks = chain.chain(orphans);
+        add_block(&mut store, &blocks[5].raw);
+
+        let hash = |id| Hash32Buf::from_slice(&chain.block(id).hash);
+        let all  = store.tips.get_all();
+        assert_eq!(all.iter().map(|tip| (tip.block_hash, tip.height, tip.status)).collect::<Vec<_>>(), vec![
+            (hash(main), 3, tips::TipStatus::Active),
+            (hash(fork), 2, tips::TipStatus::ValidFork),
+            (hash(orphans), 0, tips::TipStatus::OrphanHeaders)
+        ]);
+
+        // all blocks after genesis have the same work
+        let work = all[0].work - all[1].work;
+
+        // when the gap is filled, the orphan tip replaces the main tip
+        add_block(&mut store, &blocks[4].raw);
+
+        let best = store.tips.get_most_work_tip().unwrap();
+        assert_eq!((best.block_hash, best.height), (hash(orphans), 5));
+        assert_eq!(best.work, all[0].work + 2 * work);
+        assert_eq!(store.tips.get_tips().len(), 2);
+    }
 
-        let block1 = blk!(prev = block0;
-            tx!(bld; coinbase => b;11 ),
-            tx!(bld; b => c,e )
-        );
+    // Creates a chain of 102 blocks where the last block spends the coinbase of block 1
+    // with the given relative lock (as sequence number) and lock_time
+    fn create_locked_chain(sequence: u32, lock_time: u32) -> Vec<Vec<u8>> {
 
-        let block2 = blk!(prev = block1;
-            tx!(bld; coinbase => f;12 ),
-            tx!(bld; c => g )
-        );
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let block1    = chain.extend(genesis, 1);
+        let base      = chain.extend(block1, 99);
+        let coinbase  = chain.block(block1).coinbase.clone().unwrap();
 
-        add_block(&mut store, &block0);
-        add_block(&mut store, &block1);
-        add_block(&mut store, &block2);
+        let tx        = chain.spend(&[coinbase.output(0)], &[coinbase.output(0).value])
+            .with_lock_times(sequence, lock_time);
+        let tip       = chain.add_block(base, vec![tx]);
 
+        chain.chain(tip).into_iter().map(|block| block.raw.clone()).collect()
     }
 
     #[test]
-    fn test_blocks_reorder() {
+    fn test_lock_times() {
 
         let mut store = store::Store::new(& test_cfg!());
+        store.initial_sync = false;
+
+        // the input is confirmed 100 blocks deep, and the block is at height 101
+        for block in create_locked_chain(100, 100) {
+            add_block(&mut store, &block);
+        }
+    }
 
-        tx_builder!(bld);
+    #[test]
+    #[should_panic(expected = "SequenceLockHeightNotMet")]
+    fn test_relative_lock_not_met() {
 
-        let block0 = genesis!();
-        let block1 = blk!(prev = block0;
-            tx!(bld; coinbase => b;11 ),
-            tx!(bld; b => c,e )
-        );
+        let mut store = store::Store::new(& test_cfg!());
+        store.initial_sync = false;
 
-        let block2 = blk!(prev = block1;
-            tx!(bld; coinbase => f;12 ),
-            tx!(bld; c => g )
-        );
+        for block in create_locked_chain(101, 0) {
+            add_block(&mut store, &block);
+        }
+    }
 
-        //        add_block(&mut store, &block0);
+    #[test]
+    #[should_panic(expected = "LockTimeNotFinal")]
+    fn test_lock_time_not_final() {
 
-        println!("block1 = {:?}", block1);
-        //println!("tx1 = {:?}", ::hash::Hash32Buf::double_sha256(&tx1));
-        add_block(&mut store, &block0);
-        add_block(&mut store, &block2);
-        add_block(&mut store, &block1);
+        let mut store = store::Store::new(& test_cfg!());
+        store.initial_sync = false;
 
+        for block in create_locked_chain(0, 101) {
+            add_block(&mut store, &block);
+        }
     }
 
-}
+    #[test]
+    fn test_lock_time_unlinked() {
+
+        let mut store = store::Store::new(& test_cfg!());
+        store.initial_sync = false;
+
+        let blocks = create_locked_chain(0, 102);
+        for block in &blocks[..blocks.len() - 1] {
+            add_block(&mut store, block);
+        }
 
+        // the block failing its lock-time is not left linked to its parent
+        let end    = store.spend_tree.get_end();
+        let result = validate_block(&mut store, &blocks[blocks.len() - 1], &BlockProgress::new());
+        assert!(result.is_err());
+
+        let (stored, _) = store.spend_tree.get_blocks_from(end);
+        assert_eq!(stored.len(), 1);
+        assert_eq!(store.spend_tree.get_previous_block(stored[0]), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ImmatureCoinbase")]
+    fn test_immature_coinbase() {
+
+        let mut store = store::Store::new(& test_cfg!());
+
+        // the coinbase of block 1 is spent at height 100
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let block1    = chain.extend(genesis, 1);
+        let base      = chain.extend(block1, 98);
+        let coinbase  = chain.block(block1).coinbase.clone().unwrap();
+
+        let tx        = chain.spend(&[coinbase.output(0)], &[coinbase.output(0).value]);
+        let tip       = chain.add_block(base, vec![tx]);
+
+        for block in chain.chain(tip) {
+            add_block(&mut store, &block.raw);
+        }
+    }
+}
@@ -1,19 +1,80 @@
 //! A buffer is used for decoding raw bytes
 //!
-//! It is represented by a copyable slice
+//! It is represented by a copyable slice, together with its offset in the slice it was
+//! created from, such that parse errors can report their position.
+//!
+//! Counts read from the buffer are checked against the remaining bytes before they are used
+//! for allocation; each `Parse` type declares the minimum size of its encoding for this.
 //!
 //! This is normally imported as buffer::* such that the
 //! pub's can be considered to be in the global namespace
 
+use std::fmt;
 use std::mem;
 use std::marker;
 
-#[derive(Debug)]
-pub struct EndOfBufferError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseErrorKind {
+    EndOfBuffer,
+
+    /// A compact-size count of items that can't fit in the remaining buffer
+    CountTooLarge(usize)
+}
+
+/// Error returned when parsing fails
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParseError {
+    pub kind:      ParseErrorKind,
+
+    /// Offset in the original buffer at which the failing read started
+    pub offset:    usize,
+
+    /// The innermost structure being parsed; None if a bare primitive was parsed
+    pub structure: Option<&'static str>
+}
+
+impl ParseError {
+
+    fn new(kind: ParseErrorKind, offset: usize) -> ParseError {
+        ParseError {
+            kind,
+            offset,
+            structure: None
+        }
+    }
+
+    /// Attributes the error to the given structure, unless it is already attributed
+    /// to a structure nested in it
+    pub fn within(self, structure: &'static str) -> ParseError {
+        ParseError {
+            structure: self.structure.or(Some(structure)),
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ParseErrorKind::EndOfBuffer           => write!(fmt, "unexpected end of buffer")?,
+            ParseErrorKind::CountTooLarge(count)  => write!(fmt, "count {} exceeds buffer", count)?
+        }
+        write!(fmt, " at offset {}", self.offset)?;
+        if let Some(structure) = self.structure {
+            write!(fmt, " in {}", structure)?;
+        }
+        Ok(())
+    }
+}
+
 
 #[derive(Clone,Copy,Debug)]
 pub struct Buffer<'a> {
-    pub inner: &'a[u8]
+    pub inner: &'a[u8],
+
+    /// The number of bytes before inner in the original slice
+    offset:    usize
 }
 
 /// Trait implemented for types that can be converted to a raw slice
@@ -30,7 +91,12 @@ pub trait ToRaw<'a>
 pub trait Parse<'a>
     where Self: marker::Sized
 {
-    fn parse(buf: &mut Buffer<'a>) -> Result<Self, EndOfBufferError>;
+    /// The minimum number of bytes of an encoded value
+    ///
+    /// This bounds the counts of vectors of this type, such as inputs and outputs
+    const MIN_SIZE: usize = 1;
+
+    fn parse(buf: &mut Buffer<'a>) -> Result<Self, ParseError>;
 }
 
 
@@ -42,7 +108,8 @@ impl<'a> Buffer<'a> {
     /// The buffer makes the slice Copyable
     pub fn new(slice: &'a[u8]) -> Self {
         Buffer {
-            inner: slice
+            inner:  slice,
+            offset: 0
         }
     }
 
@@ -50,22 +117,39 @@ impl<'a> Buffer<'a> {
         self.inner.len()
     }
 
+    pub fn is_empty(self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// The position of the buffer in the slice it was created from
+    pub fn offset(self) -> usize {
+        self.offset
+    }
+
     /// Returns the buffer of what is contained in `original` that is no longer
     /// in self. That is: returns whatever is consumed since original
     pub fn consumed_since(self, original: Buffer) -> Buffer {
         let len = original.inner.len() - self.inner.len();
         Buffer {
-            inner: &original.inner[..len]
+            inner:  &original.inner[..len],
+            offset: original.offset
         }
     }
 
+    /// Calls `parse` on this buffer and attributes its errors to `structure`
+    pub fn parse_structure<T, F>(&mut self, structure: &'static str, parse: F) -> Result<T, ParseError>
+        where F: FnOnce(&mut Buffer<'a>) -> Result<T, ParseError>
+    {
+        parse(self).map_err(|err| err.within(structure))
+    }
+
     /// Reads a compact-size prefixed vector (like Vec::parse), but also returns a
     /// vector if indices since the start of the buffer
-    pub fn parse_vec_with_indices<T>(&mut self, original: Buffer) -> Result<(Vec<T>,Vec<u32>), EndOfBufferError>
+    pub fn parse_vec_with_indices<T>(&mut self, original: Buffer) -> Result<(Vec<T>,Vec<u32>), ParseError>
         where T: Parse<'a> {
 
         let original_len = original.inner.len() as u32;
-        let count = self.parse_compact_size()?;
+        let count = self.parse_count(T::MIN_SIZE)?;
         let mut result:     Vec<T>   = Vec::with_capacity(count);
         let mut result_idx: Vec<u32> = Vec::with_capacity(count);
         for _ in 0..count {
@@ -78,7 +162,7 @@ impl<'a> Buffer<'a> {
 
     /// Parse a compact size
     /// This can be 1-8 bytes; see bitcoin-spec for details
-    pub fn parse_compact_size(&mut self) -> Result<usize, EndOfBufferError> {
+    pub fn parse_compact_size(&mut self) -> Result<usize, ParseError> {
         let byte1 = { u8::parse(self)? };
         Ok(match byte1 {
             0xff => { u64::parse(self)? as usize },
@@ -88,20 +172,35 @@ impl<'a> Buffer<'a> {
         })
     }
 
+    /// Parses a compact-size count of items that take at least `min_size` bytes each
+    ///
+    /// Fails if the remaining buffer can't contain that many items; this ensures
+    /// the count can safely be used to allocate
+    pub fn parse_count(&mut self, min_size: usize) -> Result<usize, ParseError> {
+        let offset = self.offset;
+        let count  = self.parse_compact_size()?;
+
+        if count > self.inner.len() / min_size.max(1) {
+            return Err(ParseError::new(ParseErrorKind::CountTooLarge(count), offset));
+        }
+        Ok(count)
+    }
+
     /// Parses given amount of bytes
-    pub fn parse_bytes(&mut self, count: usize) -> Result<&'a[u8], EndOfBufferError> {
+    pub fn parse_bytes(&mut self, count: usize) -> Result<&'a[u8], ParseError> {
         if self.inner.len() < count {
-            return Err(EndOfBufferError);
+            return Err(ParseError::new(ParseErrorKind::EndOfBuffer, self.offset));
         }
 
         // split in result, and remaining
         let result = &self.inner[..count];
-        self.inner = &self.inner[count..];
+        self.inner   = &self.inner[count..];
+        self.offset += count;
 
         Ok(result)
     }
 
-    pub fn parse_compact_size_bytes(&mut self) -> Result<&'a[u8], EndOfBufferError> {
+    pub fn parse_compact_size_bytes(&mut self) -> Result<&'a[u8], ParseError> {
         let count = self.parse_compact_size()?;
 
         self.parse_bytes(count)
@@ -114,9 +213,9 @@ impl<'a> Buffer<'a> {
 impl<'a, T : Parse<'a>> Parse<'a> for Vec<T> {
 
     /// Parses a compact-size prefix vector of parsable stuff
-    fn parse(buffer: &mut Buffer<'a>) -> Result<Vec<T>, EndOfBufferError> {
+    fn parse(buffer: &mut Buffer<'a>) -> Result<Vec<T>, ParseError> {
 
-        let count = buffer.parse_compact_size()?;
+        let count = buffer.parse_count(T::MIN_SIZE)?;
         let mut result: Vec<T> = Vec::with_capacity(count);
         for _ in 0..count {
             result.push(T::parse(buffer)?);
@@ -125,6 +224,14 @@ impl<'a, T : Parse<'a>> Parse<'a> for Vec<T> {
     }
 }
 
+/// A compact-size prefixed byte string, such as a script or a witness item
+impl<'a> Parse<'a> for &'a [u8] {
+
+    fn parse(buffer: &mut Buffer<'a>) -> Result<&'a [u8], ParseError> {
+        buffer.parse_compact_size_bytes()
+    }
+}
+
 
 
 
@@ -133,19 +240,18 @@ macro_rules! impl_parse_primitive {
 
     (
         impl<'a> Parse<'a> for $prim_type {
-            fn parse(buffer: &mut Buffer<'a>) -> Result<$prim_type, EndOfBufferError> {
-                let sz = mem::size_of::<$prim_type>();
-                if buffer.inner.len() < sz {
-                    return Err(EndOfBufferError);
-                }
+
+            const MIN_SIZE: usize = mem::size_of::<$prim_type>();
+
+            fn parse(buffer: &mut Buffer<'a>) -> Result<$prim_type, ParseError> {
+                let sz    = mem::size_of::<$prim_type>();
+                let bytes = buffer.parse_bytes(sz)?;
 
                 // Shift-n-fold
                 let result = (0..sz)
-                    .map(|n| (buffer.inner[n] as $prim_type) << (8* n) )
+                    .map(|n| (bytes[n] as $prim_type) << (8* n) )
                     .fold(0, |a,b| a | b);
 
-                buffer.inner = &buffer.inner[sz..];
-
                 Ok(result)
             }
         }
@@ -169,14 +275,59 @@ mod tests
     #[test]
     fn test_primitive() {
         let x = &[0xff_u8, 0x00_u8, 0x00_u8, 0x00_u8];
-        let mut buf = Buffer { inner: x };
+        let mut buf = Buffer::new(x);
         let org_buf = buf;
 
         assert_eq!(u32::parse(&mut buf).unwrap(), 0xff_u32);
 
         assert_eq!(buf.len(), 0);
+        assert_eq!(buf.offset(), 4);
         assert_eq!(buf.consumed_since(org_buf).len(), 4);
     }
+
+    #[test]
+    fn test_end_of_buffer() {
+        let x = &[1, 2, 3, 4, 5, 6];
+        let mut buf = Buffer::new(x);
+
+        u32::parse(&mut buf).unwrap();
+        assert_eq!(u32::parse(&mut buf).unwrap_err(), ParseError {
+            kind:      ParseErrorKind::EndOfBuffer,
+            offset:    4,
+            structure: None
+        });
+
+        let err = buf.parse_structure("pair", |buf| u32::parse(buf)).unwrap_err();
+        assert_eq!(err.structure, Some("pair"));
+        assert_eq!(err.within("outer").structure, Some("pair"));
+        assert_eq!(format!("{}", err), "unexpected end of buffer at offset 4 in pair");
+    }
+
+    #[test]
+    fn test_count_too_large() {
+
+        // a huge count must fail before allocating
+        let x = &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f, 1, 2];
+        assert_eq!(Vec::<u8>::parse(&mut Buffer::new(x)).unwrap_err().kind,
+            ParseErrorKind::CountTooLarge(0x7fff_ffff_ffff_ffff));
+
+        // counts are checked against the minimum size of the items
+        let x = &[3, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0];
+        assert_eq!(Vec::<u32>::parse(&mut Buffer::new(x)).unwrap_err().kind,
+            ParseErrorKind::CountTooLarge(3));
+        assert_eq!(Vec::<u32>::parse(&mut Buffer::new(&x[0..9])).unwrap_err().kind,
+            ParseErrorKind::CountTooLarge(3));
+
+        let x = &[2, 1, 0, 0, 0, 2, 0, 0, 0];
+        assert_eq!(Vec::<u32>::parse(&mut Buffer::new(x)).unwrap(), vec![1, 2]);
+
+        // witness stacks: each item takes at least its length byte
+        let x = &[1, 5, 0, 0];
+        assert_eq!(Vec::<Vec<&[u8]>>::parse(&mut Buffer::new(x)).unwrap_err().kind,
+            ParseErrorKind::CountTooLarge(5));
+        let x = &[1, 2, 1, 7, 0];
+        assert_eq!(Vec::<Vec<&[u8]>>::parse(&mut Buffer::new(x)).unwrap(), vec![vec![&[7u8][..], &[]]]);
+    }
 }
 
 
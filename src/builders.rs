@@ -158,8 +158,8 @@ macro_rules! blk {
     {
         let mut block: Vec<u8> = vec![1_u8,0_u8,0_u8,0_u8]; // block version = 1
 
-        // hash of previous block
-        let hash = ::hash::Hash32Buf::double_sha256(& $prev);
+        // hash of previous block header
+        let hash = ::hash::Hash32Buf::double_sha256(& $prev[0..80]);
         block.extend(hash.as_ref().0.iter());
 
         // calculate merkle root
@@ -0,0 +1,344 @@
+//! C interface to bitcrust-db
+//!
+//! These functions are exported by the shared library built by the `capi` crate. Its build
+//! script generates the header `include/bitcrust.h` from this file.
+//!
+//! Hashes are passed as 32 bytes in the order in which they are hashed, which is the reverse
+//! of the usual hexadecimal notation. Panics are caught at the boundary and reported as
+//! `BITCRUST_RESULT_PANIC`; the store should be closed afterwards.
+
+
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use api;
+use block::BlockError;
+use store::{Pruned, Store};
+use transaction::TransactionError;
+
+
+/// Result code of the bitcrust functions
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitcrustResult {
+    Ok = 0,
+
+    /// A required pointer argument is NULL
+    NullPointer = 1,
+
+    /// The block or transaction could not be parsed
+    ParseError = 2,
+
+    /// The block or transaction violates a consensus rule
+    Invalid = 3,
+
+    /// The requested block or transaction is not stored
+    NotFound = 4,
+
+    /// The output buffer is too small; the required size is written to `out_len`
+    BufferTooSmall = 5,
+
+    /// An internal error occurred
+    Panic = 6,
+
+    /// The block or transaction is stored, but its transactions have been pruned
+    Pruned = 7
+}
+
+/// An opened store
+pub struct BitcrustStore {
+    store: Store
+}
+
+impl From<BlockError> for BitcrustResult {
+    fn from(err: BlockError) -> BitcrustResult {
+        match err {
+            BlockError::ParseError(_)         => BitcrustResult::ParseError,
+            BlockError::TransactionError(err) => err.into(),
+            _                                 => BitcrustResult::Invalid
+        }
+    }
+}
+
+impl From<TransactionError> for BitcrustResult {
+    fn from(err: TransactionError) -> BitcrustResult {
+        match err {
+            TransactionError::ParseError(_) => BitcrustResult::ParseError,
+            _                               => BitcrustResult::Invalid
+        }
+    }
+}
+
+
+// Runs `f` on the store, catching panics
+fn with_store<F>(store: *mut BitcrustStore, f: F) -> BitcrustResult
+    where F: FnOnce(&mut Store) -> BitcrustResult
+{
+    if store.is_null() {
+        return BitcrustResult::NullPointer;
+    }
+    let store = unsafe { &mut (*store).store };
+
+    panic::catch_unwind(AssertUnwindSafe(|| f(store)))
+        .unwrap_or(BitcrustResult::Panic)
+}
+
+// Copies `data` to the output buffer, or only sets the length if the buffer is too small
+unsafe fn write_output(data: &[u8], buf: *mut u8, buf_len: usize, out_len: *mut usize) -> BitcrustResult {
+
+    if out_len.is_null() {
+        return BitcrustResult::NullPointer;
+    }
+    *out_len = data.len();
+
+    if buf.is_null() || buf_len < data.len() {
+        return BitcrustResult::BufferTooSmall;
+    }
+    ptr::copy_nonoverlapping(data.as_ptr(), buf, data.len());
+    BitcrustResult::Ok
+}
+
+
+/// Opens the store in the directory `path`, creating it if needed
+///
+/// Returns NULL if the store cannot be opened; the store must be closed with `bitcrust_close`
+///
+/// # Safety
+///
+/// `path` must be NULL or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn bitcrust_open(path: *const c_char) -> *mut BitcrustStore {
+
+    if path.is_null() {
+        return ptr::null_mut();
+    }
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(_)   => return ptr::null_mut()
+    };
+
+    match panic::catch_unwind(|| api::open(path)) {
+        Ok(store) => Box::into_raw(Box::new(BitcrustStore { store })),
+        Err(_)    => ptr::null_mut()
+    }
+}
+
+/// Closes a store opened with `bitcrust_open`; does nothing if `store` is NULL
+///
+/// # Safety
+///
+/// `store` must be NULL or a store returned by `bitcrust_open` that is not yet closed. It must
+/// not be used after this call.
+#[no_mangle]
+pub unsafe extern "C" fn bitcrust_close(store: *mut BitcrustStore) {
+
+    if !store.is_null() {
+        drop(Box::from_raw(store));
+    }
+}
+
+/// Validates and stores a raw block
+///
+/// # Safety
+///
+/// `store` must be NULL or an open store that no other thread uses during the call. `data`
+/// must be NULL or point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn bitcrust_add_block(store: *mut BitcrustStore, data: *const u8, len: usize)
+    -> BitcrustResult
+{
+    if data.is_null() {
+        return BitcrustResult::NullPointer;
+    }
+    let data = slice::from_raw_parts(data, len);
+
+    with_store(store, |store| match api::validate_block(store, data) {
+        Ok(())   => BitcrustResult::Ok,
+        Err(err) => err.into()
+    })
+}
+
+/// Validates and stores a raw transaction
+///
+/// # Safety
+///
+/// `store` and `data` are as for `bitcrust_add_block`: an open store used by a single thread,
+/// and NULL or `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn bitcrust_add_transaction(store: *mut BitcrustStore, data: *const u8, len: usize)
+    -> BitcrustResult
+{
+    if data.is_null() {
+        return BitcrustResult::NullPointer;
+    }
+    let data = slice::from_raw_parts(data, len);
+
+    with_store(store, |store| match api::add_transaction(store, data) {
+        Ok(())   => BitcrustResult::Ok,
+        Err(err) => err.into()
+    })
+}
+
+/// Copies the raw transaction with the 32-byte `hash` into `buf`
+///
+/// The size of the transaction is written to `out_len`. If `buf` is NULL or smaller than the
+/// transaction, `BITCRUST_RESULT_BUFFER_TOO_SMALL` is returned and nothing is copied.
+///
+/// # Safety
+///
+/// `store` must be NULL or an open store that no other thread uses during the call. `hash`
+/// must be NULL or point to 32 readable bytes, `buf` must be NULL or point to `buf_len`
+/// writable bytes, and `out_len` must be NULL or point to a writable `size_t`.
+#[no_mangle]
+pub unsafe extern "C" fn bitcrust_get_transaction(
+    store:   *mut BitcrustStore,
+    hash:    *const u8,
+    buf:     *mut u8,
+    buf_len: usize,
+    out_len: *mut usize) -> BitcrustResult
+{
+    if hash.is_null() {
+        return BitcrustResult::NullPointer;
+    }
+    let hash = &*(hash as *const [u8; 32]);
+
+    with_store(store, |store| match api::get_transaction(store, hash) {
+        Ok(Some(tx)) => write_output(&tx, buf, buf_len, out_len),
+        Ok(None)     => BitcrustResult::NotFound,
+        Err(Pruned)  => BitcrustResult::Pruned
+    })
+}
+
+/// Copies the raw block with the 32-byte `hash` into `buf`
+///
+/// Only connected blocks are found. The buffer is handled as in `bitcrust_get_transaction`.
+///
+/// # Safety
+///
+/// The pointers must be valid as for `bitcrust_get_transaction`.
+#[no_mangle]
+pub unsafe extern "C" fn bitcrust_get_block(
+    store:   *mut BitcrustStore,
+    hash:    *const u8,
+    buf:     *mut u8,
+    buf_len: usize,
+    out_len: *mut usize) -> BitcrustResult
+{
+    if hash.is_null() {
+        return BitcrustResult::NullPointer;
+    }
+    let hash = &*(hash as *const [u8; 32]);
+
+    with_store(store, |store| match api::get_block(store, hash) {
+        Ok(Some(block)) => write_output(&block, buf, buf_len, out_len),
+        Ok(None)        => BitcrustResult::NotFound,
+        Err(Pruned)     => BitcrustResult::Pruned
+    })
+}
+
+/// Writes the 32-byte hash and the height of the connected tip with the most work
+///
+/// Returns `BITCRUST_RESULT_NOT_FOUND` if no block is stored
+///
+/// # Safety
+///
+/// `store` must be NULL or an open store that no other thread uses during the call.
+/// `hash_out` must be NULL or point to 32 writable bytes, and `height_out` must be NULL or point
+/// to a writable `uint64_t`.
+#[no_mangle]
+pub unsafe extern "C" fn bitcrust_get_best_block(
+    store:      *mut BitcrustStore,
+    hash_out:   *mut u8,
+    height_out: *mut u64) -> BitcrustResult
+{
+    if hash_out.is_null() || height_out.is_null() {
+        return BitcrustResult::NullPointer;
+    }
+
+    with_store(store, |store| match api::get_best_block(store) {
+        Some((hash, height)) => {
+            ptr::copy_nonoverlapping(hash.as_ptr(), hash_out, 32);
+            *height_out = height;
+            BitcrustResult::Ok
+        },
+        None => BitcrustResult::NotFound
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::ffi::CString;
+
+    use super::*;
+    use hash::*;
+    use test_chain::{TestChain, Violation};
+
+    #[test]
+    fn test_c_api() {
+
+        let cfg   = test_cfg!();
+        let path  = CString::new(cfg.root.to_str().unwrap()).unwrap();
+        let store = unsafe { bitcrust_open(path.as_ptr()) };
+        assert!(!store.is_null());
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let tip       = chain.extend(genesis, 2);
+        let invalid   = chain.build_block(tip).violate(Violation::BadMerkleRoot).add();
+
+        let mut hash   = [0u8; 32];
+        let mut height = 0u64;
+        let mut len    = 0usize;
+        let mut buf    = vec![0u8; 1000];
+
+        unsafe {
+            assert_eq!(bitcrust_get_best_block(store, hash.as_mut_ptr(), &mut height), BitcrustResult::NotFound);
+
+            for block in chain.chain(tip) {
+                assert_eq!(bitcrust_add_block(store, block.raw.as_ptr(), block.raw.len()), BitcrustResult::Ok);
+            }
+            let raw = &chain.block(invalid).raw;
+            assert_eq!(bitcrust_add_block(store, raw.as_ptr(), raw.len()), BitcrustResult::Invalid);
+            assert_eq!(bitcrust_add_block(store, raw.as_ptr(), 50), BitcrustResult::ParseError);
+
+            assert_eq!(bitcrust_get_best_block(store, hash.as_mut_ptr(), &mut height), BitcrustResult::Ok);
+            assert_eq!(hash, chain.block(tip).hash);
+            assert_eq!(height, 2);
+
+            // query the size first
+            let block = chain.block(tip);
+            assert_eq!(bitcrust_get_block(store, block.hash.as_ptr(), ptr::null_mut(), 0, &mut len),
+                BitcrustResult::BufferTooSmall);
+            assert_eq!(len, block.raw.len());
+            assert_eq!(bitcrust_get_block(store, block.hash.as_ptr(), buf.as_mut_ptr(), buf.len(), &mut len),
+                BitcrustResult::Ok);
+            assert_eq!(&buf[0..len], &block.raw[..]);
+
+            let unknown = chain.block(invalid).hash;
+            assert_eq!(bitcrust_get_block(store, unknown.as_ptr(), buf.as_mut_ptr(), buf.len(), &mut len),
+                BitcrustResult::NotFound);
+
+            // a loose transaction spending the coinbase of block 1
+            let coinbase = chain.chain(tip)[1].coinbase.clone().unwrap();
+            let tx = chain.spend(&[coinbase.output(0)], &[coinbase.output(0).value]);
+            assert_eq!(bitcrust_add_transaction(store, tx.raw.as_ptr(), tx.raw.len()), BitcrustResult::Ok);
+            assert_eq!(bitcrust_get_transaction(store, tx.txid.as_ptr(), buf.as_mut_ptr(), buf.len(), &mut len),
+                BitcrustResult::Ok);
+            assert_eq!(&buf[0..len], &tx.raw[..]);
+            assert_eq!(bitcrust_get_transaction(store, coinbase.txid.as_ptr(), buf.as_mut_ptr(), buf.len(), &mut len),
+                BitcrustResult::Ok);
+
+            let hash = Hash32Buf::double_sha256(&[]);
+            assert_eq!(bitcrust_get_transaction(store, hash.as_ref().0.as_ptr(), buf.as_mut_ptr(), buf.len(), &mut len),
+                BitcrustResult::NotFound);
+
+            assert_eq!(bitcrust_add_block(ptr::null_mut(), raw.as_ptr(), raw.len()), BitcrustResult::NullPointer);
+            bitcrust_close(store);
+        }
+    }
+}
@@ -0,0 +1,377 @@
+//! Compact blocks (BIP152)
+//!
+//! A compact block contains the header and 6-byte short ids of the transactions, which
+//! are calculated with SipHash-2-4 keyed by the header and a nonce. Only some transactions
+//! (normally just the coinbase) are sent in full as "prefilled" transactions.
+//!
+//! A full block is reconstructed by matching the short ids against the transactions in the
+//! mempool of the store; the indexes of the transactions that can not be found are reported
+//! such that they can be requested with a getblocktxn message.
+//!
+//! https://github.com/bitcoin/bips/blob/master/bip-0152.mediawiki
+
+
+use std::collections::HashMap;
+
+use ring;
+
+use buffer::*;
+use util::write_compact_size;
+use hash::*;
+use block::Block;
+use store::Store;
+
+
+/// Only the lower 6 bytes of the SipHash output are used
+const SHORT_ID_MASK: u64 = 0x0000_FFFF_FFFF_FFFF;
+
+const HEADER_SIZE: usize = 80;
+
+
+#[derive(Debug, PartialEq)]
+pub enum CompactBlockError {
+    /// A prefilled index is out of range or used twice
+    InvalidPrefilledIndex,
+
+    /// The number of transactions given to fill the block doesn't match the missing count
+    IncorrectTransactionCount,
+
+    ParseError(ParseError)
+}
+
+impl From<ParseError> for CompactBlockError {
+    fn from(err: ParseError) -> CompactBlockError {
+        CompactBlockError::ParseError(err)
+    }
+}
+
+pub type CompactBlockResult<T> = Result<T, CompactBlockError>;
+
+
+/// The SipHash key used to calculate the short ids of a single compact block
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShortIdKey {
+    k0: u64,
+    k1: u64
+}
+
+impl ShortIdKey {
+
+    /// The key is the first 16 bytes of SHA256(header || nonce)
+    pub fn new(header: &[u8], nonce: u64) -> ShortIdKey {
+
+        let mut input = header[0..HEADER_SIZE].to_vec();
+        input.extend_from_slice(&nonce.to_le_bytes());
+
+        let digest = ring::digest::digest(&ring::digest::SHA256, &input);
+        let digest = digest.as_ref();
+
+        let mut k0 = [0; 8];
+        let mut k1 = [0; 8];
+        k0.copy_from_slice(&digest[0..8]);
+        k1.copy_from_slice(&digest[8..16]);
+
+        ShortIdKey {
+            k0: u64::from_le_bytes(k0),
+            k1: u64::from_le_bytes(k1)
+        }
+    }
+
+    /// Returns the 6-byte short id of the given transaction hash
+    pub fn short_id(&self, tx_hash: Hash32) -> u64 {
+        siphash24(self.k0, self.k1, tx_hash.0) & SHORT_ID_MASK
+    }
+}
+
+
+/// A block with its transactions referenced by short ids
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactBlock {
+    pub header:    Vec<u8>,
+    pub nonce:     u64,
+    pub short_ids: Vec<u64>,
+
+    /// Prefilled transactions with their absolute index in the block
+    pub prefilled: Vec<(usize, Vec<u8>)>,
+}
+
+impl CompactBlock {
+
+    /// Creates a compact block from a full block; only the coinbase is prefilled
+    pub fn from_block(raw_block: &[u8], nonce: u64) -> CompactBlockResult<CompactBlock> {
+
+        let block  = Block::new(raw_block)?;
+        let header = block.header.to_raw().to_vec();
+        let key    = ShortIdKey::new(&header, nonce);
+
+        let mut prefilled = Vec::new();
+        let mut short_ids = Vec::with_capacity(block.txs.len());
+
+        for (n, tx) in block.txs.iter().enumerate() {
+            if n == 0 {
+                prefilled.push((0, tx.to_raw().to_vec()));
+            }
+            else {
+                let hash = Hash32Buf::double_sha256(tx.to_raw());
+                short_ids.push(key.short_id(hash.as_ref()));
+            }
+        }
+
+        Ok(CompactBlock {
+            header,
+            nonce,
+            short_ids,
+            prefilled
+        })
+    }
+
+    /// Total number of transactions in the block
+    pub fn tx_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+}
+
+
+/// A block of which some transactions may still be missing
+#[derive(Debug)]
+pub struct PartialBlock {
+    header: Vec<u8>,
+    txs:    Vec<Option<Vec<u8>>>
+}
+
+impl PartialBlock {
+
+    /// Returns the indexes of the transactions that are still missing
+    pub fn missing(&self) -> Vec<usize> {
+        self.txs.iter()
+            .enumerate()
+            .filter(|&(_, tx)| tx.is_none())
+            .map(|(n, _)| n)
+            .collect()
+    }
+
+    /// Fills the missing transactions (as received in a blocktxn message)
+    ///
+    /// These must be given in the order as returned by missing()
+    pub fn fill(&mut self, txs: Vec<Vec<u8>>) -> CompactBlockResult<()> {
+
+        let missing = self.missing();
+        if missing.len() != txs.len() {
+            return Err(CompactBlockError::IncorrectTransactionCount);
+        }
+
+        for (idx, tx) in missing.into_iter().zip(txs) {
+            self.txs[idx] = Some(tx);
+        }
+        Ok(())
+    }
+
+    /// Returns the serialized full block, or None if transactions are missing
+    ///
+    /// The result is not verified; a short id collision can still cause an incorrect merkle root
+    pub fn to_block(&self) -> Option<Vec<u8>> {
+
+        if self.txs.iter().any(|tx| tx.is_none()) {
+            return None;
+        }
+
+        let mut result = self.header.clone();
+        write_compact_size(&mut result, self.txs.len());
+        for tx in self.txs.iter() {
+            result.extend_from_slice(tx.as_ref().unwrap());
+        }
+        Some(result)
+    }
+}
+
+
+/// Reconstructs the block from the compact block using the transactions in the mempool
+///
+/// Short ids that match different transactions are treated as missing
+pub fn reconstruct(store: &mut Store, compact: &CompactBlock) -> CompactBlockResult<PartialBlock> {
+
+    let tx_count = compact.tx_count();
+    let mut txs: Vec<Option<Vec<u8>>> = vec![None; tx_count];
+    let mut is_prefilled = vec![false; tx_count];
+
+    for &(idx, ref tx) in compact.prefilled.iter() {
+        if idx >= tx_count || is_prefilled[idx] {
+            return Err(CompactBlockError::InvalidPrefilledIndex);
+        }
+        is_prefilled[idx] = true;
+        txs[idx] = Some(tx.clone());
+    }
+
+    // map the short ids to the remaining slots
+    let mut slots: HashMap<u64, Vec<usize>> = HashMap::new();
+    let free_slots = (0..tx_count).filter(|&n| !is_prefilled[n]);
+    for (short_id, slot) in compact.short_ids.iter().zip(free_slots) {
+        slots.entry(*short_id).or_default().push(slot);
+    }
+
+    let key = ShortIdKey::new(&compact.header, compact.nonce);
+    let mut ambiguous: Vec<usize> = Vec::new();
+
+    for (hash, ptr) in store.mempool.get_all() {
+
+        let slot = match slots.get(&key.short_id(hash.as_ref())) {
+            Some(slot) if slot.len() == 1 => slot[0],
+            Some(slot) => { ambiguous.extend(slot.iter()); continue; }
+            None => continue
+        };
+
+        let raw_tx = match store.transactions.read(ptr) {
+            Ok(raw_tx) => raw_tx,
+            Err(_)     => continue
+        };
+
+        let collision = match txs[slot] {
+            Some(ref found) => found != &raw_tx,
+            None            => false
+        };
+        if collision {
+            ambiguous.push(slot);
+        } else {
+            txs[slot] = Some(raw_tx);
+        }
+    }
+
+    for slot in ambiguous {
+        txs[slot] = None;
+    }
+
+    Ok(PartialBlock {
+        header: compact.header.clone(),
+        txs
+    })
+}
+
+
+/// SipHash-2-4 as specified at https://131002.net/siphash/
+fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+
+    let mut v0 = k0 ^ 0x736f6d6570736575;
+    let mut v1 = k1 ^ 0x646f72616e646f6d;
+    let mut v2 = k0 ^ 0x6c7967656e657261;
+    let mut v3 = k1 ^ 0x7465646279746573;
+
+    macro_rules! sip_round {
+        () => ({
+            v0 = v0.wrapping_add(v1); v1 = v1.rotate_left(13); v1 ^= v0; v0 = v0.rotate_left(32);
+            v2 = v2.wrapping_add(v3); v3 = v3.rotate_left(16); v3 ^= v2;
+            v0 = v0.wrapping_add(v3); v3 = v3.rotate_left(21); v3 ^= v0;
+            v2 = v2.wrapping_add(v1); v1 = v1.rotate_left(17); v1 ^= v2; v2 = v2.rotate_left(32);
+        })
+    }
+
+    let mut chunks = data.chunks(8);
+    let mut last: u64 = (data.len() as u64 & 0xff) << 56;
+    loop {
+        match chunks.next() {
+            Some(chunk) if chunk.len() == 8 => {
+                let mut m = [0; 8];
+                m.copy_from_slice(chunk);
+                let m = u64::from_le_bytes(m);
+                v3 ^= m;
+                sip_round!();
+                sip_round!();
+                v0 ^= m;
+            },
+            Some(chunk) => {
+                let mut m = [0; 8];
+                m[..chunk.len()].copy_from_slice(chunk);
+                last |= u64::from_le_bytes(m);
+                break;
+            },
+            None => break
+        }
+    }
+
+    v3 ^= last;
+    sip_round!();
+    sip_round!();
+    v0 ^= last;
+
+    v2 ^= 0xff;
+    sip_round!();
+    sip_round!();
+    sip_round!();
+    sip_round!();
+
+    v0 ^ v1 ^ v2 ^ v3
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use api;
+    use store;
+
+    #[test]
+    fn test_siphash() {
+
+        // test vectors from the reference implementation
+        let k0 = u64::from_le_bytes([0, 1, 2, 3, 4, 5, 6, 7]);
+        let k1 = u64::from_le_bytes([8, 9, 10, 11, 12, 13, 14, 15]);
+
+        assert_eq!(siphash24(k0, k1, &[]), 0x726fdb47dd0e0e31);
+
+        let data: Vec<u8> = (0..15).collect();
+        assert_eq!(siphash24(k0, k1, &data), 0xa129ca6149be45e5);
+
+        let data: Vec<u8> = (0..8).collect();
+        assert_eq!(siphash24(k0, k1, &data), 0x93f5f5799a932462);
+    }
+
+    #[test]
+    fn test_reconstruct() {
+
+        let mut store = store::Store::new(&test_cfg!());
+
+        tx_builder!(bld);
+        let genesis  = genesis!();
+        let coinbase = tx!(bld; coinbase => a);
+        let tx1      = tx!(bld; a => b);
+        let tx2      = tx!(bld; b => c);
+        let block    = blk!(prev = genesis; coinbase, tx1, tx2);
+
+        // only tx1 is in the mempool
+        api::add_transaction(&mut store, &tx1).unwrap();
+
+        let compact = CompactBlock::from_block(&block, 0x1234).unwrap();
+        assert_eq!(compact.tx_count(), 3);
+        assert_eq!(compact.short_ids.len(), 2);
+        assert_eq!(compact.prefilled[0].0, 0);
+
+        let key = ShortIdKey::new(&compact.header, compact.nonce);
+        assert_eq!(key.short_id(Hash32Buf::double_sha256(&tx1).as_ref()), compact.short_ids[0]);
+
+        let mut partial = reconstruct(&mut store, &compact).unwrap();
+        assert_eq!(partial.missing(), vec![2]);
+        assert!(partial.to_block().is_none());
+
+        assert_eq!(partial.fill(vec![]), Err(CompactBlockError::IncorrectTransactionCount));
+        partial.fill(vec![tx2]).unwrap();
+
+        assert_eq!(partial.missing(), Vec::<usize>::new());
+        assert_eq!(partial.to_block().unwrap(), block);
+    }
+
+    #[test]
+    fn test_invalid_prefilled() {
+
+        let mut store = store::Store::new(&test_cfg!());
+
+        let compact = CompactBlock {
+            header:    vec![0; 80],
+            nonce:     0,
+            short_ids: vec![1],
+            prefilled: vec![(2, vec![])]
+        };
+
+        assert_eq!(reconstruct(&mut store, &compact).unwrap_err(),
+            CompactBlockError::InvalidPrefilledIndex);
+    }
+}
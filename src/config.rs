@@ -5,6 +5,8 @@ use std::fs;
 use std::env;
 use std::path::PathBuf;
 
+use pow;
+
 
 // Overrides the store directory to use
 pub const ENV_BITCRUST_STORE: &'static str = "BITCRUST_STORE";
@@ -12,11 +14,40 @@ pub const ENV_BITCRUST_STORE: &'static str = "BITCRUST_STORE";
 // Set to "1" will prevent the data-folder to be cleared
 pub const ENV_BITCRUST_NOCLEAR: &'static str = "BITCRUST_NOCLEAR";
 
+/// The number of bits of the hashes indexing the root table of a new hash-index
+pub const DEFAULT_INDEX_ROOT_BITS: usize = 24;
+
+/// The size of the files of the spend-tree
+pub const DEFAULT_SPEND_TREE_FILE_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+
 
 
 #[derive(Clone)]
 pub struct Config {
-    pub root: PathBuf
+    pub root: PathBuf,
+
+    /// Maintain the index of outputs and inputs by script; see [[store::script_index]]
+    pub script_index: bool,
+
+    /// Maintain the index of the inputs spending each output; see [[store::spent_by]]
+    pub spent_by_index: bool,
+
+    /// Maintain the MuHash of the unspent outputs as of each block, and the heights of the blocks
+    /// containing the transactions that it needs; see [[store::utxo_hash]]
+    pub utxo_hash: bool,
+
+    /// Map the files read-only and never create or write them; see `Store::open_read_only`
+    pub read_only: bool,
+
+    /// The chain of which the headers are validated; see [[pow]]
+    pub network: pow::Network,
+
+    /// The number of bits of the root table of new hash-indexes; see [[store::hash_index]]
+    pub index_root_bits: usize,
+
+    /// The size of the files of the spend-tree; this must not change for an existing store.
+    /// Tests use small files to spread blocks over multiple files; see [[store::spend_tree]]
+    pub spend_tree_file_size: u64
 }
 
 
@@ -31,7 +62,7 @@ impl Config {
     pub fn new(path: &str) -> Config {
 
         let path = PathBuf::from(path);
-        Config { root: path }
+        Config { root: path, script_index: false, spent_by_index: false, utxo_hash: false, read_only: false, network: pow::Network::Main, index_root_bits: DEFAULT_INDEX_ROOT_BITS, spend_tree_file_size: DEFAULT_SPEND_TREE_FILE_SIZE }
 
     }
 
@@ -59,14 +90,44 @@ impl Config {
         if env::var(ENV_BITCRUST_NOCLEAR).unwrap_or("0".to_string()) !=  "1" {
             let _ =  fs::remove_dir_all(path.clone());
         }
-        Config { root: path }
+        Config { root: path, script_index: false, spent_by_index: false, utxo_hash: false, read_only: false, network: pow::Network::Main, index_root_bits: DEFAULT_INDEX_ROOT_BITS, spend_tree_file_size: DEFAULT_SPEND_TREE_FILE_SIZE }
+    }
+
+
+    /// Enables or disables the script index
+    pub fn with_script_index(self, enabled: bool) -> Config {
+        Config { script_index: enabled, ..self }
+    }
+
+    /// Enables or disables the spent-by index
+    pub fn with_spent_by_index(self, enabled: bool) -> Config {
+        Config { spent_by_index: enabled, ..self }
+    }
+
+    /// Enables or disables the MuHash of the unspent outputs
+    pub fn with_utxo_hash(self, enabled: bool) -> Config {
+        Config { utxo_hash: enabled, ..self }
     }
 
+    /// Sets the number of bits of the root table of new hash-indexes
+    pub fn with_index_root_bits(self, index_root_bits: usize) -> Config {
+        Config { index_root_bits, ..self }
+    }
+
+    /// Sets the size of the files of the spend-tree
+    pub fn with_spend_tree_file_size(self, spend_tree_file_size: u64) -> Config {
+        Config { spend_tree_file_size, ..self }
+    }
+
+    /// Sets the chain of which the headers are validated
+    pub fn with_network(self, network: pow::Network) -> Config {
+        Config { network, ..self }
+    }
 
     pub fn new_persist() -> Config {
 
         let path = PathBuf::from("prs");
-        Config { root: path }
+        Config { root: path, script_index: false, spent_by_index: false, utxo_hash: false, read_only: false, network: pow::Network::Main, index_root_bits: DEFAULT_INDEX_ROOT_BITS, spend_tree_file_size: DEFAULT_SPEND_TREE_FILE_SIZE }
 
     }
 }
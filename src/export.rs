@@ -0,0 +1,131 @@
+//! Export of stored blocks to bitcoin-core style blk*.dat files
+//!
+//! Blocks are rebuilt from the spend-tree: the end-of-block record points to the header,
+//! and the transaction records point into the transaction store. Input records are skipped as
+//! these are part of the transactions.
+
+
+use std::io;
+use std::ops::Range;
+
+use blkfile::Exporter;
+
+use hash::*;
+use util::write_compact_size;
+use store::{BlockPtr, HashIndexGuard, Pruned, Store};
+use store::best_chain;
+
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io(io::Error),
+
+    /// The tip of the chain to export is not a connected block
+    UnknownBlock,
+
+    /// Transactions of a block in the range have been pruned
+    Pruned
+}
+
+impl From<io::Error> for ExportError {
+    fn from(err: io::Error) -> ExportError {
+        ExportError::Io(err)
+    }
+}
+
+impl From<Pruned> for ExportError {
+    fn from(_: Pruned) -> ExportError {
+        ExportError::Pruned
+    }
+}
+
+
+/// Returns the pointer to the block with the given hash if it is stored and connected
+pub fn get_block(store: &mut Store, hash: Hash32) -> Option<BlockPtr> {
+
+    store.block_index.get(hash)
+        .into_iter()
+        .find(|ptr| !ptr.is_guard())
+}
+
+/// Returns the blocks of the chain ending at `tip` with a height in `heights`, from low to high
+///
+/// Only the blocks of the tip that are not in the best chain are found by walking back; the others
+/// are taken from the best chain by height
+fn get_chain(store: &mut Store, tip: Hash32, heights: Range<u64>) -> Result<Vec<BlockPtr>, ExportError> {
+
+    let mut block  = get_block(store, tip).ok_or(ExportError::UnknownBlock)?;
+    let mut hash   = tip.as_buf();
+    let mut height = best_chain::get_height(store, tip).ok_or(ExportError::UnknownBlock)?;
+    let end        = heights.end.min(height + 1);
+
+    // walk back until the best chain, or until below the range
+    let mut fork = vec![];
+    loop {
+        if height < heights.start {
+            fork.reverse();
+            return Ok(fork);
+        }
+        if store.best_chain.get_hash(height).as_ref() == Some(&hash) {
+            break;
+        }
+        if height < end {
+            fork.push(block);
+        }
+        block = match store.spend_tree.get_previous_block(block) {
+            Some(previous) => previous,
+            None           => {
+                fork.reverse();
+                return Ok(fork);
+            }
+        };
+        hash    = store.get_block_hash(block);
+        height -= 1;
+    }
+
+    // a reorganisation may have replaced the best chain since
+    let mut chain = (heights.start..end.min(height + 1))
+        .map(|height| best_chain::get_block_at(store, height).ok_or(ExportError::UnknownBlock))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    fork.reverse();
+    chain.extend(fork);
+    Ok(chain)
+}
+
+/// Rebuilds the raw block from the spend-tree and the transaction store
+pub fn read_block(store: &mut Store, block: BlockPtr) -> Result<Vec<u8>, Pruned> {
+
+    let header_ptr = store.spend_tree.get_block_header_ptr(block);
+    let mut result = store.block_headers.read(header_ptr).to_vec();
+
+    let records = store.spend_tree.get_block_mut(block);
+    let txs: Vec<_> = records[1..records.len() - 1].iter()
+        .filter(|rec| rec.is_transaction() && !rec.is_unmatched_input())
+        .map(|rec| rec.get_transaction_ptr())
+        .collect();
+
+    write_compact_size(&mut result, txs.len());
+    for tx in txs {
+        result.extend(store.transactions.read(tx)?);
+    }
+    Ok(result)
+}
+
+/// Writes the blocks of the chain ending at `tip` with a height in `heights`
+///
+/// Returns the number of blocks written
+pub fn export_chain(store: &mut Store, exporter: &mut Exporter, tip: Hash32, heights: Range<u64>)
+    -> Result<u64, ExportError>
+{
+    let chain = get_chain(store, tip, heights)?;
+
+    let mut count = 0;
+    for block in chain {
+        exporter.write_block(&read_block(store, block)?)?;
+        count += 1;
+    }
+
+    exporter.finish()?;
+    Ok(count)
+}
@@ -0,0 +1,161 @@
+//! Transaction finality checks that need the context of the chain
+//!
+//! When a block is connected, the absolute lock_time of each transaction is checked against
+//! the height of the block or the median time past of its parent (BIP113), and the relative
+//! lock-times of the inputs (BIP68) are checked against the confirmation of the spent outputs.
+//!
+//! The height of the block is passed in by the caller, which knows it from the tips or the
+//! height index. The confirming blocks of spent outputs are not indexed; these are found by
+//! walking back through the spend-tree. These walks are bounded by the relative lock values and
+//! only needed for inputs that actually use relative locks.
+
+
+use buffer::*;
+use block::BlockHeader;
+use store::{Store, BlockPtr, Record};
+use transaction::{Transaction, TransactionError, RelativeLock};
+
+/// Number of blocks used for the median time past
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+
+/// Returns the timestamp of the header of the given block
+fn get_block_time(store: &mut Store, block: BlockPtr) -> u32 {
+
+    let hdr_ptr = store.spend_tree.get_block_header_ptr(block);
+    let hdr_raw = store.block_headers.read(hdr_ptr);
+
+    BlockHeader::parse(&mut Buffer::new(hdr_raw))
+        .expect("Invalid blockheader in store")
+        .time
+}
+
+/// Returns the median of the timestamps of the given block and its 10 predecessors
+pub fn get_median_time_past(store: &mut Store, block: BlockPtr) -> u32 {
+
+    let mut times = Vec::with_capacity(MEDIAN_TIME_SPAN);
+    let mut next  = Some(block);
+
+    while let Some(block) = next {
+        if times.len() == MEDIAN_TIME_SPAN {
+            break;
+        }
+        times.push(get_block_time(store, block));
+        next = store.spend_tree.get_previous_block(block);
+    }
+
+    times.sort();
+    times[times.len() / 2]
+}
+
+/// Returns the median time past of the parent of block, or of block itself for genesis
+fn get_previous_median_time_past(store: &mut Store, block: BlockPtr) -> u32 {
+
+    let previous = store.spend_tree.get_previous_block(block).unwrap_or(block);
+    get_median_time_past(store, previous)
+}
+
+/// Verifies a relative lock of the input at `input_idx` of the records of `block`
+///
+/// Walks back from the spending block to the block that contains the spent transaction.
+/// The walk is stopped as soon as the lock is known to be satisfied
+fn verify_relative_lock(
+    store:            &mut Store,
+    block:            BlockPtr,
+    input_idx:        usize,
+    lock:             RelativeLock,
+    median_time_past: u32) -> Result<(), TransactionError>
+{
+    let records: &[Record] = store.spend_tree.get_block_mut(block);
+    let seek_tx            = records[input_idx].to_transaction();
+
+    // first we only search the records before the input
+    let mut search: &[Record] = &records[..input_idx];
+    let mut current = block;
+    let mut depth   = 0;
+
+    loop {
+        let found = search.iter().any(|rec| rec.is_transaction() && rec.to_transaction() == seek_tx);
+
+        match lock {
+            RelativeLock::Blocks(blocks) => {
+
+                if depth >= blocks {
+                    return Ok(());
+                }
+                if found {
+                    return Err(TransactionError::SequenceLockHeightNotMet);
+                }
+            },
+            RelativeLock::Seconds(seconds) => {
+
+                // The median time past of a block is never before that of its parent, so if
+                // the lock is satisfied for this block, it is also for any earlier block
+                let coin_time = get_previous_median_time_past(store, current) as u64;
+                let reached   = coin_time + seconds as u64 <= median_time_past as u64;
+
+                if reached {
+                    return Ok(());
+                }
+                if found {
+                    return Err(TransactionError::SequenceLockTimeNotMet);
+                }
+            }
+        }
+
+        current = match store.spend_tree.get_previous_block(current) {
+
+            // This can't normally happen as the spend-tree has verified the output
+            None       => return Ok(()),
+            Some(prev) => prev
+        };
+
+        search = store.spend_tree.get_block_mut(current);
+        depth += 1;
+    }
+}
+
+/// Verifies the absolute and relative lock-times of all transactions in the given block
+///
+/// The block must be connected to its parent in the spend-tree, at the given height
+pub fn verify_block_finality(store: &mut Store, block: BlockPtr, height: u64) -> Result<(), TransactionError> {
+
+    let records: Vec<Record> = store.spend_tree.get_block_mut(block).to_vec();
+
+    // this is only calculated when needed
+    let mut median_time_past: Option<u32> = None;
+
+    for (idx, rec) in records.iter().enumerate() {
+
+        if !rec.is_transaction() {
+            continue;
+        }
+
+        let tx_raw = store.transactions.read(rec.get_transaction_ptr())
+            .expect("Transaction of connected block is pruned");
+        let tx     = Transaction::parse(&mut Buffer::new(&tx_raw))
+            .expect("Invalid tx data in database");
+
+        let relative_locks = tx.get_relative_locks();
+
+        if tx.lock_time == 0 && relative_locks.is_empty() {
+            continue;
+        }
+
+        if median_time_past.is_none() {
+            median_time_past = Some(get_previous_median_time_past(store, block));
+        }
+        let median_time_past = median_time_past.unwrap();
+
+        if tx.lock_time > 0 {
+            tx.verify_lock_time(height, median_time_past)?;
+        }
+
+        // the output-records of the inputs directly follow the transaction-record
+        for (input, lock) in relative_locks {
+            verify_relative_lock(store, block, idx + 1 + input, lock, median_time_past)?;
+        }
+    }
+
+    Ok(())
+}
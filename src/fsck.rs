@@ -0,0 +1,365 @@
+//! Offline integrity check of a store
+//!
+//! This walks the entire spend-tree and both hash-indexes and verifies that they agree with
+//! each other and with the transaction and header stores. It is meant to be run on a store that
+//! is not in use, after a crash or to validate changes to the store format.
+//!
+//! Hashes are reported as 32 bytes in the order in which they are hashed, and records as their
+//! index in the spend-tree.
+
+
+use std::collections::HashSet;
+
+use buffer::*;
+use hash::*;
+use store::{BlockPtr, FlatFilePtr, HashIndexGuard, Record, RecordPtr, Store, TxPtr};
+use store::prune;
+use transaction::Transaction;
+
+
+/// An inconsistency found by `check`
+#[derive(Debug, PartialEq)]
+pub enum Inconsistency {
+
+    /// The spend-tree does not consist of start-of-block, content and end-of-block records
+    CorruptSpendTree { record: u64 },
+
+    /// A block-index entry does not point to a block in the spend-tree
+    InvalidBlockPtr { hash: [u8; 32] },
+
+    /// The header of a block in the block-index does not have the hash it is stored at
+    BlockHashMismatch { hash: [u8; 32] },
+
+    /// An orphan block is stored as guard at a hash that is not its previous block
+    BlockGuardMismatch { hash: [u8; 32] },
+
+    /// A transaction-record does not point to a stored transaction
+    UnreadableTransaction { record: u64 },
+
+    /// A transaction in the tx-index does not have the hash it is stored at
+    TransactionHashMismatch { hash: [u8; 32] },
+
+    /// An input is stored as guard at a hash that is not the transaction it spends
+    TransactionGuardMismatch { hash: [u8; 32] },
+
+    /// A transaction in a block is not in the tx-index, while not all its outputs are spent
+    UnindexedTransaction { record: u64 },
+
+    /// A connected block contains an input that is not resolved to an output
+    UnresolvedInput { record: u64 },
+
+    /// A spend references an output that does not exist
+    MissingOutput { record: u64 },
+
+    /// A transaction or spend in a block that has been built upon is not in the spend-index
+    MissingInSpendIndex { record: u64 }
+}
+
+
+/// The result of `check`
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub blocks:         usize,
+    pub transactions:   usize,
+    pub spends:         usize,
+
+    /// Transaction and spend records of which the transaction is pruned; these are not checked
+    pub pruned:         usize,
+
+    /// Blocks that are neither connected nor in the block-index, as they have been collected
+    /// as stale branch; their records are not checked
+    pub collected:      usize,
+    pub inconsistencies: Vec<Inconsistency>
+}
+
+impl CheckReport {
+
+    /// Returns true if no inconsistencies were found
+    pub fn is_ok(&self) -> bool {
+        self.inconsistencies.is_empty()
+    }
+}
+
+
+/// Checks the entire store
+pub fn check(store: &mut Store) -> CheckReport {
+
+    let mut report = CheckReport::default();
+
+    let blocks = check_spend_tree(store, &mut report);
+    check_spend_index(store, &blocks, &mut report);
+    check_block_index(store, &mut report);
+    check_tx_index(store, &mut report);
+
+    report
+}
+
+
+// Returns the transaction at `ptr`, if it is within the transaction store
+fn read_transaction(store: &mut Store, ptr: TxPtr) -> Option<Vec<u8>> {
+
+    let end = store.transactions.get_end();
+    if (ptr.get_file_number(), ptr.get_file_offset()) >= (end.get_file_number(), end.get_file_offset()) {
+        return None;
+    }
+    store.transactions.next(ptr).map(|(tx, _)| tx)
+}
+
+// Returns true if the block-pointer points to a start-of-block and a matching end-of-block record
+fn is_valid_block(store: &mut Store, block: BlockPtr) -> bool {
+
+    let end = store.spend_tree.get_end().to_index();
+    if block.length < 2 || block.start.to_index() + block.length > end {
+        return false;
+    }
+
+    let last = store.spend_tree.get_record(block.end());
+    store.spend_tree.get_record(block.start).is_block_start()
+        && last.is_block_end()
+        && last.get_record_count() as u64 + 2 == block.length
+}
+
+// Walks the spend-tree and checks each record; returns the blocks found
+fn check_spend_tree(store: &mut Store, report: &mut CheckReport) -> Vec<BlockPtr> {
+
+    let end = store.spend_tree.get_end().to_index();
+
+    // the blocks and orphans in the block-index, by the index of their start-of-block record
+    let mut indexed = HashSet::new();
+    store.block_index.for_each(|_, values| {
+        indexed.extend(values.iter().map(|block| block.start.to_index()));
+    });
+
+    let mut blocks = vec![];
+    let mut start  = store.spend_tree.skip_unused(RecordPtr::new(0)).to_index();
+    while start < end {
+
+        // find the end-of-block record; a block is never split over files
+        let mut block_end = start + 1;
+        while block_end < end && !store.spend_tree.get_record(RecordPtr::new(block_end)).is_block_end() {
+            block_end += 1;
+        }
+        let block = BlockPtr {
+            start:    RecordPtr::new(start),
+            length:   block_end + 1 - start,
+            is_guard: false
+        };
+
+        if block_end == end || !is_valid_block(store, block) {
+            report.inconsistencies.push(Inconsistency::CorruptSpendTree { record: start });
+            break;
+        }
+
+        let records   = store.spend_tree.get_block_mut(block).to_vec();
+        let connected = records[0].previous_block().is_some();
+        if !connected && !indexed.contains(&start) {
+            report.collected += 1;
+        }
+        else {
+            for (n, &rec) in records[1..records.len() - 1].iter().enumerate() {
+                check_record(store, rec, start + 1 + n as u64, connected, report);
+            }
+        }
+
+        report.blocks += 1;
+        blocks.push(block);
+        start = store.spend_tree.skip_unused(RecordPtr::new(block_end + 1)).to_index();
+    }
+    blocks
+}
+
+// Checks that a transaction-record resolves to an indexed transaction and that a spend
+// references an existing output
+fn check_record(store: &mut Store, rec: Record, record: u64, connected: bool, report: &mut CheckReport) {
+
+    if rec.is_unmatched_input() {
+        if connected {
+            report.inconsistencies.push(Inconsistency::UnresolvedInput { record });
+        }
+        return;
+    }
+
+    let ptr = rec.get_transaction_ptr();
+    if store.transactions.is_pruned(ptr) {
+        report.pruned += 1;
+        return;
+    }
+
+    let raw = match read_transaction(store, ptr) {
+        Some(raw) => raw,
+        None      => {
+            report.inconsistencies.push(Inconsistency::UnreadableTransaction { record });
+            return;
+        }
+    };
+    let tx = match Transaction::parse(&mut Buffer::new(&raw)) {
+        Ok(tx) => tx,
+        Err(_) => {
+            report.inconsistencies.push(Inconsistency::UnreadableTransaction { record });
+            return;
+        }
+    };
+
+    if rec.is_output() {
+        report.spends += 1;
+
+        if rec.get_output_index() as usize >= tx.txs_out.len() {
+            report.inconsistencies.push(Inconsistency::MissingOutput { record });
+        }
+    }
+    else {
+        report.transactions += 1;
+
+        // fully spent transactions may have been pruned from the tx-index
+        let hash = Hash32Buf::double_sha256(tx.to_raw());
+        if !store.tx_index.get(hash.as_ref()).contains(&ptr)
+            && !prune::is_spent(&store.spend_index, ptr, &tx) {
+
+            report.inconsistencies.push(Inconsistency::UnindexedTransaction { record });
+        }
+    }
+}
+
+// Checks that the records of each block that another block is connected to are in the
+// spend-index; these are added when the next block is connected
+fn check_spend_index(store: &mut Store, blocks: &[BlockPtr], report: &mut CheckReport) {
+
+    let previous: HashSet<u64> = blocks.iter()
+        .filter_map(|block| store.spend_tree.get_record(block.start).previous_block())
+        .map(|end| end.to_index())
+        .collect();
+
+    for &block in blocks.iter().filter(|block| previous.contains(&block.end().to_index())) {
+
+        let start = block.start.to_index();
+        let records = store.spend_tree.get_block_mut(block).to_vec();
+        for (n, rec) in records[1..records.len() - 1].iter().enumerate() {
+
+            if !rec.is_unmatched_input() && !store.spend_index.exists(rec.hash()) {
+                let record = start + 1 + n as u64;
+                report.inconsistencies.push(Inconsistency::MissingInSpendIndex { record });
+            }
+        }
+    }
+}
+
+// Checks that each block in the block-index has the hash it is stored at, and that each
+// orphan block is stored at its previous block
+fn check_block_index(store: &mut Store, report: &mut CheckReport) {
+
+    let mut entries = vec![];
+    store.block_index.for_each(|hash, values| entries.push((hash.as_buf(), values)));
+
+    for (hash, values) in entries {
+        let key = *hash.as_ref().0;
+
+        for block in values {
+            if !is_valid_block(store, block) {
+                report.inconsistencies.push(Inconsistency::InvalidBlockPtr { hash: key });
+                continue;
+            }
+
+            let header_ptr = store.spend_tree.get_block_header_ptr(block);
+            let header     = store.block_headers.read(header_ptr);
+
+            if block.is_guard() {
+                if header.len() != 80 || header[4..36] != key[..] {
+                    report.inconsistencies.push(Inconsistency::BlockGuardMismatch { hash: key });
+                }
+            }
+            else if Hash32Buf::double_sha256(header).as_ref() != hash.as_ref() {
+                report.inconsistencies.push(Inconsistency::BlockHashMismatch { hash: key });
+            }
+        }
+    }
+}
+
+// Checks that each transaction in the tx-index has the hash it is stored at, and that each
+// guarding input spends the transaction it is stored at
+fn check_tx_index(store: &mut Store, report: &mut CheckReport) {
+
+    let mut entries = vec![];
+    store.tx_index.for_each(|hash, values| entries.push((hash.as_buf(), values)));
+
+    for (hash, values) in entries {
+        let key = *hash.as_ref().0;
+
+        for ptr in values {
+            let tx_ptr = TxPtr::new(ptr.get_file_number(), ptr.get_file_offset());
+            let raw    = read_transaction(store, tx_ptr);
+            let tx     = raw.as_ref().and_then(|raw| Transaction::parse(&mut Buffer::new(raw)).ok());
+
+            let matches = match tx {
+                None => false,
+
+                Some(ref tx) if ptr.is_guard() => tx.txs_in
+                    .get(ptr.get_input_index() as usize)
+                    .is_some_and(|input| input.prev_tx_out == hash.as_ref()),
+
+                Some(ref tx) => Hash32Buf::double_sha256(tx.to_raw()).as_ref() == hash.as_ref()
+            };
+
+            if !matches {
+                report.inconsistencies.push(if ptr.is_guard() {
+                    Inconsistency::TransactionGuardMismatch { hash: key }
+                } else {
+                    Inconsistency::TransactionHashMismatch { hash: key }
+                });
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use block_add;
+    use test_chain::TestChain;
+
+    #[test]
+    fn test_check() {
+
+        let mut store = Store::new(& test_cfg!());
+
+        // a block spending the coinbase of block 1 and a block on top of that
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let tip       = chain.extend(genesis, 101);
+        let coinbase  = chain.chain(tip)[1].coinbase.clone().unwrap();
+        let tx        = chain.spend(&[coinbase.output(0)], &[coinbase.output(0).value]);
+        let tip       = chain.build_block(tip).transaction(tx.clone()).add();
+        let tip       = chain.extend(tip, 1);
+
+        // the last block comes in first and waits as orphan
+        let blocks = chain.chain(tip);
+        block_add::add_block(&mut store, &blocks[103].raw);
+        for block in &blocks[0..103] {
+            block_add::add_block(&mut store, &block.raw);
+        }
+
+        let report = check(&mut store);
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(report.blocks, 104);
+        assert_eq!(report.transactions, 105);
+        assert_eq!(report.spends, 1);
+
+        // overwrite the header of the genesis block
+        let hash   = chain.block(genesis).hash;
+        let block  = ::export::get_block(&mut store, Hash32Buf::from_slice(&hash).as_ref()).unwrap();
+        let header = store.spend_tree.get_block_header_ptr(block);
+        store.block_headers.read_mut_slice::<u8>(header, 84)[14] ^= 1;
+
+        // and add a guard for an input that spends another transaction
+        let wrong_hash = Hash32Buf::double_sha256(b"not the input");
+        let tx_ptr     = store.tx_index.get(Hash32Buf::from_slice(&tx.txid).as_ref())[0];
+        assert_eq!(store.tx_index.get_or_set(wrong_hash.as_ref(), tx_ptr.to_input(0)), None);
+
+        let report = check(&mut store);
+        assert_eq!(report.inconsistencies, vec![
+            Inconsistency::BlockHashMismatch        { hash },
+            Inconsistency::TransactionGuardMismatch { hash: *wrong_hash.as_ref().0 },
+        ]);
+    }
+}
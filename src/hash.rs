@@ -59,8 +59,11 @@ pub struct Hash32<'a>(pub &'a[u8;32]);
 
 
 impl<'a> buffer::Parse<'a> for Hash32<'a> {
+
+    const MIN_SIZE: usize = 32;
+
     /// Parses the hash from a buffer; with 0-copy
-    fn parse(buffer: &mut buffer::Buffer<'a>) -> Result<Hash32<'a>, buffer::EndOfBufferError> {
+    fn parse(buffer: &mut buffer::Buffer<'a>) -> Result<Hash32<'a>, buffer::ParseError> {
 
         Ok(Hash32(
 
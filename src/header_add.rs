@@ -0,0 +1,353 @@
+//! Header-first synchronisation
+//!
+//! Headers can be added before their blocks. Each header is checked for proof of work, for its
+//! target against that of its parent and for its timestamp, and stored with its height and chain work, such that the best header chain is known before any
+//! block comes in. Blocks for these headers can then be requested in order with
+//! `get_missing_bodies`, instead of buffering large sets of orphan blocks.
+//!
+//! Blocks are still added with `block_add`, which marks their header as having a body.
+
+
+use std::convert;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use buffer::*;
+use hash::*;
+use block::BlockHeader;
+use block_add::is_genesis_block;
+use finality::MEDIAN_TIME_SPAN;
+use pow;
+use snapshot;
+use store::{HashIndexGuard, HeaderRecord, Store};
+
+
+// How far in seconds the timestamp of a header may be ahead of our clock
+const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderError {
+    ParseError(ParseError),
+
+    /// The previous header is not yet added; headers must be added in order
+    UnknownPrevious,
+
+    InvalidProofOfWork,
+
+    /// The target is easier than the limit of the network, or doesn't follow from the parent
+    IncorrectDifficulty,
+
+    /// The timestamp is not after the median time past of the parent
+    TimeTooOld,
+
+    /// The timestamp is too far ahead of our clock
+    TimeTooNew
+}
+
+impl convert::From<ParseError> for HeaderError {
+
+    fn from(err: ParseError) -> HeaderError {
+
+        HeaderError::ParseError(err)
+    }
+}
+
+type HeaderResult<T> = Result<T, HeaderError>;
+
+
+/// Validates and stores a block header
+///
+/// Headers that are already stored are ignored
+pub fn add_header(store: &mut Store, raw: &[u8]) -> HeaderResult<()> {
+
+    let header = BlockHeader::parse(&mut Buffer::new(raw))?;
+    let hash   = Hash32Buf::double_sha256(header.to_raw());
+
+    if store.headers.get(hash.as_ref()).is_some() {
+        return Ok(());
+    }
+
+    let _lock = snapshot::lock_writes(store);
+
+    // another store may have added it in the meantime
+    if store.headers.get(hash.as_ref()).is_some() {
+        return Ok(());
+    }
+
+    let network = store.get_config().network;
+
+    if !pow::within_limit(header.bits(), network.pow_limit()) {
+        return Err(HeaderError::IncorrectDifficulty);
+    }
+    if !pow::verify_proof_of_work(hash.as_ref(), header.bits()) {
+        return Err(HeaderError::InvalidProofOfWork);
+    }
+
+    let work = pow::work_from_compact(header.bits());
+
+    let (height, work) = if is_genesis_block(hash.as_ref()) {
+        (0, work)
+    } else {
+        let previous = store.headers.get(header.prev_hash)
+            .ok_or(HeaderError::UnknownPrevious)?;
+
+        if network.retargets() && header.bits() != get_next_bits(store, &previous, network) {
+            return Err(HeaderError::IncorrectDifficulty);
+        }
+        if header.time <= get_median_time_past(store, &previous) {
+            return Err(HeaderError::TimeTooOld);
+        }
+
+        (previous.height + 1, previous.work().saturating_add(work))
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Clock before 1970").as_secs();
+    if header.time as u64 > now + MAX_FUTURE_BLOCK_TIME {
+        return Err(HeaderError::TimeTooNew);
+    }
+
+    store.headers.add(header.to_raw(), height, work);
+    Ok(())
+}
+
+
+// Returns the target the child of `previous` must have
+fn get_next_bits(store: &mut Store, previous: &HeaderRecord, network: pow::Network) -> u32 {
+
+    if !(previous.height + 1).is_multiple_of(pow::RETARGET_INTERVAL) {
+        return previous.bits();
+    }
+
+    // the first block of the period that ends with previous
+    let mut first = *previous;
+    for _ in 1..pow::RETARGET_INTERVAL {
+        first = store.headers.get(first.prev_hash().as_ref()).expect("Header chain is incomplete");
+    }
+    pow::next_retarget(previous.bits(), first.time(), previous.time(), network.pow_limit())
+}
+
+// Returns the median of the timestamps of the given header and its 10 predecessors
+fn get_median_time_past(store: &mut Store, header: &HeaderRecord) -> u32 {
+
+    let mut times = vec![header.time()];
+    let mut next  = *header;
+
+    while times.len() < MEDIAN_TIME_SPAN && next.height > 0 {
+        next = store.headers.get(next.prev_hash().as_ref()).expect("Header chain is incomplete");
+        times.push(next.time());
+    }
+
+    times.sort();
+    times[times.len() / 2]
+}
+
+
+/// Returns the hashes of the blocks of the best header chain that are not yet stored,
+/// ordered by height, up to `max` hashes
+///
+/// The chain is walked forward from the lowest block that is not connected. This height is kept
+/// between calls, so a call only passes the blocks connected since the previous one.
+pub fn get_missing_bodies(store: &mut Store, max: usize) -> Vec<Hash32Buf> {
+
+    let best = match store.headers.get_best() {
+        Some(best) => best,
+        None       => return vec![]
+    };
+
+    // the connected blocks are normally those of the best chain
+    if store.headers.connected_below == 0 {
+        store.headers.connected_below = store.best_chain.len();
+    }
+    let mut height = store.headers.connected_below.min(best.height + 1);
+
+    // if the header chain has changed, we go back to where it is connected
+    while height > 0 && !is_connected(store, height - 1) {
+        height -= 1;
+    }
+    while height <= best.height && is_connected(store, height) {
+        height += 1;
+    }
+    store.headers.connected_below = height;
+
+    let mut result = Vec::new();
+    while height <= best.height && result.len() < max {
+
+        let hash = store.headers.get_chain_hash(height).expect("Best header chain is incomplete");
+        let has_body = store.headers.get(hash.as_ref()).is_some_and(|record| record.has_body());
+        if !has_body {
+            result.push(hash);
+        }
+        height += 1;
+    }
+    result
+}
+
+// Returns true if the block at the given height of the best header chain is connected
+fn is_connected(store: &mut Store, height: u64) -> bool {
+
+    match store.headers.get_chain_hash(height) {
+        Some(hash) => store.block_index.get(hash.as_ref()).iter().any(|ptr| !ptr.is_guard()),
+        None       => false
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use block_add::add_block;
+    use store;
+    use test_chain::{TestChain, REGTEST_BITS};
+
+    // Sets the timestamp of the header and grinds the nonce such that it has a valid proof of work
+    fn with_time(header: &[u8], time: u32) -> Vec<u8> {
+
+        let mut header = header.to_vec();
+        header[68..72].copy_from_slice(&time.to_le_bytes());
+        for nonce in 0u32.. {
+            header[76..80].copy_from_slice(&nonce.to_le_bytes());
+
+            let hash = Hash32Buf::double_sha256(&header);
+            if pow::verify_proof_of_work(hash.as_ref(), REGTEST_BITS) {
+                break;
+            }
+        }
+        header
+    }
+
+    fn hash(block: &[u8]) -> Hash32Buf {
+        Hash32Buf::double_sha256(&block[0..80])
+    }
+
+    fn regtest_store() -> store::Store {
+        store::Store::new(&test_cfg!().with_network(pow::Network::Regtest))
+    }
+
+    #[test]
+    fn test_add_headers() {
+
+        let mut store = regtest_store();
+        let mut chain = TestChain::new();
+        let block1    = chain.add_block(chain.genesis(), vec![]);
+        let tip       = chain.extend(block1, 2);
+        let blocks: Vec<_> = chain.chain(tip).iter().map(|block| block.raw.clone()).collect();
+
+        assert_eq!(add_header(&mut store, &blocks[1][0..80]), Err(HeaderError::UnknownPrevious));
+        assert_eq!(add_header(&mut store, &blocks[0][0..40]), Err(HeaderError::ParseError(ParseError {
+            kind:      ParseErrorKind::EndOfBuffer,
+            offset:    36,
+            structure: Some("block-header")
+        })));
+
+        for block in blocks.iter() {
+            add_header(&mut store, &block[0..80]).unwrap();
+        }
+
+        let best = store.headers.get_best().unwrap();
+        assert_eq!(best.height, 3);
+        assert_eq!(best.work(), 0x1_0001_0001 + 3 * 2);
+        assert_eq!(best.hash(), hash(&blocks[3]));
+
+        // a shorter fork doesn't change the best header
+        let fork = chain.add_block(block1, vec![]);
+        add_header(&mut store, chain.block(fork).header()).unwrap();
+        assert_eq!(store.headers.get(hash(&chain.block(fork).raw).as_ref()).unwrap().height, 2);
+        assert_eq!(store.headers.get_best().unwrap().hash(), hash(&blocks[3]));
+
+        // adding again is a noop
+        add_header(&mut store, &blocks[3][0..80]).unwrap();
+
+        let mut invalid = blocks[3].clone();
+        invalid[72..76].copy_from_slice(&[0xff, 0xff, 0x00, 0x1d]);
+        assert_eq!(add_header(&mut store, &invalid[0..80]), Err(HeaderError::InvalidProofOfWork));
+    }
+
+    #[test]
+    fn test_difficulty() {
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let block1    = chain.add_block(genesis, vec![]);
+
+        // the regtest target is easier than the limit of the main chain
+        let mut store = store::Store::new(&test_cfg!());
+        add_header(&mut store, chain.block(genesis).header()).unwrap();
+        assert_eq!(add_header(&mut store, chain.block(block1).header()),
+            Err(HeaderError::IncorrectDifficulty));
+
+        let mut easy = chain.block(genesis).header().to_vec();
+        easy[72..76].copy_from_slice(&0x1d01_0000u32.to_le_bytes());
+        assert_eq!(add_header(&mut store, &easy), Err(HeaderError::IncorrectDifficulty));
+
+        // regtest doesn't retarget
+        let mut store = regtest_store();
+        add_header(&mut store, chain.block(genesis).header()).unwrap();
+        add_header(&mut store, chain.block(block1).header()).unwrap();
+    }
+
+    #[test]
+    fn test_timestamps() {
+
+        let mut store = regtest_store();
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let tip       = chain.extend(genesis, 11);
+
+        for block in chain.chain(tip) {
+            add_header(&mut store, block.header()).unwrap();
+        }
+
+        // the median of the last 11 timestamps is that of block 6
+        let next   = chain.add_block(tip, vec![]);
+        let header = chain.block(next).header();
+        let median = chain.chain(tip)[6].header();
+        let median = u32::from_le_bytes([median[68], median[69], median[70], median[71]]);
+
+        assert_eq!(add_header(&mut store, &with_time(header, median)), Err(HeaderError::TimeTooOld));
+        add_header(&mut store, &with_time(header, median + 1)).unwrap();
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+        assert_eq!(add_header(&mut store, &with_time(header, now + 3 * 60 * 60)),
+            Err(HeaderError::TimeTooNew));
+        add_header(&mut store, &with_time(header, now + 60 * 60)).unwrap();
+    }
+
+    #[test]
+    fn test_missing_bodies() {
+
+        let mut store = regtest_store();
+        let mut chain = TestChain::new();
+        let block1    = chain.add_block(chain.genesis(), vec![]);
+        let tip       = chain.extend(block1, 2);
+        let blocks: Vec<_> = chain.chain(tip).iter().map(|block| block.raw.clone()).collect();
+        let hashes: Vec<_> = blocks.iter().map(|b| hash(b)).collect();
+
+        for block in blocks.iter() {
+            add_header(&mut store, &block[0..80]).unwrap();
+        }
+        assert_eq!(get_missing_bodies(&mut store, 10), hashes);
+        assert_eq!(get_missing_bodies(&mut store, 2), &hashes[0..2]);
+
+        // block 2 comes in before block 1
+        add_block(&mut store, &blocks[0]);
+        add_block(&mut store, &blocks[2]);
+        assert_eq!(get_missing_bodies(&mut store, 10), vec![hashes[1], hashes[3]]);
+
+        add_block(&mut store, &blocks[1]);
+        assert_eq!(get_missing_bodies(&mut store, 10), vec![hashes[3]]);
+
+        add_block(&mut store, &blocks[3]);
+        assert!(get_missing_bodies(&mut store, 10).is_empty());
+
+        // a header fork with more work from block 1
+        let fork_tip = chain.extend(block1, 3);
+        let fork: Vec<_> = chain.chain(fork_tip)[2..].iter().map(|block| block.raw.clone()).collect();
+        for block in fork.iter() {
+            add_header(&mut store, &block[0..80]).unwrap();
+        }
+
+        let fork_hashes: Vec<_> = fork.iter().map(|block| hash(block)).collect();
+        assert_eq!(get_missing_bodies(&mut store, 10), fork_hashes);
+    }
+}
@@ -12,6 +12,7 @@ extern crate itertools;
 extern crate rand;
 extern crate ring;
 extern crate rayon;
+extern crate blkfile;
 
 #[macro_use]
 pub extern crate slog ;
@@ -34,14 +35,27 @@ pub mod metrics;
 pub mod transaction;
 pub mod block;
 pub mod script;
+pub mod compact_block;
+pub mod scheduler;
+pub mod test_chain;
 
 mod ffi;
-mod buffer;
+pub mod capi;
+pub mod buffer;
 mod util;
 // mod store;
 mod config;
 mod merkle_tree;
 mod block_add;
+mod header_add;
+mod pow;
+mod muhash;
+mod export;
+mod recovery;
+mod fsck;
+mod snapshot;
+mod utxo;
+mod finality;
 mod api;
 mod store;
 
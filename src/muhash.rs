@@ -0,0 +1,311 @@
+//! MuHash3072, the rolling hash of a set as used by `gettxoutsetinfo`
+//!
+//! Each element is hashed to a number modulo the prime 2^3072 - 1103717: its SHA256 is the key of
+//! a ChaCha20 keystream of 384 bytes, read as a little-endian number. The hash of a set is the
+//! product of the numbers of its elements, so elements can be added and removed in any order.
+//! Removed elements are multiplied into a denominator, such that the expensive inverse is only
+//! taken when the hash is finalized.
+//!
+//! This follows the implementation of Bitcoin Core and gives the same hashes.
+
+
+use ring;
+
+
+// the number of 64-bit limbs of a number
+pub const LIMBS: usize = 48;
+
+// the prime is 2^3072 - MAX_PRIME_DIFF
+const MAX_PRIME_DIFF: u64 = 1103717;
+
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+
+// A number modulo the prime, as little-endian limbs
+#[derive(Clone, Copy, PartialEq)]
+struct Num3072([u64; LIMBS]);
+
+impl Num3072 {
+
+    fn one() -> Num3072 {
+        let mut limbs = [0; LIMBS];
+        limbs[0] = 1;
+        Num3072(limbs)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Num3072 {
+
+        let mut limbs = [0; LIMBS];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks(8)) {
+            let mut buf = [0; 8];
+            buf.copy_from_slice(chunk);
+            *limb = u64::from_le_bytes(buf);
+        }
+        Num3072(limbs)
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        self.0.iter().flat_map(|limb| limb.to_le_bytes().to_vec()).collect()
+    }
+
+    // True if the number is not fully reduced, which is only possible in the range [p, 2^3072)
+    fn is_overflow(&self) -> bool {
+
+        self.0[0] > u64::MAX - MAX_PRIME_DIFF && self.0[1..].iter().all(|&limb| limb == u64::MAX)
+    }
+
+    // Adds `value` and returns the carry out of the top limb
+    fn add_small(&mut self, value: u128) -> u128 {
+
+        let mut carry = value;
+        for limb in self.0.iter_mut() {
+            if carry == 0 {
+                break;
+            }
+            let sum = *limb as u128 + carry;
+            *limb   = sum as u64;
+            carry   = sum >> 64;
+        }
+        carry
+    }
+
+    fn mul(&self, other: &Num3072) -> Num3072 {
+
+        let mut wide = [0u64; 2 * LIMBS];
+        for (i, &a) in self.0.iter().enumerate() {
+            let mut carry = 0u128;
+            for (j, &b) in other.0.iter().enumerate() {
+                let t = a as u128 * b as u128 + wide[i + j] as u128 + carry;
+                wide[i + j] = t as u64;
+                carry       = t >> 64;
+            }
+            wide[i + LIMBS] = carry as u64;
+        }
+
+        // 2^3072 is MAX_PRIME_DIFF modulo the prime, so the high part is folded into the low part
+        let mut result = [0u64; LIMBS];
+        let mut carry  = 0u128;
+        for i in 0..LIMBS {
+            let t = wide[i] as u128 + wide[i + LIMBS] as u128 * MAX_PRIME_DIFF as u128 + carry;
+            result[i] = t as u64;
+            carry     = t >> 64;
+        }
+
+        let mut result = Num3072(result);
+        while carry > 0 {
+            carry = result.add_small(carry * MAX_PRIME_DIFF as u128);
+        }
+        if result.is_overflow() {
+            result.add_small(MAX_PRIME_DIFF as u128);
+        }
+        result
+    }
+
+    // Returns the inverse as self^(p - 2), using a window of 4 bits
+    fn inverse(&self) -> Num3072 {
+
+        let mut table = [Num3072::one(); 16];
+        for i in 1..16 {
+            table[i] = table[i - 1].mul(self);
+        }
+
+        let mut exponent = [u64::MAX; LIMBS];
+        exponent[0] = u64::MAX - MAX_PRIME_DIFF - 1;
+
+        let mut result = Num3072::one();
+        for limb in exponent.iter().rev() {
+            for shift in (0..16).rev() {
+                for _ in 0..4 {
+                    result = result.mul(&result);
+                }
+                result = result.mul(&table[((limb >> (shift * 4)) & 0xF) as usize]);
+            }
+        }
+        result
+    }
+}
+
+
+/// The rolling hash of a set of byte strings
+#[derive(Clone, PartialEq)]
+pub struct MuHash3072 {
+    numerator:   Num3072,
+    denominator: Num3072
+}
+
+impl Default for MuHash3072 {
+    fn default() -> MuHash3072 {
+        MuHash3072::new()
+    }
+}
+
+impl MuHash3072 {
+
+    /// Creates the hash of the empty set
+    pub fn new() -> MuHash3072 {
+        MuHash3072 {
+            numerator:   Num3072::one(),
+            denominator: Num3072::one()
+        }
+    }
+
+    /// Restores the state as returned by `to_limbs`
+    pub fn from_limbs(numerator: [u64; LIMBS], denominator: [u64; LIMBS]) -> MuHash3072 {
+        MuHash3072 {
+            numerator:   Num3072(numerator),
+            denominator: Num3072(denominator)
+        }
+    }
+
+    /// Returns the numerator and denominator, to be stored
+    pub fn to_limbs(&self) -> ([u64; LIMBS], [u64; LIMBS]) {
+        (self.numerator.0, self.denominator.0)
+    }
+
+    pub fn insert(&mut self, data: &[u8]) {
+        self.numerator = self.numerator.mul(&to_num3072(data));
+    }
+
+    pub fn remove(&mut self, data: &[u8]) {
+        self.denominator = self.denominator.mul(&to_num3072(data));
+    }
+
+    /// Adds the elements of another set
+    pub fn combine(&mut self, other: &MuHash3072) {
+        self.numerator   = self.numerator.mul(&other.numerator);
+        self.denominator = self.denominator.mul(&other.denominator);
+    }
+
+    /// Returns the SHA256 of the number representing the set
+    ///
+    /// As bitcoin hashes are shown, the hex form used by `gettxoutsetinfo` is reversed
+    pub fn finalize(&self) -> [u8; 32] {
+
+        let number = self.numerator.mul(&self.denominator.inverse());
+        let digest = ring::digest::digest(&ring::digest::SHA256, &number.to_bytes());
+
+        let mut result = [0; 32];
+        result.copy_from_slice(digest.as_ref());
+        result
+    }
+}
+
+// Hashes an element to a number; the SHA256 of the data is the key of the ChaCha20 keystream
+fn to_num3072(data: &[u8]) -> Num3072 {
+
+    let digest = ring::digest::digest(&ring::digest::SHA256, data);
+
+    let mut key = [0u32; 8];
+    for (word, chunk) in key.iter_mut().zip(digest.as_ref().chunks(4)) {
+        *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+
+    let stream: Vec<u8> = (0..LIMBS as u32 / 8)
+        .flat_map(|counter| chacha20_block(&key, counter).to_vec())
+        .collect();
+
+    Num3072::from_bytes(&stream)
+}
+
+// Returns a block of the ChaCha20 keystream with a zero nonce
+fn chacha20_block(key: &[u32; 8], counter: u32) -> [u8; 64] {
+
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&CHACHA_CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter;
+
+    let mut x = input;
+    for _ in 0..10 {
+        quarter_round(&mut x, 0, 4,  8, 12);
+        quarter_round(&mut x, 1, 5,  9, 13);
+        quarter_round(&mut x, 2, 6, 10, 14);
+        quarter_round(&mut x, 3, 7, 11, 15);
+        quarter_round(&mut x, 0, 5, 10, 15);
+        quarter_round(&mut x, 1, 6, 11, 12);
+        quarter_round(&mut x, 2, 7,  8, 13);
+        quarter_round(&mut x, 3, 4,  9, 14);
+    }
+
+    let mut result = [0; 64];
+    for (i, chunk) in result.chunks_mut(4).enumerate() {
+        chunk.copy_from_slice(&x[i].wrapping_add(input[i]).to_le_bytes());
+    }
+    result
+}
+
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+
+    x[a] = x[a].wrapping_add(x[b]); x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]); x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]); x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]); x[b] = (x[b] ^ x[c]).rotate_left(7);
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use util::*;
+
+    // the element used in the tests of Bitcoin Core
+    fn from_int(n: u8) -> [u8; 32] {
+        let mut data = [0; 32];
+        data[0] = n;
+        data
+    }
+
+    #[test]
+    fn test_chacha20() {
+
+        // the keystream of the all-zero key
+        let block = chacha20_block(&[0; 8], 0);
+        assert_eq!(block.to_vec(), from_hex(
+            "76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7\
+             da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586"));
+    }
+
+    #[test]
+    fn test_muhash() {
+
+        let mut hash = MuHash3072::new();
+        hash.insert(&from_int(0));
+        hash.insert(&from_int(1));
+        hash.remove(&from_int(2));
+
+        let mut expected = from_hex("10d312b100cbd32ada024a6646e40d3482fcff103668d2625f10002a607d5863");
+        expected.reverse();
+        assert_eq!(hash.finalize().to_vec(), expected);
+
+        // the order doesn't matter, and an element that is added and removed is gone
+        let mut other = MuHash3072::new();
+        other.remove(&from_int(2));
+        other.insert(&from_int(3));
+        other.insert(&from_int(1));
+        other.remove(&from_int(3));
+
+        let mut first = MuHash3072::new();
+        first.insert(&from_int(0));
+        first.combine(&other);
+        assert_eq!(first.finalize(), hash.finalize());
+
+        let (numerator, denominator) = first.to_limbs();
+        assert!(MuHash3072::from_limbs(numerator, denominator) == first);
+    }
+
+    #[test]
+    fn test_reduce() {
+
+        // p - 1 squared is 1
+        let mut limbs = [u64::MAX; LIMBS];
+        limbs[0] = u64::MAX - MAX_PRIME_DIFF;
+        let minus_one = Num3072(limbs);
+        assert!(minus_one.mul(&minus_one) == Num3072::one());
+
+        let mut two = Num3072::one();
+        two.add_small(1);
+        assert!(two.mul(&two.inverse()) == Num3072::one());
+        assert!(minus_one.mul(&minus_one.inverse()) == Num3072::one());
+    }
+}
@@ -0,0 +1,291 @@
+//! Proof of work
+//!
+//! The target is encoded in the block header in the compact "bits" format. The hash of the
+//! header, read as a little endian 256-bit number, may not exceed this target.
+//!
+//! The target may not be easier than the limit of the network. On the main chain, it is adjusted
+//! every `RETARGET_INTERVAL` blocks to the time these took, and kept in between.
+
+
+use std::cmp::Ordering;
+
+use hash::*;
+
+
+/// The number of blocks after which the target is adjusted
+pub const RETARGET_INTERVAL: u64 = 2016;
+
+// the time in seconds that `RETARGET_INTERVAL` blocks should take
+const TARGET_TIMESPAN: i64 = 14 * 24 * 60 * 60;
+
+
+/// The chain of which the blocks are validated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Network {
+    Main,
+
+    /// A local test chain with a trivial target, which is never adjusted
+    Regtest
+}
+
+impl Network {
+
+    /// The easiest allowed target, in compact form
+    pub fn pow_limit(self) -> u32 {
+        match self {
+            Network::Main    => 0x1d00ffff,
+            Network::Regtest => 0x207fffff
+        }
+    }
+
+    /// True if the target is adjusted every `RETARGET_INTERVAL` blocks and must be kept in between
+    pub fn retargets(self) -> bool {
+        self == Network::Main
+    }
+}
+
+
+/// Decodes the compact target into a little endian 256-bit number
+///
+/// Returns None for negative, zero or overflowing targets
+pub fn target_from_compact(bits: u32) -> Option<[u8; 32]> {
+
+    let exponent = (bits >> 24) as usize;
+    let mantissa = bits & 0x007F_FFFF;
+
+    if bits & 0x0080_0000 != 0 || mantissa == 0 {
+        return None;
+    }
+
+    // the mantissa is shifted by (exponent - 3) bytes
+    let mut target = [0u8; 32];
+    for n in 0..3 {
+        let byte = (mantissa >> (n * 8)) as u8;
+        let pos  = exponent + n;
+
+        if pos < 3 {
+            continue;
+        }
+        if pos - 3 >= 32 {
+            if byte != 0 {
+                return None;
+            }
+            continue;
+        }
+        target[pos - 3] = byte;
+    }
+
+    if target.iter().all(|&b| b == 0) {
+        None
+    } else {
+        Some(target)
+    }
+}
+
+/// Encodes a little endian 256-bit number in the compact form, truncating it to 3 bytes
+pub fn compact_from_target(target: &[u8; 32]) -> u32 {
+
+    let mut size = 32 - target.iter().rev().take_while(|&&b| b == 0).count();
+
+    let mut mantissa = 0u32;
+    for n in 0..3 {
+        if size + n >= 3 {
+            mantissa |= (target[size + n - 3] as u32) << (n * 8);
+        }
+    }
+
+    // the high bit of the mantissa is the sign
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+    (size as u32) << 24 | mantissa
+}
+
+/// Returns true if the target is valid and not easier than `limit`
+pub fn within_limit(bits: u32, limit: u32) -> bool {
+
+    match (target_from_compact(bits), target_from_compact(limit)) {
+        (Some(target), Some(limit)) => target.iter().rev().cmp(limit.iter().rev()) != Ordering::Greater,
+        _                           => false
+    }
+}
+
+/// Returns the target of a block that starts a retarget period, in compact form
+///
+/// `bits` is the target of the previous block, and the times are those of the first and the last
+/// block of the previous period. The change is limited to a factor 4, and to `limit`.
+pub fn next_retarget(bits: u32, first_time: u32, last_time: u32, limit: u32) -> u32 {
+
+    let timespan = (last_time as i64 - first_time as i64)
+        .clamp(TARGET_TIMESPAN / 4, TARGET_TIMESPAN * 4);
+
+    let target = match target_from_compact(bits) {
+        Some(target) => target,
+        None         => return limit
+    };
+
+    match mul_div(&target, timespan as u64, TARGET_TIMESPAN as u64) {
+        Some(target) if within_limit(compact_from_target(&target), limit) => compact_from_target(&target),
+        _ => limit
+    }
+}
+
+// Returns the little endian 256-bit number times `mul` divided by `div`, or None if it overflows
+fn mul_div(value: &[u8; 32], mul: u64, div: u64) -> Option<[u8; 32]> {
+
+    let mut limbs = [0u64; 5];
+    for (n, chunk) in value.chunks(8).enumerate() {
+        limbs[n] = chunk.iter().rev().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+    }
+
+    let mut carry = 0u128;
+    for limb in limbs.iter_mut() {
+        let product = *limb as u128 * mul as u128 + carry;
+        *limb = product as u64;
+        carry = product >> 64;
+    }
+
+    let mut remainder = 0u128;
+    for limb in limbs.iter_mut().rev() {
+        let current = (remainder << 64) | *limb as u128;
+        *limb     = (current / div as u128) as u64;
+        remainder = current % div as u128;
+    }
+
+    if limbs[4] != 0 {
+        return None;
+    }
+    let mut result = [0u8; 32];
+    for (n, limb) in limbs[0..4].iter().enumerate() {
+        result[n * 8..n * 8 + 8].copy_from_slice(&limb.to_le_bytes());
+    }
+    Some(result)
+}
+
+/// Returns true if the hash meets the target
+pub fn verify_proof_of_work(hash: Hash32, bits: u32) -> bool {
+
+    match target_from_compact(bits) {
+        None         => false,
+        Some(target) => hash.0.iter().rev().cmp(target.iter().rev()) != Ordering::Greater
+    }
+}
+
+/// Returns the expected number of hashes needed to meet the target
+///
+/// This is calculated as 2^256 / target with 64 bits of precision; it saturates for targets
+/// below 2^128, which are far beyond any realistic difficulty
+pub fn work_from_compact(bits: u32) -> u128 {
+
+    let target = match target_from_compact(bits) {
+        None         => return 0,
+        Some(target) => target
+    };
+
+    let high = target[16..32].iter().rev().fold(0u128, |acc, &b| (acc << 8) | b as u128);
+    let low  = target[0..16].iter().rev().fold(0u128, |acc, &b| (acc << 8) | b as u128);
+
+    if high == 0 {
+        return u128::MAX;
+    }
+
+    // take the 64 most significant bits of the target
+    let bit_len = 256 - high.leading_zeros();
+    let top = if bit_len >= 192 {
+        (high >> (bit_len - 192)) as u64
+    } else {
+        ((high << (192 - bit_len)) | (low >> (bit_len - 64))) as u64
+    };
+
+    // 2^256 / target = (2^127 / top) * 2^(193 - bit_len)
+    let quotient = (1u128 << 127) / top as u128;
+    if bit_len > 193 {
+        quotient >> (bit_len - 193)
+    } else {
+        quotient << (193 - bit_len)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use util::*;
+
+    #[test]
+    fn test_target_from_compact() {
+
+        let target = target_from_compact(0x1d00ffff).unwrap();
+        assert_eq!(&target[26..28], &[0xff, 0xff]);
+        assert!(target[0..26].iter().chain(target[28..].iter()).all(|&b| b == 0));
+
+        let target = target_from_compact(0x03123456).unwrap();
+        assert_eq!(&target[0..4], &[0x56, 0x34, 0x12, 0]);
+
+        let target = target_from_compact(0x02123456).unwrap();
+        assert_eq!(&target[0..3], &[0x34, 0x12, 0]);
+
+        assert_eq!(target_from_compact(0x04923456), None);
+        assert_eq!(target_from_compact(0x1d000000), None);
+        assert_eq!(target_from_compact(0x23123456), None);
+    }
+
+    #[test]
+    fn test_work_from_compact() {
+
+        assert_eq!(work_from_compact(0x1d00ffff), 0x1_0001_0001);
+        assert_eq!(work_from_compact(0x207fffff), 2);
+        assert_eq!(work_from_compact(0x1b0404cb), 0x3fb3_ab76_4c00);
+        assert_eq!(work_from_compact(0x1d800000), 0);
+    }
+
+    #[test]
+    fn test_verify_proof_of_work() {
+
+        let genesis = from_hex("0100000000000000000000000000000000000000000000000000000000000000\
+           000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa\
+           4b1e5e4a29ab5f49ffff001d1dac2b7c");
+
+        let hash = Hash32Buf::double_sha256(&genesis);
+        assert!(verify_proof_of_work(hash.as_ref(), 0x1d00ffff));
+        assert!(!verify_proof_of_work(hash.as_ref(), 0x1a00ffff));
+    }
+
+    #[test]
+    fn test_compact_from_target() {
+
+        for &bits in &[0x1d00ffff, 0x1b0404cb, 0x207fffff, 0x03123456, 0x02123400, 0x01120000] {
+            assert_eq!(compact_from_target(&target_from_compact(bits).unwrap()), bits);
+        }
+
+        // the mantissa may not have the sign bit set
+        let mut target = [0u8; 32];
+        target[0] = 0x80;
+        assert_eq!(compact_from_target(&target), 0x02008000);
+    }
+
+    #[test]
+    fn test_within_limit() {
+
+        assert!(within_limit(0x1d00ffff, Network::Main.pow_limit()));
+        assert!(within_limit(0x1b0404cb, Network::Main.pow_limit()));
+        assert!(!within_limit(0x1d010000, Network::Main.pow_limit()));
+        assert!(!within_limit(0x207fffff, Network::Main.pow_limit()));
+        assert!(within_limit(0x207fffff, Network::Regtest.pow_limit()));
+        assert!(!within_limit(0x1d800000, Network::Main.pow_limit()));
+    }
+
+    #[test]
+    fn test_next_retarget() {
+
+        let limit = Network::Main.pow_limit();
+
+        // the retargets of blocks 32256, 2016 and two made up to hit the bounds, as in core
+        assert_eq!(next_retarget(0x1d00ffff, 1261130161, 1262152739, limit), 0x1d00d86a);
+        assert_eq!(next_retarget(0x1d00ffff, 1231006505, 1233061996, limit), 0x1d00ffff);
+        assert_eq!(next_retarget(0x1c05a3f4, 1279008237, 1279297671, limit), 0x1c0168fd);
+        assert_eq!(next_retarget(0x1c387f6f, 1263163443, 1269211443, limit), 0x1d00e1fd);
+    }
+}
@@ -0,0 +1,335 @@
+//! Recovery of a store after a crash
+//!
+//! Data is written to the filesets in steps: the transactions, the block header and the
+//! spend-tree records are appended, and then the hash-indexes are updated. If the process dies
+//! in between, the tail of a fileset can be partly written, transactions can leave guards in the
+//! tx-index that are never resolved, and blocks can be stored in the spend-tree without being
+//! added to the block-index.
+//!
+//! `recover` is run when a store is opened, while holding the exclusive lock on writes such that
+//! no other store is halfway an operation. If another store is writing, the data can't be told
+//! apart from data left by a crash, and recovery is skipped. It discards data that is partly
+//! written, removes the guards of transactions that are not in the tx-index and validates
+//! recently stored blocks again if they are not in the block-index.
+
+
+use block_add;
+use buffer::*;
+use export;
+use hash::*;
+use scheduler::BlockProgress;
+use store::{BlockHeaderPtr, BlockPtr, FlatFilePtr, HashIndexGuard, RecordPtr, Store, TxPtr};
+use transaction::Transaction;
+
+
+/// The number of most recent blocks in the spend-tree that are checked
+const RECOVERY_WINDOW: usize = 16;
+
+
+/// The repairs made by `recover`
+#[derive(Debug, Default, PartialEq)]
+pub struct RecoveryReport {
+
+    /// Records of a partly written block that were discarded from the spend-tree
+    pub spend_tree_records: u64,
+
+    /// A partly written block header was discarded
+    pub block_header: bool,
+
+    /// Partly written or unindexed transactions were discarded
+    pub transactions: bool,
+
+    /// Guards removed from the tx-index for transactions that were not indexed
+    pub tx_guards: usize,
+
+    /// Blocks that were stored but not in the block-index, and have been validated again
+    pub blocks_finished: usize,
+
+    /// Blocks that were validated again but turned out invalid
+    pub blocks_discarded: usize
+}
+
+impl RecoveryReport {
+
+    /// Returns true if nothing was repaired
+    pub fn is_clean(&self) -> bool {
+        *self == RecoveryReport::default()
+    }
+}
+
+
+/// Checks the tails of the filesets and repairs what was left by an interrupted write
+///
+/// The caller must hold the exclusive lock on writes, or otherwise be sure the store is not used
+/// by others
+pub fn recover(store: &mut Store) -> RecoveryReport {
+
+    let mut report = RecoveryReport {
+        spend_tree_records: store.spend_tree.truncate_incomplete(),
+        ..Default::default()
+    };
+
+    // the most recent blocks, oldest first
+    let mut blocks = vec![];
+    let mut end    = store.spend_tree.get_end();
+    while blocks.len() < RECOVERY_WINDOW {
+        match store.spend_tree.get_block_before(end) {
+            Some(block) => { end = block.start; blocks.push(block); },
+            None        => break
+        }
+    }
+    blocks.reverse();
+
+    let last_block = blocks.last().cloned();
+
+    let header_ptr = match last_block {
+        Some(block) => store.spend_tree.get_block_header_ptr(block),
+        None        => BlockHeaderPtr::first()
+    };
+    report.block_header = store.block_headers.truncate_block_headers(header_ptr);
+
+    let tx_ptr = match last_block {
+        Some(block) => last_transaction(store, block),
+        None        => TxPtr::first()
+    };
+    recover_transactions(store, tx_ptr, &mut report);
+
+    for block in blocks {
+        if !is_indexed(store, block) {
+            finish_block(store, block, &mut report);
+        }
+    }
+
+    report
+}
+
+// Returns the transaction of the block that was written last
+fn last_transaction(store: &mut Store, block: BlockPtr) -> TxPtr {
+
+    let key = |ptr: TxPtr| (ptr.get_file_number(), ptr.get_file_offset());
+
+    let records = store.spend_tree.get_block_mut(block);
+    records[1..records.len() - 1].iter()
+        .filter(|rec| rec.is_transaction() && !rec.is_unmatched_input())
+        .map(|rec| rec.get_transaction_ptr())
+        .max_by_key(|&ptr| key(ptr))
+        .unwrap_or_else(TxPtr::first)
+}
+
+// Checks the transactions written from `from` and discards the ones at the end that are not
+// in the tx-index. Their inputs may have left guards at the transactions they spend; these are
+// removed, as the position will be reused.
+fn recover_transactions(store: &mut Store, from: TxPtr, report: &mut RecoveryReport) {
+
+    let (txs, stopped) = store.transactions.read_from(from);
+
+    // the position after the last indexed transaction
+    let mut keep = from;
+    for (n, &(ptr, ref raw)) in txs.iter().enumerate() {
+
+        let tx = match Transaction::parse(&mut Buffer::new(raw)) {
+            Ok(tx) => tx,
+            Err(_) => continue
+        };
+        let hash = Hash32Buf::double_sha256(tx.to_raw());
+
+        if store.tx_index.get(hash.as_ref()).contains(&ptr) {
+            keep = txs.get(n + 1).map_or(stopped, |&(next, _)| next);
+            continue;
+        }
+
+        if !tx.is_coinbase() {
+            for (index, input) in tx.txs_in.iter().enumerate() {
+                if store.tx_index.remove_guard(input.prev_tx_out, ptr.to_input(index as u16)) {
+                    report.tx_guards += 1;
+                }
+            }
+        }
+    }
+
+    // A zero length followed by data is a write that was interrupted while others
+    // continued; we can't find the transactions after it so we leave it
+    let end = store.transactions.get_end();
+    if stopped != end && !store.transactions.is_zero_from(stopped) {
+        return;
+    }
+
+    if keep != end {
+        store.transactions.truncate(keep);
+        report.transactions = true;
+    }
+}
+
+// Returns the hash of the block and the hash of its previous block
+fn get_hashes(store: &mut Store, block: BlockPtr) -> (Hash32Buf, Hash32Buf) {
+
+    let header_ptr = store.spend_tree.get_block_header_ptr(block);
+    let header     = store.block_headers.read(header_ptr);
+
+    (Hash32Buf::double_sha256(header), Hash32Buf::from_slice(&header[4..36]))
+}
+
+// Returns true if the block is in the block-index, or waits as an orphan for its previous block
+fn is_indexed(store: &mut Store, block: BlockPtr) -> bool {
+
+    let (hash, prev_hash) = get_hashes(store, block);
+
+    if store.block_index.get(hash.as_ref()).iter().any(|ptr| !ptr.is_guard()) {
+        return true;
+    }
+
+    if block_add::is_genesis_block(hash.as_ref()) {
+        return false;
+    }
+
+    // if the previous block is in, this block should have been connected to it
+    let previous = store.block_index.get(prev_hash.as_ref());
+    previous.contains(&block.to_guard()) && previous.iter().all(|ptr| ptr.is_guard())
+}
+
+// Validates the block again, after removing the traces of the interrupted attempt
+fn finish_block(store: &mut Store, block: BlockPtr, report: &mut RecoveryReport) {
+
+    let (hash, prev_hash) = get_hashes(store, block);
+    let raw = export::read_block(store, block).expect("Transaction of recent block is pruned");
+
+    info!(store.logger, "recover - finishing block"; "hash" => format!("{:?}", hash));
+
+    store.block_index.remove_guard(prev_hash.as_ref(), block.to_guard());
+    store.spend_tree.disconnect_block(block);
+    if let Some(ref mut utxo_hashes) = store.utxo_hashes {
+        utxo_hashes.remove(hash.as_ref(), block);
+    }
+
+    // the block is stored again at the end of the spend-tree
+    let end = store.spend_tree.get_end();
+    let result = block_add::validate_block_locked(store, &raw, &BlockProgress::new());
+
+    report.blocks_finished += 1;
+    if let Err(err) = result {
+
+        warn!(store.logger, "recover - discarding invalid block";
+            "hash"  => format!("{:?}", hash),
+            "error" => format!("{:?}", err));
+
+        discard_from(store, hash.as_ref(), end);
+        report.blocks_discarded += 1;
+    }
+}
+
+// Discards the spend-tree records from `end`, which contain the failed attempt to store the
+// block with the given hash. Blocks waiting for it that were linked to it are disconnected again.
+fn discard_from(store: &mut Store, hash: Hash32, end: RecordPtr) {
+
+    for child in store.block_index.get(hash) {
+        let linked = store.spend_tree.get_previous_block(child)
+            .is_some_and(|prev| prev.start.to_index() >= end.to_index());
+
+        if child.is_guard() && linked {
+            let child_hash = store.get_block_hash(child);
+            store.spend_tree.disconnect_block(child);
+            if let Some(ref mut utxo_hashes) = store.utxo_hashes {
+                utxo_hashes.remove(child_hash.as_ref(), child);
+            }
+        }
+    }
+    store.spend_tree.truncate(end);
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use block::Block;
+    use test_chain::TestChain;
+
+    #[test]
+    fn test_recover_clean() {
+
+        let mut store = Store::new(& test_cfg!());
+        assert!(recover(&mut store).is_clean());
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let tip       = chain.extend(genesis, 3);
+        for block in chain.chain(tip) {
+            block_add::add_block(&mut store, &block.raw);
+        }
+        assert!(recover(&mut store).is_clean());
+    }
+
+    #[test]
+    fn test_recover_unindexed_block() {
+
+        let mut store = Store::new(& test_cfg!());
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let tip       = chain.extend(genesis, 3);
+        let blocks    = chain.chain(tip);
+        for block in &blocks[0..3] {
+            block_add::add_block(&mut store, &block.raw);
+        }
+
+        // the last block is stored, but the process dies before it is connected
+        let last = &blocks[3];
+        let ptr  = block_add::store_block(&mut store, &Block::new(&last.raw).unwrap(),
+            Hash32Buf::from_slice(&last.hash).as_ref(), &BlockProgress::new()).unwrap();
+        assert_eq!(export::get_block(&mut store, Hash32Buf::from_slice(&last.hash).as_ref()), None);
+
+        // and a block header is half-written
+        let _ = store.block_headers.write(&[0; 80]);
+
+        let report = recover(&mut store);
+        assert_eq!(report, RecoveryReport {
+            block_header:    true,
+            blocks_finished: 1,
+            ..Default::default()
+        });
+
+        let found = export::get_block(&mut store, Hash32Buf::from_slice(&last.hash).as_ref()).unwrap();
+        assert!(found != ptr);
+        assert_eq!(store.tips.get_most_work_tip().unwrap().height, 3);
+        assert!(recover(&mut store).is_clean());
+    }
+
+    #[test]
+    fn test_recover_transaction_guards() {
+
+        let mut store = Store::new(& test_cfg!());
+        store.initial_sync = false;
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let tip       = chain.extend(genesis, 1);
+        let coinbase  = chain.block(tip).coinbase.clone().unwrap();
+        let parent    = chain.spend(&[coinbase.output(0)], &[coinbase.output(0).value]);
+        let child     = chain.spend(&[parent.output(0)], &[parent.output(0).value]);
+
+        for block in chain.chain(tip) {
+            block_add::add_block(&mut store, &block.raw);
+        }
+
+        // the child is written and guards its input, but the process dies before it is indexed
+        let tx   = Transaction::parse(&mut Buffer::new(&child.raw)).unwrap();
+        let ptr  = store.transactions.write(&tx);
+        let parent_hash = Hash32Buf::from_slice(&parent.txid);
+        assert_eq!(store.tx_index.get_or_set(parent_hash.as_ref(), ptr.to_input(0)), None);
+        assert!(store.tx_index.get(parent_hash.as_ref()).iter().all(|ptr| ptr.is_guard()));
+
+        let report = recover(&mut store);
+        assert_eq!(report, RecoveryReport {
+            transactions: true,
+            tx_guards:    1,
+            ..Default::default()
+        });
+        assert_eq!(store.transactions.get_end(), ptr);
+        assert_eq!(store.tx_index.get(parent_hash.as_ref()), vec![]);
+
+        // the parent can now be added without finding the child
+        let tx   = Transaction::parse(&mut Buffer::new(&parent.raw)).unwrap();
+        let ptr  = store.transactions.write(&tx);
+        assert!(store.tx_index.set(parent_hash.as_ref(), ptr, &[], false));
+    }
+}
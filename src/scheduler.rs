@@ -0,0 +1,488 @@
+//! Asynchronous block validation
+//!
+//! The scheduler validates multiple blocks concurrently. Each worker thread uses its own clone
+//! of the store, so a long running ("toxic") block does not hold up the validation of
+//! competing blocks.
+//!
+//! Submitting a block returns a `BlockHandle`, which reports the progress and cost of the
+//! validation such that the peer layer can punish the sender. The handle can also be used to
+//! cancel the block, or to deprioritise it; deprioritised blocks are only started when no other
+//! blocks are queued.
+//!
+//! A panic during validation can leave the store of a worker partly written, so the worker stops
+//! without using it again. The partly written data is repaired by `recover` when the store is
+//! opened again. Once all workers have stopped, queued and newly submitted blocks are cancelled.
+
+
+use std::collections::VecDeque;
+use std::panic;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use block::BlockError;
+use block_add;
+use store::Store;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockStatus {
+    Queued,
+    Validating,
+    Valid,
+    Invalid(BlockError),
+    Cancelled,
+
+    /// Validation panicked; this is a bug rather than an invalid block
+    Failed
+}
+
+impl BlockStatus {
+    pub fn is_finished(&self) -> bool {
+        !matches!(*self, BlockStatus::Queued | BlockStatus::Validating)
+    }
+}
+
+
+/// Snapshot of the progress and cost of the validation of a block
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub status:      BlockStatus,
+
+    /// Number of transactions in the block; zero until the block is parsed
+    pub tx_count:    usize,
+    pub txs_done:    usize,
+    pub inputs_done: usize,
+
+    /// Time spent validating so far
+    pub cost:        Duration
+}
+
+
+struct ProgressState {
+    status:  BlockStatus,
+    started: Option<Instant>,
+    cost:    Duration
+}
+
+/// The progress of a single block, shared between the validating thread and the handle
+pub struct BlockProgress {
+    cancelled:     AtomicBool,
+    deprioritised: AtomicBool,
+
+    tx_count:      AtomicUsize,
+    txs_done:      AtomicUsize,
+    inputs_done:   AtomicUsize,
+
+    state:         Mutex<ProgressState>,
+    finished:      Condvar
+}
+
+impl BlockProgress {
+
+    pub fn new() -> BlockProgress {
+        BlockProgress {
+            cancelled:     AtomicBool::new(false),
+            deprioritised: AtomicBool::new(false),
+            tx_count:      AtomicUsize::new(0),
+            txs_done:      AtomicUsize::new(0),
+            inputs_done:   AtomicUsize::new(0),
+            state:         Mutex::new(ProgressState {
+                status:  BlockStatus::Queued,
+                started: None,
+                cost:    Duration::from_secs(0)
+            }),
+            finished:      Condvar::new()
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_tx_count(&self, tx_count: usize) {
+        self.tx_count.store(tx_count, Ordering::Relaxed);
+    }
+
+    /// Registers a verified transaction with the given number of inputs
+    pub fn add_transaction(&self, input_count: usize) {
+        self.txs_done.fetch_add(1, Ordering::Relaxed);
+        self.inputs_done.fetch_add(input_count, Ordering::Relaxed);
+    }
+
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+
+        // a block that is not yet started is cancelled immediately
+        let mut state = self.state.lock().unwrap();
+        if state.status == BlockStatus::Queued {
+            state.status = BlockStatus::Cancelled;
+            self.finished.notify_all();
+        }
+    }
+
+    /// Marks the block as being validated; returns false if it was already cancelled
+    fn start(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.status != BlockStatus::Queued {
+            return false;
+        }
+        state.status  = BlockStatus::Validating;
+        state.started = Some(Instant::now());
+        true
+    }
+
+    fn finish(&self, status: BlockStatus) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(started) = state.started {
+            state.cost = started.elapsed();
+        }
+        state.status = status;
+        self.finished.notify_all();
+    }
+
+    fn snapshot(&self, state: &ProgressState) -> Progress {
+        let cost = match (state.status == BlockStatus::Validating, state.started) {
+            (true, Some(started)) => started.elapsed(),
+            _                     => state.cost
+        };
+
+        Progress {
+            status:      state.status.clone(),
+            tx_count:    self.tx_count.load(Ordering::Relaxed),
+            txs_done:    self.txs_done.load(Ordering::Relaxed),
+            inputs_done: self.inputs_done.load(Ordering::Relaxed),
+            cost
+        }
+    }
+}
+
+impl Default for BlockProgress {
+    fn default() -> BlockProgress {
+        BlockProgress::new()
+    }
+}
+
+
+/// Handle to a block submitted to the scheduler
+#[derive(Clone)]
+pub struct BlockHandle {
+    progress: Arc<BlockProgress>
+}
+
+impl BlockHandle {
+
+    /// Cancels the validation
+    ///
+    /// A block that is already stored in the spend-tree will still be connected
+    pub fn cancel(&self) {
+        self.progress.cancel();
+    }
+
+    /// Moves the block behind all other queued blocks
+    pub fn deprioritise(&self) {
+        self.progress.deprioritised.store(true, Ordering::Relaxed);
+    }
+
+    pub fn progress(&self) -> Progress {
+        let state = self.progress.state.lock().unwrap();
+        self.progress.snapshot(&state)
+    }
+
+    /// Blocks until the validation is finished
+    pub fn wait(&self) -> Progress {
+        let mut state = self.progress.state.lock().unwrap();
+        while !state.status.is_finished() {
+            state = self.progress.finished.wait(state).unwrap();
+        }
+        self.progress.snapshot(&state)
+    }
+}
+
+
+struct Job {
+    block:    Vec<u8>,
+    progress: Arc<BlockProgress>
+}
+
+struct Queue {
+    jobs:     VecDeque<Job>,
+    shutdown: bool
+}
+
+impl Queue {
+
+    /// Takes the oldest job that is not deprioritised, or else the oldest job
+    fn pop(&mut self) -> Option<Job> {
+        let idx = self.jobs.iter()
+            .position(|job| !job.progress.deprioritised.load(Ordering::Relaxed))
+            .unwrap_or(0);
+
+        self.jobs.remove(idx)
+    }
+}
+
+struct Shared {
+    queue:     Mutex<Queue>,
+    available: Condvar,
+
+    // number of workers that haven't stopped after a panic
+    workers:   AtomicUsize
+}
+
+
+/// Validates blocks concurrently on a fixed number of worker threads
+pub struct Scheduler {
+    shared:  Arc<Shared>,
+    workers: Vec<thread::JoinHandle<()>>
+}
+
+impl Scheduler {
+
+    /// Creates a scheduler with `worker_count` threads; each opens its own clone of `store`
+    pub fn new(store: &Store, worker_count: usize) -> Scheduler {
+
+        let shared = Arc::new(Shared {
+            queue:     Mutex::new(Queue { jobs: VecDeque::new(), shutdown: false }),
+            available: Condvar::new(),
+            workers:   AtomicUsize::new(worker_count)
+        });
+
+        let workers = (0..worker_count).map(|_| {
+            let cfg          = store.get_config().clone();
+            let initial_sync = store.initial_sync;
+            let shared       = shared.clone();
+
+            thread::spawn(move || {
+                let mut worker_store = Store::new(&cfg);
+                worker_store.initial_sync = initial_sync;
+
+                run_worker(worker_store, shared)
+            })
+        }).collect();
+
+        Scheduler {
+            shared,
+            workers
+        }
+    }
+
+    /// Queues a block for validation
+    pub fn submit(&self, block: Vec<u8>) -> BlockHandle {
+
+        let progress = Arc::new(BlockProgress::new());
+
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.shutdown {
+            progress.cancel();
+        } else {
+            queue.jobs.push_back(Job { block, progress: progress.clone() });
+            self.shared.available.notify_one();
+        }
+
+        BlockHandle { progress }
+    }
+
+    /// Number of blocks waiting for a worker
+    pub fn queued(&self) -> usize {
+        self.shared.queue.lock().unwrap().jobs.len()
+    }
+}
+
+impl Drop for Scheduler {
+
+    // Running blocks are finished; queued blocks are cancelled
+    fn drop(&mut self) {
+
+        shutdown(&self.shared);
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+
+// Stops the workers once they finish their block, and cancels the queued blocks
+fn shutdown(shared: &Shared) {
+    let mut queue = shared.queue.lock().unwrap();
+    queue.shutdown = true;
+    for job in queue.jobs.drain(..) {
+        job.progress.cancel();
+    }
+    shared.available.notify_all();
+}
+
+fn next_job(shared: &Shared) -> Option<Job> {
+    let mut queue = shared.queue.lock().unwrap();
+    loop {
+        if queue.shutdown {
+            return None;
+        }
+        if let Some(job) = queue.pop() {
+            return Some(job);
+        }
+        queue = shared.available.wait(queue).unwrap();
+    }
+}
+
+fn run_worker(mut store: Store, shared: Arc<Shared>) {
+
+    while let Some(job) = next_job(&shared) {
+
+        if !job.progress.start() {
+            continue;
+        }
+
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            block_add::validate_block(&mut store, &job.block, &job.progress)
+        }));
+
+        let panicked = result.is_err();
+        job.progress.finish(match result {
+            Ok(Ok(()))                     => BlockStatus::Valid,
+            Ok(Err(BlockError::Cancelled)) => BlockStatus::Cancelled,
+            Ok(Err(err))                   => BlockStatus::Invalid(err),
+            Err(_)                         => BlockStatus::Failed
+        });
+
+        // the store may be partly written; it must be recovered before it is used again
+        if panicked {
+            drop(store);
+            if shared.workers.fetch_sub(1, Ordering::SeqCst) == 1 {
+                shutdown(&shared);
+            }
+            return;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use hash::*;
+    use store;
+    use store::HashIndexGuard;
+    use test_chain::{TestChain, Violation};
+
+    // Creates a chain of `count` coinbase-only blocks on top of genesis
+    fn create_chain(count: usize) -> Vec<Vec<u8>> {
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let tip       = chain.extend(genesis, count);
+
+        chain.chain(tip).into_iter().map(|block| block.raw.clone()).collect()
+    }
+
+    #[test]
+    fn test_scheduler_concurrent() {
+
+        let mut store = store::Store::new(&test_cfg!());
+        let blocks    = create_chain(8);
+
+        let handles: Vec<_> = {
+            let scheduler = Scheduler::new(&store, 4);
+
+            // submit in reverse to also exercise out-of-order connection
+            let handles: Vec<_> = blocks.iter().rev()
+                .map(|block| scheduler.submit(block.clone()))
+                .collect();
+
+            for handle in handles.iter() {
+                let progress = handle.wait();
+                assert_eq!(progress.status, BlockStatus::Valid);
+                assert_eq!(progress.txs_done, progress.tx_count);
+            }
+            handles
+        };
+        assert_eq!(handles[0].progress().inputs_done, 1);
+
+        for block in blocks.iter() {
+            let hash = Hash32Buf::double_sha256(&block[0..80]);
+            assert!(store.block_index.get(hash.as_ref()).iter().any(|ptr| !ptr.is_guard()));
+        }
+    }
+
+    #[test]
+    fn test_scheduler_invalid() {
+
+        let store     = store::Store::new(&test_cfg!());
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let block     = chain.build_block(genesis).violate(Violation::BadMerkleRoot).add();
+
+        let scheduler = Scheduler::new(&store, 1);
+        let handle    = scheduler.submit(chain.block(block).raw.clone());
+
+        assert_eq!(handle.wait().status, BlockStatus::Invalid(BlockError::IncorrectMerkleRoot));
+    }
+
+    #[test]
+    fn test_scheduler_failed() {
+
+        let cfg = test_cfg!();
+        let _   = store::Store::new(&cfg);
+
+        // validating with a read-only store panics
+        let store     = store::Store::open_read_only(&cfg).unwrap();
+        let blocks    = create_chain(1);
+        let scheduler = Scheduler::new(&store, 1);
+
+        let failed = scheduler.submit(blocks[0].clone());
+        let queued = scheduler.submit(blocks[1].clone());
+        assert_eq!(failed.wait().status, BlockStatus::Failed);
+
+        // the worker has stopped
+        assert_eq!(queued.wait().status, BlockStatus::Cancelled);
+        assert_eq!(scheduler.submit(blocks[1].clone()).wait().status, BlockStatus::Cancelled);
+    }
+
+    #[test]
+    fn test_scheduler_cancel_queued() {
+
+        let store     = store::Store::new(&test_cfg!());
+        let scheduler = Scheduler::new(&store, 0);
+
+        let handle = scheduler.submit(create_chain(0).remove(0));
+        assert_eq!(handle.progress().status, BlockStatus::Queued);
+
+        handle.cancel();
+        assert_eq!(handle.wait().status, BlockStatus::Cancelled);
+    }
+
+    #[test]
+    fn test_validate_cancelled() {
+
+        let mut store = store::Store::new(&test_cfg!());
+        let blocks    = create_chain(1);
+
+        let progress = BlockProgress::new();
+        progress.cancel();
+
+        assert_eq!(block_add::validate_block(&mut store, &blocks[0], &progress),
+            Err(BlockError::Cancelled));
+
+        let hash = Hash32Buf::double_sha256(&blocks[0][0..80]);
+        assert!(store.block_index.get(hash.as_ref()).is_empty());
+    }
+
+    #[test]
+    fn test_deprioritise() {
+
+        let store     = store::Store::new(&test_cfg!());
+        let scheduler = Scheduler::new(&store, 0);
+
+        let first  = scheduler.submit(vec![1]);
+        let _      = scheduler.submit(vec![2]);
+        first.deprioritise();
+
+        let mut queue = scheduler.shared.queue.lock().unwrap();
+        assert_eq!(queue.pop().unwrap().block, vec![2]);
+        assert_eq!(queue.pop().unwrap().block, vec![1]);
+        assert!(queue.pop().is_none());
+    }
+}
@@ -0,0 +1,375 @@
+//! Consistent snapshots of a store that is in use
+//!
+//! Stores write to the same files concurrently, so copying the directory while blocks are added
+//! gives filesets and indexes that don't match each other. Each operation that writes takes a
+//! shared lock on the `write.lock` file of the store with `lock_writes`. `snapshot` takes an
+//! exclusive lock, which waits for running operations and holds off new ones, such that the
+//! write positions of the filesets and the roots of the indexes in the snapshot are from the
+//! same moment.
+//!
+//! The transactions and the block headers are only appended to. Under the lock, their full files
+//! are hard-linked to the snapshot and the write positions of their last files are recorded.
+//! These last files, and full files that can't be linked, are copied after the lock is released
+//! up to the recorded positions; the data that is added meanwhile is left out. The indexes and
+//! the spend-tree are updated in place, and are copied while holding the lock. Files are copied
+//! skipping the parts that are zero, such that the copy is as sparse as the original.
+
+extern crate libc;
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use store::{Store, set_write_pos};
+
+
+const LOCK_FILE: &str = "write.lock";
+
+// the size of the parts that are copied or skipped if zero
+const COPY_CHUNK: usize = 64 * 1024;
+
+
+/// The shared lock held by an operation that writes; released when dropped
+pub struct WriteLock {
+    _file: File
+}
+
+
+/// The result of `snapshot`
+#[derive(Debug, Default, PartialEq)]
+pub struct SnapshotReport {
+
+    /// Number of files copied
+    pub copied: usize,
+
+    /// Number of files that are no longer written and have been hard-linked
+    pub linked: usize,
+
+    /// Number of bytes of data copied
+    pub bytes: u64
+}
+
+
+fn open_lock_file(store: &Store) -> File {
+
+    // a read-only store can take the lock for a snapshot if a writer has created the file
+    let writable = !store.is_read_only();
+    OpenOptions::new()
+        .read(true)
+        .write(writable)
+        .create(writable)
+        .truncate(false)
+        .open(store.get_config().root.join(LOCK_FILE))
+        .expect("Cannot create files in store")
+}
+
+/// Takes the lock that prevents a snapshot while data is written
+///
+/// Many writers can hold this at the same time
+pub fn lock_writes(store: &Store) -> WriteLock {
+
+    assert!(!store.is_read_only(), "Cannot write to a read-only store");
+
+    let file = open_lock_file(store);
+    file.lock_shared().expect("Cannot lock store");
+
+    WriteLock { _file: file }
+}
+
+
+/// Takes the exclusive lock, waiting for the operations that are writing
+///
+/// While this is held, operations of other stores wait for it; this is used to remove data
+/// that concurrent writers may still reference
+pub fn lock_exclusive(store: &Store) -> WriteLock {
+
+    assert!(!store.is_read_only(), "Cannot write to a read-only store");
+
+    let file = open_lock_file(store);
+    file.lock().expect("Cannot lock store");
+
+    WriteLock { _file: file }
+}
+
+
+/// Takes the exclusive lock if no operation is writing, or returns None
+///
+/// While this is held, operations of other stores wait for it; this is used to repair the store
+pub fn try_lock_exclusive(store: &Store) -> Option<WriteLock> {
+
+    let file = open_lock_file(store);
+    file.try_lock().ok()?;
+
+    Some(WriteLock { _file: file })
+}
+
+
+/// Writes a snapshot of the store to `target`, which can be opened with `Store::new`
+///
+/// The target must not exist or be empty; to hard-link files it must be on the same file system
+pub fn snapshot(store: &mut Store, target: &Path) -> io::Result<SnapshotReport> {
+
+    if target.exists() && fs::read_dir(target)?.next().is_some() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "The snapshot target is not empty"));
+    }
+
+    let lock = open_lock_file(store);
+    lock.lock()?;
+
+    let full: HashSet<PathBuf> = store.transactions.get_full_files().into_iter()
+        .chain(store.block_headers.get_full_files())
+        .collect();
+
+    let mut last: HashMap<PathBuf, u64> = store.transactions.get_last_files().into_iter().collect();
+    let (path, pos) = store.block_headers.get_last_file_pos();
+    last.insert(path, pos);
+
+    let root         = store.get_config().root.clone();
+    let mut report   = SnapshotReport::default();
+    let mut appended = vec![];
+    {
+        let mut files = AppendedFiles { full: &full, last: &last, copy_later: &mut appended };
+        copy_dir(&root, target, &mut files, &mut report)?;
+    }
+    drop(lock);
+
+    for (from, to) in appended {
+
+        let result = match last.get(&from) {
+            Some(&pos) => copy_sparse(&from, &to, pos).and_then(|bytes| {
+                set_write_pos(&to, pos)?;
+                Ok(bytes)
+            }),
+            None => copy_sparse(&from, &to, u64::MAX)
+        };
+
+        match result {
+            Ok(bytes) => {
+                report.bytes  += bytes;
+                report.copied += 1;
+            },
+
+            // a full file that is pruned after the lock is released is left out, as if it was
+            // pruned before
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound && full.contains(&from) => {},
+            Err(err) => return Err(err)
+        }
+    }
+
+    info!(store.logger, "snapshot written";
+        "target" => format!("{:?}", target),
+        "copied" => report.copied,
+        "linked" => report.linked);
+
+    Ok(report)
+}
+
+// The files of the filesets that are only appended to
+struct AppendedFiles<'a> {
+
+    // files that are no longer written
+    full: &'a HashSet<PathBuf>,
+
+    // the last files, with their write positions
+    last: &'a HashMap<PathBuf, u64>,
+
+    // the files that are copied after the lock is released
+    copy_later: &'a mut Vec<(PathBuf, PathBuf)>
+}
+
+fn copy_dir(from: &Path, to: &Path, files: &mut AppendedFiles, report: &mut SnapshotReport)
+    -> io::Result<()>
+{
+    fs::create_dir_all(to)?;
+
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let path  = entry.path();
+        let name  = entry.file_name();
+        let dest  = to.join(&name);
+
+        // temporary files are written by operations that don't take the lock
+        if name == LOCK_FILE || path.extension().is_some_and(|ext| ext == "tmp") {
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            copy_dir(&path, &dest, files, report)?;
+        }
+        else if files.full.contains(&path) && fs::hard_link(&path, &dest).is_ok() {
+            report.linked += 1;
+        }
+        else if files.full.contains(&path) || files.last.contains_key(&path) {
+            files.copy_later.push((path, dest));
+        }
+        else {
+            report.bytes  += copy_sparse(&path, &dest, u64::MAX)?;
+            report.copied += 1;
+        }
+    }
+    Ok(())
+}
+
+// Returns the next range of the file from `pos` that may contain data
+//
+// The holes of a sparse file are skipped where the file system supports this
+#[cfg(unix)]
+fn next_data(file: &File, pos: u64, len: u64) -> Option<(u64, u64)> {
+
+    use std::os::unix::io::AsRawFd;
+
+    if pos >= len {
+        return None;
+    }
+
+    let fd    = file.as_raw_fd();
+    let start = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+    if start < 0 {
+        return match io::Error::last_os_error().raw_os_error() {
+            Some(libc::ENXIO) => None,
+            _                 => Some((pos, len))
+        };
+    }
+    let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+
+    Some((start as u64, if end < 0 { len } else { end as u64 }))
+}
+
+#[cfg(not(unix))]
+fn next_data(_file: &File, pos: u64, len: u64) -> Option<(u64, u64)> {
+
+    if pos >= len { None } else { Some((pos, len)) }
+}
+
+// Copies a file, leaving its holes and the chunks that are zero as holes; returns the bytes
+// written
+//
+// Only the data before `until` is copied; the copy has the size of the original
+fn copy_sparse(from: &Path, to: &Path, until: u64) -> io::Result<u64> {
+
+    let mut input  = File::open(from)?;
+    let mut output = File::create(to)?;
+    let len        = input.metadata()?.len();
+
+    let mut buffer  = vec![0; COPY_CHUNK];
+    let mut written = 0;
+    let mut pos     = 0;
+    let limit       = len.min(until);
+    while let Some((start, end)) = next_data(&input, pos, limit) {
+
+        let end = end.min(limit);
+
+        input.seek(SeekFrom::Start(start))?;
+        pos = start;
+        while pos < end {
+            let size = input.read(&mut buffer[..COPY_CHUNK.min((end - pos) as usize)])?;
+            if size == 0 {
+                break;
+            }
+
+            if buffer[..size].iter().any(|&byte| byte != 0) {
+                output.seek(SeekFrom::Start(pos))?;
+                output.write_all(&buffer[..size])?;
+                written += size as u64;
+            }
+            pos += size as u64;
+        }
+        pos = pos.max(end);
+    }
+    output.set_len(len)?;
+    Ok(written)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use block_add;
+    use config;
+    use fsck;
+    use store::FlatFilePtr;
+    use test_chain::TestChain;
+
+    #[test]
+    fn test_snapshot_restore() {
+
+        let cfg       = test_cfg!();
+        let mut store = Store::new(&cfg);
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let tip       = chain.extend(genesis, 102);
+        let coinbase  = chain.chain(tip)[1].coinbase.clone().unwrap();
+        let tx        = chain.spend(&[coinbase.output(0)], &[coinbase.output(0).value]);
+        let tip       = chain.add_block(tip, vec![tx]);
+        let next      = chain.extend(tip, 1);
+
+        for block in chain.chain(tip) {
+            block_add::add_block(&mut store, &block.raw);
+        }
+
+        // another store adds blocks while the snapshot is taken
+        let target = PathBuf::from(format!("{}-snapshot", cfg.root.display()));
+        let _      = fs::remove_dir_all(&target);
+
+        let writer = {
+            let cfg = cfg.clone();
+            let raw = chain.block(next).raw.clone();
+            ::std::thread::spawn(move || {
+                let mut store = Store::new(&cfg);
+                block_add::add_block(&mut store, &raw);
+            })
+        };
+        let report = snapshot(&mut store, &target).unwrap();
+        writer.join().unwrap();
+
+        assert!(report.copied > 0);
+        assert!(report.bytes > 0);
+
+        // the snapshot opens, is consistent and has either all of the last block or nothing
+        let mut restored = Store::new(&config::Config::new(target.to_str().unwrap()));
+        assert!(::recovery::recover(&mut restored).is_clean());
+
+        let check = fsck::check(&mut restored);
+        assert!(check.is_ok(), "{:?}", check);
+
+        let height = restored.tips.get_most_work_tip().unwrap().height;
+        assert!(height == 103 || height == 104, "{}", height);
+
+        // and it is independent of the store
+        let end = restored.transactions.get_end();
+        let next = chain.extend(if height == 103 { tip } else { next }, 1);
+        block_add::add_block(&mut restored, &chain.block(next).raw);
+        assert!(restored.transactions.get_end().get_file_offset() > end.get_file_offset());
+        assert_eq!(store.tips.get_most_work_tip().unwrap().height, 104);
+
+        assert!(snapshot(&mut store, &target).is_err());
+        let _ = fs::remove_dir_all(&target);
+    }
+
+    #[test]
+    fn test_copy_sparse() {
+
+        let cfg  = test_cfg!();
+        fs::create_dir_all(&cfg.root).unwrap();
+        let from = cfg.root.join("from");
+        let to   = cfg.root.join("to");
+
+        let mut data = vec![0u8; 4 * COPY_CHUNK + 10];
+        data[COPY_CHUNK + 1] = 1;
+        data[4 * COPY_CHUNK + 9] = 2;
+        fs::write(&from, &data).unwrap();
+
+        assert_eq!(copy_sparse(&from, &to, u64::MAX).unwrap(), COPY_CHUNK as u64 + 10);
+        assert_eq!(fs::read(&to).unwrap(), data);
+
+        // data from `until` is left out
+        assert_eq!(copy_sparse(&from, &to, COPY_CHUNK as u64 + 2).unwrap(), 2);
+        data[4 * COPY_CHUNK + 9] = 0;
+        assert_eq!(fs::read(&to).unwrap(), data);
+    }
+}
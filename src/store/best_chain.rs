@@ -0,0 +1,309 @@
+//! The heights of the connected blocks, and the hashes of the best chain by height
+//!
+//! The height of each connected block, on any branch, is stored in a hash-index by block hash.
+//!
+//! The best chain is the `best-chain` file at the root of the store, which holds the 32 byte
+//! hash of the block at each height, up to the tip with the most work. When a block is connected
+//! and its tip has the most work, the hashes are written back from it until the stored hash
+//! matches, and the file is cut after it. This follows a reorganisation to a chain that is
+//! shorter as well as longer.
+//!
+//! Stores update the file while holding an exclusive lock on it, after their tip is written, so
+//! the last one to update sees the tip with the most work. Readers don't take the lock; during a
+//! reorganisation they may see part of the new chain.
+//!
+//! Stores from before this index are caught up by `update`, which `api::open` calls.
+
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use config;
+use hash::*;
+use store::{Store, BlockPtr, HashIndexGuard};
+use store::hash_index::HashIndex;
+
+
+const CHAIN_FILE: &str = "best-chain";
+
+
+/// The height of a connected block, as stored in the height index
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct BlockHeight(pub u64);
+
+impl HashIndexGuard for BlockHeight {
+    fn is_guard(self) -> bool { false }
+}
+
+
+pub struct BestChain {
+    heights: HashIndex<BlockHeight>,
+    path:    PathBuf
+}
+
+impl BestChain {
+
+    /// Opens the index at the location given in the config
+    ///
+    /// Creates a new fileset if needed
+    pub fn new(cfg: &config::Config) -> BestChain {
+        BestChain {
+            heights: HashIndex::new(cfg, "block-height-index"),
+            path:    cfg.root.join(CHAIN_FILE)
+        }
+    }
+
+    fn open(&self) -> File {
+
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)
+            .expect("Cannot create files in store")
+    }
+
+    /// Returns the number of blocks in the best chain
+    pub fn len(&self) -> u64 {
+        self.path.metadata().map_or(0, |meta| meta.len() / 32)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the hash of the block at the given height in the best chain
+    pub fn get_hash(&self, height: u64) -> Option<Hash32Buf> {
+
+        read_hash(&mut File::open(&self.path).ok()?, height)
+    }
+
+    /// Returns the hashes of the best chain from the given height up
+    pub fn iter_from(&self, height: u64) -> BestChainIter {
+        BestChainIter {
+            file: File::open(&self.path).ok().map(io::BufReader::new),
+            height,
+            first: true
+        }
+    }
+
+    /// Returns the stored height of the block, which is connected or has been
+    pub fn get_height(&mut self, block_hash: Hash32) -> Option<u64> {
+
+        self.heights.get(block_hash).into_iter().next().map(|height| height.0)
+    }
+
+    fn set_height(&mut self, block_hash: Hash32, height: u64) {
+
+        self.heights.set(block_hash, BlockHeight(height), &[], true);
+    }
+}
+
+fn read_hash(file: &mut File, height: u64) -> Option<Hash32Buf> {
+
+    let mut hash = [0; 32];
+    file.seek(SeekFrom::Start(height * 32)).ok()?;
+    file.read_exact(&mut hash).ok()?;
+    Some(Hash32Buf::from_slice(&hash))
+}
+
+
+/// The hashes of the best chain from a height up, with their heights
+pub struct BestChainIter {
+    file:   Option<io::BufReader<File>>,
+    height: u64,
+    first:  bool
+}
+
+impl Iterator for BestChainIter {
+    type Item = (u64, [u8; 32]);
+
+    fn next(&mut self) -> Option<(u64, [u8; 32])> {
+
+        let file = self.file.as_mut()?;
+        if self.first {
+            file.seek(SeekFrom::Start(self.height * 32)).ok()?;
+            self.first = false;
+        }
+
+        let mut hash = [0; 32];
+        file.read_exact(&mut hash).ok()?;
+        self.height += 1;
+        Some((self.height - 1, hash))
+    }
+}
+
+
+/// Stores the height of a block that was just connected, and makes it the tip of the best chain
+/// if its tip has the most work
+pub fn connect_block(store: &mut Store, block: BlockPtr, block_hash: Hash32, height: u64) {
+
+    store.best_chain.set_height(block_hash, height);
+    update_from(store, block, block_hash.as_buf(), height);
+}
+
+/// Brings the best chain up to date with the tip with the most work
+///
+/// Does nothing for a read-only store
+pub fn update(store: &mut Store) {
+
+    if store.is_read_only() {
+        return;
+    }
+
+    let best = match store.tips.get_most_work_tip() {
+        Some(best) => best,
+        None       => return
+    };
+    if let Some(block) = get_block(store, best.block_hash.as_ref()) {
+        update_from(store, block, best.block_hash, best.height);
+    }
+}
+
+// Writes the best chain back from the given tip, if it is the tip with the most work
+fn update_from(store: &mut Store, tip: BlockPtr, tip_hash: Hash32Buf, tip_height: u64) {
+
+    let mut file = store.best_chain.open();
+    file.lock().expect("Cannot lock best chain");
+
+    if store.tips.get_most_work_tip().is_none_or(|best| best.block_hash != tip_hash) {
+        return;
+    }
+
+    let mut block  = tip;
+    let mut hash   = tip_hash;
+    let mut height = tip_height;
+    while read_hash(&mut file, height).as_ref() != Some(&hash) {
+
+        file.seek(SeekFrom::Start(height * 32))
+            .and_then(|_| file.write_all(hash.as_ref().0))
+            .expect("Cannot write best chain");
+
+        // stores from before the height index only have the heights of the blocks added since
+        if store.best_chain.get_height(hash.as_ref()).is_none() {
+            store.best_chain.set_height(hash.as_ref(), height);
+        }
+
+        block = match store.spend_tree.get_previous_block(block) {
+            Some(previous) => previous,
+            None           => break
+        };
+        hash   = store.get_block_hash(block);
+        height -= 1;
+    }
+
+    file.set_len((tip_height + 1) * 32).expect("Cannot write best chain");
+}
+
+fn get_block(store: &mut Store, hash: Hash32) -> Option<BlockPtr> {
+
+    store.block_index.get(hash).into_iter().find(|ptr| !ptr.is_guard())
+}
+
+
+/// Returns the block at the given height in the best chain
+pub fn get_block_at(store: &mut Store, height: u64) -> Option<BlockPtr> {
+
+    let hash = store.best_chain.get_hash(height)?;
+    get_block(store, hash.as_ref())
+}
+
+/// Returns the height of a connected block
+///
+/// Blocks connected before the height index was kept are found by walking back to one that has
+/// a height
+pub fn get_height(store: &mut Store, block_hash: Hash32) -> Option<u64> {
+
+    let mut block = get_block(store, block_hash)?;
+    let mut hash  = block_hash.as_buf();
+    let mut steps = 0;
+    loop {
+        if let Some(height) = store.best_chain.get_height(hash.as_ref()) {
+            return Some(height + steps);
+        }
+        block = match store.spend_tree.get_previous_block(block) {
+            Some(previous) => previous,
+            None           => return Some(steps)
+        };
+        hash   = store.get_block_hash(block);
+        steps += 1;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use block_add;
+    use test_chain::TestChain;
+
+    fn get_hashes(store: &Store, height: u64) -> Vec<(u64, [u8; 32])> {
+        store.best_chain.iter_from(height).collect()
+    }
+
+    #[test]
+    fn test_best_chain() {
+
+        let mut store = Store::new(& test_cfg!());
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let base      = chain.extend(genesis, 3);
+        let main      = chain.extend(base, 2);
+        let fork      = chain.extend(base, 3);
+
+        assert!(store.best_chain.is_empty());
+        for block in chain.chain(main) {
+            block_add::add_block(&mut store, &block.raw);
+        }
+
+        let expected: Vec<_> = chain.chain(main).iter().map(|block| (block.height, block.hash)).collect();
+        assert_eq!(store.best_chain.len(), 6);
+        assert_eq!(get_hashes(&store, 0), expected);
+        assert_eq!(get_hashes(&store, 4), expected[4..].to_vec());
+        assert_eq!(get_hashes(&store, 6), vec![]);
+        assert_eq!(*store.best_chain.get_hash(3).unwrap().as_ref().0, chain.block(base).hash);
+
+        // a fork with less work is not in the best chain, but has heights
+        let fork_blocks = chain.chain(fork);
+        for block in &fork_blocks[4..6] {
+            block_add::add_block(&mut store, &block.raw);
+        }
+        assert_eq!(get_hashes(&store, 0), expected);
+        assert_eq!(get_height(&mut store, Hash32(&fork_blocks[5].hash)), Some(5));
+
+        // until it has more
+        block_add::add_block(&mut store, &fork_blocks[6].raw);
+        let expected: Vec<_> = fork_blocks.iter().map(|block| (block.height, block.hash)).collect();
+        assert_eq!(get_hashes(&store, 0), expected);
+        assert_eq!(get_height(&mut store, Hash32(&chain.block(main).hash)), Some(5));
+        assert_eq!(get_block_at(&mut store, 6), get_block(&mut store, Hash32(&chain.block(fork).hash)));
+        assert_eq!(get_height(&mut store, Hash32(&[1; 32])), None);
+    }
+
+    #[test]
+    fn test_update() {
+
+        let cfg       = test_cfg!();
+        let mut store = Store::new(&cfg);
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let tip       = chain.extend(genesis, 4);
+        for block in chain.chain(tip) {
+            block_add::add_block(&mut store, &block.raw);
+        }
+
+        // a store from before the index
+        ::std::fs::remove_file(cfg.root.join(CHAIN_FILE)).unwrap();
+        assert_eq!(get_hashes(&store, 0), vec![]);
+
+        update(&mut store);
+        let expected: Vec<_> = chain.chain(tip).iter().map(|block| (block.height, block.hash)).collect();
+        assert_eq!(get_hashes(&store, 0), expected);
+    }
+}
@@ -31,3 +31,11 @@ impl FlatFilePtr for BlockHeaderPtr {
     fn get_file_offset(self) -> u64 { self.file_offset as u64 }
 
 }
+
+impl BlockHeaderPtr {
+
+    /// Returns the pointer to the first header in the fileset
+    pub fn first() -> BlockHeaderPtr {
+        BlockHeaderPtr::new(0, super::flatfile::INITIAL_WRITEPOS)
+    }
+}
@@ -12,7 +12,8 @@ use std::ptr;
 use std::thread;
 use std::time;
 use std::sync::atomic;
-use std::io::Write;
+use std::io;
+use std::io::{Seek, SeekFrom, Write};
 use std::fmt::{Debug,Formatter,Error};
 
 use std::path::{Path};
@@ -23,6 +24,19 @@ const WRITEPOS_OFFSET  : isize = 8;
 const MAGIC_FILEID     : u64   = 0x62634D4B_00000000;
 pub const INITIAL_WRITEPOS : u64   = 0x10;
 
+// times to try opening a file that another process is creating
+const RETRIES: isize = 50;
+
+/// Sets the write position in the header of a file that isn't mapped
+///
+/// This is used for a copy of a file of which the data after `pos` is left out
+pub fn set_write_pos(path: &Path, pos: u64) -> io::Result<()> {
+
+    let mut file = fs::OpenOptions::new().write(true).open(path)?;
+    file.seek(SeekFrom::Start(WRITEPOS_OFFSET as u64))?;
+    file.write_all(&pos.to_ne_bytes())
+}
+
 pub struct FlatFile {
 
     file:      Option<fs::File>,
@@ -69,6 +83,40 @@ impl FlatFile {
         }
     }
 
+    /// Opens an existing flatfile with a read-only memory map
+    ///
+    /// Data written by others to the file is seen. Returns None if the file doesn't exist
+    pub fn open_read_only(path: &Path, size: u64) -> Option<Self> {
+
+        // a file that is being created is complete when it has its size
+        for _ in 0..RETRIES {
+
+            let file = match fs::File::open(path) {
+                Ok(file) => file,
+                Err(_)   => return None
+            };
+
+            let file_len = file.metadata().expect("Cannot query file info").len();
+            if file_len == size {
+                let map = memmap::Mmap::open(&file, memmap::Protection::Read)
+                    .expect("Failed to open memory map");
+
+                // writing through these pointers would fault; the fileset prevents it
+                let ptr = map.ptr() as *mut u8;
+                let write_ptr = unsafe { mem::transmute::<*mut u8, *mut atomic::AtomicU64>(ptr.offset(WRITEPOS_OFFSET)) };
+
+                return Some(FlatFile {
+                    file:      Some(file),
+                    map:       Some(map),
+                    ptr,
+                    write_ptr
+                });
+            }
+            thread::sleep(time::Duration::from_millis(50));
+        }
+        panic!("Data file '{:?}' exists but has invalid size", path)
+    }
+
     fn open_or_create(path: &Path, size: u64) -> fs::File {
 
         // first we try creating a new file
@@ -98,7 +146,6 @@ impl FlatFile {
 
 
         // if we can't create, we'll try a few times to open it
-        const RETRIES: isize = 50;
         for _ in 0..RETRIES {
 
             let open_result = fs::OpenOptions::new().read(true).write(true).open(path);
@@ -189,6 +236,30 @@ impl FlatFile {
 
     }
 
+    /// Returns the position after the last allocated byte
+    pub fn get_write_pos(&self) -> u64 {
+
+        let write_ptr = unsafe { &*self.write_ptr };
+        write_ptr.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Discards everything written from `pos`
+    ///
+    /// The discarded bytes are zeroed, as readers use a zero length to detect the end of data.
+    /// This must not be used while others may be writing to the file
+    pub fn truncate(&self, pos: u64) {
+
+        let write_pos = self.get_write_pos();
+        if pos >= write_pos {
+            return;
+        }
+
+        unsafe {
+            ptr::write_bytes(self.ptr.offset(pos as isize), 0, (write_pos - pos) as usize);
+            (*self.write_ptr).store(pos, atomic::Ordering::Relaxed);
+        }
+    }
+
 }
 
 impl Debug for FlatFile {
@@ -224,5 +295,22 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_open_read_only() {
+        let dir = tempdir::TempDir::new("test1").unwrap();
+        let path = dir.path().join("tx-0001");
+
+        assert!(FlatFile::open_read_only(&path, 64).is_none());
+
+        // the read-only map sees what is written after it is opened
+        let flatfile = FlatFile::open(&path, 64);
+        let readonly = FlatFile::open_read_only(&path, 64).unwrap();
+        let pos      = flatfile.alloc_write(4, 64).unwrap();
+        flatfile.put(&7u32, pos as usize);
+
+        assert_eq!(readonly.get_write_pos(), INITIAL_WRITEPOS + 4);
+        assert_eq!(*readonly.get::<u32>(pos as usize), 7);
+    }
+
 
 }
\ No newline at end of file
@@ -20,6 +20,7 @@ use itertools::Itertools;
 use itertools::MinMaxResult::{NoElements, OneElement, MinMax};
 
 use store::flatfile::FlatFile;
+pub use store::flatfile::set_write_pos;
 
 
 
@@ -48,6 +49,9 @@ pub struct FlatFileSet<P: FlatFilePtr + Copy + Clone> {
     start_size: u64,
     max_size:   u64,
 
+    // files are mapped read-only and never created
+    read_only:  bool,
+
     // We tie the type of FlatFilePtr to the structure to ensure one ptr type is used
     // for one flatfileset
     phantom:    ::std::marker::PhantomData<P>
@@ -71,6 +75,7 @@ impl<P : FlatFilePtr + Copy + Clone> Clone for FlatFileSet<P> {
             files:      f,
             start_size: self.start_size,
             max_size:   self.max_size,
+            read_only:  self.read_only,
             phantom:    ::std::marker::PhantomData
         }
     }
@@ -165,21 +170,36 @@ impl<P: FlatFilePtr + Copy + Clone> FlatFileSet<P> {
         file_size: u64,
         max_size: u64) -> FlatFileSet<P> {
 
+        FlatFileSet::open(path, prefix, file_size, max_size, false)
+    }
+
+    /// Loads a fileset, which is read-only if `read_only` is set
+    ///
+    /// A read-only fileset maps its files read-only and doesn't create them. It sees the data
+    /// and files added by others, and panics when written to
+    pub fn open(
+        path:      &Path,
+        prefix:    &'static str,
+        file_size: u64,
+        max_size:  u64,
+        read_only: bool) -> FlatFileSet<P> {
+
         assert!(file_size >= max_size);
 
-        if !path.exists() {
+        if !path.exists() && !read_only {
             fs::create_dir_all(path)
                 .expect(&format!("Could not create {:?}", path));
         }
 
         // Find the range of files currently on disk
-        let (min,max) = find_min_max_filenumbers(path, prefix);
+        let (min,max) = if path.exists() { find_min_max_filenumbers(path, prefix) } else { (0, 1) };
 
         FlatFileSet {
             path:       PathBuf::from(path),
             prefix:     prefix,
             start_size: file_size,
             max_size:   max_size,
+            read_only,
             files:       (min..max).map(|_| None).collect(),
             first_file: min,
             last_file:  max,
@@ -208,10 +228,17 @@ impl<P: FlatFilePtr + Copy + Clone> FlatFileSet<P> {
                 fileno
             );
 
-            self.files[file_idx] = Some(FlatFile::open(
-                &name,
-                self.start_size as u64
-            ));
+            // only the last file is created; earlier files that are gone have been removed
+            if fileno < self.last_file - 1 && !name.exists() {
+                panic!("Data file {:?} has been removed", name);
+            }
+
+            self.files[file_idx] = Some(if self.read_only {
+                FlatFile::open_read_only(&name, self.start_size)
+                    .unwrap_or_else(|| panic!("Data file {:?} does not exist", name))
+            } else {
+                FlatFile::open(&name, self.start_size)
+            });
         }
 
         self.files[file_idx].as_mut().unwrap()
@@ -241,6 +268,8 @@ impl<P: FlatFilePtr + Copy + Clone> FlatFileSet<P> {
     /// Allocation occurs atomically but lock-free
     /// Returns a pointer to where size bytes can be stored
     pub fn alloc_write_space(&mut self, size: u64) -> P {
+        self.assert_writable();
+
         let fileno = self.last_file - 1;
         let max_size = self.max_size;
 
@@ -267,6 +296,152 @@ impl<P: FlatFilePtr + Copy + Clone> FlatFileSet<P> {
         }
     }
 
+    /// Returns true if the fileset was opened read-only
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn assert_writable(&self) {
+        assert!(!self.read_only, "Cannot write to read-only fileset {:?}", self.path);
+    }
+
+    /// Returns true if the given file is mapped or exists on disk
+    pub fn file_exists(&self, fileno: i16) -> bool {
+
+        let mapped = fileno >= self.first_file
+            && self.files.get((fileno - self.first_file) as usize).is_some_and(|f| f.is_some());
+
+        mapped || fileno_to_filename(&self.path, self.prefix, fileno).exists()
+    }
+
+    // Clones of the fileset create new files independently; this picks up the files that
+    // were created after our last file
+    fn refresh_last_file(&mut self) {
+
+        while fileno_to_filename(&self.path, self.prefix, self.last_file).exists() {
+            self.files.push(None);
+            self.last_file += 1;
+        }
+    }
+
+    /// Returns the position after the last allocated data
+    pub fn get_write_pos(&mut self) -> P {
+
+        self.refresh_last_file();
+        let fileno = self.last_file - 1;
+
+        // a read-only fileset doesn't create the first file
+        if self.read_only && !self.file_exists(fileno) {
+            return P::new(fileno, super::flatfile::INITIAL_WRITEPOS);
+        }
+        let pos    = self.get_flatfile(fileno).get_write_pos();
+
+        P::new(fileno, pos)
+    }
+
+    /// Returns the number of the last file of the set
+    pub fn get_last_file(&mut self) -> i16 {
+        self.refresh_last_file();
+        self.last_file - 1
+    }
+
+    /// Returns the number of the first file of the set, as found when it was opened
+    pub fn get_first_file(&self) -> i16 {
+        self.first_file
+    }
+
+    /// Returns true if the given file has been removed with `remove_file`
+    ///
+    /// A file that is still mapped by this fileset is not reported, as it remains readable
+    pub fn is_removed(&mut self, fileno: i16) -> bool {
+
+        if fileno < self.first_file {
+            return true;
+        }
+        let file_idx = (fileno - self.first_file) as usize;
+        if fileno >= self.last_file - 1 || self.files.get(file_idx).is_some_and(|f| f.is_some()) {
+            return false;
+        }
+
+        !fileno_to_filename(&self.path, self.prefix, fileno).exists()
+    }
+
+    /// Removes the given file from disk; the last file cannot be removed
+    ///
+    /// Other filesets that have the file mapped can still read it; its space is released
+    /// when they are dropped
+    pub fn remove_file(&mut self, fileno: i16) {
+
+        self.assert_writable();
+        assert!(fileno < self.last_file - 1, "The last file of a fileset cannot be removed");
+        if self.is_removed(fileno) {
+            return;
+        }
+
+        let file_idx = (fileno - self.first_file) as usize;
+        if file_idx < self.files.len() {
+            self.files[file_idx] = None;
+        }
+        let _ = fs::remove_file(fileno_to_filename(&self.path, self.prefix, fileno));
+    }
+
+    /// Returns the paths of the files before the last one, which are no longer written
+    pub fn get_full_files(&mut self) -> Vec<PathBuf> {
+
+        let last = self.get_last_file();
+        (self.first_file..last)
+            .map(|fileno| fileno_to_filename(&self.path, self.prefix, fileno))
+            .filter(|path| path.exists())
+            .collect()
+    }
+
+    /// Returns the path of the last file and the position after the last allocated data in it
+    pub fn get_last_file_pos(&mut self) -> (PathBuf, u64) {
+
+        let last = self.get_last_file();
+        let pos  = self.get_file_write_pos(last).get_file_offset();
+        (fileno_to_filename(&self.path, self.prefix, last), pos)
+    }
+
+    /// Returns the position after the last allocated data in the given file
+    pub fn get_file_write_pos(&mut self, fileno: i16) -> P {
+
+        let pos = self.get_flatfile(fileno).get_write_pos();
+        P::new(fileno, pos)
+    }
+
+    /// Discards all data written from `pos`; files after the one `pos` points to are removed
+    ///
+    /// This is used for recovery and must not be used while the fileset is used elsewhere
+    pub fn truncate(&mut self, pos: P) {
+        self.assert_writable();
+
+
+        let fileno = pos.get_file_number();
+        while self.last_file - 1 > fileno {
+
+            let last = self.last_file - 1;
+            self.files.truncate((last - self.first_file) as usize);
+
+            let _ = fs::remove_file(fileno_to_filename(&self.path, self.prefix, last));
+            self.last_file -= 1;
+        }
+
+        self.get_flatfile(fileno).truncate(pos.get_file_offset());
+    }
+
+    /// Returns true if nothing is written from `pos` up to the write position
+    pub fn is_zero_from(&mut self, pos: P) -> bool {
+
+        let end = self.get_write_pos();
+        if pos.get_file_number() != end.get_file_number() {
+            return false;
+        }
+
+        let len = end.get_file_offset().saturating_sub(pos.get_file_offset()) as usize;
+        self.read_mut_slice::<u8>(pos, len).iter().all(|&b| b == 0)
+    }
+
     pub fn read_mut_slice<T>(&mut self, ptr: P, count: usize) -> &'static mut [T] {
 
         let flatfile   = self.get_flatfile(ptr.get_file_number());
@@ -391,9 +566,8 @@ impl<P: FlatFilePtr + Copy + Clone> FlatFileSet<P> {
         (result, pos)
     }
 
-    /// This is a special reader used for the reindex benchmark
+    /// This is a special reader used for the reindex benchmark and the export
     /// It reads and returns all blockheaders+txcount
-    #[cfg(test)]
     pub fn read_block_headers(&mut self) -> Vec<(&'static [u8], usize)> {
         let mut result = Vec::new();
         let mut pos = P::new(0, super::flatfile::INITIAL_WRITEPOS);
@@ -412,6 +586,32 @@ impl<P: FlatFilePtr + Copy + Clone> FlatFileSet<P> {
         result
 
     }
+
+    /// Discards an incomplete blockheader+txcount at the end, reading the headers from `pos`
+    ///
+    /// The header and the txcount are written separately, and space is allocated before it is
+    /// written, so an interrupted write leaves a header without count or a zero length.
+    /// Returns true if anything was discarded
+    pub fn truncate_block_headers(&mut self, pos: P) -> bool {
+
+        let end = self.get_write_pos();
+        let key = |pos: P| (pos.get_file_number(), pos.get_file_offset());
+
+        let mut pos = pos;
+        while key(pos) < key(end) {
+
+            let len  = self.read(pos).len();
+            let next = self.offset(pos, len + 4);
+            let next = self.offset(next, mem::size_of::<usize>());
+
+            if len == 0 || key(next) > key(end) {
+                self.truncate(pos);
+                return true;
+            }
+            pos = next;
+        }
+        false
+    }
 }
 
 
@@ -482,6 +682,111 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_truncate() {
+        let dir = tempdir::TempDir::new("test1").unwrap();
+        let path = dir.path();
+
+        let mut ff: FlatFileSet<TxPtr> = FlatFileSet::new(path, "tx1-", 2000, 900);
+
+        let first  = ff.write(&[1; 400]);
+        let second = ff.write(&[2; 400]);
+        let _      = ff.write(&[3; 400]);
+        let fourth = ff.write(&[4; 400]);
+        assert_eq!(fourth.get_file_number(), 1);
+
+        // truncating into the first file removes the second
+        ff.truncate(second);
+        assert!(!path.join("tx1-0001").exists());
+        assert_eq!(ff.get_write_pos(), second);
+        assert_eq!(ff.read(second).len(), 0);
+        assert_eq!(ff.read(first), &[1; 400][..]);
+
+        assert_eq!(ff.write(&[5; 10]), second);
+        assert_eq!(ff.read(second), &[5; 10]);
+    }
+
+    #[test]
+    fn test_remove_file() {
+        let dir = tempdir::TempDir::new("test1").unwrap();
+        let path = dir.path();
+
+        let mut ff: FlatFileSet<TxPtr> = FlatFileSet::new(path, "tx1-", 2000, 900);
+        let ptrs: Vec<TxPtr> = (0..7).map(|n| ff.write(&[n; 400])).collect();
+        assert_eq!(ff.get_last_file(), 2);
+
+        ff.remove_file(1);
+        assert!(!path.join("tx1-0001").exists());
+        assert!(ff.is_removed(1));
+        assert!(!ff.is_removed(0));
+        assert_eq!(ff.read(ptrs[6]), &[6; 400][..]);
+
+        // a new fileset starts after the removed file and does not recreate it
+        let mut ff: FlatFileSet<TxPtr> = FlatFileSet::new(path, "tx1-", 2000, 900);
+        assert!(ff.is_removed(1));
+        assert_eq!(ff.read(ptrs[0]), &[0; 400][..]);
+        assert_eq!(ff.write(&[7; 10]).get_file_number(), 2);
+        assert!(!path.join("tx1-0001").exists());
+    }
+
+    #[test]
+    fn test_read_only() {
+        let dir = tempdir::TempDir::new("test1").unwrap();
+        let path = dir.path().join("set");
+
+        // nothing is created
+        let mut ro: FlatFileSet<TxPtr> = FlatFileSet::open(&path, "tx1-", 2000, 900, true);
+        assert!(!path.exists());
+        assert_eq!(ro.get_write_pos(), TxPtr::new(0, super::super::flatfile::INITIAL_WRITEPOS));
+
+        // and data and files written later are seen
+        let mut ff: FlatFileSet<TxPtr> = FlatFileSet::new(&path, "tx1-", 2000, 900);
+        let ptrs: Vec<TxPtr> = (0..4).map(|n| ff.write(&[n; 400])).collect();
+        assert_eq!(ptrs[3].get_file_number(), 1);
+
+        assert_eq!(ro.get_write_pos(), ff.get_write_pos());
+        assert_eq!(ro.read(ptrs[0]), &[0; 400][..]);
+        assert_eq!(ro.read(ptrs[3]), &[3; 400][..]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot write")]
+    fn test_read_only_write() {
+        let dir = tempdir::TempDir::new("test1").unwrap();
+        let path = dir.path();
+
+        let _ = FlatFileSet::<TxPtr>::new(path, "tx1-", 2000, 900).write(&[1; 10]);
+
+        let mut ro: FlatFileSet<TxPtr> = FlatFileSet::open(path, "tx1-", 2000, 900, true);
+        ro.write(&[2; 10]);
+    }
+
+    #[test]
+    fn test_truncate_block_headers() {
+        let dir = tempdir::TempDir::new("test1").unwrap();
+        let path = dir.path();
+
+        let mut ff: FlatFileSet<TxPtr> = FlatFileSet::new(path, "bh-", 10_000, 9_000);
+
+        let first = ff.write(&[1; 80]);
+        let _     = ff.write_fixed(&1usize);
+        let start = ff.get_write_pos();
+        assert!(!ff.truncate_block_headers(first));
+
+        // the header is written, but not the txcount
+        let second = ff.write(&[2; 80]);
+        assert_eq!(second, start);
+        assert!(ff.truncate_block_headers(first));
+        assert_eq!(ff.get_write_pos(), start);
+
+        // space is allocated, but nothing is written
+        let _ = ff.alloc_write_space(84);
+        assert!(ff.truncate_block_headers(first));
+        assert_eq!(ff.get_write_pos(), start);
+
+        assert_eq!(ff.read_block_headers(), vec![(&[1u8; 80][..], 1)]);
+    }
+
     #[test]
     fn test_concurrent() {
 
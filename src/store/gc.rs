@@ -0,0 +1,371 @@
+//! Garbage collection of stale branches
+//!
+//! Blocks on forks that the best chain has left behind, and orphan blocks of which the previous
+//! block never comes in, keep their records in the spend-tree and their entries in the indexes.
+//! A pass of `collect` removes the branches that are more than `depth` blocks behind the best tip:
+//!
+//! * A fork is stale when its tip is more than `depth` blocks lower than the best tip. Its blocks
+//!   down to where it branches off a live chain are disconnected in the spend-tree and removed
+//!   from the block-index, and its tip is removed.
+//! * An orphan is stale when it was stored before the block of the best chain that is `depth`
+//!   blocks below the tip. Its guard in the block-index and its tip are removed.
+//!
+//! Live blocks are the blocks of the best chain, of forks that are not stale and orphans that
+//! are not stale. Transactions of collected blocks that are not in a live block are removed from
+//! the tx-index, together with the guards of their inputs. The records and transactions stay in
+//! their files; as these are only appended to, the space is not reclaimed.
+//!
+//! A pass holds the exclusive write lock, so blocks and transactions that are added by other
+//! stores wait until it is done, and can't be connected to a branch that is being collected.
+
+
+use std::collections::HashSet;
+
+use hash::*;
+use snapshot;
+use buffer::*;
+use store::{Store, TxPtr, Record, BlockPtr, HashIndexGuard};
+use store::tips::{Tip, TipStatus};
+use transaction::Transaction;
+
+
+/// The number of blocks a branch must be behind the best tip before it is collected
+pub const DEFAULT_GC_DEPTH: u64 = 288;
+
+
+/// The result of a garbage collection pass
+#[derive(Debug, Default, PartialEq)]
+pub struct GcReport {
+
+    /// Number of stale forks collected
+    pub branches: usize,
+
+    /// Number of blocks of stale forks that were disconnected and removed from the block-index
+    pub blocks: usize,
+
+    /// Number of stale orphan blocks removed from the block-index
+    pub orphans: usize,
+
+    /// Number of transactions only in collected blocks, removed from the tx-index
+    pub transactions: usize,
+
+    /// Number of guards of their inputs removed from the tx-index
+    pub tx_guards: usize
+}
+
+
+// The blocks of the best chain, walked back from the tip as far as needed
+struct MainChain {
+    height: u64,
+    blocks: Vec<BlockPtr>
+}
+
+impl MainChain {
+
+    // Returns the block at the given height
+    fn at(&mut self, store: &mut Store, height: u64) -> Option<BlockPtr> {
+
+        let index = self.height.checked_sub(height)? as usize;
+        while self.blocks.len() <= index {
+            let last     = self.blocks[self.blocks.len() - 1];
+            let previous = store.spend_tree.get_previous_block(last)?;
+            self.blocks.push(previous);
+        }
+        Some(self.blocks[index])
+    }
+}
+
+
+/// Collects the branches that are more than `depth` blocks behind the best tip
+pub fn collect(store: &mut Store, depth: u64) -> GcReport {
+
+    let _lock      = snapshot::lock_exclusive(store);
+    let mut report = GcReport::default();
+
+    let best = match store.tips.get_most_work_tip() {
+        Some(best) => best,
+        None       => return report
+    };
+    let mut main = match get_block(store, best.block_hash.as_ref()) {
+        Some(block) => MainChain { height: best.height, blocks: vec![block] },
+        None        => return report
+    };
+
+    let (stale, live): (Vec<Tip>, Vec<Tip>) = store.tips.get_tips().into_iter()
+        .filter(|tip| tip.status != TipStatus::OrphanHeaders && tip.block_hash != best.block_hash)
+        .partition(|tip| tip.height + depth < best.height);
+
+    // the transactions of live blocks; the tip of the best chain is not yet in the spend-index
+    let mut live_txs    = HashSet::new();
+    let mut live_blocks = HashSet::new();
+    add_transactions(store, main.blocks[0], &mut live_txs);
+
+    for tip in &live {
+        for block in walk_branch(store, &mut main, tip, &live_blocks).0 {
+            live_blocks.insert(block.start.to_index());
+            add_transactions(store, block, &mut live_txs);
+        }
+    }
+
+    // the blocks of stale forks; a fork of a stale fork ends at the blocks of the other
+    let mut stale_blocks = vec![];
+    let mut lowest       = best.height;
+    for tip in &stale {
+        let (blocks, height) = walk_branch(store, &mut main, tip, &live_blocks);
+
+        live_blocks.extend(blocks.iter().map(|block| block.start.to_index()));
+        stale_blocks.extend(blocks);
+        lowest = lowest.min(height);
+
+        store.tips.remove_tip(tip.block_hash.as_ref());
+        report.branches += 1;
+    }
+
+    // stale forks can only share transactions with the best chain above where they branch off
+    for height in lowest + 1..best.height {
+        if let Some(block) = main.at(store, height) {
+            add_transactions(store, block, &mut live_txs);
+        }
+    }
+
+    let horizon = match best.height.checked_sub(depth) {
+        Some(height) => main.at(store, height).map_or(0, |block| block.start.to_index()),
+        None         => 0
+    };
+    let mut stale_orphans = vec![];
+    for (prev_hash, block) in get_orphans(store) {
+        if block.start.to_index() < horizon {
+            stale_orphans.push((prev_hash, block));
+        } else {
+            add_transactions(store, block, &mut live_txs);
+        }
+    }
+
+    let mut collected = HashSet::new();
+    for block in stale_blocks {
+        let hash = store.get_block_hash(block);
+
+        store.block_index.remove(hash.as_ref(), block);
+        store.spend_tree.disconnect_block(block);
+        if let Some(ref mut utxo_hashes) = store.utxo_hashes {
+            utxo_hashes.remove(hash.as_ref(), block);
+        }
+        report.blocks += 1;
+
+        for tx_ptr in get_transactions(store, block) {
+            if !live_txs.contains(&tx_ptr) && collected.insert(tx_ptr) {
+                remove_transaction(store, tx_ptr, &mut report);
+            }
+        }
+    }
+
+    for (prev_hash, block) in stale_orphans {
+        let hash = store.get_block_hash(block);
+
+        store.block_index.remove_guard(prev_hash.as_ref(), block);
+        store.tips.remove_tip(hash.as_ref());
+        report.orphans += 1;
+
+        // the transactions of the best chain below the stale forks are found in the spend-index
+        for tx_ptr in get_transactions(store, block) {
+            let in_chain = store.spend_index.exists(Record::new_transaction(tx_ptr).hash());
+
+            if !in_chain && !live_txs.contains(&tx_ptr) && collected.insert(tx_ptr) {
+                remove_transaction(store, tx_ptr, &mut report);
+            }
+        }
+    }
+
+    if report != GcReport::default() {
+        info!(store.logger, "collected stale branches";
+            "branches" => report.branches,
+            "blocks"   => report.blocks,
+            "orphans"  => report.orphans);
+    }
+    report
+}
+
+// Returns the connected block with the given hash
+fn get_block(store: &mut Store, hash: Hash32) -> Option<BlockPtr> {
+
+    store.block_index.get(hash).into_iter().find(|ptr| !ptr.is_guard())
+}
+
+// Returns the orphan blocks with the hash of the block they are waiting for
+fn get_orphans(store: &mut Store) -> Vec<(Hash32Buf, BlockPtr)> {
+
+    let mut orphans = vec![];
+    store.block_index.for_each(|hash, blocks| {
+        for block in blocks.into_iter().filter(|block| block.is_guard()) {
+            orphans.push((hash.as_buf(), block));
+        }
+    });
+    orphans
+}
+
+// Walks back from the tip of a fork until a block of the best chain or one of `stop`
+//
+// Returns the blocks of the branch, tip first, and the height of the block it branches off
+fn walk_branch(store: &mut Store, main: &mut MainChain, tip: &Tip, stop: &HashSet<u64>)
+    -> (Vec<BlockPtr>, u64)
+{
+    let mut blocks = vec![];
+    let mut height = tip.height;
+    let mut next   = get_block(store, tip.block_hash.as_ref());
+
+    while let Some(block) = next {
+
+        let on_main = main.at(store, height).is_some_and(|ptr| ptr.start == block.start);
+        if on_main || stop.contains(&block.start.to_index()) {
+            break;
+        }
+
+        blocks.push(block);
+        next   = store.spend_tree.get_previous_block(block);
+        height = height.saturating_sub(1);
+    }
+    (blocks, height)
+}
+
+// Returns the pointers to the transactions of a block
+fn get_transactions(store: &mut Store, block: BlockPtr) -> Vec<TxPtr> {
+
+    let records = store.spend_tree.get_block_mut(block);
+    records[1..records.len() - 1].iter()
+        .filter(|rec| rec.is_transaction() && !rec.is_unmatched_input())
+        .map(|rec| rec.get_transaction_ptr())
+        .collect()
+}
+
+fn add_transactions(store: &mut Store, block: BlockPtr, txs: &mut HashSet<TxPtr>) {
+
+    txs.extend(get_transactions(store, block));
+}
+
+// Removes a transaction from the tx-index, with the guards of its inputs
+fn remove_transaction(store: &mut Store, tx_ptr: TxPtr, report: &mut GcReport) {
+
+    let raw = match store.transactions.read(tx_ptr) {
+        Ok(raw) => raw,
+        Err(_)  => return
+    };
+    let tx = match Transaction::parse(&mut Buffer::new(&raw)) {
+        Ok(tx) => tx,
+        Err(_) => return
+    };
+
+    let hash = Hash32Buf::double_sha256(&raw);
+    if store.tx_index.remove(hash.as_ref(), tx_ptr) {
+        report.transactions += 1;
+    }
+
+    if !tx.is_coinbase() {
+        for (index, input) in tx.txs_in.iter().enumerate() {
+            if store.tx_index.remove_guard(input.prev_tx_out, tx_ptr.to_input(index as u16)) {
+                report.tx_guards += 1;
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use block_add;
+    use test_chain::TestChain;
+
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_collect() {
+
+        let mut store = Store::new(& test_cfg!().with_utxo_hash(true));
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let base      = chain.extend(genesis, 101);
+        let coinbase  = chain.chain(base)[1].coinbase.clone().unwrap();
+        let tx        = chain.spend(&[coinbase.output(0)], &[coinbase.output(0).value]);
+
+        // a fork and the best chain both contain the transaction
+        let fork      = chain.add_block(base, vec![tx.clone()]);
+        let fork_tip  = chain.extend(fork, 1);
+        let main      = chain.add_block(base, vec![tx.clone()]);
+        let main_tip  = chain.extend(main, 5);
+
+        // a recent fork off block 105, and an orphan of which the previous block never comes in
+        let recent    = chain.extend(main + 3, 1);
+        let missing   = chain.extend(base, 1);
+        let orphan    = chain.extend(missing, 1);
+
+        for block in chain.chain(base) {
+            block_add::add_block(&mut store, &block.raw);
+        }
+        block_add::add_block(&mut store, &chain.block(orphan).raw);
+
+        // the spend-index is shared by the branches, so both spends must be validated before
+        // either branch is extended
+        block_add::add_block(&mut store, &chain.block(fork).raw);
+        for block in &chain.chain(main_tip)[102..] {
+            block_add::add_block(&mut store, &block.raw);
+        }
+        block_add::add_block(&mut store, &chain.block(fork_tip).raw);
+        block_add::add_block(&mut store, &chain.block(recent).raw);
+
+        let fork_hash  = Hash32Buf::from_slice(&chain.block(fork_tip).hash);
+        let fork_block = get_block(&mut store, fork_hash.as_ref()).unwrap();
+        assert!(store.utxo_hashes.as_mut().unwrap().get(fork_hash.as_ref(), fork_block).is_some());
+
+        let report = collect(&mut store, 3);
+        assert_eq!(report, GcReport {
+            branches:     1,
+            blocks:       2,
+            orphans:      1,
+            transactions: 3,
+            tx_guards:    0
+        });
+
+        // the shared transaction is kept, the blocks of the fork are gone
+        assert!(!store.tx_index.get(Hash32Buf::from_slice(&tx.txid).as_ref()).is_empty());
+        assert_eq!(get_block(&mut store, fork_hash.as_ref()), None);
+        assert!(store.utxo_hashes.as_mut().unwrap().get(fork_hash.as_ref(), fork_block).is_none());
+        assert_eq!(store.tips.get_tips().len(), 2);
+
+        let check = ::fsck::check(&mut store);
+        assert!(check.is_ok(), "{:?}", check);
+        assert_eq!(check.collected, 3);
+
+        assert_eq!(collect(&mut store, 3), GcReport::default());
+
+        // the best chain can be extended
+        let next = chain.extend(main_tip, 1);
+        block_add::add_block(&mut store, &chain.block(next).raw);
+        assert_eq!(store.tips.get_most_work_tip().unwrap().height, 108);
+    }
+
+    #[test]
+    fn test_collect_waits_for_writers() {
+
+        let cfg     = test_cfg!();
+        let store   = Store::new(&cfg);
+
+        // a pass must not run while another store is writing
+        let writing = snapshot::lock_writes(&store);
+        let pass    = thread::spawn(move || {
+            let mut store = Store::new(&cfg);
+            let report    = collect(&mut store, 3);
+            (Instant::now(), report)
+        });
+
+        thread::sleep(Duration::from_millis(200));
+        let released = Instant::now();
+        drop(writing);
+
+        let (finished, report) = pass.join().unwrap();
+        assert!(finished >= released);
+        assert_eq!(report, GcReport::default());
+    }
+}
@@ -1,5 +1,3 @@
-
-
 //! Index that maps hashes to content pointers
 //!
 //! This is used for transactions & blockheaders; the values found for a hash can be:
@@ -20,15 +18,28 @@
 //!  The block cannot be found, but the blocks pointed to by these ptrs are having the given hash as previous block;
 //!  they are "expecting" this block, and should be appended when this block comes in
 //!
-//! The implementation is a large root hash table, with colliding keys added to an unbalanced binary tree.
+//! The implementation is a hash array mapped trie (HAMT). The root table is indexed by the first
+//! bits of the hash. A slot is empty, points to an entry holding a hash and its values, or
+//! points to a node of 4 slots indexed by the next 2 bits of the hash. When an entry is added
+//! at a slot that holds an entry with another hash, the slot is replaced by nodes down to the
+//! depth at which both entries get their own slot. The narrow nodes keep the trie about the
+//! size of the binary trees it replaced, while the depth stays logarithmic in the number of
+//! hashes per root slot.
+//!
+//! The number of root bits is taken from the config when an index is created. The default of 24
+//! bits makes a root of 128 MB, as the tree index had; with 100 million hashes this is 6 hashes
+//! per slot, found in about 2 nodes. A low-resource setup can use 16 bits, a root of 512 KB, at
+//! the cost of about 4 more nodes per lookup.
 //!
-//!  TODO There is probably quite some gain to be made by moving to HAMT instead unbalanced binary trees
-//!  for the branches; especially for low-resource uses.
+//! The first file of an index starts with a header holding a magic, the format version and the
+//! number of root bits. Indexes without it, such as those of the tree format, are refused.
+//!
+//! Nothing is overwritten in place except for slots and the values of entries, which are
+//! replaced atomically; this allows concurrent use without locks.
 
 
-use std::{mem};
+use std::{mem, ptr, thread, time};
 use std::sync::atomic;
-use std::cmp::{Ord,Ordering};
 
 use config;
 use hash::*;
@@ -41,7 +52,15 @@ use store::flatfileset::FlatFileSet;
 const FILE_SIZE:          u64 = 1 * 1024*1024*1024;
 const MAX_CONTENT_SIZE:   u64 = FILE_SIZE - 10 * 1024*1024;
 
-const HASH_ROOT_COUNT:  usize = 256*256*256;
+const NODE_BITS:        usize = 2;
+const NODE_SLOTS:       usize = 1 << NODE_BITS;
+
+const INDEX_MAGIC:      [u8; 4] = *b"bchi";
+
+// version 1 is the tree format, which has no header
+const INDEX_VERSION:    u32 = 2;
+
+const HEADER_RETRIES:   usize = 100;
 
 
 /// Trait for objects that can be used as a guard
@@ -58,7 +77,9 @@ pub struct HashIndex<T : HashIndexGuard + Copy + Clone> {
 
     fileset:         FlatFileSet<IndexPtr>,
 
-    hash_index_root: &'static [IndexPtr; HASH_ROOT_COUNT],
+    // None for a read-only index that a writer hasn't created yet
+    hash_index_root: Option<&'static [IndexPtr]>,
+    root_bits:       usize,
 
     phantom:         ::std::marker::PhantomData<T>
 
@@ -70,11 +91,15 @@ impl<T : HashIndexGuard + Copy + Clone> Clone for HashIndex<T> {
     fn clone(&self) -> HashIndex<T> {
 
         let mut fileset = self.fileset.clone();
-        let root = fileset.read_fixed(IndexPtr::new(0, super::flatfile::INITIAL_WRITEPOS));
+        let root: Option<&'static [IndexPtr]> = match self.hash_index_root {
+            Some(root) => Some(fileset.read_mut_slice(root_ptr(), root.len())),
+            None       => None
+        };
         HashIndex {
 
             fileset:         fileset,
             hash_index_root: root,
+            root_bits:       self.root_bits,
             phantom:         ::std::marker::PhantomData
 
         }
@@ -82,12 +107,15 @@ impl<T : HashIndexGuard + Copy + Clone> Clone for HashIndex<T> {
     }
 }
 
+// The kind of a pointer to a node; pointers to entries and leaves have kind 0
+const KIND_NODE: u16 = 1;
+
 /// A persistent pointer into the hash-index
 #[derive(Debug, Clone, Copy)]
 pub struct IndexPtr {
     file_offset: u32,
     file_number: i16,
-    zero: u16
+    kind: u16
 }
 
 impl FlatFilePtr for IndexPtr {
@@ -96,7 +124,7 @@ impl FlatFilePtr for IndexPtr {
         IndexPtr {
             file_offset: file_offset as u32,
             file_number: file_number,
-            zero: 0  // we must pad with zero to ensure atomic CAS works
+            kind: 0  // this must be set to ensure atomic CAS works
         }
     }
 
@@ -112,6 +140,12 @@ impl IndexPtr {
 
     pub fn is_null(&self) -> bool { self.file_offset == 0 && self.file_number == 0 }
 
+    fn to_node(self) -> IndexPtr {
+        IndexPtr { kind: KIND_NODE, ..self }
+    }
+
+    fn is_node(self) -> bool { self.kind == KIND_NODE }
+
 
     /// atomically replaces a hash indexptr value with a new_value,
     /// fails if the current value is no longer the value supplied
@@ -131,27 +165,50 @@ impl IndexPtr {
 }
 
 
+// The slot returned when a read-only index has no root yet; it is never written
+static NULL_SLOT: IndexPtr = IndexPtr { file_offset: 0, file_number: 0, kind: 0 };
+
+
 /// The result used internally when searched for hash
 enum FindNodeResult {
 
-    /// Tha hash is found and the location is returned
-    Found(&'static Node),
+    /// Tha hash is found and the entry is returned
+    Found(&'static Entry),
+
+    /// The hash is not found; the slot where the entry should be inserted is returned,
+    /// with its current value and its depth in the trie. The value is null, or an entry with
+    /// another hash which is moved to a new node together with the new entry
+    NotFound(&'static IndexPtr, IndexPtr, usize)
+}
 
-    /// The hash is not found; the location where the node should be inserted
-    /// is returned
-    NotFound(&'static IndexPtr)
+/// The header at the start of the first file of an index
+#[derive(Debug)]
+struct IndexHeader {
+    magic:     [u8; 4],
+    version:   u32,
+    root_bits: u32,
+    _reserved: u32
+}
+
+// The root table follows the header
+fn root_ptr() -> IndexPtr {
+    IndexPtr::new(0, super::flatfile::INITIAL_WRITEPOS + mem::size_of::<IndexHeader>() as u64)
 }
 
 /// Structures as stored in the fileset
 #[derive(Debug)]
-struct Node {
+struct Entry {
     hash: Hash32Buf,
-    prev: IndexPtr,  // to Node
-    next: IndexPtr,  // to Node
     leaf: IndexPtr,  // to Leaf
 }
 
-/// Leaf of the binary tree
+/// A node of the trie
+#[derive(Debug)]
+struct Node {
+    slots: [IndexPtr; NODE_SLOTS] // to Node or Entry
+}
+
+/// Leaf of the list of values of an entry
 /// The supplied Type is the type of the elements that are stored in the tree
 struct Leaf<T : HashIndexGuard> {
     value: T, /// to Data file
@@ -169,27 +226,30 @@ impl<T : HashIndexGuard> Leaf<T> {
 }
 
 
-impl Node {
+impl Entry {
     fn new(hash: Hash32, leaf_ptr: IndexPtr) -> Self {
-        Node {
+        Entry {
             hash: hash.as_buf(),
-            prev: IndexPtr::null(),
-            next: IndexPtr::null(),
             leaf: leaf_ptr
         }
     }
 }
 
 
-// Returns the first 24-bits of the hash
+// Returns the index in the root table (depth 0) or in a node for the given hash
 //
-// This is the index into the root-hash table
-fn hash_to_index(hash: Hash32) -> usize {
-
-    (hash.0[0] as usize) |
-        (hash.0[1] as usize) << 8  |
-        (hash.0[2] as usize) << 16
+// The root table uses the first `root_bits` bits; each next level uses the next 2 bits
+fn slot_index(hash: Hash32, depth: usize, root_bits: usize) -> usize {
 
+    if depth == 0 {
+        ((hash.0[0] as usize) |
+            (hash.0[1] as usize) << 8 |
+            (hash.0[2] as usize) << 16) & ((1 << root_bits) - 1)
+    }
+    else {
+        let bit = root_bits + (depth - 1) * NODE_BITS;
+        ((hash.0[bit / 8] >> (bit % 8)) as usize) & (NODE_SLOTS - 1)
+    }
 }
 
 
@@ -205,40 +265,76 @@ impl<T :'static> HashIndex<T>
 
         let is_new = !dir.exists();
 
-        let mut fileset = FlatFileSet::new(
-            dir, "hi-", FILE_SIZE, MAX_CONTENT_SIZE);
+        let mut fileset = FlatFileSet::open(
+            dir, "hi-", FILE_SIZE, MAX_CONTENT_SIZE, cfg.read_only);
 
-        let hash_root_fileptr = if is_new {
+        let mut index = HashIndex {
+            fileset,
+            hash_index_root: None,
+            root_bits:       0,
+            phantom:         ::std::marker::PhantomData
+        };
 
-            // allocate space for root hash table
-            fileset.alloc_write_space(mem::size_of::<[IndexPtr; HASH_ROOT_COUNT]>() as u64)
+        // a read-only store reads an index that doesn't exist as empty, until a writer creates it
+        if is_new && cfg.read_only {
+            return index;
         }
-        else {
-            // hash root must have been the first thing written
-            IndexPtr::new(0, super::flatfile::INITIAL_WRITEPOS)
-        };
 
-        // and keep a reference to it
-        let hash_root_ref: &'static [IndexPtr; HASH_ROOT_COUNT]
-            = fileset.read_fixed(hash_root_fileptr);
+        if is_new {
+            let root_bits = cfg.index_root_bits;
+            assert!(root_bits.is_multiple_of(NODE_BITS) && (8..=24).contains(&root_bits),
+                "Index root bits must be even and between 8 and 24");
+
+            // the header and root hash table are the first things written
+            index.fileset.write_fixed(&IndexHeader {
+                magic:     INDEX_MAGIC,
+                version:   INDEX_VERSION,
+                root_bits: root_bits as u32,
+                _reserved: 0
+            });
+            index.fileset.alloc_write_space((mem::size_of::<IndexPtr>() << root_bits) as u64);
+        }
 
-        HashIndex {
-            fileset: fileset,
-            hash_index_root: hash_root_ref,
-            phantom: ::std::marker::PhantomData
+        index.get_root();
+        index
+    }
+
+    // Returns the root hash table, mapping it if a writer created the index after we opened it
+    //
+    // Panics if the index has another format
+    fn get_root(&mut self) -> Option<&'static [IndexPtr]> {
+
+        if self.hash_index_root.is_none() && self.fileset.file_exists(0) {
+
+            let header: &IndexHeader = self.fileset.read_fixed(
+                IndexPtr::new(0, super::flatfile::INITIAL_WRITEPOS));
+
+            // a writer that just created the index may not have written the header yet
+            for _ in 0..HEADER_RETRIES {
+                if unsafe { ptr::read_volatile(&header.magic) } == INDEX_MAGIC {
+                    break;
+                }
+                thread::sleep(time::Duration::from_millis(10));
+            }
+            if header.magic != INDEX_MAGIC || header.version != INDEX_VERSION {
+                panic!("Index has an unsupported format; the store must be recreated");
+            }
+
+            self.root_bits       = header.root_bits as usize;
+            self.hash_index_root = Some(self.fileset.read_mut_slice(root_ptr(), 1 << self.root_bits));
         }
+        self.hash_index_root
     }
 
 
-    /// Collects all the values stored at the given node
-    fn collect_node_values(&mut self, node: &Node) -> Vec<T> {
+    /// Collects all the values stored at the given entry
+    fn collect_entry_values(&mut self, entry: &Entry) -> Vec<T> {
 
         let mut result : Vec<T> = Vec::new();
-        let mut leaf_ptr = node.leaf;
+        let mut leaf_ptr = entry.leaf;
 
         while !leaf_ptr.is_null() {
-            let v: &T = self.fileset.read_fixed(leaf_ptr);
-            let leaf: Leaf<T> = Leaf::new(*v);
+            let leaf: &Leaf<T> = self.fileset.read_fixed(leaf_ptr);
             result.push(leaf.value);
 
             leaf_ptr = leaf.next;
@@ -246,24 +342,80 @@ impl<T :'static> HashIndex<T>
         result
     }
 
-    // Finds the node containing the hash, or the location the hash should be inserted
+    // Finds the entry containing the hash, or the slot the hash should be inserted
     fn find_node(&mut self, hash: Hash32) -> FindNodeResult {
 
-        // use the first 24-bit as index in the root hash table
-        let mut ptr = &self.hash_index_root[hash_to_index(hash)];
+        let root = match self.get_root() {
+            Some(root) => root,
+            None       => return FindNodeResult::NotFound(&NULL_SLOT, IndexPtr::null(), 0)
+        };
+
+        let root_bits = self.root_bits;
+        let mut depth = 0;
+        let mut slot  = &root[slot_index(hash, depth, root_bits)];
 
-        // from there, we follow the binary tree
-        while !ptr.is_null() {
-            let node: &Node = self.fileset.read_fixed(*ptr);
+        // from there, we follow the nodes
+        loop {
+            let ptr = *slot;
+            if ptr.is_null() {
+                return FindNodeResult::NotFound(slot, ptr, depth);
+            }
 
-            ptr = match hash.0.cmp(&node.hash.as_ref().0) {
-                Ordering::Less    => &node.prev,
-                Ordering::Greater => &node.next,
-                Ordering::Equal   => return FindNodeResult::Found(node)
+            if ptr.is_node() {
+                let node: &Node = self.fileset.read_fixed(ptr);
+                depth += 1;
+                slot   = &node.slots[slot_index(hash, depth, root_bits)];
+                continue;
+            }
+
+            let entry: &Entry = self.fileset.read_fixed(ptr);
+            return if entry.hash.as_ref().0 == hash.0 {
+                FindNodeResult::Found(entry)
+            } else {
+                FindNodeResult::NotFound(slot, ptr, depth)
             };
         }
+    }
+
+    // Inserts a new entry for the hash with the given value at a slot
+    //
+    // If the slot holds an entry with another hash, it is replaced by nodes down to the depth
+    // at which both entries get their own slot
+    fn insert_entry(&mut self, slot: &IndexPtr, current: IndexPtr, depth: usize, hash: Hash32, value: T) -> bool {
+
+        // create and write a leaf;
+        let new_leaf     = Leaf::new(value);
+        let new_leaf_ptr = self.fileset.write_fixed(&new_leaf);
+
+        // create and write an entry holding the leaf
+        let new_entry = Entry::new(hash, new_leaf_ptr);
+        let mut new_ptr = self.fileset.write_fixed(&new_entry);
+
+        if !current.is_null() {
+            let existing: &Entry = self.fileset.read_fixed(current);
+            let existing_hash = existing.hash.as_ref();
+
+            let root_bits = self.root_bits;
+
+            let mut split_depth = depth + 1;
+            while slot_index(existing_hash, split_depth, root_bits) == slot_index(hash, split_depth, root_bits) {
+                split_depth += 1;
+            }
+
+            let mut node = Node { slots: [IndexPtr::null(); NODE_SLOTS] };
+            node.slots[slot_index(existing_hash, split_depth, root_bits)] = current;
+            node.slots[slot_index(hash, split_depth, root_bits)] = new_ptr;
+            new_ptr = self.fileset.write_fixed(&node).to_node();
+
+            for node_depth in (depth + 1..split_depth).rev() {
+                let mut node = Node { slots: [IndexPtr::null(); NODE_SLOTS] };
+                node.slots[slot_index(hash, node_depth, root_bits)] = new_ptr;
+                new_ptr = self.fileset.write_fixed(&node).to_node();
+            }
+        }
 
-        FindNodeResult::NotFound(ptr)
+        // then atomically update the pointer
+        slot.atomic_replace(current, new_ptr)
     }
 
 
@@ -271,12 +423,42 @@ impl<T :'static> HashIndex<T>
     pub fn get(&mut self, hash: Hash32) -> Vec<T> {
 
         match self.find_node(hash) {
-            FindNodeResult::NotFound(_) => {
+            FindNodeResult::NotFound(..) => {
                 Vec::new()
             },
-            FindNodeResult::Found(node) => {
+            FindNodeResult::Found(entry) => {
+
+                self.collect_entry_values(entry)
+            }
+        }
+    }
+
+    /// Calls `f` with each hash in the index and the values stored at it
+    ///
+    /// This walks the entire index and is meant for offline checks
+    pub fn for_each<F>(&mut self, mut f: F)
+        where F: FnMut(Hash32, Vec<T>)
+    {
+        let root = match self.get_root() {
+            Some(root) => root,
+            None       => return
+        };
+        for &root_ptr in root.iter() {
+
+            let mut todo = vec![root_ptr];
+            while let Some(ptr) = todo.pop() {
+                if ptr.is_null() {
+                    continue;
+                }
+                if ptr.is_node() {
+                    let node: &Node = self.fileset.read_fixed(ptr);
+                    todo.extend(node.slots.iter().cloned());
+                    continue;
+                }
 
-                self.collect_node_values(node)
+                let entry: &Entry = self.fileset.read_fixed(ptr);
+                let values = self.collect_entry_values(entry);
+                f(entry.hash.as_ref(), values);
             }
         }
     }
@@ -300,30 +482,22 @@ impl<T :'static> HashIndex<T>
         // this loops through retries when the CAS operation fails
         loop {
             match self.find_node(hash) {
-                FindNodeResult::NotFound(target) => {
-
-                    // create and write a leaf;
-                    let new_leaf     = Leaf::new(store_ptr);
-                    let new_leaf_ptr = self.fileset.write_fixed(&new_leaf);
-
-                    // create and write a node holding the leaf
-                    let new_node     = Node::new(hash, new_leaf_ptr);
-                    let new_node_ptr = self.fileset.write_fixed(&new_node);
+                FindNodeResult::NotFound(slot, current, depth) => {
 
-                    // then atomically update the pointer
-                    if target.atomic_replace(IndexPtr::null(), new_node_ptr) {
+                    if self.insert_entry(slot, current, depth, hash, store_ptr) {
                         return true;
                     }
-
                 },
-                FindNodeResult::Found(node) => {
+                FindNodeResult::Found(entry) => {
 
-                    let first_value_ptr = node.leaf;
+                    let first_value_ptr = entry.leaf;
 
                     // check if there is anything waiting that is not supplied in `verified_ptrs`
+                    // an entry without values is left after its guards are removed
+                    let values = self.collect_entry_values(entry);
                     if !force_store &&
-                        !self
-                        .collect_node_values(node)
+                        !values.is_empty() &&
+                        !values
                         .into_iter()
                         .any(|val| verified_ptrs.contains(&val)) {
 
@@ -336,7 +510,7 @@ impl<T :'static> HashIndex<T>
                     let new_leaf_ptr = self.fileset.write_fixed(&new_leaf);
 
                     // then atomically update the pointer
-                    if node.leaf.atomic_replace(first_value_ptr, new_leaf_ptr) {
+                    if entry.leaf.atomic_replace(first_value_ptr, new_leaf_ptr) {
                         return true;
                     }
 
@@ -357,42 +531,32 @@ impl<T :'static> HashIndex<T>
         loop {
             match self.find_node(hash) {
 
-                FindNodeResult::NotFound(ptr) => {
+                FindNodeResult::NotFound(slot, current, depth) => {
 
                     // The transaction doesn't exist; we insert guard_ptr instead
-
-                    // create and write a leaf;
-                    let new_leaf = Leaf::new(guard_ptr);
-                    let new_leaf_ptr = self.fileset.write_fixed(&new_leaf);
-
-                    // create and write a node holding the leaf
-                    let new_node = Node::new(hash, new_leaf_ptr);
-                    let new_node_ptr = self.fileset.write_fixed(&new_node);
-
-                    // then atomically update the pointer
-                    if ptr.atomic_replace(IndexPtr::null(), new_node_ptr) {
+                    if self.insert_entry(slot, current, depth, hash, guard_ptr) {
                         return None;
                     }
                 },
 
-                FindNodeResult::Found(node) => {
+                FindNodeResult::Found(entry) => {
 
                     // load first leaf
-                    let first_value_ptr = node.leaf;
-                    let val: &T         = self.fileset.read_fixed(first_value_ptr);
-                    let leaf: Leaf<T>   = Leaf::new(*val);
-
+                    let first_value_ptr = entry.leaf;
+                    if !first_value_ptr.is_null() {
+                        let leaf: &Leaf<T> = self.fileset.read_fixed(first_value_ptr);
 
-                    if !leaf.value.is_guard() {
-                        return Some(leaf.value);
+                        if !leaf.value.is_guard() {
+                            return Some(leaf.value);
+                        }
                     }
 
                     // create a new leaf, pointing to the previous one
-                    let new_leaf     = Leaf { value: guard_ptr, next: node.leaf };
+                    let new_leaf     = Leaf { value: guard_ptr, next: entry.leaf };
                     let new_leaf_ptr = self.fileset.write_fixed(&new_leaf);
 
                     // then atomically update the pointer
-                    if node.leaf.atomic_replace(first_value_ptr, new_leaf_ptr) {
+                    if entry.leaf.atomic_replace(first_value_ptr, new_leaf_ptr) {
                         return None;
                     }
 
@@ -401,6 +565,67 @@ impl<T :'static> HashIndex<T>
         }
 
     }
+
+    /// Removes a guard from the given hash
+    ///
+    /// This is used to clean up guards of inputs that will never be verified, as the
+    /// transaction they belong to was not stored. Returns false if the guard was not found
+    pub fn remove_guard(&mut self, hash: Hash32, guard_ptr: T) -> bool {
+
+        debug_assert!(guard_ptr.is_guard());
+
+        // this loops through retries when the CAS operation fails
+        loop {
+            let entry = match self.find_node(hash) {
+                FindNodeResult::NotFound(..)  => return false,
+                FindNodeResult::Found(entry) => entry
+            };
+
+            let first_value_ptr = entry.leaf;
+            let values          = self.collect_entry_values(entry);
+            if !values.contains(&guard_ptr) {
+                return false;
+            }
+
+            // rebuild the chain of leaves without the guard
+            let mut new_leaf_ptr = IndexPtr::null();
+            for value in values.into_iter().rev().filter(|&val| val != guard_ptr) {
+                let new_leaf = Leaf { value, next: new_leaf_ptr };
+                new_leaf_ptr = self.fileset.write_fixed(&new_leaf);
+            }
+
+            // then atomically update the pointer
+            if entry.leaf.atomic_replace(first_value_ptr, new_leaf_ptr) {
+                return true;
+            }
+        }
+    }
+
+    /// Removes the value stored at the given hash
+    ///
+    /// This is used for pruning; afterwards the hash is treated as if it was never stored.
+    /// Returns false if `store_ptr` is not the value stored at the hash
+    pub fn remove(&mut self, hash: Hash32, store_ptr: T) -> bool {
+
+        debug_assert!(!store_ptr.is_guard());
+
+        let entry = match self.find_node(hash) {
+            FindNodeResult::NotFound(..)  => return false,
+            FindNodeResult::Found(entry) => entry
+        };
+
+        // a stored value is never followed by other leaves, and is only replaced by `set`
+        let first_value_ptr = entry.leaf;
+        if first_value_ptr.is_null() {
+            return false;
+        }
+        let leaf: &Leaf<T> = self.fileset.read_fixed(first_value_ptr);
+        if leaf.value != store_ptr {
+            return false;
+        }
+
+        entry.leaf.atomic_replace(first_value_ptr, IndexPtr::null())
+    }
 }
 
 
@@ -409,7 +634,7 @@ impl<T :'static> HashIndex<T>
 
 #[cfg(test)]
 mod tests {
-    use super::Node;
+    use super::{Entry, Node};
     use std::mem;
 
     extern crate tempdir;
@@ -417,6 +642,7 @@ mod tests {
     use std::path::PathBuf;
 
     use std::thread;
+    use std::time::Instant;
 
     use super::*;
     use self::rand::Rng;
@@ -424,11 +650,140 @@ mod tests {
     use hash::Hash32Buf;
     use store::TxPtr;
     use store::flatfileset::FlatFilePtr;
+    use store::tree_index::TreeIndex;
 
     #[test]
     fn test_size_of_node() {
-        assert_eq!(mem::size_of::<Node>(), 56);
+        assert_eq!(mem::size_of::<Entry>(), 40);
+        assert_eq!(mem::size_of::<Node>(), 32);
+
+    }
+
+    #[test]
+    fn test_guards() {
+
+        let dir = tempdir::TempDir::new("test1").unwrap();
+        let cfg = config::Config::new(dir.path().to_str().unwrap());
+        let mut idx: HashIndex<TxPtr> = HashIndex::new(&cfg, "test");
+
+        let hash   = Hash32Buf::double_sha256(b"tx");
+        let guard1 = TxPtr::new(0, 100).to_input(0);
+        let guard2 = TxPtr::new(0, 200).to_input(1);
+
+        assert_eq!(idx.get_or_set(hash.as_ref(), guard1), None);
+        assert_eq!(idx.get_or_set(hash.as_ref(), guard2), None);
+        assert_eq!(idx.get(hash.as_ref()), vec![guard2, guard1]);
+
+        assert!(idx.remove_guard(hash.as_ref(), guard1));
+        assert!(!idx.remove_guard(hash.as_ref(), guard1));
+        assert_eq!(idx.get(hash.as_ref()), vec![guard2]);
+
+        // the transaction can be stored once the last guard is removed
+        let tx = TxPtr::new(0, 300);
+        assert!(!idx.set(hash.as_ref(), tx, &[], false));
+        assert!(idx.remove_guard(hash.as_ref(), guard2));
+        assert!(idx.get(hash.as_ref()).is_empty());
+        assert_eq!(idx.get_or_set(hash.as_ref(), guard1), None);
+        assert!(idx.remove_guard(hash.as_ref(), guard1));
+
+        assert!(idx.set(hash.as_ref(), tx, &[], false));
+        assert_eq!(idx.get(hash.as_ref()), vec![tx]);
+
+        // a removed transaction is no longer found
+        assert!(!idx.remove(hash.as_ref(), TxPtr::new(0, 400)));
+        assert!(idx.remove(hash.as_ref(), tx));
+        assert!(idx.get(hash.as_ref()).is_empty());
+        assert_eq!(idx.get_or_set(hash.as_ref(), guard1), None);
+    }
+
+    #[test]
+    fn test_split() {
+
+        let dir = tempdir::TempDir::new("test1").unwrap();
+        let cfg = config::Config::new(dir.path().to_str().unwrap());
+        let mut idx: HashIndex<TxPtr> = HashIndex::new(&cfg, "test");
+
+        // hashes that only differ in the last byte share a slot down to the last nodes
+        let hash = |n: u8| {
+            let mut bytes = [0x5A_u8; 32];
+            bytes[31] = n;
+            Hash32Buf::from_slice(&bytes)
+        };
+
+        for n in 0..32 {
+            assert!(idx.set(hash(n).as_ref(), TxPtr::new(0, 16 + n as u64), &[], false));
+        }
+        for n in 32..40 {
+            assert_eq!(idx.get_or_set(hash(n).as_ref(), TxPtr::new(0, 16 + n as u64).to_input(0)), None);
+        }
+
+        for n in 0..32 {
+            assert_eq!(idx.get(hash(n).as_ref()), vec![TxPtr::new(0, 16 + n as u64)]);
+        }
+        for n in 32..40 {
+            assert_eq!(idx.get(hash(n).as_ref()), vec![TxPtr::new(0, 16 + n as u64).to_input(0)]);
+        }
+        assert!(idx.get(hash(40).as_ref()).is_empty());
+
+        let mut count = 0;
+        idx.for_each(|_, values| { assert_eq!(values.len(), 1); count += 1; });
+        assert_eq!(count, 40);
+    }
+
+    #[test]
+    fn test_read_only_created_later() {
+
+        let dir = tempdir::TempDir::new("test1").unwrap();
+        let cfg = config::Config { read_only: true, ..config::Config::new(dir.path().to_str().unwrap()) };
+        let mut ro: HashIndex<TxPtr> = HashIndex::new(&cfg, "test");
+
+        let hash = Hash32Buf::double_sha256(b"tx");
+        assert!(ro.get(hash.as_ref()).is_empty());
+        assert!(!dir.path().join("test").exists());
+
+        // the index is mapped once a writer creates it
+        let mut idx: HashIndex<TxPtr> = HashIndex::new(&config::Config { read_only: false, ..cfg }, "test");
+        assert!(idx.set(hash.as_ref(), TxPtr::new(0, 100), &[], false));
 
+        assert_eq!(ro.get(hash.as_ref()), vec![TxPtr::new(0, 100)]);
+        assert_eq!(ro.clone().get(hash.as_ref()), vec![TxPtr::new(0, 100)]);
+    }
+
+    #[test]
+    fn test_root_bits() {
+
+        let dir = tempdir::TempDir::new("test1").unwrap();
+        let cfg = config::Config::new(dir.path().to_str().unwrap()).with_index_root_bits(16);
+        let mut idx: HashIndex<TxPtr> = HashIndex::new(&cfg, "test");
+
+        let hashes: Vec<_> = (0..1000u32).map(|n| Hash32Buf::double_sha256(&n.to_le_bytes())).collect();
+        for (n, hash) in hashes.iter().enumerate() {
+            assert!(idx.set(hash.as_ref(), TxPtr::new(0, 16 + n as u64), &[], false));
+        }
+
+        // an existing index keeps its number of root bits
+        let mut idx: HashIndex<TxPtr> = HashIndex::new(&cfg.with_index_root_bits(24), "test");
+        assert_eq!(idx.get_root().unwrap().len(), 1 << 16);
+        for (n, hash) in hashes.iter().enumerate() {
+            assert_eq!(idx.get(hash.as_ref()), vec![TxPtr::new(0, 16 + n as u64)]);
+        }
+        assert_eq!(idx.clone().get(hashes[0].as_ref()), vec![TxPtr::new(0, 16)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "unsupported format")]
+    fn test_refuse_old_format() {
+
+        let dir = tempdir::TempDir::new("test1").unwrap();
+        let cfg = config::Config::new(dir.path().to_str().unwrap());
+
+        // the tree format starts with the root table
+        let mut fileset: FlatFileSet<IndexPtr> = FlatFileSet::open(
+            &dir.path().join("test"), "hi-", FILE_SIZE, MAX_CONTENT_SIZE, false);
+        fileset.alloc_write_space(mem::size_of::<IndexPtr>() as u64 * 256 * 256 * 256);
+        fileset.write_fixed(&IndexPtr::new(0, 100));
+
+        let _idx: HashIndex<TxPtr> = HashIndex::new(&cfg, "test");
     }
 
     #[test]
@@ -440,7 +795,7 @@ mod tests {
 
         let dir = tempdir::TempDir::new("test1").unwrap();
         let path = PathBuf::from(dir.path());
-        let cfg = config::Config { root: path.clone() };
+        let cfg = config::Config { root: path.clone(), ..config::Config::new("") };
 
         let _idx: HashIndex<TxPtr> = HashIndex::new(& cfg, "test" );
 
@@ -457,7 +812,7 @@ mod tests {
             let path = path.clone();
             thread::spawn( move | | {
                 let mut rng = rand::thread_rng();
-                let cfg = config::Config { root: path };
+                let cfg = config::Config { root: path, ..config::Config::new("") };
 
                 let mut idx = HashIndex::new(&cfg, "test");
 
@@ -517,4 +872,94 @@ mod tests {
 
         }
     }
-}
\ No newline at end of file
+
+    // The operations of the tx-index workload, such that the HAMT can be compared with the trees
+    // it replaced
+    trait BenchIndex {
+        fn set(&mut self, hash: Hash32, ptr: TxPtr) -> bool;
+        fn get_or_set(&mut self, hash: Hash32, guard: TxPtr) -> Option<TxPtr>;
+        fn get(&mut self, hash: Hash32) -> Vec<TxPtr>;
+        fn size(&mut self) -> u64;
+    }
+
+    impl BenchIndex for HashIndex<TxPtr> {
+        fn set(&mut self, hash: Hash32, ptr: TxPtr) -> bool { HashIndex::set(self, hash, ptr, &[], false) }
+        fn get_or_set(&mut self, hash: Hash32, guard: TxPtr) -> Option<TxPtr> { HashIndex::get_or_set(self, hash, guard) }
+        fn get(&mut self, hash: Hash32) -> Vec<TxPtr> { HashIndex::get(self, hash) }
+        fn size(&mut self) -> u64 {
+            let end = self.fileset.get_write_pos();
+            end.get_file_number() as u64 * FILE_SIZE + end.get_file_offset()
+        }
+    }
+
+    impl BenchIndex for TreeIndex<TxPtr> {
+        fn set(&mut self, hash: Hash32, ptr: TxPtr) -> bool { TreeIndex::set(self, hash, ptr, &[], false) }
+        fn get_or_set(&mut self, hash: Hash32, guard: TxPtr) -> Option<TxPtr> { TreeIndex::get_or_set(self, hash, guard) }
+        fn get(&mut self, hash: Hash32) -> Vec<TxPtr> { TreeIndex::get(self, hash) }
+        fn size(&mut self) -> u64 { TreeIndex::size(self) }
+    }
+
+    fn bench_index<I: BenchIndex>(name: &str, idx: &mut I, tx_count: usize, missing_count: usize) {
+
+        let hashes: Vec<Hash32Buf> = (0..tx_count + missing_count)
+            .map(|n| Hash32Buf::double_sha256(format!("{}", n).as_bytes()))
+            .collect();
+        let tx = |n: usize| TxPtr::new((n >> 20) as i16, 16 + (n as u64 & 0xF_FFFF) * 256);
+
+        let ms = |timer: Instant| {
+            let elapsed = timer.elapsed();
+            elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000
+        };
+
+        // store the transactions
+        let timer = Instant::now();
+        for (n, hash) in hashes[..tx_count].iter().enumerate() {
+            assert!(idx.set(hash.as_ref(), tx(n)));
+        }
+        let set = ms(timer);
+
+        // each transaction spends an output of an earlier one
+        let timer = Instant::now();
+        for n in 1..tx_count {
+            assert_eq!(idx.get_or_set(hashes[n / 2].as_ref(), tx(n).to_input(0)), Some(tx(n / 2)));
+        }
+        let spend = ms(timer);
+
+        // inputs spending unknown transactions leave guards
+        let timer = Instant::now();
+        for (n, hash) in hashes.iter().enumerate().skip(tx_count) {
+            assert_eq!(idx.get_or_set(hash.as_ref(), tx(n).to_input(0)), None);
+        }
+        let guard = ms(timer);
+
+        let timer = Instant::now();
+        for hash in &hashes[..tx_count] {
+            assert_eq!(idx.get(hash.as_ref()).len(), 1);
+        }
+        let get = ms(timer);
+
+        println!("{}: {} transactions: set {} ms, spend {} ms, {} guards {} ms, get {} ms, {} MB",
+            name, tx_count, set, spend, missing_count, guard, get, idx.size() / (1024 * 1024));
+    }
+
+    // Benchmark of a tx-index workload, for the HAMT and the trees it replaced;
+    // run in release mode with --ignored --nocapture, and with TMPDIR on a tmpfs to measure
+    // the index instead of the disk
+    #[test]
+    #[ignore]
+    fn bench_tx_index() {
+
+        for &(tx_count, missing_count) in &[(2_000_000, 200_000), (16_000_000, 1_600_000)] {
+            {
+                let dir = tempdir::TempDir::new("bench").unwrap();
+                let cfg = config::Config::new(dir.path().to_str().unwrap());
+                bench_index("trees", &mut TreeIndex::new(&cfg, "trees"), tx_count, missing_count);
+            }
+            {
+                let dir = tempdir::TempDir::new("bench").unwrap();
+                let cfg = config::Config::new(dir.path().to_str().unwrap());
+                bench_index("hamt", &mut HashIndex::new(&cfg, "hamt"), tx_count, missing_count);
+            }
+        }
+    }
+}
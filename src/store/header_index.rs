@@ -0,0 +1,268 @@
+//! Index of block headers for header-first synchronisation
+//!
+//! Headers are stored as fixed size records, together with their height and the chain work up
+//! to and including the header, and can be found by hash through a hash-index. Headers are only
+//! accepted on top of known headers, so unlike the block-index there are no guards.
+//!
+//! The first thing written in the fileset is a pointer to the header with the most work.
+//!
+//! The `best-header-chain` file at the root of the store holds the 32 byte hash of the header at
+//! each height of the chain with the most work, such that it can be walked forward. Like the
+//! `best-chain` file of connected blocks, it is written back from a new best header until the
+//! stored hash matches.
+
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use config;
+use hash::*;
+
+use store::flatfileset::{FlatFilePtr, FlatFileSet};
+use store::hash_index::{HashIndex, HashIndexGuard};
+
+
+const MB:                 u64 = 1024 * 1024;
+const FILE_SIZE:          u64 = 1024 * MB;
+const MAX_CONTENT_SIZE:   u64 = FILE_SIZE - 10 * MB;
+
+
+/// A pointer to a header record
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct HeaderPtr {
+    file_offset: u32,
+    file_number: i16,
+    zero:        u16
+}
+
+impl FlatFilePtr for HeaderPtr {
+
+    fn new(file_number: i16, file_offset: u64) -> Self {
+        HeaderPtr {
+            file_offset: file_offset as u32,
+            file_number,
+            zero:        0
+        }
+    }
+
+    fn get_file_number(self) -> i16 { self.file_number }
+
+    fn get_file_offset(self) -> u64 { self.file_offset as u64 }
+}
+
+impl HashIndexGuard for HeaderPtr {
+    fn is_guard(self) -> bool { false }
+}
+
+impl HeaderPtr {
+    fn is_null(self) -> bool { self.file_offset == 0 }
+}
+
+
+/// A stored header with its position in the header-tree
+#[derive(Clone, Copy)]
+pub struct HeaderRecord {
+    pub header: [u8; 80],
+    pub height: u64,
+
+    // the chain work as u128, split to keep the record 8-byte aligned
+    work:       [u64; 2],
+
+    has_body:   u64
+}
+
+impl HeaderRecord {
+
+    pub fn hash(&self) -> Hash32Buf {
+        Hash32Buf::double_sha256(&self.header)
+    }
+
+    pub fn prev_hash(&self) -> Hash32Buf {
+        Hash32Buf::from_slice(&self.header[4..36])
+    }
+
+    pub fn time(&self) -> u32 {
+        u32::from_le_bytes([self.header[68], self.header[69], self.header[70], self.header[71]])
+    }
+
+    pub fn bits(&self) -> u32 {
+        u32::from_le_bytes([self.header[72], self.header[73], self.header[74], self.header[75]])
+    }
+
+    /// The total work of the chain up to and including this header
+    pub fn work(&self) -> u128 {
+        (self.work[0] as u128) << 64 | self.work[1] as u128
+    }
+
+    /// True if the block for this header has been stored
+    pub fn has_body(&self) -> bool {
+        self.has_body != 0
+    }
+}
+
+
+pub struct HeaderIndex {
+    fileset: FlatFileSet<HeaderPtr>,
+    index:   HashIndex<HeaderPtr>,
+
+    // None for a read-only store of which a writer hasn't created the header-index yet
+    best:    Option<&'static mut HeaderPtr>,
+    chain:   PathBuf,
+
+    /// The height in the best header chain below which all blocks are known to be connected
+    pub connected_below: u64
+}
+
+impl HeaderIndex {
+
+    /// Opens the header-index at the location given in the config
+    ///
+    /// Creates a new fileset if needed
+    pub fn new(cfg: &config::Config) -> HeaderIndex {
+
+        let dir    = &cfg.root.clone().join("headers-first");
+        let is_new = !dir.exists();
+
+        let mut fileset = FlatFileSet::open(dir, "hf-", FILE_SIZE, MAX_CONTENT_SIZE, cfg.read_only);
+
+        let best = if is_new && cfg.read_only {
+            None
+        } else if is_new {
+            let best_ptr = fileset.write_fixed(&HeaderPtr::new(0, 0));
+            Some(fileset.read_fixed(best_ptr))
+        } else {
+            Some(fileset.read_fixed(HeaderPtr::new(0, super::flatfile::INITIAL_WRITEPOS)))
+        };
+
+        let mut headers = HeaderIndex {
+            best,
+            fileset,
+            index:   HashIndex::new(cfg, "header-index"),
+            chain:   cfg.root.join("best-header-chain"),
+            connected_below: 0
+        };
+
+        // stores from before the chain file only have the best header
+        if let Some(best) = headers.get_best() {
+            if !cfg.read_only && headers.get_chain_hash(best.height) != Some(best.hash()) {
+                let mut file = headers.lock_chain();
+                headers.update_chain(&mut file, best);
+            }
+        }
+        headers
+    }
+
+    fn get_ptr(&mut self, hash: Hash32) -> Option<HeaderPtr> {
+        self.index.get(hash).into_iter().next()
+    }
+
+    /// Returns the header with the given hash
+    pub fn get(&mut self, hash: Hash32) -> Option<HeaderRecord> {
+
+        let ptr = self.get_ptr(hash)?;
+        Some(*self.fileset.read_fixed::<HeaderRecord>(ptr))
+    }
+
+    // Returns the pointer to the header with the most work, mapping it if a writer created the
+    // header-index after we opened it
+    fn get_best_ptr(&mut self) -> HeaderPtr {
+
+        if self.best.is_none() && self.fileset.file_exists(0) {
+            self.best = Some(self.fileset.read_fixed(HeaderPtr::new(0, super::flatfile::INITIAL_WRITEPOS)));
+        }
+        self.best.as_ref().map_or(HeaderPtr::new(0, 0), |best| **best)
+    }
+
+    /// Returns the header with the most work
+    pub fn get_best(&mut self) -> Option<HeaderRecord> {
+
+        let best = self.get_best_ptr();
+        if best.is_null() {
+            None
+        } else {
+            Some(*self.fileset.read_fixed::<HeaderRecord>(best))
+        }
+    }
+
+    /// Stores a header; this doesn't check whether it already exists
+    pub fn add(&mut self, header: &[u8], height: u64, work: u128) {
+
+        let mut record = HeaderRecord {
+            header:   [0; 80],
+            height,
+            work:     [(work >> 64) as u64, work as u64],
+            has_body: 0
+        };
+        record.header.copy_from_slice(&header[0..80]);
+
+        let ptr = self.fileset.write_fixed(&record);
+        self.index.set(record.hash().as_ref(), ptr, &[], false);
+
+        // the lock on the chain file serialises the compare and update of the best header
+        let mut file = self.lock_chain();
+        if self.get_best().is_none_or(|best| work > best.work()) {
+            if let Some(best) = self.best.as_mut() {
+                **best = ptr;
+            }
+            self.update_chain(&mut file, record);
+        }
+    }
+
+    /// Returns the hash of the header at the given height in the chain with the most work
+    pub fn get_chain_hash(&self, height: u64) -> Option<Hash32Buf> {
+
+        let mut hash = [0; 32];
+        let mut file = File::open(&self.chain).ok()?;
+        file.seek(SeekFrom::Start(height * 32)).ok()?;
+        file.read_exact(&mut hash).ok()?;
+        Some(Hash32Buf::from_slice(&hash))
+    }
+
+    // Opens the best header chain file with an exclusive lock, which is held until it is dropped
+    fn lock_chain(&self) -> File {
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.chain)
+            .expect("Cannot create files in store");
+
+        file.lock().expect("Cannot lock best header chain");
+        file
+    }
+
+    // Writes the chain back from the given best header until the stored hash matches
+    fn update_chain(&mut self, file: &mut File, best: HeaderRecord) {
+
+        let mut next = Some(best);
+        while let Some(record) = next {
+
+            let hash = record.hash();
+            if self.get_chain_hash(record.height).as_ref() == Some(&hash) {
+                break;
+            }
+            file.seek(SeekFrom::Start(record.height * 32))
+                .and_then(|_| file.write_all(hash.as_ref().0))
+                .expect("Cannot write best header chain");
+
+            next = if record.height == 0 {
+                None
+            } else {
+                self.get(record.prev_hash().as_ref())
+            };
+        }
+
+        file.set_len((best.height + 1) * 32).expect("Cannot write best header chain");
+    }
+
+    /// Marks that the block of the header is stored; does nothing if the header is unknown
+    pub fn set_has_body(&mut self, hash: Hash32) {
+
+        if let Some(ptr) = self.get_ptr(hash) {
+            self.fileset.read_fixed::<HeaderRecord>(ptr).has_body = 1;
+        }
+    }
+}
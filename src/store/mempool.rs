@@ -0,0 +1,242 @@
+//! The transactions that are stored but not yet in a connected block
+//!
+//! The `mempool` file at the root of the store holds an entry with the hash and the pointer of
+//! each transaction added with `api::add_transaction`. The entry is removed when a block
+//! containing the transaction is connected. Compact blocks are reconstructed from these
+//! entries, without scanning the transactions of the blocks.
+//!
+//! Stores update the file while holding an exclusive lock on it, and read it under a shared lock.
+//! Transactions of blocks that are disconnected by a reorganisation are not added back. The file
+//! holds at most `MAX_ENTRIES` entries; when it is full, the oldest quarter is dropped and those
+//! transactions are requested from the peer when they are in a compact block.
+
+
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use config;
+use hash::*;
+use store::{Store, TxPtr, BlockPtr};
+use store::flatfileset::FlatFilePtr;
+
+
+const MEMPOOL_FILE: &str = "mempool";
+
+// the hash, the file number and the file offset
+const ENTRY_SIZE: usize = 32 + 2 + 4;
+
+// about 11 MB of entries
+const MAX_ENTRIES: usize = 300_000;
+
+
+pub struct Mempool {
+    path:        PathBuf,
+    max_entries: usize
+}
+
+impl Mempool {
+
+    pub fn new(cfg: &config::Config) -> Mempool {
+        Mempool {
+            path:        cfg.root.join(MEMPOOL_FILE),
+            max_entries: MAX_ENTRIES
+        }
+    }
+
+    fn open(&self) -> File {
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)
+            .expect("Cannot create files in store");
+
+        file.lock().expect("Cannot lock mempool");
+        file
+    }
+
+    /// Returns the number of transactions in the mempool
+    pub fn len(&self) -> usize {
+        self.path.metadata().map_or(0, |meta| meta.len() as usize / ENTRY_SIZE)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds a transaction that was just stored
+    pub fn add(&mut self, hash: Hash32, ptr: TxPtr) {
+
+        let mut entry = Vec::with_capacity(ENTRY_SIZE);
+        entry.extend_from_slice(hash.0);
+        entry.extend_from_slice(&ptr.get_file_number().to_le_bytes());
+        entry.extend_from_slice(&(ptr.get_file_offset() as u32).to_le_bytes());
+
+        let mut file = self.open();
+        self.drop_oldest(&mut file).expect("Cannot write mempool");
+
+        file.seek(SeekFrom::End(0))
+            .and_then(|_| file.write_all(&entry))
+            .expect("Cannot write mempool");
+    }
+
+    // Drops the oldest quarter of the entries if the file is full; dropping more than one entry
+    // prevents rewriting the file on every add
+    fn drop_oldest(&self, file: &mut File) -> io::Result<()> {
+
+        let count = file.metadata()?.len() as usize / ENTRY_SIZE;
+        if count < self.max_entries {
+            return Ok(());
+        }
+
+        let mut data = vec![];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut data)?;
+
+        let keep = self.max_entries * 3 / 4;
+        let kept = data[(count - keep) * ENTRY_SIZE .. count * ENTRY_SIZE].to_vec();
+
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&kept)?;
+        file.set_len(kept.len() as u64)
+    }
+
+    /// Returns the hashes and pointers of the transactions in the mempool
+    pub fn get_all(&self) -> Vec<(Hash32Buf, TxPtr)> {
+
+        let mut data = vec![];
+        if let Ok(mut file) = File::open(&self.path) {
+            file.lock_shared().expect("Cannot lock mempool");
+            file.read_to_end(&mut data).expect("Cannot read mempool");
+        }
+        parse_entries(&data)
+    }
+
+    /// Removes the transactions with the given hashes
+    pub fn remove(&mut self, hashes: &HashSet<[u8; 32]>) -> io::Result<()> {
+
+        let mut file = self.open();
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+
+        let kept: Vec<u8> = data.chunks(ENTRY_SIZE)
+            .zip(parse_entries(&data))
+            .filter(|&(_, (hash, _))| !hashes.contains(hash.as_ref().0))
+            .flat_map(|(entry, _)| entry.iter().cloned())
+            .collect();
+
+        if kept.len() < data.len() {
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&kept)?;
+            file.set_len(kept.len() as u64)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_entries(data: &[u8]) -> Vec<(Hash32Buf, TxPtr)> {
+
+    data.chunks(ENTRY_SIZE)
+        .filter(|entry| entry.len() == ENTRY_SIZE)
+        .map(|entry| {
+            let mut file_number = [0; 2];
+            let mut file_offset = [0; 4];
+            file_number.copy_from_slice(&entry[32..34]);
+            file_offset.copy_from_slice(&entry[34..38]);
+
+            let ptr = TxPtr::new(i16::from_le_bytes(file_number), u32::from_le_bytes(file_offset) as u64);
+            (Hash32Buf::from_slice(&entry[0..32]), ptr)
+        })
+        .collect()
+}
+
+
+/// Removes the transactions of a block that was just connected from the mempool
+pub fn connect_block(store: &mut Store, block: BlockPtr) {
+
+    if store.mempool.is_empty() {
+        return;
+    }
+
+    // on initial sync, the transactions of a block are stored again, so we match them by hash
+    let ptrs: Vec<TxPtr> = store.spend_tree.get_block_mut(block).iter()
+        .filter(|rec| rec.is_transaction() && !rec.is_unmatched_input())
+        .map(|rec| rec.get_transaction_ptr())
+        .collect();
+
+    let hashes = ptrs.into_iter()
+        .map(|ptr| store.transactions.read(ptr).expect("Transaction of a block that is connected is not pruned"))
+        .map(|raw| *Hash32Buf::double_sha256(&raw).as_ref().0)
+        .collect();
+
+    store.mempool.remove(&hashes).expect("Cannot write mempool");
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use api;
+    use block_add;
+    use test_chain::TestChain;
+
+    #[test]
+    fn test_mempool() {
+
+        let mut store = Store::new(& test_cfg!());
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let base      = chain.extend(genesis, 101);
+        let coinbase  = chain.chain(base)[1].coinbase.clone().unwrap();
+        let value     = coinbase.output(0).value;
+
+        let tx1       = chain.spend(&[coinbase.output(0)], &[value / 2, value / 2]);
+        let tx2       = chain.spend(&[tx1.output(0)], &[value / 2]);
+        let tip       = chain.add_block(base, vec![tx1.clone()]);
+
+        for block in chain.chain(base) {
+            block_add::add_block(&mut store, &block.raw);
+        }
+        assert!(store.mempool.is_empty());
+
+        api::add_transaction(&mut store, &tx1.raw).unwrap();
+        api::add_transaction(&mut store, &tx2.raw).unwrap();
+
+        let hashes: Vec<_> = store.mempool.get_all().into_iter().map(|(hash, _)| *hash.as_ref().0).collect();
+        assert_eq!(hashes, vec![tx1.txid, tx2.txid]);
+
+        // adding again doesn't add an entry
+        api::add_transaction(&mut store, &tx1.raw).unwrap();
+        assert_eq!(store.mempool.len(), 2);
+
+        block_add::add_block(&mut store, &chain.block(tip).raw);
+
+        let hashes: Vec<_> = store.mempool.get_all().into_iter().map(|(hash, _)| *hash.as_ref().0).collect();
+        assert_eq!(hashes, vec![tx2.txid]);
+    }
+
+    #[test]
+    fn test_mempool_bounded() {
+
+        let mut store   = Store::new(& test_cfg!());
+        let mempool     = &mut store.mempool;
+        mempool.max_entries = 8;
+
+        let hashes: Vec<Hash32Buf> = (0..10_u8).map(|n| Hash32Buf::double_sha256(&[n])).collect();
+        for (n, hash) in hashes.iter().enumerate() {
+            mempool.add(hash.as_ref(), TxPtr::new(0, 16 + n as u64));
+        }
+
+        // the 9th add dropped the oldest 2 entries
+        let found: Vec<_> = mempool.get_all().into_iter().map(|(hash, ptr)| (hash, ptr.get_file_offset())).collect();
+        let expected: Vec<_> = hashes.into_iter().enumerate().skip(2).map(|(n, hash)| (hash, 16 + n as u64)).collect();
+        assert_eq!(found, expected);
+    }
+}
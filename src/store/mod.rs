@@ -36,6 +36,34 @@
 //!
 //! A bit-index that is used as a "broom-wagon" for the spend_tree to prevent deep-tree searching
 //!
+//! # headers
+//!
+//! Block headers that are added on their own for header-first synchronisation,
+//! with their height and chain work. See [[header_index]]
+//!
+//! # utxo_hashes
+//!
+//! The MuHash of the unspent outputs as of each connected block, and the heights of the blocks
+//! containing transactions, if enabled in the config. See [[utxo_hash]]
+//!
+//! # best_chain
+//!
+//! The height of each connected block, and the hashes of the best chain by height.
+//! See [[best_chain]]
+//!
+//! # mempool
+//!
+//! The transactions that are not yet in a connected block. See [[mempool]]
+//!
+//! # script_index
+//!
+//! Optionally, the outputs paying to each script and the inputs spending them.
+//! See [[script_index]]
+//!
+//! # spent_by
+//!
+//! Optionally, the transaction and input spending each output. See [[spent_by]]
+//!
 
 
 use slog ;
@@ -51,11 +79,21 @@ mod flatfile;
 mod flatfileset;
 
 mod hash_index;
+#[cfg(test)]
+mod tree_index;
 mod spend_index;
 
 mod spend_tree;
 
-mod prune;
+mod header_index;
+pub mod utxo_hash;
+pub mod best_chain;
+pub mod mempool;
+pub mod script_index;
+pub mod spent_by;
+
+pub mod prune;
+pub mod gc;
 
 pub mod tips;
 
@@ -67,13 +105,16 @@ pub use self::spend_tree::record::{RecordPtr,Record};
 
 pub use self::txptr::TxPtr;
 pub use self::hash_index::{HashIndex, HashIndexGuard};
+pub use self::header_index::HeaderRecord;
 pub use self::blockheaderptr::BlockHeaderPtr;
 
-pub use self::flatfileset::{FlatFilePtr,FlatFileSet};
+pub use self::flatfileset::{FlatFilePtr,FlatFileSet,set_write_pos};
 
-pub use self::transactions::Transactions;
+pub use self::transactions::{Transactions, Pruned};
 pub type TxIndex = HashIndex<TxPtr>;
 
+use std::io;
+
 use config;
 use hash::*;
 
@@ -92,7 +133,7 @@ pub const MAX_CONTENT_SIZE:   u64 = FILE_SIZE - 10 * MB as u64 ;
 /// This is the accessor to all stuff on disk.
 /// A single store cannot be used from multiple threads without precaution,
 /// but multiple Stores from different threads/processes can use the same
-/// files concurrently
+/// files concurrently. Stores that only read can be opened with `open_read_only`
 pub struct Store {
     // Flat files contain transactions and blockheaders
     pub transactions: transactions::Transactions,
@@ -105,6 +146,18 @@ pub struct Store {
     pub spend_tree: spend_tree::SpendTree,
     pub spend_index: spend_index::SpendIndex,
 
+    pub headers: header_index::HeaderIndex,
+
+    pub utxo_hashes: Option<utxo_hash::UtxoHashIndex>,
+
+    pub best_chain: best_chain::BestChain,
+
+    pub mempool: mempool::Mempool,
+
+    pub script_index: Option<script_index::ScriptIndex>,
+
+    pub spent_by: Option<spent_by::SpentByIndex>,
+
     pub tips: tips::Tips,
 
     // todo; this needs to go; structured logging is superior
@@ -129,11 +182,12 @@ impl Store {
             transactions:  transactions::Transactions::new(&cfg),
 
 
-            block_headers:  FlatFileSet::new(
+            block_headers:  FlatFileSet::open(
                 &cfg.root.clone().join("headers"),
                 "bh",
                 FILE_SIZE,
-                MAX_CONTENT_SIZE),
+                MAX_CONTENT_SIZE,
+                cfg.read_only),
 
             tx_index:     hash_index::HashIndex::new(&cfg, "tx-index"),
             block_index:  hash_index::HashIndex::new(&cfg, "block-index"),
@@ -141,6 +195,13 @@ impl Store {
             spend_tree:   spend_tree::SpendTree::new(&cfg),
             spend_index:  spend_index::SpendIndex::new(&cfg),
 
+            headers:      header_index::HeaderIndex::new(cfg),
+            utxo_hashes:  if cfg.utxo_hash { Some(utxo_hash::UtxoHashIndex::new(cfg)) } else { None },
+            best_chain:   best_chain::BestChain::new(cfg),
+            mempool:      mempool::Mempool::new(cfg),
+            script_index: if cfg.script_index { Some(script_index::ScriptIndex::new(cfg)) } else { None },
+            spent_by:     if cfg.spent_by_index { Some(spent_by::SpentByIndex::new(cfg)) } else { None },
+
             tips:         tips::Tips::new(&cfg),
 
             metrics:       Metrics::new(),
@@ -154,6 +215,31 @@ impl Store {
 
 
 
+    /// Opens an existing store without ever writing to it
+    ///
+    /// The files are mapped read-only, and the data that other stores add is seen. The optional
+    /// indexes are used if the store has them. Operations that write panic
+    pub fn open_read_only(cfg: &config::Config) -> io::Result<Store> {
+
+        if !cfg.root.join("spend-tree").is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("No store at {:?}", cfg.root)));
+        }
+
+        let cfg = config::Config {
+            script_index:   script_index::ScriptIndex::exists(cfg),
+            spent_by_index: spent_by::SpentByIndex::exists(cfg),
+            utxo_hash:      utxo_hash::UtxoHashIndex::exists(cfg),
+            read_only:      true,
+            ..cfg.clone()
+        };
+        Ok(Store::new(&cfg))
+    }
+
+    /// Returns true if the store was opened with `open_read_only`
+    pub fn is_read_only(&self) -> bool {
+        self.cfg.read_only
+    }
+
     /// Gets the block hash from a block-ptr;
     /// This follows the indirection through the spend-tree
     ///
@@ -168,6 +254,13 @@ impl Store {
 
     }
 
+    /// Returns the configuration this store was opened with
+    ///
+    /// As a Store can't be sent to another thread, this can be used to open one there
+    pub fn get_config(&self) -> &config::Config {
+        &self.cfg
+    }
+
 
 
 }
@@ -219,6 +312,76 @@ mod tests {
         let _ = Store::new(& test_cfg!());
     }
 
+    // Returns the files of the store with their size and modification time
+    fn list_files(path: &::std::path::Path) -> Vec<(::std::path::PathBuf, u64, ::std::time::SystemTime)> {
+
+        let mut files = vec![];
+        for entry in ::std::fs::read_dir(path).unwrap() {
+            let entry = entry.unwrap();
+            let meta  = entry.metadata().unwrap();
+            if meta.is_dir() {
+                files.extend(list_files(&entry.path()));
+            } else {
+                files.push((entry.path(), meta.len(), meta.modified().unwrap()));
+            }
+        }
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_open_read_only() {
+
+        use block_add;
+        use test_chain::TestChain;
+
+        let cfg = test_cfg!();
+        assert!(Store::open_read_only(&cfg).is_err());
+
+        let mut store = Store::new(&cfg.clone().with_script_index(true));
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let tip       = chain.extend(genesis, 3);
+        let next      = chain.extend(tip, 1);
+        for block in chain.chain(tip) {
+            block_add::add_block(&mut store, &block.raw);
+        }
+
+        // reading doesn't change or create files
+        let files  = list_files(&cfg.root);
+        let mut ro = Store::open_read_only(&cfg).unwrap();
+        assert!(ro.is_read_only());
+        assert!(ro.script_index.is_some() && ro.spent_by.is_none());
+
+        assert_eq!(ro.tips.get_most_work_tip().unwrap().height, 3);
+        assert_eq!(*ro.best_chain.get_hash(3).unwrap().as_ref().0, chain.block(tip).hash);
+        assert!(ro.headers.get_best().is_none());
+        assert_eq!(list_files(&cfg.root), files);
+
+        // and sees the blocks added by a writer
+        block_add::add_block(&mut store, &chain.block(next).raw);
+        assert_eq!(ro.tips.get_most_work_tip().unwrap().height, 4);
+        assert_eq!(best_chain::get_height(&mut ro, Hash32(&chain.block(next).hash)), Some(4));
+    }
+
+    #[test]
+    #[should_panic(expected = "read-only")]
+    fn test_read_only_add_block() {
+
+        use block_add;
+        use test_chain::TestChain;
+
+        let cfg = test_cfg!();
+        let _   = Store::new(&cfg);
+
+        let chain = TestChain::new();
+        let genesis   = chain.genesis();
+
+        let mut ro = Store::open_read_only(&cfg).unwrap();
+        block_add::add_block(&mut ro, &chain.block(genesis).raw);
+    }
+
     // this takes a fake spend tree (created with block! macro's) and use it to construct
     // valid transactions and blocks
     /*fn test_create_store_from_spend_tree(spend_tree: RecordPtr) -> Store {
@@ -1,104 +1,611 @@
+//! Online pruning of the tx-index
+//!
+//! A transaction is only looked up in the tx-index when one of its outputs is spent. Once all
+//! of its outputs are spent in blocks that are buried deep enough in the best chain, it is
+//! removed from the tx-index. The transaction itself stays in the transaction store, as the
+//! spend-tree still points to it.
+//!
+//! A pass walks the best chain from the block where the previous pass stopped up to `depth`
+//! blocks below the tip. The hash of that block is kept in the `prune-checkpoint` file, such that
+//! each block is walked once. Spends in the blocks above it are collected first; a transaction
+//! with an output spent in one of these is not pruned until a later pass.
+//!
+//! The spend-index is shared by all branches, so it also has the spends of stale forks. These
+//! are collected from the tips that are not in the best chain. An output spent in a stale fork
+//! only counts as spent if it is spent in one of the blocks walked by the pass as well.
+//!
+//! Removal from the tx-index is atomic, so a pass can run on its own store while blocks are
+//! added elsewhere. Only one pruner should run for a store at a time.
+//!
+//! With a disk budget, files of the transaction store are removed as well, oldest first, until
+//! the store fits the budget. Only files of which all transactions are pruned from the tx-index
+//! are removed; records in the spend-tree that point to their transactions are kept, and reading
+//! them returns `Pruned`.
 
 
-//! Preliminairy pruning of tx-index
-//! This is probably less needed when using HAMT
-
-// this is needed because of #[ignore]
-#![allow(unused_imports)]
-#![allow(dead_code)]
-
-use std::env;
+use std::collections::HashSet;
 use std::fs;
-use std::path;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use hash::*;
+use snapshot;
 use buffer::*;
-use config::Config;
-use store::hash_index;
-use store::{Store,TxPtr,Record};
+use store::{Store, TxPtr, Record, BlockPtr};
+use store::flatfile::INITIAL_WRITEPOS;
+use store::flatfileset::FlatFilePtr;
+use store::spend_index::SpendIndex;
+use store::tips::TipStatus;
 use transaction::Transaction;
 
 
-/// Prunes the tx-index of the store to a separate tx-index-pruned folder
-fn prune_to_new_index() {
+const CHECKPOINT_FILE: &str = "prune-checkpoint";
+
+/// The minimal number of blocks kept on top of a spend before the transaction is pruned
+pub const DEFAULT_PRUNE_DEPTH: u64 = 288;
+
+
+/// Determines which transactions are pruned and how often the background pruner runs
+#[derive(Debug, Clone)]
+pub struct PrunePolicy {
+
+    /// Number of blocks that must be on top of the block with the last spend of a transaction;
+    /// reorganisations deeper than this are no longer possible once pruned
+    pub depth: u64,
+
+    /// Time between passes of the background `Pruner`
+    pub interval: Duration,
+
+    /// The number of bytes the transaction store may use; if set, files of the transaction
+    /// store are removed when it grows beyond this
+    pub disk_budget: Option<u64>
+}
+
+impl Default for PrunePolicy {
+    fn default() -> PrunePolicy {
+        PrunePolicy {
+            depth:       DEFAULT_PRUNE_DEPTH,
+            interval:    Duration::from_secs(60),
+            disk_budget: None
+        }
+    }
+}
+
+
+/// The result of a pruning pass
+#[derive(Debug, Default, PartialEq)]
+pub struct PruneReport {
+
+    /// Number of blocks walked
+    pub blocks: usize,
+
+    /// Number of transactions removed from the tx-index
+    pub transactions: usize,
+
+    /// Number of files removed from the transaction store
+    pub files: usize
+}
+
+
+/// Returns true if all outputs of the transaction stored at `tx_ptr` are spent according to the
+/// spend-index, or can never be spent
+pub fn is_spent(spend_index: &SpendIndex, tx_ptr: TxPtr, tx: &Transaction) -> bool {
+
+    tx.txs_out.iter().enumerate()
+        .filter(|&(_, output)| !output.is_unspendable())
+        .all(|(n, _)| spend_index.exists(Record::new_output(tx_ptr, n as u32).hash()))
+}
+
+
+/// Runs a pass of `prune_tx_index`, and removes files of the transaction store if it
+/// exceeds the disk budget of the policy
+pub fn prune(store: &mut Store, policy: &PrunePolicy) -> PruneReport {
+
+    let mut report = prune_tx_index(store, policy);
+
+    if let Some(budget) = policy.disk_budget {
+        report.files = prune_transaction_files(store, budget);
+    }
+    report
+}
+
+
+// The spends that determine whether an output counts as spent in a pass
+struct PassSpends {
+
+    // spends in the blocks above the horizon
+    shallow: HashSet<u64>,
+
+    // spends in connected blocks that are not in the best chain
+    stale:   HashSet<u64>,
+
+    // spends in the blocks of the best chain walked by the pass
+    walked:  HashSet<u64>
+}
+
+impl PassSpends {
+
+    // Returns true if the output is spent in the best chain below the horizon
+    fn is_spent(&self, spend_index: &SpendIndex, hash: u64) -> bool {
+
+        !self.shallow.contains(&hash)
+            && (!self.stale.contains(&hash) || self.walked.contains(&hash))
+            && spend_index.exists(hash)
+    }
+}
+
 
-    let cfg = test_cfg!();
-    let mut store = Store::new(&cfg);
+/// Removes the transactions that are spent deeper than `policy.depth` in the best chain
+/// from the tx-index, continuing where the previous pass stopped
+pub fn prune_tx_index(store: &mut Store, policy: &PrunePolicy) -> PruneReport {
 
+    let _lock      = snapshot::lock_writes(store);
+    let mut report = PruneReport::default();
 
-    let mut new_tx_index: hash_index::HashIndex<TxPtr>
-        = hash_index::HashIndex::new(&cfg, "tx-index-pruned");
+    let tip = match store.tips.get_most_work_tip() {
+        Some(tip) => ::export::get_block(store, tip.block_hash.as_ref()),
+        None      => None
+    };
+
+    // spends in the top blocks prevent pruning
+    let mut shallow = HashSet::new();
+    let mut next    = tip;
+    for _ in 0..policy.depth.max(1) {
+        match next {
+            None        => return report,
+            Some(block) => {
+                shallow.extend(get_spends(store, block).iter().map(|rec| rec.hash()));
+                next = store.spend_tree.get_previous_block(block);
+            }
+        }
+    }
+    let horizon = match next {
+        None        => return report,
+        Some(block) => block
+    };
 
-    let mut tx_ptr = TxPtr::first();
-    let mut count: u64 = 0;
-    let mut count_purged: u64 = 0;
-    loop {
-        let result = store.transactions.next(tx_ptr);
+    // walk back to where the previous pass stopped; after a reorganisation past the
+    // checkpoint this restarts at genesis
+    let checkpoint = read_checkpoint(store)
+        .and_then(|hash| ::export::get_block(store, hash.as_ref()));
 
-        if result == None {
+    let mut blocks = vec![];
+    let mut next   = Some(horizon);
+    while let Some(block) = next {
+        if checkpoint == Some(block) {
             break;
         }
-        let (tx_raw, next_ptr) = result.unwrap();
+        blocks.push(block);
+        next = store.spend_tree.get_previous_block(block);
+    }
 
-        // we parse it only for the output_count
-        let tx = Transaction::parse(&mut Buffer::new(tx_raw.as_slice())).unwrap();
+    let spends: Vec<Record> = blocks.iter().rev()
+        .flat_map(|&block| get_spends(store, block))
+        .collect();
 
-        let input_count = tx.txs_out.len() as u32;
+    let pass_spends = PassSpends {
+        shallow,
+        stale:  get_stale_spends(store),
+        walked: spends.iter().map(|rec| rec.hash()).collect()
+    };
 
-        let tx_ptr_copy = tx_ptr;
-        let spend_outputs = (0..input_count)
-            .map(|n|   Record::new_output(tx_ptr_copy, n))
-            .map(|rec| rec.hash())
-            .filter(|hash| store.spend_index.exists(*hash))
-            .count() as u32;
+    let mut seen = HashSet::new();
+    for rec in spends {
 
-        if spend_outputs  < input_count {
+        let tx_ptr = rec.get_transaction_ptr();
+        if seen.insert(tx_ptr) && prune_transaction(store, tx_ptr, &pass_spends) {
+            report.transactions += 1;
+        }
+    }
+    report.blocks = blocks.len();
 
-            // we still need this one
-            let hash = Hash32Buf::double_sha256(tx.to_raw());
+    if !blocks.is_empty() {
+        let hash = store.get_block_hash(horizon);
+        write_checkpoint(store, hash.as_ref())
+            .expect("Cannot write prune checkpoint to store");
+    }
+
+    info!(store.logger, "pruned tx-index";
+        "blocks"       => report.blocks,
+        "transactions" => report.transactions);
 
-            assert_eq!(store.tx_index.get(hash.as_ref()).len(),1);
+    report
+}
 
-            new_tx_index.set(hash.as_ref(), tx_ptr, &[], true);
+// Returns the spend records of the block
+fn get_spends(store: &mut Store, block: BlockPtr) -> Vec<Record> {
+
+    let records = store.spend_tree.get_block_mut(block);
+    records[1..records.len() - 1].iter()
+        .filter(|rec| rec.is_output())
+        .cloned()
+        .collect()
+}
+
+// Returns the spends of the connected blocks that are not in the best chain
+fn get_stale_spends(store: &mut Store) -> HashSet<u64> {
+
+    let mut stale = HashSet::new();
+    for tip in store.tips.get_tips() {
+
+        if tip.status == TipStatus::OrphanHeaders {
+            continue;
         }
-            else {
-                // all inputs are spend; don't add it to the new-index
-                count_purged += 1;
+
+        // walk back to where the fork leaves the best chain
+        let mut height = tip.height;
+        let mut next   = ::export::get_block(store, tip.block_hash.as_ref());
+        while let Some(block) = next {
+
+            let hash = store.get_block_hash(block);
+            if store.best_chain.get_hash(height) == Some(hash) {
+                break;
+            }
+            stale.extend(get_spends(store, block).iter().map(|rec| rec.hash()));
+
+            if height == 0 {
+                break;
             }
+            height -= 1;
+            next = store.spend_tree.get_previous_block(block);
+        }
+    }
+    stale
+}
+
+// Removes the transaction from the tx-index if all its outputs count as spent in the pass
+fn prune_transaction(store: &mut Store, tx_ptr: TxPtr, spends: &PassSpends) -> bool {
+
+    let raw = match store.transactions.read(tx_ptr) {
+        Ok(raw) => raw,
+        Err(_)  => return false
+    };
+    let tx  = match Transaction::parse(&mut Buffer::new(&raw)) {
+        Ok(tx) => tx,
+        Err(_) => return false
+    };
 
+    let spent = tx.txs_out.iter().enumerate()
+        .filter(|&(_, output)| !output.is_unspendable())
+        .all(|(n, _)| spends.is_spent(&store.spend_index, Record::new_output(tx_ptr, n as u32).hash()));
+
+    if !spent {
+        return false;
+    }
 
-        count = count + 1;
-        if count % 1000 == 0 {
-            println!("Done: {}; purged {} %", count, count_purged as u64 * 100 / count as u64);
+    let hash = Hash32Buf::double_sha256(tx.to_raw());
+    store.tx_index.remove(hash.as_ref(), tx_ptr)
+}
+
+// Removes files of the transaction store, oldest first, until it fits in `budget` bytes
+//
+// Returns the number of files removed
+fn prune_transaction_files(store: &mut Store, budget: u64) -> usize {
+
+    let _lock     = snapshot::lock_writes(store);
+    let mut count = 0;
+    for fileno in store.transactions.get_files() {
+
+        if store.transactions.get_size() <= budget {
+            break;
         }
+        if is_file_pruned(store, fileno) {
+            store.transactions.remove_file(fileno);
+            count += 1;
+        }
+    }
 
-        tx_ptr = next_ptr;
+    if count > 0 {
+        info!(store.logger, "pruned transactions";
+            "files" => count,
+            "size"  => store.transactions.get_size());
     }
+    count
+}
+
+// Returns true if all transactions in the file are spent and pruned from the tx-index
+fn is_file_pruned(store: &mut Store, fileno: i16) -> bool {
 
-    println!("Done");
-    println!("  {} transactions", count);
-    println!("  {} purged ({} %)", count_purged , count_purged as u64 * 100 / count as u64);
-    println!("  {} remain", count - count_purged ,);
+    let mut ptr = TxPtr::new(fileno, INITIAL_WRITEPOS);
+    while ptr.get_file_number() == fileno {
 
+        let (raw, next) = match store.transactions.next(ptr) {
+            Some(found) => found,
+            None        => break
+        };
+        let tx = match Transaction::parse(&mut Buffer::new(&raw)) {
+            Ok(tx) => tx,
+            Err(_) => return false
+        };
+
+        let hash = Hash32Buf::double_sha256(&raw);
+        if !is_spent(&store.spend_index, ptr, &tx) || store.tx_index.get(hash.as_ref()).contains(&ptr) {
+            return false;
+        }
+        ptr = next;
+    }
+    true
+}
+
+fn checkpoint_path(store: &Store) -> PathBuf {
+    store.get_config().root.join(CHECKPOINT_FILE)
+}
+
+fn read_checkpoint(store: &Store) -> Option<Hash32Buf> {
+
+    fs::read(checkpoint_path(store)).ok()
+        .filter(|bytes| bytes.len() == 32)
+        .map(|bytes| Hash32Buf::from_slice(&bytes))
+}
+
+// The checkpoint is replaced atomically
+fn write_checkpoint(store: &Store, hash: Hash32) -> io::Result<()> {
+
+    let path     = checkpoint_path(store);
+    let tmp_path = path.with_extension("tmp");
+
+    fs::write(&tmp_path, &hash.0[..])?;
+    fs::rename(tmp_path, path)
+}
+
+
+/// Prunes the store on a background thread with its own store
+///
+/// The pruner runs a pass every `policy.interval` until it is dropped
+pub struct Pruner {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    thread:  Option<thread::JoinHandle<()>>
+}
+
+impl Pruner {
+
+    pub fn new(store: &Store, policy: PrunePolicy) -> Pruner {
+
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        let cfg     = store.get_config().clone();
+
+        let thread_stopped = stopped.clone();
+        let thread = thread::spawn(move || {
+            let mut store = Store::new(&cfg);
+
+            let (lock, wakeup) = &*thread_stopped;
+            let mut stopped = lock.lock().unwrap();
+            while !*stopped {
+                drop(stopped);
+                prune(&mut store, &policy);
+
+                stopped = lock.lock().unwrap();
+                if !*stopped {
+                    stopped = wakeup.wait_timeout(stopped, policy.interval).unwrap().0;
+                }
+            }
+        });
+
+        Pruner {
+            stopped,
+            thread: Some(thread)
+        }
+    }
 }
 
-#[ignore]
-#[test]
-fn prune_tx_index() {
+impl Drop for Pruner {
 
+    // A running pass is finished first
+    fn drop(&mut self) {
+        {
+            let (lock, wakeup) = &*self.stopped;
+            *lock.lock().unwrap() = true;
+            wakeup.notify_all();
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use block_add;
+    use test_chain::TestChain;
+
+    #[test]
+    fn test_prune_tx_index() {
 
-    let store_path = env::var(::config::ENV_BITCRUST_STORE).
-        expect(&format!("Use {} env var to specify a store to prune", ::config::ENV_BITCRUST_STORE));
+        let mut store = Store::new(& test_cfg!());
+        let policy    = PrunePolicy { depth: 2, ..Default::default() };
 
-    // add pruned tx-index to store_path
-    let pruned_path   = path::PathBuf::from(&store_path).join("tx-index-pruned");
-    let tx_index_path = path::PathBuf::from(&store_path).join("tx-index");
-    let _ =  fs::remove_dir_all(&pruned_path); // if exists
+        // tx spends the coinbase of block 1 and its outputs are spent in the next two blocks
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let tip       = chain.extend(genesis, 101);
+        let coinbase  = chain.chain(tip)[1].coinbase.clone().unwrap();
+        let value     = coinbase.output(0).value;
+        let tx        = chain.spend(&[coinbase.output(0)], &[value / 2, value / 2]);
+        let spend1    = chain.spend(&[tx.output(0)], &[value / 2]);
+        let spend2    = chain.spend(&[tx.output(1)], &[value / 2]);
+        let tip       = chain.build_block(tip).transaction(tx.clone()).add();
+        let tip       = chain.build_block(tip).transaction(spend1.clone()).add();
+        let with_spend2 = chain.build_block(tip).transaction(spend2.clone()).add();
+        let tip         = chain.extend(with_spend2, 2);
 
-    prune_to_new_index();
+        let blocks = chain.chain(tip);
+        let in_index = |store: &mut Store, txid: &[u8; 32]|
+            !store.tx_index.get(Hash32Buf::from_slice(txid).as_ref()).is_empty();
 
-    // move the new index into position
-    fs::remove_dir_all(&tx_index_path).expect("Couldn't remove old tx-index");
-    fs::rename(pruned_path, &tx_index_path).expect("Failed to move tx-index after pruning");
+        // only the coinbase is spent deep enough
+        for block in &blocks[0..106] {
+            block_add::add_block(&mut store, &block.raw);
+        }
+        assert_eq!(prune_tx_index(&mut store, &policy), PruneReport { blocks: 104, transactions: 1, files: 0 });
+        assert!(!in_index(&mut store, &coinbase.txid));
+        assert!(in_index(&mut store, &tx.txid));
+
+        // the second output is spent in block 104, which is buried once 106 is added
+        block_add::add_block(&mut store, &blocks[106].raw);
+        assert_eq!(prune_tx_index(&mut store, &policy), PruneReport { blocks: 1, transactions: 1, files: 0 });
+        assert!(!in_index(&mut store, &tx.txid));
+        assert!(in_index(&mut store, &spend1.txid));
+        assert!(in_index(&mut store, &spend2.txid));
+
+        assert_eq!(prune_tx_index(&mut store, &policy), PruneReport::default());
+        assert!(::fsck::check(&mut store).is_ok());
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_stale_spends() {
+
+        let mut store = Store::new(& test_cfg!());
+        let policy    = PrunePolicy { depth: 2, ..Default::default() };
+
+        // the first output of tx is spent in the best chain, the second only in a fork
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let base      = chain.extend(genesis, 101);
+        let coinbase  = chain.chain(base)[1].coinbase.clone().unwrap();
+        let value     = coinbase.output(0).value;
+        let tx        = chain.spend(&[coinbase.output(0)], &[value / 2, value / 2]);
+        let spend0    = chain.spend(&[tx.output(0)], &[value / 2]);
+        let spend1    = chain.spend(&[tx.output(1)], &[value / 2]);
+        let with_tx   = chain.build_block(base).transaction(tx.clone()).add();
+        let fork      = chain.build_block(with_tx).transaction(spend1).add();
+        let fork_tip  = chain.extend(fork, 1);
+        let main      = chain.build_block(with_tx).transaction(spend0).add();
+        let tip       = chain.extend(main, 4);
+
+        for block in chain.chain(fork_tip) {
+            block_add::add_block(&mut store, &block.raw);
+        }
+        for block in &chain.chain(tip)[103..] {
+            block_add::add_block(&mut store, &block.raw);
+        }
+        assert_eq!(store.tips.get_most_work_tip().unwrap().block_hash, Hash32Buf::from_slice(&chain.block(tip).hash));
+
+        let report = prune_tx_index(&mut store, &policy);
+        assert_eq!(report.transactions, 1);
+        assert!(store.tx_index.get(Hash32Buf::from_slice(&coinbase.txid).as_ref()).is_empty());
+        assert!(!store.tx_index.get(Hash32Buf::from_slice(&tx.txid).as_ref()).is_empty());
+        assert!(::fsck::check(&mut store).is_ok());
+    }
+
+    #[test]
+    fn test_prune_transaction_files() {
+
+        let cfg       = test_cfg!();
+        let mut store = Store::new(&cfg);
+        let policy    = PrunePolicy { depth: 2, disk_budget: Some(0), ..Default::default() };
+
+        // large transactions fill multiple files; their outputs are spent in small blocks
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let tip       = chain.extend(genesis, 108);
+        let coinbases: Vec<_> = chain.chain(tip)[1..9].iter()
+            .map(|block| block.coinbase.clone().unwrap())
+            .collect();
+
+        // OP_PUSHDATA1 <97 bytes> OP_DROP OP_TRUE
+        let mut pk_script = vec![0x4C, 97];
+        pk_script.extend_from_slice(&[0; 97]);
+        pk_script.extend_from_slice(&[0x75, 0x51]);
+
+        let mut large   = vec![];
+        let mut outputs = vec![];
+        for coinbase in &coinbases {
+            let value = coinbase.output(0).value / 100;
+            let tx    = chain.spend_with_script(&[coinbase.output(0)], &[value; 100], &pk_script);
+
+            outputs.extend((0..100).map(|n| tx.output(n)));
+            large.push(tx);
+        }
+        let large_txid = large[0].txid;
+        let with_large = chain.add_block(tip, large);
+
+        let mut tip = with_large;
+        for inputs in outputs.chunks(200) {
+            let value = inputs.iter().map(|input| input.value).sum();
+            let tx    = chain.spend(inputs, &[value]);
+            tip = chain.add_block(tip, vec![tx]);
+        }
+        let with_spends = tip;
+        let tip         = chain.extend(with_spends, 2);
+
+        for block in chain.chain(tip) {
+            block_add::add_block(&mut store, &block.raw);
+        }
+        let size = store.transactions.get_size();
+
+        let report = prune(&mut store, &policy);
+        assert!(report.files > 0, "{:?}", report);
+        assert!(store.transactions.get_size() < size);
+
+        // the block with the large transactions can no longer be read
+        let large_hash = chain.block(with_large).hash;
+        assert_eq!(::api::get_block(&mut store, &large_hash), Err(::store::Pruned));
+        assert!(::api::get_block(&mut store, &chain.block(with_spends).hash).unwrap().is_some());
+        assert_eq!(::api::get_transaction(&mut store, &large_txid), Ok(None));
+
+        let check = ::fsck::check(&mut store);
+        assert!(check.is_ok(), "{:?}", check);
+        assert!(check.pruned > 0);
+
+        // the store can be opened again and blocks can be added
+        let mut store = Store::new(&cfg);
+        let tip = chain.extend(tip, 1);
+        block_add::validate_block(&mut store, &chain.block(tip).raw, &::scheduler::BlockProgress::new())
+            .unwrap();
+        assert_eq!(::api::get_block(&mut store, &large_hash), Err(::store::Pruned));
+        assert_eq!(prune(&mut store, &policy).files, 0);
+    }
+
+    #[test]
+    fn test_pruner() {
+
+        let mut store = Store::new(& test_cfg!());
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let tip       = chain.extend(genesis, 101);
+        let coinbase  = chain.chain(tip)[1].coinbase.clone().unwrap();
+        let tx        = chain.spend(&[coinbase.output(0)], &[coinbase.output(0).value]);
+        let tip       = chain.build_block(tip).transaction(tx).add();
+        let tip       = chain.extend(tip, 20);
+
+        // blocks are added while the pruner runs
+        {
+            let _pruner = Pruner::new(&store, PrunePolicy {
+                depth:    5,
+                interval: Duration::from_millis(1),
+                ..Default::default()
+            });
+            for block in chain.chain(tip) {
+                block_add::add_block(&mut store, &block.raw);
+            }
+        }
+
+        prune_tx_index(&mut store, &PrunePolicy { depth: 5, ..Default::default() });
+        assert!(store.tx_index.get(Hash32Buf::from_slice(&coinbase.txid).as_ref()).is_empty());
+        assert!(::fsck::check(&mut store).is_ok());
+    }
+
+    /// Prunes the tx-index of an existing store
+    #[ignore]
+    #[test]
+    fn prune_tx_index_of_store() {
+
+        let store_path = env::var(::config::ENV_BITCRUST_STORE).unwrap_or_else(|_|
+            panic!("Use {} env var to specify a store to prune", ::config::ENV_BITCRUST_STORE));
+
+        let mut store = Store::new(&::config::Config::new(&store_path));
+        let report    = prune_tx_index(&mut store, &PrunePolicy::default());
+
+        println!("Done");
+        println!("  {} blocks", report.blocks);
+        println!("  {} transactions purged", report.transactions);
+    }
+}
@@ -0,0 +1,391 @@
+//! Optional index of the outputs paying to a script, and the inputs spending them
+//!
+//! The index is kept when the store is opened with `Config::script_index`. A script is found by
+//! its SHA256, and has an entry for each output paying to it and for each input spending such an
+//! output. Like the inputs waiting for a transaction in the tx-index, the entries are stored as
+//! guards, such that many can be added to the same script concurrently.
+//!
+//! The entries of a block are added when it is connected, on whichever branch. A lookup only
+//! returns the entries in blocks of the best chain, so a reorganisation doesn't change the index.
+//! Entries in blocks that are connected again by recovery, or collected, are skipped likewise.
+//!
+//! For a store with blocks from before the index was enabled, `build` adds the blocks that are
+//! connected. Its position is kept in the `script-index-checkpoint` file, such that it continues
+//! where it stopped. Once built, all stores that add blocks must have the index enabled.
+
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+
+use config;
+use hash::*;
+use buffer::*;
+use store::{Store, TxPtr, BlockPtr, RecordPtr, HashIndexGuard};
+use store::flatfileset::FlatFilePtr;
+use store::best_chain;
+use store::hash_index::HashIndex;
+use transaction::Transaction;
+
+
+const DIR: &str             = "script-index";
+const CHECKPOINT_FILE: &str = "script-index-checkpoint";
+
+
+/// An output paying to a script, or an input spending one, in a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ScriptEntry {
+
+    // the index of the start-of-block record of the block
+    block:  u64,
+    height: u64,
+
+    // the transaction; with the index of the input for a spend
+    tx_ptr: TxPtr,
+    output: u32,
+    zero:   u32
+}
+
+impl HashIndexGuard for ScriptEntry {
+
+    // all entries are guards such that they can be added to the same hash
+    fn is_guard(self) -> bool { true }
+}
+
+impl ScriptEntry {
+
+    fn new(block: BlockPtr, height: u64, tx_ptr: TxPtr, output: u32) -> ScriptEntry {
+        ScriptEntry {
+            block: block.start.to_index(),
+            height,
+            tx_ptr,
+            output,
+            zero:  0
+        }
+    }
+
+    fn is_spend(&self) -> bool {
+        self.tx_ptr.is_guard()
+    }
+}
+
+
+/// An output of the best chain paying to a script
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptOutput {
+    pub txid:     [u8; 32],
+    pub index:    u32,
+    pub height:   u64,
+    pub value:    i64,
+
+    /// The input of the best chain that spends the output
+    pub spent_by: Option<ScriptSpend>
+}
+
+/// An input spending an output paying to a script
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptSpend {
+    pub txid:   [u8; 32],
+    pub input:  u32,
+    pub height: u64
+}
+
+
+pub struct ScriptIndex {
+    index: HashIndex<ScriptEntry>
+}
+
+impl ScriptIndex {
+
+    /// Opens the index at the location given in the config
+    ///
+    /// Creates a new fileset if needed
+    pub fn new(cfg: &config::Config) -> ScriptIndex {
+        ScriptIndex {
+            index: HashIndex::new(cfg, DIR)
+        }
+    }
+
+    /// Returns true if the store at the location given in the config has the index
+    pub fn exists(cfg: &config::Config) -> bool {
+        cfg.root.join(DIR).is_dir()
+    }
+
+    fn add(&mut self, script: &[u8], entry: ScriptEntry) {
+        self.index.get_or_set(hash_script(script).as_ref(), entry);
+    }
+
+    fn get(&mut self, script: &[u8]) -> Vec<ScriptEntry> {
+        self.index.get(hash_script(script).as_ref())
+    }
+}
+
+fn hash_script(script: &[u8]) -> Hash32Buf {
+
+    Hash32Buf::from_slice(ring::digest::digest(&ring::digest::SHA256, script).as_ref())
+}
+
+fn read_transaction(store: &mut Store, tx_ptr: TxPtr) -> Option<Vec<u8>> {
+
+    store.transactions.read(tx_ptr).ok()
+}
+
+
+/// Adds the outputs and inputs of a block that was just connected at `height`
+///
+/// Does nothing if the index is not enabled
+pub fn index_block(store: &mut Store, block: BlockPtr, height: u64) {
+
+    if store.script_index.is_none() {
+        return;
+    }
+
+    // the records of the inputs of a transaction follow its transaction-record
+    let records    = store.spend_tree.get_block_mut(block).to_vec();
+    let mut tx_ptr = None;
+    let mut input  = 0;
+
+    let mut entries = vec![];
+    for rec in &records[1..records.len() - 1] {
+
+        if rec.is_transaction() && !rec.is_unmatched_input() {
+            let ptr = rec.get_transaction_ptr();
+            tx_ptr  = Some(ptr);
+            input   = 0;
+
+            // the outputs of a pruned transaction are all spent and need no entries
+            let raw = match read_transaction(store, ptr) { Some(raw) => raw, None => continue };
+            let tx  = Transaction::parse(&mut Buffer::new(&raw))
+                .expect("Stored transaction can be parsed");
+
+            for (n, output) in tx.txs_out.iter().enumerate() {
+                entries.push((output.pk_script().to_vec(), ScriptEntry::new(block, height, ptr, n as u32)));
+            }
+        }
+        else if rec.is_output() {
+            let spending = tx_ptr.expect("A spend follows its transaction").to_input(input);
+            input += 1;
+
+            let raw = match read_transaction(store, rec.get_transaction_ptr()) { Some(raw) => raw, None => continue };
+            let tx  = Transaction::parse(&mut Buffer::new(&raw))
+                .expect("Stored transaction can be parsed");
+
+            let script = tx.txs_out[rec.get_output_index() as usize].pk_script().to_vec();
+            entries.push((script, ScriptEntry::new(block, height, spending, 0)));
+        }
+    }
+
+    let index = store.script_index.as_mut().unwrap();
+    for (script, entry) in entries {
+        index.add(&script, entry);
+    }
+}
+
+
+/// Adds the connected blocks that were stored before the index was enabled
+///
+/// Continues after the blocks added by a previous call; returns the number of blocks added
+pub fn build(store: &mut Store) -> io::Result<usize> {
+
+    if store.script_index.is_none() {
+        return Err(io::Error::other("The script index is not enabled"));
+    }
+
+    let checkpoint = store.get_config().root.join(CHECKPOINT_FILE);
+    let start = fs::read(&checkpoint).ok()
+        .filter(|bytes| bytes.len() == 8)
+        .map_or(0, |bytes| {
+            let mut buf = [0; 8];
+            buf.copy_from_slice(&bytes);
+            u64::from_le_bytes(buf)
+        });
+
+    let (blocks, end) = store.spend_tree.get_blocks_from(RecordPtr::new(start));
+
+    // heights of the blocks seen, by the index of their start-of-block record
+    let mut heights: HashMap<u64, u64> = HashMap::new();
+    let mut count = 0;
+    for block in blocks {
+
+        // orphans are added when they are connected
+        let hash = store.get_block_hash(block);
+        if !store.block_index.get(hash.as_ref()).iter().any(|ptr| !ptr.is_guard() && ptr.start == block.start) {
+            continue;
+        }
+
+        let height = get_height(store, block, &mut heights);
+        index_block(store, block, height);
+        count += 1;
+    }
+
+    let tmp = checkpoint.with_extension("tmp");
+    fs::write(&tmp, end.to_index().to_le_bytes())?;
+    fs::rename(tmp, checkpoint)?;
+
+    info!(store.logger, "script index built"; "blocks" => count);
+    Ok(count)
+}
+
+// Returns the height of a connected block, walking back until a block of which it is known
+fn get_height(store: &mut Store, block: BlockPtr, heights: &mut HashMap<u64, u64>) -> u64 {
+
+    let mut path  = vec![block];
+    let mut start = None;
+    while let Some(prev) = store.spend_tree.get_previous_block(path[path.len() - 1]) {
+        if let Some(&height) = heights.get(&prev.start.to_index()) {
+            start = Some(height);
+            break;
+        }
+        path.push(prev);
+    }
+
+    let mut height = start.map_or(0, |h| h + 1);
+    for ptr in path.iter().rev() {
+        heights.insert(ptr.start.to_index(), height);
+        height += 1;
+    }
+    height - 1
+}
+
+
+/// Returns the outputs of the best chain paying to the script, lowest first, with the inputs of
+/// the best chain spending them
+///
+/// Returns None if the index is not enabled. Outputs of transactions that are pruned are left out
+pub fn get_outputs(store: &mut Store, script: &[u8]) -> Option<Vec<ScriptOutput>> {
+
+    let entries = store.script_index.as_mut()?.get(script);
+
+    // a block can be added twice while the index is built
+    let mut entries: Vec<ScriptEntry> = entries.into_iter()
+        .collect::<HashSet<_>>().into_iter()
+        .filter(|entry| best_chain::get_block_at(store, entry.height).is_some_and(|block| block.start.to_index() == entry.block))
+        .collect();
+
+    entries.sort_by_key(|entry|
+        (entry.height, entry.tx_ptr.get_file_number(), entry.tx_ptr.get_file_offset(), entry.output));
+
+    let mut outputs = vec![];
+    let mut spends  = HashMap::new();
+    for entry in entries {
+
+        let raw = match read_transaction(store, entry.tx_ptr) { Some(raw) => raw, None => continue };
+        let tx  = Transaction::parse(&mut Buffer::new(&raw))
+            .expect("Stored transaction can be parsed");
+        let txid = *Hash32Buf::double_sha256(&raw).as_ref().0;
+
+        if entry.is_spend() {
+            let input = entry.tx_ptr.get_input_index() as usize;
+            let spent = (*tx.txs_in[input].prev_tx_out.0, tx.txs_in[input].prev_tx_out_idx);
+
+            spends.insert(spent, ScriptSpend { txid, input: input as u32, height: entry.height });
+        }
+        else {
+            outputs.push(ScriptOutput {
+                txid,
+                index:    entry.output,
+                height:   entry.height,
+                value:    tx.txs_out[entry.output as usize].value(),
+                spent_by: None
+            });
+        }
+    }
+
+    for output in &mut outputs {
+        output.spent_by = spends.remove(&(output.txid, output.index));
+    }
+    Some(outputs)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use block_add;
+    use test_chain::TestChain;
+
+    fn get_txids(outputs: &[ScriptOutput]) -> Vec<([u8; 32], Option<[u8; 32]>)> {
+        outputs.iter()
+            .map(|output| (output.txid, output.spent_by.as_ref().map(|spend| spend.txid)))
+            .collect()
+    }
+
+    #[test]
+    fn test_script_index() {
+
+        let mut store = Store::new(& test_cfg!().with_script_index(true));
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let base      = chain.extend(genesis, 101);
+        let coinbase  = chain.chain(base)[1].coinbase.clone().unwrap();
+        let value     = coinbase.output(0).value;
+
+        let script    = [0x51, 0x52, 0x87];
+        let tx1       = chain.spend_with_script(&[coinbase.output(0)], &[value], &script);
+        let tx2       = chain.spend(&[tx1.output(0)], &[value]);
+        let main      = chain.add_block(base, vec![tx1.clone()]);
+        let main_tip  = chain.add_block(main, vec![tx2.clone()]);
+
+        // a fork with more work spends the output in another transaction
+        let tx3       = chain.spend(&[tx1.output(0)], &[value - 1]);
+        let fork      = chain.add_block(main, vec![tx3.clone()]);
+        let fork_tip  = chain.extend(fork, 1);
+
+        for block in chain.chain(main_tip) {
+            block_add::add_block(&mut store, &block.raw);
+        }
+
+        let outputs = get_outputs(&mut store, &script).unwrap();
+        assert_eq!(get_txids(&outputs), vec![(tx1.txid, Some(tx2.txid))]);
+        assert_eq!((outputs[0].index, outputs[0].height, outputs[0].value), (0, 102, value as i64));
+        assert_eq!(outputs[0].spent_by.as_ref().unwrap().height, 103);
+
+        block_add::add_block(&mut store, &chain.block(fork).raw);
+        block_add::add_block(&mut store, &chain.block(fork_tip).raw);
+
+        let outputs = get_outputs(&mut store, &script).unwrap();
+        assert_eq!(get_txids(&outputs), vec![(tx1.txid, Some(tx3.txid))]);
+
+        assert_eq!(get_outputs(&mut store, &[0x6a]).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_build() {
+
+        let cfg = test_cfg!();
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let base      = chain.extend(genesis, 101);
+        let coinbase  = chain.chain(base)[1].coinbase.clone().unwrap();
+        let script    = [0x51, 0x53, 0x87];
+        let tx        = chain.spend_with_script(&[coinbase.output(0)], &[coinbase.output(0).value], &script);
+        let tip       = chain.add_block(base, vec![tx.clone()]);
+        let next      = chain.extend(tip, 1);
+
+        // the blocks are added without the index
+        {
+            let mut store = Store::new(&cfg);
+            assert_eq!(get_outputs(&mut store, &script), None);
+            assert!(build(&mut store).is_err());
+
+            for block in chain.chain(tip) {
+                block_add::add_block(&mut store, &block.raw);
+            }
+        }
+
+        let mut store = Store::new(&cfg.with_script_index(true));
+        assert_eq!(get_outputs(&mut store, &script).unwrap(), vec![]);
+
+        assert_eq!(build(&mut store).unwrap(), 103);
+        assert_eq!(get_txids(&get_outputs(&mut store, &script).unwrap()), vec![(tx.txid, None)]);
+
+        // a block added later is indexed when it is connected; building again adds it twice
+        block_add::add_block(&mut store, &chain.block(next).raw);
+        assert_eq!(build(&mut store).unwrap(), 1);
+        assert_eq!(build(&mut store).unwrap(), 0);
+        assert_eq!(get_outputs(&mut store, &script).unwrap().len(), 1);
+    }
+}
@@ -2,27 +2,47 @@
 //!
 //! This serves as a broom-wagon for the spend-tree.
 //! After X blocks, outputs and transactions are stored here,
-//! and when outputs aren't found in the spend tree for X blocks,
+//! and when outputs aren't found in the spend-tree for X blocks,
 //! they are searched here.
 //!
 //! The data-structure here is a simple bit-index where each transaction and each spend-output
 //! are given a unique bit which is set if the given transaction or spend exists
+//!
+//! The bits are split in segments of one file each. As the hash of a record is derived from
+//! the position of its transaction, a segment roughly covers one file of the transaction store.
+//! Segments are created when the first bit in them is set.
 
 use std::sync::atomic::{AtomicU64,Ordering};
 
 use config;
-use store::flatfileset::FlatFileSet;
-use store::RecordPtr;
+use store::flatfile::INITIAL_WRITEPOS;
+use store::flatfileset::{FlatFilePtr, FlatFileSet};
+
+
+// number of bits per segment
+const SEGMENT_BITS:     u64 = 1 << 28;
+const SEGMENT_WORDS:  usize = (SEGMENT_BITS / 64) as usize;
+
+const FILE_SIZE:        u64 = INITIAL_WRITEPOS + SEGMENT_BITS / 8;
 
 
-const MB:                 u64 = 1024 * 1024;
-const FILE_SIZE:          u64 = 16 * 1024 * MB ;
-const MAX_CONTENT_SIZE:   u64 = FILE_SIZE - 10 * MB ;
+/// A pointer to the start of a segment
+#[derive(Debug, Clone, Copy)]
+struct SegmentPtr {
+    file_number: i16
+}
+
+impl FlatFilePtr for SegmentPtr {
+
+    fn new(file_number: i16, _file_offset: u64) -> Self {
+        SegmentPtr { file_number }
+    }
+
+    fn get_file_number(self) -> i16 { self.file_number }
+
+    fn get_file_offset(self) -> u64 { INITIAL_WRITEPOS }
+}
 
-// TODO; make this dynamic using fileset continuation;
-// this isn't hastily needed as the OS does not actually allocate
-// all the space; (compare ls with du).
-const VEC_SIZE:         usize = 500_000_000;
 
 /// Index to lookup spends
 ///
@@ -30,10 +50,9 @@ const VEC_SIZE:         usize = 500_000_000;
 ///
 pub struct SpendIndex {
 
-    #[allow(dead_code)]
-    fileset:      FlatFileSet<RecordPtr>,
+    fileset:      FlatFileSet<SegmentPtr>,
 
-    bitvector:    &'static [AtomicU64]
+    segments:     Vec<&'static [AtomicU64]>
 
 }
 
@@ -47,37 +66,68 @@ impl SpendIndex
     pub fn new(cfg: &config::Config) -> SpendIndex {
         let dir = &cfg.root.clone().join("spend-index");
 
-        let mut fileset = FlatFileSet::new(
-            dir, "si-", FILE_SIZE, MAX_CONTENT_SIZE);
+        let fileset = FlatFileSet::open(
+            dir, "si-", FILE_SIZE, FILE_SIZE, cfg.read_only);
 
-        let bitvector = fileset.read_mut_slice(RecordPtr::new(0), VEC_SIZE);
-        SpendIndex {
-            fileset: fileset,
-            bitvector: bitvector
-        }
+        let mut result = SpendIndex {
+            fileset,
+            segments: vec![]
+        };
+
+        // map the segments that exist
+        let last = result.fileset.get_last_file();
+        result.reserve((last as u64 + 1) * SEGMENT_BITS - 1);
+        result
     }
 
+    /// Maps the segments up to the one that contains the given hash, creating them if needed
+    ///
+    /// `exists` can only find hashes in mapped segments; as it is called concurrently,
+    /// the segments must be mapped before. A read-only index maps the segments that exist.
+    pub fn reserve(&mut self, hash: u64) {
 
+        let segment = (hash / SEGMENT_BITS) as usize;
+        while self.segments.len() <= segment {
+
+            if self.fileset.is_read_only() && !self.fileset.file_exists(self.segments.len() as i16) {
+                break;
+            }
+
+            let ptr = SegmentPtr::new(self.segments.len() as i16, INITIAL_WRITEPOS);
+            let segment = self.fileset.read_mut_slice(ptr, SEGMENT_WORDS);
+
+            self.segments.push(segment);
+        }
+    }
 
 
     /// Tests if the given hash exists.
     pub fn exists(&self, hash: u64) -> bool {
 
-        let idx =  (hash >> 6) as usize;
-        (self.bitvector[idx].load(Ordering::Relaxed) & (1 << (hash & 0x3F))) > 0
+        let segment = (hash / SEGMENT_BITS) as usize;
+        let idx     = ((hash % SEGMENT_BITS) >> 6) as usize;
+
+        match self.segments.get(segment) {
+            Some(words) => (words[idx].load(Ordering::Relaxed) & (1 << (hash & 0x3F))) > 0,
+            None        => false
+        }
     }
 
 
     /// Stores a record hash; this should uniquely identify an output or a transaction
     pub fn set(&mut self, hash: u64)  {
 
+        self.reserve(hash);
+
+        let segment = (hash / SEGMENT_BITS) as usize;
+        let idx     = ((hash % SEGMENT_BITS) >> 6) as usize;
+
         // CAS-loop
         loop {
-            let idx = (hash >> 6) as usize;
-            let org = self.bitvector[idx].load(Ordering::Acquire);
+            let org = self.segments[segment][idx].load(Ordering::Acquire);
             let new = org | (1 << (hash & 0x3F));
 
-            if self.bitvector[idx].compare_exchange(org, new, Ordering::Release, Ordering::Relaxed) == Ok(org) {
+            if self.segments[segment][idx].compare_exchange(org, new, Ordering::Release, Ordering::Relaxed) == Ok(org) {
                 break;
             }
         }
@@ -119,4 +169,24 @@ mod tests {
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_segments() {
+
+        let cfg = test_cfg!();
+        let mut idx = SpendIndex::new(&cfg);
+        assert!(!idx.exists(3 * SEGMENT_BITS + 5));
+
+        idx.set(2 * SEGMENT_BITS - 1);
+        idx.set(3 * SEGMENT_BITS + 5);
+        assert!(idx.exists(2 * SEGMENT_BITS - 1));
+        assert!(idx.exists(3 * SEGMENT_BITS + 5));
+        assert!(!idx.exists(3 * SEGMENT_BITS + 4));
+        assert!(!idx.exists(5 * SEGMENT_BITS));
+
+        // a reopened index maps the existing segments
+        let idx = SpendIndex::new(&cfg);
+        assert!(idx.exists(2 * SEGMENT_BITS - 1));
+        assert!(idx.exists(3 * SEGMENT_BITS + 5));
+    }
+}
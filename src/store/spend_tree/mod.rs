@@ -27,32 +27,42 @@ use slog;
 
 use store;
 use store::{TxPtr,BlockHeaderPtr};
-use store::flatfileset::FlatFileSet;
+use store::flatfile::INITIAL_WRITEPOS;
+use store::flatfileset::{FlatFilePtr, FlatFileSet};
 
 use store::hash_index::{HashIndex,HashIndexGuard};
 use store::spend_index::SpendIndex;
 
 use transaction::Transaction;
 
+use std::cmp;
+use std::mem;
+
 pub mod record;
 pub use self::record::{Record,RecordPtr};
+use self::record::RECORDS_PER_FILE;
 
 const MB:                 u64 = 1024 * 1024;
-const FILE_SIZE:          u64 = 16 * 1024 * MB ;
-const MAX_CONTENT_SIZE:   u64 = FILE_SIZE - 10 * MB ;
+
+/// The largest size of a file of the spend-tree. RecordPtrs are laid out as if each file has
+/// this size; see [[config::Config::spend_tree_file_size]] for the size with which files are created
+pub const MAX_FILE_SIZE:  u64 = 2 * 1024 * MB;
+
+/// The space at the end of a file kept free for the largest block
+const MAX_BLOCK_SIZE:     u64 = 10 * MB;
 
 const SUBPATH: &'static str   = "spend-tree";
 const PREFIX:  &'static str   = "st-";
 
 
-// temporarily we use a vec instead of the dynamic growing flatfileset
-// this isn't a big problem because the OS will not allocate the trailing zeros
-const VEC_SIZE: usize = 800_000_000;
+// number of blocks before a coinbase output can be spent
+const COINBASE_MATURITY: usize = 100;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SpendingError {
     OutputNotFound,
     OutputAlreadySpend,
+    ImmatureCoinbase,
 }
 
 /// A pointer into the spend-tree.
@@ -97,7 +107,30 @@ impl BlockPtr {
 
 pub struct SpendTree {
 
-    fileset:    FlatFileSet<RecordPtr>
+    fileset:    FlatFileSet<RecordPtr>,
+
+    // the number of records that fit in a file; the rest of the RECORDS_PER_FILE are never used
+    file_records:   u64,
+
+    // the size in bytes of the largest block that is guaranteed to fit in a file
+    max_block_size: u64
+}
+
+
+/// The records of all files of the spend-tree, indexed by the index of their RecordPtr
+///
+/// Each file is mapped separately; a block is never split over two files, and neither are the
+/// lookups within a block
+pub struct Records {
+    files: Vec<&'static [Record]>
+}
+
+impl ::std::ops::Index<usize> for Records {
+    type Output = Record;
+
+    fn index(&self, index: usize) -> &Record {
+        &self.files[index / RECORDS_PER_FILE as usize][index % RECORDS_PER_FILE as usize]
+    }
 }
 
 
@@ -136,7 +169,7 @@ impl ::std::ops::Add for SpendTreeStats {
 /// This is the algorithm to check double-spends and the existence of outputs
 /// It will call the verify_spend function on Record in parallel for each output
 fn seek_and_set_inputs(
-                       records: &[Record],
+                       records: &Records,
                        block: &mut [Record],
                        block_idx: usize,
                        spend_index: &SpendIndex,
@@ -167,18 +200,34 @@ fn seek_and_set_inputs(
 impl SpendTree {
     pub fn new(cfg: &config::Config) -> SpendTree {
 
-        let dir = &cfg.root.clone().join(SUBPATH);
+        let dir       = &cfg.root.clone().join(SUBPATH);
+        let file_size = cfg.spend_tree_file_size;
+
+        assert!(file_size > INITIAL_WRITEPOS && file_size <= MAX_FILE_SIZE,
+            "Spend-tree file size must be at most {} bytes", MAX_FILE_SIZE);
+
+        // small files, as used in tests, keep half of the file free
+        let max_block_size = cmp::min(MAX_BLOCK_SIZE, file_size / 2);
 
         SpendTree {
-            fileset: FlatFileSet::new(
-                dir, PREFIX, FILE_SIZE, MAX_CONTENT_SIZE)
+            fileset: FlatFileSet::open(
+                dir, PREFIX, file_size, file_size - max_block_size, cfg.read_only),
+
+            file_records:   (file_size - INITIAL_WRITEPOS) / mem::size_of::<Record>() as u64,
+            max_block_size
         }
     }
 
-    // Returns the full spend-tree as record-slice.
-    pub fn get_all_records(&mut self) -> &[Record] {
+    // Returns the records of all files of the spend-tree
+    pub fn get_all_records(&mut self) -> Records {
 
-        self.fileset.read_mut_slice(RecordPtr::new(0), VEC_SIZE)
+        let last  = self.fileset.get_last_file();
+        let files = (0..last + 1)
+            .map(|file| self.fileset.read_mut_slice(
+                RecordPtr::new(file as u64 * RECORDS_PER_FILE), self.file_records as usize) as &[Record])
+            .collect();
+
+        Records { files }
     }
 
     // Returns the given block as a mutable slice
@@ -193,6 +242,51 @@ impl SpendTree {
         * self.fileset.read_fixed(ptr)
     }
 
+    /// Returns the block preceding the given block
+    ///
+    /// Returns None for genesis and for orphan blocks that are not yet connected
+    pub fn get_previous_block(&mut self, block: BlockPtr) -> Option<BlockPtr> {
+
+        let previous_end = self.get_record(block.start).previous_block()?;
+        let count        = self.get_record(previous_end).get_record_count() as u64;
+
+        Some(BlockPtr {
+            start:    RecordPtr::new(previous_end.to_index() - count - 1),
+            length:   count + 2,
+            is_guard: false
+        })
+    }
+
+    /// Returns the pointer to the blockheader of the given block
+    pub fn get_block_header_ptr(&mut self, block: BlockPtr) -> BlockHeaderPtr {
+
+        self.get_record(block.end()).get_block_header_ptr()
+    }
+
+    /// Returns the coinbase transaction-records of the given block and its predecessors
+    /// that are not yet mature for spending in the block
+    fn get_immature_coinbases(&mut self, block: BlockPtr) -> Vec<Record> {
+
+        let mut result = Vec::with_capacity(COINBASE_MATURITY);
+        let mut next   = Some(block);
+
+        for _ in 0..COINBASE_MATURITY {
+            let block = match next {
+                None        => break,
+                Some(block) => block
+            };
+
+            // the coinbase is always the first transaction of a block
+            let first = self.get_record(RecordPtr::new(block.start.to_index() + 1));
+            if first.is_coinbase() {
+                result.push(first.to_transaction());
+            }
+
+            next = self.get_previous_block(block);
+        }
+        result
+    }
+
     /// Stores a block in the spend_tree. The block will be initially orphan.
     ///
     /// The result is a BlockPtr that can be stored in the hash-index
@@ -205,6 +299,10 @@ impl SpendTree {
             .chain(vec![Record::new_block_end(block_header_ptr, count)]).into_iter()
             .collect();
 
+        // a block that doesn't fit in the free space at the end of a file would overrun it
+        let size = (block.len() * mem::size_of::<Record>()) as u64;
+        assert!(size <= self.max_block_size,
+            "Block of {} records doesn't fit in a file of the spend-tree", block.len());
 
         let result_ptr = self.fileset.write_all(&block);
 
@@ -216,6 +314,120 @@ impl SpendTree {
     }
 
 
+    /// Returns the pointer after the last record
+    pub fn get_end(&mut self) -> RecordPtr {
+
+        self.fileset.get_write_pos()
+    }
+
+    /// Returns the position of the first record at or after `ptr` that is written
+    ///
+    /// A block that doesn't fit in a file is written to the next; this skips the unused
+    /// space at the end of the file
+    pub fn skip_unused(&mut self, ptr: RecordPtr) -> RecordPtr {
+
+        let file     = ptr.get_file_number();
+        let file_end = self.fileset.get_file_write_pos(file);
+
+        if file < self.fileset.get_last_file() && ptr.to_index() >= file_end.to_index() {
+            RecordPtr::new((file as u64 + 1) * RECORDS_PER_FILE)
+        } else {
+            ptr
+        }
+    }
+
+    /// Returns the block that ends just before `end`
+    ///
+    /// Returns None if the records before `end` are not a complete block
+    pub fn get_block_before(&mut self, end: RecordPtr) -> Option<BlockPtr> {
+
+        // at the start of a file, the previous block ends where the previous file was filled
+        let file = end.get_file_number();
+        let end  = if file > 0 && end.to_index() == file as u64 * RECORDS_PER_FILE {
+            self.fileset.get_file_write_pos(file - 1).to_index()
+        } else {
+            end.to_index()
+        };
+        if end == 0 {
+            return None;
+        }
+
+        let last = self.get_record(RecordPtr::new(end - 1));
+        if !last.is_block_end() || (last.get_record_count() as u64) + 2 > end {
+            return None;
+        }
+
+        let length = last.get_record_count() as u64 + 2;
+        let start  = RecordPtr::new(end - length);
+        if !self.get_record(start).is_block_start() {
+            return None;
+        }
+
+        Some(BlockPtr {
+            start,
+            length,
+            is_guard: false
+        })
+    }
+
+    /// Returns the complete blocks stored from `start`, connected or not, and the position
+    /// after the last
+    ///
+    /// Stops at a block that is still being written
+    pub fn get_blocks_from(&mut self, start: RecordPtr) -> (Vec<BlockPtr>, RecordPtr) {
+
+        let end = self.get_end().to_index();
+
+        let mut blocks = vec![];
+        let mut start  = self.skip_unused(start).to_index();
+        while start < end {
+
+            let mut block_end = start + 1;
+            while block_end < end && !self.get_record(RecordPtr::new(block_end)).is_block_end() {
+                block_end += 1;
+            }
+            if block_end == end {
+                break;
+            }
+
+            blocks.push(BlockPtr {
+                start:    RecordPtr::new(start),
+                length:   block_end + 1 - start,
+                is_guard: false
+            });
+            start = self.skip_unused(RecordPtr::new(block_end + 1)).to_index();
+        }
+        (blocks, RecordPtr::new(start))
+    }
+
+    /// Discards the records after the last end-of-block record
+    ///
+    /// A block is written at once, but if this is interrupted the space allocated for it is
+    /// left (partly) zero. Returns the number of records discarded
+    pub fn truncate_incomplete(&mut self) -> u64 {
+
+        let end     = self.get_end().to_index();
+        let mut pos = end;
+        while pos > 0 && !self.get_record(RecordPtr::new(pos - 1)).is_block_end() {
+            pos -= 1;
+        }
+
+        self.fileset.truncate(RecordPtr::new(pos));
+        end - pos
+    }
+
+    /// Discards all records from `pos`
+    pub fn truncate(&mut self, pos: RecordPtr) {
+
+        self.fileset.truncate(pos);
+    }
+
+    /// Disconnects a block from its previous block, making it an orphan again
+    pub fn disconnect_block(&mut self, block: BlockPtr) {
+
+        *self.fileset.read_fixed::<Record>(block.start) = Record::new_orphan_block_start();
+    }
+
     /// If an orphan block is stored in the spend-tree, some transaction-inputs might not be resolved
     /// to their outputs. These will still be unmatched_output records instead of output-pointers
     ///
@@ -232,7 +444,8 @@ impl SpendTree {
 
             if record.is_unmatched_input() {
 
-                let bytes   = transactions.read(last_tx_ptr.unwrap());
+                let bytes   = transactions.read(last_tx_ptr.unwrap())
+                    .expect("Transaction of orphan block is pruned");
                 let mut buf = Buffer::new(&bytes);
                 let tx      = Transaction::parse(&mut buf).unwrap();
 
@@ -278,26 +491,43 @@ impl SpendTree {
 
         let block_idx              = target_block.start.to_index();
         let block:   &mut [Record] = self.fileset.read_mut_slice(target_block.start, target_block.length as usize);
-        let records: &[Record]     = self.get_all_records();
+        let records: Records       = self.get_all_records();
 
 
         // Make the link,
         block[0] = Record::new_block_start(previous_block);
 
+        // the lookups in the spend-index can only find hashes in the parts that are mapped
+        let max_hash = block[1..block.len()-1].iter()
+            .filter(|rec| !rec.is_unmatched_input())
+            .map(|rec| rec.hash())
+            .max();
+        if let Some(max_hash) = max_hash {
+            spend_index.reserve(max_hash);
+        }
+
 
         // Update the spend-index
         // TODO this should jump more blocks back; and register its parent-requirement.
         // This is important once we allow forks
-        let s = previous_block.start.to_index() as usize;
         let l = previous_block.length as usize;
-        let immutable_block: &[Record] = &records[s+1..s+l-1];
+        let immutable_block: &[Record] = &self.get_block_mut(previous_block)[1..l-1];
         for rec in immutable_block.iter() {
 
             spend_index.set(rec.hash());
         }
 
         // verify all inputs in the spend tree and spend-index
-        let input_count = seek_and_set_inputs(records, block, block_idx as usize, spend_index, logger)?;
+        let input_count = seek_and_set_inputs(&records, block, block_idx as usize, spend_index, logger)?;
+
+        // verify that none of the inputs spends a coinbase of the last COINBASE_MATURITY blocks
+        let immature = self.get_immature_coinbases(target_block);
+        if block.iter()
+            .filter(|rec| rec.is_output())
+            .any(|rec| immature.contains(&rec.to_transaction())) {
+
+            return Err(SpendingError::ImmatureCoinbase);
+        }
 
         let elapsed : isize = timer.elapsed().as_secs() as isize * 1000 +
             timer.elapsed().subsec_nanos() as isize / 1_000_000 as isize;
@@ -326,7 +556,6 @@ mod tests {
     use slog_term;
     use slog;
     use slog::DrainExt;
-    use store::flatfileset::FlatFilePtr;
     use super::*;
     use store::spend_index::SpendIndex;
     use store::{BlockHeaderPtr, TxPtr};
@@ -365,6 +594,102 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_truncate_incomplete() {
+
+        let mut st = SpendTree::new(& test_cfg!());
+        let end    = st.get_end();
+        assert_eq!(st.get_block_before(end), None);
+
+        let block1 = st.store(block!(blk 1 =>
+            [tx 2]
+        ));
+        let block2 = st.store(block!(blk 3 =>
+            [tx 4 => (2;0)]
+        ));
+        let end = st.get_end();
+        assert_eq!(st.get_block_before(end), Some(block2));
+        assert_eq!(st.get_block_before(block2.start), Some(block1));
+
+        // a block is allocated, but only partly written
+        let ptr: RecordPtr = st.fileset.alloc_write_space(4 * 8);
+        *st.fileset.read_fixed(ptr) = Record::new_orphan_block_start();
+
+        let end = st.get_end();
+        assert_eq!(st.get_block_before(end), None);
+        assert_eq!(st.truncate_incomplete(), 4);
+        assert_eq!(st.truncate_incomplete(), 0);
+        let end = st.get_end();
+        assert_eq!(st.get_block_before(end), Some(block2));
+
+        // the next block is written at the same position
+        let block3 = st.store(block!(blk 5 =>
+            [tx 6]
+        ));
+        assert_eq!(block3.start, ptr);
+    }
+
+    #[test]
+    fn test_multiple_files() {
+        let log = slog::Logger::root(slog_term::streamer().compact().build().fuse(), o!());
+
+        let cfg     = test_cfg!().with_spend_tree_file_size(4096);
+        let mut st  = SpendTree::new(&cfg);
+        let mut si  = SpendIndex::new(&cfg);
+
+        // blocks of 200 transactions; only two of these fit in a file
+        let mut blocks: Vec<BlockPtr> = vec![];
+        for n in 0..5 {
+            let txs = (0..200)
+                .map(|i| Record::new_transaction(TxPtr::new(0, (n * 200 + i) * 1024)))
+                .collect();
+            let block = st.store_block(BlockHeaderPtr::new(0, n), txs);
+            if let Some(&previous) = blocks.last() {
+                st.connect_block(&mut si, &log, previous, block).unwrap();
+            }
+            blocks.push(block);
+        }
+        assert_eq!(blocks[1].start.get_file_number(), 0);
+        assert_eq!(blocks[2].start.get_file_number(), 1);
+        assert_eq!(blocks[4].start.get_file_number(), 2);
+        assert!(st.get_all_records()[blocks[4].start.to_index() as usize].is_block_start());
+
+        // walking back skips the unused space at the end of each file
+        let mut end = st.get_end();
+        for &block in blocks.iter().rev() {
+            assert_eq!(st.get_block_before(end), Some(block));
+            end = block.start;
+        }
+        assert_eq!(st.get_block_before(end), None);
+        assert_eq!(st.skip_unused(blocks[1].end()).to_index(), blocks[1].end().to_index());
+        assert_eq!(st.skip_unused(RecordPtr::new(blocks[1].end().to_index() + 1)), blocks[2].start);
+
+        // one output is found in the previous file, the other in the spend-index
+        let block5 = st.store(block!(blk 10 =>
+            [tx 1000 * 1024 => (650 * 1024;0), (5 * 1024;1)]
+        ));
+        st.connect_block(&mut si, &log, blocks[4], block5).unwrap();
+
+        let block6 = st.store(block!(blk 11 =>
+            [tx 1001 * 1024 => (5 * 1024;1)]
+        ));
+        assert_eq!(
+            st.connect_block(&mut si, &log, block5, block6).unwrap_err(),
+            SpendingError::OutputAlreadySpend);
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't fit in a file")]
+    fn test_block_too_large() {
+        let mut st = SpendTree::new(&test_cfg!().with_spend_tree_file_size(4096));
+
+        // half of a file is kept free for a block; this one takes 258 records of 8 bytes
+        let txs = (0..256)
+            .map(|i| Record::new_transaction(TxPtr::new(0, i * 1024)))
+            .collect();
+        st.store_block(BlockHeaderPtr::new(0, 0), txs);
+    }
+
     #[test]
     fn test_spend_tree_connect() {
         let log = slog::Logger::root(slog_term::streamer().compact().build().fuse(), o!());
@@ -421,6 +746,48 @@ mod tests {
         ));
         st.connect_block(&mut si, &log, block2b, block4a).unwrap();
 
+        // walking back from 4a leads to 2b, 1 and then ends
+        let prev = st.get_previous_block(block4a).unwrap();
+        assert_eq!(prev, block2b.to_non_guard());
+        let prev = st.get_previous_block(prev).unwrap();
+        assert_eq!(prev, block1);
+        assert_eq!(st.get_previous_block(prev), None);
+
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let log = slog::Logger::root(slog_term::streamer().compact().build().fuse(), o!());
+
+        let mut st  = SpendTree::new(& test_cfg!());
+        let mut si  = SpendIndex::new(& test_cfg!());
+
+        let coinbase = |hdr: u64, tx: u64|
+            (BlockHeaderPtr::new(0, hdr), vec![Record::new_coinbase(TxPtr::new(0, tx))]);
+
+        let mut prev = st.store(coinbase(1, 2));
+
+        // the coinbase can only be spent 100 blocks later
+        for n in 0..98 {
+            let block = st.store(coinbase(100 + n, 1000 + n));
+            st.connect_block(&mut si, &log, prev, block).unwrap();
+            prev = block;
+        }
+
+        let spend = st.store(block!(blk 3 =>
+            [tx 4 => (2;0)]
+        ));
+        assert_eq!(
+            st.connect_block(&mut si, &log, prev, spend).unwrap_err(),
+            SpendingError::ImmatureCoinbase);
+
+        let block = st.store(coinbase(200, 2000));
+        st.connect_block(&mut si, &log, prev, block).unwrap();
+
+        let spend = st.store(block!(blk 3 =>
+            [tx 4 => (2;0)]
+        ));
+        st.connect_block(&mut si, &log, block, spend).unwrap();
     }
 
     #[test]
@@ -8,7 +8,7 @@ use store::FlatFilePtr;
 
 
 use store::spend_tree::SpendingError;
-use store::spend_tree::BlockPtr;
+use store::spend_tree::{BlockPtr, Records};
 use store::spend_index::SpendIndex;
 
 use store::flatfile::INITIAL_WRITEPOS;
@@ -26,6 +26,9 @@ const END_OF_BLOCK:u64   = 0x8000_0000_0000_0000;
 const TRANSACTION:u64    = 0x0000_0000_0000_0000;
 const OUTPUT:u64         = 0x4000_0000_0000_0000;
 
+// flag on a transaction-record that marks a coinbase
+const COINBASE:u64       = 0x2000_0000_0000_0000;
+
 // Record layout
 // -------------
 //
@@ -39,6 +42,7 @@ const OUTPUT:u64         = 0x4000_0000_0000_0000;
 // TRANSACTION:
 // bits 0 -31   fileoffset of transaction
 // bits 32-47   filenumber of transaction
+// bit  61      set if the transaction is a coinbase
 //
 // OUTPUT:
 // bits 0 -31   fileoffset of transaction
@@ -74,6 +78,15 @@ impl Record {
         )
     }
 
+    pub fn new_coinbase(tx_ptr: TxPtr) -> Record {
+
+        Record(
+            COINBASE |
+            (tx_ptr.get_file_number() as u64) << 32 |
+            tx_ptr.get_file_offset()
+        )
+    }
+
     pub fn new_orphan_block_start() -> Record {
 
         Record(
@@ -113,10 +126,26 @@ impl Record {
         )
     }
 
-    /// If called on block-record, returns the index of the block-record and the record-count
-    /// of the previous block
-    pub fn previous_block(self) -> (usize, usize) {
-        unimplemented!()
+    /// If called on a start-of-block record, returns the pointer to the end-of-block record
+    /// of the previous block, or None if the block is not connected (genesis or orphan)
+    pub fn previous_block(self) -> Option<RecordPtr> {
+
+        debug_assert!(self.is_block_start());
+
+        if self.0 == ORPHAN_START_OF_BLOCK {
+            None
+        } else {
+            Some(RecordPtr::new(self.0 & !START_OF_BLOCK))
+        }
+    }
+
+    /// If called on an end-of-block record, returns the number of records in the block
+    /// excluding the start and end markers
+    pub fn get_record_count(self) -> usize {
+
+        debug_assert!(self.is_block_end());
+
+        ((self.0 >> 32) & 0x3FFF_FFFF) as usize
     }
 
     pub fn is_transaction(self) -> bool {
@@ -124,6 +153,11 @@ impl Record {
         (self.0 & RECORD_TYPE) == TRANSACTION
     }
 
+    pub fn is_coinbase(self) -> bool {
+
+        self.is_transaction() && (self.0 & COINBASE) == COINBASE
+    }
+
     pub fn get_transaction_ptr(self) -> TxPtr {
 
         debug_assert!(self.is_transaction() || self.is_output());
@@ -134,6 +168,14 @@ impl Record {
         )
     }
 
+    /// If called on an output record, returns the index of the output in its transaction
+    pub fn get_output_index(self) -> u32 {
+
+        debug_assert!(self.is_output());
+
+        ((self.0 >> 48) & 0x3FFF) as u32
+    }
+
     pub fn get_block_header_ptr(self) -> BlockHeaderPtr {
 
         debug_assert!((self.0 & RECORD_TYPE) == END_OF_BLOCK);
@@ -169,17 +211,22 @@ impl Record {
         // The result is just as unique but smaller; we just drop the info to find the transaction
         // or to find the transaction from an output
         // The resulting number is used for the spend-index
+        // The coinbase flag of a transaction is not part of the hash
 
+        let output_index = if self.is_output() { self.0 & 0x3FFF_0000_0000_0000 } else { 0 };
 
         ((self.0 & 0xFFFF_FFFF_FFFF) >> 4)          // file-offset and file-number
         + (self.0 >> 62)                            // the bit that indicates its an output
-        + ((self.0 & 0x3FFF_0000_0000_0000) >> 48)  // output-index
+        + (output_index >> 48)                      // output-index
     }
 
 
-    fn to_transaction(self) -> Record {
+    /// Returns the plain transaction-record of an output or transaction record
+    ///
+    /// This strips the output-index or coinbase flag, such that the results can be compared
+    pub fn to_transaction(self) -> Record {
 
-        debug_assert!(self.is_output());
+        debug_assert!(self.is_output() || self.is_transaction());
 
         Record(self.0 & 0x0000_FFFF_FFFF_FFFF)
     }
@@ -198,18 +245,24 @@ impl Record {
 #[derive(PartialEq, Copy, Clone)]
 pub struct RecordPtr(u64);
 
+/// The number of records that fit in a file of the spend-tree of the largest size
+///
+/// A RecordPtr is an index over all files; the space at the end of a file that is too small for
+/// the next block is not used, and neither are the indices past the end of a smaller file, so
+/// these indices are skipped
+pub const RECORDS_PER_FILE: u64 = (super::MAX_FILE_SIZE - INITIAL_WRITEPOS) / mem::size_of::<Record>() as u64;
+
 impl FlatFilePtr for RecordPtr {
     fn new(file_number: i16, file_offset: u64) -> RecordPtr {
-        assert_eq!(file_number, 0); // can only have one spend-tree file
 
-        RecordPtr((file_offset as u64 - INITIAL_WRITEPOS)
-                      / mem::size_of::<Record>() as u64)
+        RecordPtr(file_number as u64 * RECORDS_PER_FILE +
+            (file_offset - INITIAL_WRITEPOS) / mem::size_of::<Record>() as u64)
     }
 
 
-    fn get_file_number(self) -> i16 { 0 }
+    fn get_file_number(self) -> i16 { (self.0 / RECORDS_PER_FILE) as i16 }
     fn get_file_offset(self) -> u64 {
-        INITIAL_WRITEPOS + self.0 * mem::size_of::<Record>() as u64
+        INITIAL_WRITEPOS + (self.0 % RECORDS_PER_FILE) * mem::size_of::<Record>() as u64
     }
 }
 
@@ -264,7 +317,7 @@ impl Record {
         &mut self,
         spend_index: &SpendIndex,
         seek_idx: usize,
-        records: &[Record],
+        records: &Records,
         logger: &slog::Logger) -> Result<usize, SpendingError>
 
     {
@@ -314,7 +367,7 @@ impl Record {
 
                 trace!(logger, format!("FL# Jump to {:?} @ {:?}", seek_rec, seek_idx));
 
-            } else if seek_rec == seek_transaction {
+            } else if seek_rec.is_transaction() && seek_rec.to_transaction() == seek_transaction {
 
                 // Found tx before spend => all ok
                 return Ok(1);
@@ -0,0 +1,265 @@
+//! Optional index of the input that spends each output
+//!
+//! The spend-index only records that an output is spent. This index is kept when the store is
+//! opened with `Config::spent_by_index`, and maps an output to the transaction, the input and the
+//! block spending it.
+//!
+//! The entries are added from the output-records of a block when it is connected. An output is
+//! found by the hash of its transaction and its index, as taken from the spending input, such
+//! that lookups don't need the tx-index, from which spent transactions are pruned. An output can
+//! be spent in blocks of different branches; like the waiting inputs in the tx-index, the entries
+//! are stored as guards such that an output can have many. A lookup only returns the one in the
+//! best chain.
+//!
+//! The spending transaction hash is stored with the entry, so lookups still work when it is
+//! pruned. Blocks connected before the index was enabled are not included.
+
+
+use buffer::*;
+use config;
+use hash::*;
+use store::{Store, BlockPtr, HashIndexGuard};
+use store::best_chain;
+use store::hash_index::HashIndex;
+use transaction::Transaction;
+
+
+const DIR: &str = "spent-by-index";
+
+/// The input spending an output, in a block
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpentByEntry {
+
+    // the hash of the spending transaction
+    txid:   [u8; 32],
+
+    // the index of the start-of-block record of the block
+    block:  u64,
+    height: u64,
+    input:  u32,
+    zero:   u32
+}
+
+impl HashIndexGuard for SpentByEntry {
+
+    // all entries are guards such that an output can be spent in different branches
+    fn is_guard(self) -> bool { true }
+}
+
+
+/// The input of the best chain spending an output
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpentBy {
+    pub txid:       [u8; 32],
+    pub input:      u32,
+    pub block_hash: [u8; 32],
+    pub height:     u64
+}
+
+
+pub struct SpentByIndex {
+    index: HashIndex<SpentByEntry>
+}
+
+impl SpentByIndex {
+
+    /// Opens the index at the location given in the config
+    ///
+    /// Creates a new fileset if needed
+    pub fn new(cfg: &config::Config) -> SpentByIndex {
+        SpentByIndex {
+            index: HashIndex::new(cfg, DIR)
+        }
+    }
+
+    /// Returns true if the store at the location given in the config has the index
+    pub fn exists(cfg: &config::Config) -> bool {
+        cfg.root.join(DIR).is_dir()
+    }
+
+    fn add(&mut self, txid: Hash32, index: u32, entry: SpentByEntry) {
+        self.index.get_or_set(hash_output(txid, index).as_ref(), entry);
+    }
+
+    fn get(&mut self, txid: Hash32, index: u32) -> Vec<SpentByEntry> {
+        self.index.get(hash_output(txid, index).as_ref())
+    }
+}
+
+// The key of an output
+fn hash_output(txid: Hash32, index: u32) -> Hash32Buf {
+
+    let mut key = Vec::with_capacity(36);
+    key.extend_from_slice(txid.0);
+    key.extend_from_slice(&index.to_le_bytes());
+
+    Hash32Buf::from_slice(ring::digest::digest(&ring::digest::SHA256, &key).as_ref())
+}
+
+
+/// Adds the spends of a block that was just connected at `height`
+///
+/// Does nothing if the index is not enabled
+pub fn index_block(store: &mut Store, block: BlockPtr, height: u64) {
+
+    if store.spent_by.is_none() {
+        return;
+    }
+
+    // the records of the inputs of a transaction follow its transaction-record
+    let records   = store.spend_tree.get_block_mut(block).to_vec();
+    let mut txid  = None;
+    let mut spent = vec![];
+    let mut input = 0;
+
+    let mut entries = vec![];
+    for rec in &records[1..records.len() - 1] {
+
+        if rec.is_unmatched_input() {
+            input += 1;
+        }
+        else if rec.is_transaction() {
+            let raw = store.transactions.read(rec.get_transaction_ptr())
+                .expect("Transaction of a block that is connected is not pruned");
+            let tx  = Transaction::parse(&mut Buffer::new(&raw))
+                .expect("Stored transaction can be parsed");
+
+            txid  = Some(*Hash32Buf::double_sha256(&raw).as_ref().0);
+            spent = tx.txs_in.iter().map(|tx_in| tx_in.prev_tx_out.as_buf()).collect();
+            input = 0;
+        }
+        else if rec.is_output() {
+            let entry = SpentByEntry {
+                txid:   txid.expect("A spend follows its transaction"),
+                block:  block.start.to_index(),
+                height,
+                input,
+                zero:   0
+            };
+            entries.push((spent[input as usize], rec.get_output_index(), entry));
+            input += 1;
+        }
+    }
+
+    let index = store.spent_by.as_mut().unwrap();
+    for (txid, output_index, entry) in entries {
+        index.add(txid.as_ref(), output_index, entry);
+    }
+}
+
+
+/// Returns the input of the best chain that spends output `index` of the transaction
+///
+/// Returns None if the index is not enabled, or the output is unknown or not spent in the best
+/// chain
+pub fn get_spent_by(store: &mut Store, txid: Hash32, index: u32) -> Option<SpentBy> {
+
+    let entries = store.spent_by.as_mut()?.get(txid, index);
+
+    for entry in entries {
+        let block = match best_chain::get_block_at(store, entry.height) {
+            Some(block) if block.start.to_index() == entry.block => block,
+            _ => continue
+        };
+
+        return Some(SpentBy {
+            txid:       entry.txid,
+            input:      entry.input,
+            block_hash: *store.get_block_hash(block).as_ref().0,
+            height:     entry.height
+        });
+    }
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use block_add;
+    use store::prune::{prune_tx_index, PrunePolicy};
+    use test_chain::TestChain;
+
+    #[test]
+    fn test_spent_by() {
+
+        let mut store = Store::new(& test_cfg!().with_spent_by_index(true));
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let base      = chain.extend(genesis, 101);
+        let coinbase  = chain.chain(base)[1].coinbase.clone().unwrap();
+        let value     = coinbase.output(0).value;
+
+        let tx1       = chain.spend(&[coinbase.output(0)], &[value / 2, value / 2]);
+        let tx2       = chain.spend(&[tx1.output(0), tx1.output(1)], &[value]);
+        let main      = chain.add_block(base, vec![tx1.clone()]);
+        let main_tip  = chain.add_block(main, vec![tx2.clone()]);
+
+        // a fork with more work spends the second output only
+        let tx3       = chain.spend(&[tx1.output(1)], &[value / 2]);
+        let fork      = chain.add_block(main, vec![tx3.clone()]);
+        let fork_tip  = chain.extend(fork, 1);
+
+        for block in chain.chain(main_tip) {
+            block_add::add_block(&mut store, &block.raw);
+        }
+
+        let spent_by = get_spent_by(&mut store, Hash32(&tx1.txid), 1).unwrap();
+        assert_eq!(spent_by, SpentBy {
+            txid:       tx2.txid,
+            input:      1,
+            block_hash: chain.block(main_tip).hash,
+            height:     103
+        });
+        assert_eq!(get_spent_by(&mut store, Hash32(&tx1.txid), 0).unwrap().input, 0);
+        assert_eq!(get_spent_by(&mut store, Hash32(&tx2.txid), 0), None);
+
+        block_add::add_block(&mut store, &chain.block(fork).raw);
+        block_add::add_block(&mut store, &chain.block(fork_tip).raw);
+
+        let spent_by = get_spent_by(&mut store, Hash32(&tx1.txid), 1).unwrap();
+        assert_eq!((spent_by.txid, spent_by.input, spent_by.height), (tx3.txid, 0, 103));
+        assert_eq!(get_spent_by(&mut store, Hash32(&tx1.txid), 0), None);
+
+        // without the index
+        let mut store = Store::new(& test_cfg!());
+        assert_eq!(get_spent_by(&mut store, Hash32(&tx1.txid), 0), None);
+    }
+
+    #[test]
+    fn test_spent_by_pruned() {
+
+        let mut store = Store::new(& test_cfg!().with_spent_by_index(true));
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let base      = chain.extend(genesis, 101);
+        let coinbase  = chain.chain(base)[1].coinbase.clone().unwrap();
+        let value     = coinbase.output(0).value;
+
+        let tx1       = chain.spend(&[coinbase.output(0)], &[value / 2, value / 2]);
+        let tx2       = chain.spend(&[tx1.output(0), tx1.output(1)], &[value]);
+        let with_tx1  = chain.add_block(base, vec![tx1.clone()]);
+        let with_tx2  = chain.add_block(with_tx1, vec![tx2.clone()]);
+        let tip       = chain.extend(with_tx2, 3);
+
+        for block in chain.chain(tip) {
+            block_add::add_block(&mut store, &block.raw);
+        }
+
+        // the spent transaction is removed from the tx-index, but its spends are still found
+        let policy = PrunePolicy { depth: 2, ..Default::default() };
+        assert!(prune_tx_index(&mut store, &policy).transactions > 0);
+        assert!(store.tx_index.get(Hash32(&tx1.txid)).is_empty());
+
+        let spent_by = get_spent_by(&mut store, Hash32(&tx1.txid), 1).unwrap();
+        assert_eq!(spent_by, SpentBy {
+            txid:       tx2.txid,
+            input:      1,
+            block_hash: chain.block(with_tx2).hash,
+            height:     103
+        });
+    }
+}
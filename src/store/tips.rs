@@ -1,65 +1,71 @@
-
-
-
 //! These rules manage the set of tips
 //!
 //! * When genesis is added, it is stored as tip 0
-//! * When a block is added to a block with a tip, the tip is updated
+//! * When a block is added to a block with a tip, the tip is replaced by one for the new block
 //! * When a block is added to a block without a tip, a tip is added
-//! * Tips are eventually pruned TBD
-
+//! * When a block can't be connected yet, it is stored as orphan tip; when it is connected,
+//!   the tip is replaced
+//!
 //! Tips do not use flatfileset. Instead, each tip is a file on disk
 //! as these cover the requirements better and nicely allow atomic replacement
 
 //! A tip is referenced by the block hash and it contains
-//! * The block hash
-//! * The difficulty target
-//! * Softfork info
+//! * Whether the block is connected or an orphan
+//! * The height
+//! * The total work of the chain up to and including the block
+//! * The hash of the previous block
 //!
-//! This is a draft implementation; the format is TBD
-
+//! A new tip is written before the tip it replaces is removed. If the process dies in between,
+//! the previous tip is removed when the tips are read.
+//!
+//! The connected tip with the most work is kept in the `best-tip` file as well, such that it is
+//! found without reading all tips. It is written under a lock when a tip with more work is added
+//! or the best tip is removed, and rewritten from all tips when a writer opens the store.
+//!
+//! For an orphan tip, the height and work only count the orphan blocks of which it is the tip,
+//! as the rest of the chain is not yet known.
 
 
+use std::collections::HashSet;
 use std::path::{PathBuf};
 use std::fs;
-//use std::fs::File;
 use std::io;
-//use std::io::prelude::*;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-//use util::*;
+use util::*;
 use hash::*;
 use config;
 
 
-pub struct Tips {
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
-    path: PathBuf
-}
+const BEST_TIP_FILE: &str = "best-tip";
+
+
+/// The status of a tip, as reported by `getchaintips`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TipStatus {
 
-pub fn add_tip(
-    tips: &Tips,
-    block_hash: Hash32Buf,
-    _previous_hash: Option<Hash32Buf>,
-    difficulty: u64,
-    height: u64)
-{
+    /// The tip of the connected chain with the most work
+    Active,
 
+    /// The tip of another connected chain
+    ValidFork,
 
-    let tip = Tip {
-        block_hash: block_hash,
-        difficulty: difficulty,
-        height: height
-    };
-    let path = tips.path.join(format!("{}", tip.filename()));
+    /// The tip of blocks waiting for their previous block
+    OrphanHeaders
+}
 
-    println!("ADD TIP: {:?}", path);
 
-    let path = tips.path.join(format!("{}", tip.filename()));
+pub struct Tips {
 
-    let mut file = fs::File::create(path)
-        .expect("Cannot create files in store");
+    path: PathBuf,
+    best: PathBuf,
 
-    tip.write(&mut file);
+    // tips are not written, and replaced tips are left for a writer to remove
+    read_only: bool
 }
 
 
@@ -68,92 +74,387 @@ impl Tips {
     pub fn new(cfg: &config::Config) -> Tips {
         let path = &cfg.root.clone().join("tips");
 
-        if !path.exists() {
+        if !path.exists() && !cfg.read_only {
             fs::create_dir_all(path)
-                .expect(&format!("Could not create {:?}", path));
+                .unwrap_or_else(|_| panic!("Could not create {:?}", path));
         }
 
-        Tips {
-            path: PathBuf::from(path)
+        let tips = Tips {
+            path:      PathBuf::from(path),
+            best:      cfg.root.join(BEST_TIP_FILE),
+            read_only: cfg.read_only
+        };
+
+        // the best tip may be behind if a process died while adding a tip
+        if !cfg.read_only {
+            let mut file = tips.lock_best();
+            tips.write_best(&mut file, tips.find_most_work_tip());
         }
+        tips
     }
 
+    /// Stores the tip, and removes the tip of its previous block which is no longer a tip
+    pub fn add_tip(&self, tip: &Tip) {
+
+        assert!(!self.read_only, "Cannot write tips of a read-only store");
+
+        // write to a temporary file first such that the tip is replaced atomically; other
+        // stores may write the same tip concurrently so the name must be unique
+        let unique   = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path     = self.path.join(tip.filename());
+        let tmp_path = path.with_extension(format!("{}-{}.tmp", process::id(), unique));
+
+        let mut file = fs::File::create(&tmp_path)
+            .expect("Cannot create files in store");
 
-    pub fn get_tips() -> Vec<Tip> {
-        vec![]
+        tip.write(&mut file).expect("Cannot write tip");
+        drop(file);
+
+        fs::rename(tmp_path, path).expect("Cannot write tip");
+
+        if tip.status != TipStatus::OrphanHeaders {
+            let mut file = self.lock_best();
+            let more_work = Tips::read_best(&mut file)
+                .is_none_or(|best| (tip.work, tip.height) > (best.work, best.height));
+
+            if more_work {
+                self.write_best(&mut file, Some(tip.clone()));
+            }
+        }
+
+        self.remove_tip(tip.previous.as_ref());
     }
 
-    pub fn get_most_work_tip() -> Tip {
-        unimplemented!()
+    /// Returns the tip of the given block, if it is a tip
+    pub fn get(&self, block_hash: Hash32) -> Option<Tip> {
+
+        let hash = block_hash.as_buf();
+        let raw  = fs::read_to_string(self.path.join(to_hex_rev(&hash))).ok()?;
+
+        Tip::parse(hash, &raw)
+    }
+
+    /// Returns all tips; an orphan tip has status `OrphanHeaders` and others `ValidFork`
+    ///
+    /// Tips left behind by an interrupted replacement are removed
+    pub fn get_tips(&self) -> Vec<Tip> {
+
+        let entries = match fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(_)      => return vec![]
+        };
+
+        let tips: Vec<Tip> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                if name.len() != 64 {
+                    return None;
+                }
+                let hash = Hash32Buf::from_slice(&from_hex_rev(&name));
+                let raw  = fs::read_to_string(entry.path()).ok()?;
+
+                Tip::parse(hash, &raw)
+            })
+            .collect();
+
+        // a tip that is the previous of another tip has been replaced
+        let previous: HashSet<[u8; 32]> = tips.iter()
+            .map(|tip| *tip.previous.as_ref().0)
+            .collect();
+
+        let (replaced, tips): (Vec<Tip>, Vec<Tip>) = tips.into_iter()
+            .partition(|tip| previous.contains(tip.block_hash.as_ref().0));
+
+        // the tip replacing it has more work, so the best tip needs no update
+        for tip in replaced.iter().filter(|_| !self.read_only) {
+            self.remove_tip_file(tip.block_hash.as_ref());
+        }
+        tips
+    }
+
+    /// Returns the connected tip with the most work, with status `Active`
+    ///
+    /// If multiple tips have the same work, the highest wins
+    pub fn get_most_work_tip(&self) -> Option<Tip> {
+
+        // stores from before the best-tip file don't have it until a writer opens them
+        let mut file = match fs::File::open(&self.best) {
+            Ok(file) => file,
+            Err(_)   => return self.find_most_work_tip()
+        };
+        file.lock_shared().expect("Cannot lock best tip");
+        Tips::read_best(&mut file)
     }
 
-    pub fn remove_tip(_tip: Tip) {
-        unimplemented!()
+    // Returns the connected tip with the most work by reading all tips
+    fn find_most_work_tip(&self) -> Option<Tip> {
+
+        self.get_tips().into_iter()
+            .filter(|tip| tip.status != TipStatus::OrphanHeaders)
+            .max_by_key(|tip| (tip.work, tip.height))
+            .map(|tip| Tip { status: TipStatus::Active, ..tip })
     }
 
-    pub fn get_all() {
-        
+    // Opens the best-tip file with an exclusive lock, which is held until it is dropped
+    fn lock_best(&self) -> fs::File {
+
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.best)
+            .expect("Cannot create files in store");
+
+        file.lock().expect("Cannot lock best tip");
+        file
+    }
+
+    // The file holds the hash of the best tip followed by the tip, and is empty without tips
+    fn read_best(file: &mut fs::File) -> Option<Tip> {
+
+        let mut raw = String::new();
+        file.read_to_string(&mut raw).ok()?;
+
+        let (hash, tip) = raw.split_once(',')?;
+        if hash.len() != 64 {
+            return None;
+        }
+        Tip::parse(Hash32Buf::from_slice(&from_hex_rev(hash)), tip)
+            .map(|tip| Tip { status: TipStatus::Active, ..tip })
+    }
+
+    fn write_best(&self, file: &mut fs::File, best: Option<Tip>) {
+
+        let mut raw = vec![];
+        if let Some(best) = best {
+            write!(raw, "{},", best.filename()).and_then(|_| best.write(&mut raw))
+                .expect("Cannot write best tip");
+        }
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| file.write_all(&raw))
+            .expect("Cannot write best tip");
+    }
+
+    /// Removes the tip of the given block; does nothing if it isn't a tip
+    pub fn remove_tip(&self, block_hash: Hash32) {
+
+        assert!(!self.read_only, "Cannot write tips of a read-only store");
+
+        self.remove_tip_file(block_hash);
+
+        let mut file = self.lock_best();
+        if Tips::read_best(&mut file).is_some_and(|best| best.block_hash.as_ref() == block_hash) {
+            self.write_best(&mut file, self.find_most_work_tip());
+        }
+    }
+
+    fn remove_tip_file(&self, block_hash: Hash32) {
+        let _ = fs::remove_file(self.path.join(to_hex_rev(&block_hash.as_buf())));
+    }
+
+    /// Returns all tips with their status, most work first
+    pub fn get_all(&self) -> Vec<Tip> {
+
+        let best = self.get_most_work_tip();
+
+        let mut tips: Vec<Tip> = self.get_tips().into_iter()
+            .map(|tip| match best {
+                Some(ref best) if best.block_hash == tip.block_hash => best.clone(),
+                _ => tip
+            })
+            .collect();
+
+        tips.sort_by_key(|tip| ::std::cmp::Reverse((tip.work, tip.height)));
+        tips
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct Tip {
 
-    block_hash: Hash32Buf,
+    pub block_hash: Hash32Buf,
+    pub previous:   Hash32Buf,
 
-    difficulty: u64,
-    height: u64
+    pub height:     u64,
+    pub work:       u128,
+    pub status:     TipStatus
 
-    
     // softfork rules
 
 }
 
 impl Tip {
 
+    /// Creates the tip of a connected block
+    pub fn new(block_hash: Hash32Buf, previous: Hash32Buf, height: u64, work: u128) -> Tip {
+
+        Tip {
+            block_hash,
+            previous,
+            height,
+            work,
+            status: TipStatus::ValidFork
+        }
+    }
 
-    pub fn new(block_hash: Hash32Buf, difficulty: u64, height: u64) -> Tip {
+    /// Creates the tip of a block that is not yet connected
+    pub fn new_orphan(block_hash: Hash32Buf, previous: Hash32Buf, height: u64, work: u128) -> Tip {
 
         Tip {
-            block_hash: block_hash,
-            difficulty: difficulty,
-            height: height
+            status: TipStatus::OrphanHeaders,
+            ..Tip::new(block_hash, previous, height, work)
         }
     }
 
-    fn write<W: io::Write>(&self, writer: &mut W) {
+    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
 
-        write!(writer,"{},{}", self.difficulty, self.height).unwrap();
+        let status = match self.status {
+            TipStatus::OrphanHeaders => "orphan",
+            _                        => "connected"
+        };
+        write!(writer, "{},{},{},{}", status, self.height, self.work, to_hex_rev(&self.previous))
     }
 
-    fn filename(&self) -> String {
+    fn parse(block_hash: Hash32Buf, raw: &str) -> Option<Tip> {
+
+        let fields: Vec<&str> = raw.trim().split(',').collect();
+        if fields.len() != 4 || fields[3].len() != 64 {
+            return None;
+        }
 
-        self.block_hash
-            .as_ref().0
-            .iter()
-            .rev()
-            .map(|n| format!("{:02x}", n))
-            .collect::<Vec<_>>()
-            .concat()
+        let height   = fields[1].parse().ok()?;
+        let work     = fields[2].parse().ok()?;
+        let previous = Hash32Buf::from_slice(&from_hex_rev(fields[3]));
 
+        match fields[0] {
+            "connected" => Some(Tip::new(block_hash, previous, height, work)),
+            "orphan"    => Some(Tip::new_orphan(block_hash, previous, height, work)),
+            _           => None
+        }
+    }
+
+    fn filename(&self) -> String {
+        to_hex_rev(&self.block_hash)
     }
 }
 
+// Formats a hash as hex in the usual reversed byte order
+fn to_hex_rev(hash: &Hash32Buf) -> String {
+
+    hash.as_ref().0
+        .iter()
+        .rev()
+        .map(|n| format!("{:02x}", n))
+        .collect::<Vec<_>>()
+        .concat()
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
-    use util::*;
+
+    const HASH1: &str = "212300e77d897f2f059366ed03c8bf2757bc2b1dd30df15d34f6f1ee521e58e8";
+    const HASH2: &str = "4feec9316077e49b59bc23173303e13be9e9f5f9fa0660a58112a04a65a84ef1";
+    const HASH3: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+    const HASH4: &str = "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048";
+
+    fn hash(hex: &str) -> Hash32Buf {
+        Hash32Buf::from_slice(&from_hex_rev(hex))
+    }
 
     #[test]
     fn test_create_tip() {
 
-        const HASH1: &'static str = "212300e77d897f2f059366ed03c8bf2757bc2b1dd30df15d34f6f1ee521e58e8";
-        const HASH2: &'static str = "4feec9316077e49b59bc23173303e13be9e9f5f9fa0660a58112a04a65a84ef1";
+        let tips = Tips::new(&test_cfg!());
+
+        tips.add_tip(&Tip::new(hash(HASH1), hash(HASH3), 1, 2));
+        tips.add_tip(&Tip::new_orphan(hash(HASH2), hash(HASH4), 3, 4));
+
+        assert_eq!(tips.get(hash(HASH1).as_ref()), Some(Tip::new(hash(HASH1), hash(HASH3), 1, 2)));
+        assert_eq!(tips.get(hash(HASH2).as_ref()).unwrap().status, TipStatus::OrphanHeaders);
+        assert_eq!(tips.get(hash(HASH3).as_ref()), None);
+        assert_eq!(tips.get_tips().len(), 2);
+    }
+
+    #[test]
+    fn test_replace_tip() {
 
         let tips = Tips::new(&test_cfg!());
 
-        add_tip(&tips, Hash32Buf::from_slice(&from_hex_rev(HASH1)), None, 1, 2);
-        add_tip(&tips, Hash32Buf::from_slice(&from_hex_rev(HASH2)), None, 3, 4);
+        tips.add_tip(&Tip::new(hash(HASH1), hash(HASH3), 1, 10));
+        tips.add_tip(&Tip::new(hash(HASH2), hash(HASH1), 2, 20));
+        assert_eq!(tips.get(hash(HASH1).as_ref()), None);
+
+        // a fork with less work
+        tips.add_tip(&Tip::new(hash(HASH4), hash(HASH3), 1, 15));
 
+        let best = tips.get_most_work_tip().unwrap();
+        assert_eq!(best.block_hash, hash(HASH2));
+        assert_eq!(best.status, TipStatus::Active);
+
+        let all = tips.get_all();
+        assert_eq!(all.iter().map(|tip| tip.status).collect::<Vec<_>>(),
+            vec![TipStatus::Active, TipStatus::ValidFork]);
+
+        tips.remove_tip(hash(HASH2).as_ref());
+        assert_eq!(tips.get_most_work_tip().unwrap().block_hash, hash(HASH4));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_interrupted_replace() {
+
+        let cfg  = test_cfg!();
+        let tips = Tips::new(&cfg);
+
+        tips.add_tip(&Tip::new(hash(HASH1), hash(HASH3), 1, 10));
+
+        // the new tip is written, but the best tip is not updated and the previous one is
+        // not yet removed
+        let mut file = fs::File::create(tips.path.join(HASH2)).unwrap();
+        Tip::new(hash(HASH2), hash(HASH1), 2, 20).write(&mut file).unwrap();
+        assert_eq!(tips.get_most_work_tip().unwrap().block_hash, hash(HASH1));
+
+        assert_eq!(tips.get_tips().len(), 1);
+        assert_eq!(tips.get(hash(HASH1).as_ref()), None);
+
+        // the best tip is found again when the store is opened
+        let tips = Tips::new(&cfg);
+        assert_eq!(tips.get_most_work_tip().unwrap().block_hash, hash(HASH2));
+    }
+
+    #[test]
+    fn test_best_tip() {
+
+        let cfg  = test_cfg!();
+        let tips = Tips::new(&cfg);
+        assert_eq!(tips.get_most_work_tip(), None);
+
+        // orphans and forks with less work don't change the best tip
+        tips.add_tip(&Tip::new(hash(HASH1), hash(HASH3), 1, 10));
+        tips.add_tip(&Tip::new_orphan(hash(HASH2), hash(HASH3), 3, 40));
+        tips.add_tip(&Tip::new(hash(HASH4), hash(HASH3), 1, 5));
+        assert_eq!(tips.get_most_work_tip().unwrap().block_hash, hash(HASH1));
+
+        // a read-only store reads it as well
+        let ro = Tips::new(&::config::Config { read_only: true, ..cfg.clone() });
+        assert_eq!(ro.get_most_work_tip(), tips.get_most_work_tip());
+
+        // stores from before the best-tip file read all tips
+        fs::remove_file(cfg.root.join(BEST_TIP_FILE)).unwrap();
+        assert_eq!(ro.get_most_work_tip().unwrap().block_hash, hash(HASH1));
+
+        // a writer writes it when it opens the store
+        let tips = Tips::new(&cfg);
+        assert!(cfg.root.join(BEST_TIP_FILE).exists());
+
+        // removing the best tip finds the next
+        tips.remove_tip(hash(HASH1).as_ref());
+        assert_eq!(tips.get_most_work_tip().unwrap().block_hash, hash(HASH4));
+        tips.remove_tip(hash(HASH4).as_ref());
+        assert_eq!(tips.get_most_work_tip(), None);
+    }
+}
@@ -7,6 +7,9 @@
 //! as they are still WIP
 
 
+use std::collections::HashMap;
+use std::path::PathBuf;
+
 use buffer::*;
 use config;
 use store::flatfileset::FlatFileSet;
@@ -14,10 +17,25 @@ use store::TxPtr;
 
 use transaction::Transaction;
 use store::flatfileset::FlatFilePtr;
+use store::flatfile::INITIAL_WRITEPOS;
 
 const MB:                 u64 = 1024 * 1024;
+
+#[cfg(not(test))]
 const FILE_SIZE:          u64 = 2 * 1024 * MB ;
-const MAX_CONTENT_SIZE:   u64 = FILE_SIZE - 10 * MB ;
+#[cfg(not(test))]
+pub const MAX_CONTENT_SIZE: u64 = FILE_SIZE - 10 * MB ;
+
+// Tests use small files such that files can be pruned
+#[cfg(test)]
+const FILE_SIZE:          u64 = 64 * 1024;
+#[cfg(test)]
+pub const MAX_CONTENT_SIZE: u64 = FILE_SIZE - 32 * 1024;
+
+
+/// The error returned when reading a transaction of which the file has been pruned
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pruned;
 
 
 /// Transaction store
@@ -29,6 +47,8 @@ pub struct Transactions {
     // part2 stores the outputs
     transactions2: FlatFileSet<TxPtr>,
 
+    // the files of part1 referenced by each full file of part2
+    inputs_files: HashMap<i16, (i16, i16)>,
 }
 
 impl Clone for Transactions {
@@ -39,7 +59,8 @@ impl Clone for Transactions {
         Transactions {
 
             transactions1: self.transactions1.clone(),
-            transactions2: self.transactions2.clone()
+            transactions2: self.transactions2.clone(),
+            inputs_files:  self.inputs_files.clone()
         }
 
     }
@@ -56,8 +77,9 @@ impl Transactions
         let dir2 = &cfg.root.clone().join("transactions2");
 
         Transactions {
-            transactions1: FlatFileSet::new(dir1, "t1-", FILE_SIZE, MAX_CONTENT_SIZE),
-            transactions2: FlatFileSet::new(dir2, "t2-", FILE_SIZE, MAX_CONTENT_SIZE)
+            transactions1: FlatFileSet::open(dir1, "t1-", FILE_SIZE, MAX_CONTENT_SIZE, cfg.read_only),
+            transactions2: FlatFileSet::open(dir2, "t2-", FILE_SIZE, MAX_CONTENT_SIZE, cfg.read_only),
+            inputs_files:  HashMap::new()
         }
     }
 
@@ -103,15 +125,23 @@ impl Transactions
 
 
     /// Reads the full transaction from the given pointer
-    pub fn read(&mut self, ptr: TxPtr) -> Vec<u8> {
+    pub fn read(&mut self, ptr: TxPtr) -> Result<Vec<u8>, Pruned> {
+        if self.is_pruned(ptr) {
+            return Err(Pruned);
+        }
         let (tx,_) = self.next(ptr).unwrap();
-        tx
+        Ok(tx)
     }
 
     /// Reads the full transaction from the given pointer
     /// Returns the transaction and a pointer to the next one
+    ///
+    /// Returns None at the end, or if the transaction is pruned
     pub fn next(&mut self, ptr: TxPtr) -> Option<(Vec<u8>, TxPtr)> {
 
+        if self.is_pruned(ptr) {
+            return None;
+        }
         let part2 = self.transactions2.read(ptr);
         let len = part2.len() as u32;
         if len == 0 {
@@ -139,6 +169,154 @@ impl Transactions
     }
 
 
+    /// Returns the pointer after the last stored transaction
+    pub fn get_end(&mut self) -> TxPtr {
+        self.transactions2.get_write_pos()
+    }
+
+    /// Returns true if the file containing the transaction has been pruned
+    pub fn is_pruned(&mut self, ptr: TxPtr) -> bool {
+        self.transactions2.is_removed(ptr.get_file_number())
+    }
+
+    /// Returns the pointer to the first transaction that is not pruned
+    pub fn first(&mut self) -> TxPtr {
+
+        let mut fileno = self.transactions2.get_first_file();
+        while self.transactions2.is_removed(fileno) {
+            fileno += 1;
+        }
+        TxPtr::new(fileno, INITIAL_WRITEPOS)
+    }
+
+    /// Returns the numbers of the files that can be pruned, which are all but the last
+    pub fn get_files(&mut self) -> Vec<i16> {
+
+        let last = self.transactions2.get_last_file();
+        (self.transactions2.get_first_file()..last)
+            .filter(|&fileno| !self.transactions2.is_removed(fileno))
+            .collect()
+    }
+
+    /// Returns the paths of the files of both filesets that are no longer written
+    pub fn get_full_files(&mut self) -> Vec<PathBuf> {
+
+        let mut files = self.transactions1.get_full_files();
+        files.extend(self.transactions2.get_full_files());
+        files
+    }
+
+    /// Returns the paths of the last files of both filesets, and the positions after the last
+    /// allocated data in them
+    pub fn get_last_files(&mut self) -> Vec<(PathBuf, u64)> {
+        vec![self.transactions1.get_last_file_pos(), self.transactions2.get_last_file_pos()]
+    }
+
+    /// Returns the number of bytes used by the files of the store that are not pruned
+    pub fn get_size(&mut self) -> u64 {
+
+        fn fileset_size(fileset: &mut FlatFileSet<TxPtr>) -> u64 {
+
+            let mut size = 0;
+            for fileno in fileset.get_first_file()..fileset.get_last_file() + 1 {
+                if !fileset.is_removed(fileno) {
+                    size += fileset.get_file_write_pos(fileno).get_file_offset();
+                }
+            }
+            size
+        }
+
+        fileset_size(&mut self.transactions1) + fileset_size(&mut self.transactions2)
+    }
+
+    /// Removes the file with the given number, after which its transactions are pruned
+    ///
+    /// The inputs parts are stored in another fileset; its files are removed when none of the
+    /// transactions in the files that are kept references them
+    pub fn remove_file(&mut self, fileno: i16) {
+
+        self.transactions2.remove_file(fileno);
+
+        let last = self.transactions2.get_last_file();
+        let mut referenced: Vec<(i16, i16)> = vec![];
+        for n in self.transactions2.get_first_file()..last + 1 {
+            if !self.transactions2.is_removed(n) {
+                referenced.push(self.get_inputs_files(n));
+            }
+        }
+
+        // A transaction that is being written has its inputs part in the last file, or in the
+        // one before if that just filled up
+        let last_inputs = self.transactions1.get_last_file();
+        for n in self.transactions1.get_first_file()..last_inputs - 1 {
+            if !referenced.iter().any(|&(from, to)| from <= n && n <= to) {
+                self.transactions1.remove_file(n);
+            }
+        }
+    }
+
+    // Returns the lowest and the highest file of the inputs parts referenced by the transactions
+    // in the given file
+    //
+    // Files that are no longer written don't change, so their result is kept
+    fn get_inputs_files(&mut self, fileno: i16) -> (i16, i16) {
+
+        if let Some(&files) = self.inputs_files.get(&fileno) {
+            return files;
+        }
+
+        let end       = self.transactions2.get_file_write_pos(fileno).get_file_offset();
+        let mut files = (i16::MAX, i16::MIN);
+        let mut pos   = INITIAL_WRITEPOS;
+        while pos < end {
+            let part2 = self.transactions2.read(TxPtr::new(fileno, pos));
+            if part2.is_empty() {
+                break;
+            }
+            let inputs_file = bytes_to_u32(&part2[0..4]) as i16;
+            files = (files.0.min(inputs_file), files.1.max(inputs_file));
+            pos  += part2.len() as u64 + 4;
+        }
+
+        if fileno < self.transactions2.get_last_file() {
+            self.inputs_files.insert(fileno, files);
+        }
+        files
+    }
+
+    /// Reads the transactions stored from `ptr`
+    ///
+    /// Returns the pointer and the raw transaction of each, and the pointer at which reading
+    /// stopped. This is the end, or a zero length where a write was interrupted
+    pub fn read_from(&mut self, ptr: TxPtr) -> (Vec<(TxPtr, Vec<u8>)>, TxPtr) {
+
+        let end     = self.get_end();
+        let before  = |p: TxPtr| (p.get_file_number(), p.get_file_offset()) <
+                                 (end.get_file_number(), end.get_file_offset());
+        let mut ptr = ptr;
+        let mut txs = vec![];
+        while before(ptr) {
+            match self.next(ptr) {
+                Some((tx, next)) => { txs.push((ptr, tx)); ptr = next; },
+                None             => break
+            }
+        }
+        (txs, ptr)
+    }
+
+    /// Returns true if nothing is written from `ptr` onwards
+    pub fn is_zero_from(&mut self, ptr: TxPtr) -> bool {
+        self.transactions2.is_zero_from(ptr)
+    }
+
+    /// Discards the transactions stored from `ptr`
+    ///
+    /// Only the outputs part is truncated; the inputs part is only reachable through it
+    pub fn truncate(&mut self, ptr: TxPtr) {
+        self.transactions2.truncate(ptr);
+    }
+
+
     /// Returns only an output from the given transaction
     /// The resulting Vec overflows until the end of the transaction
     pub fn read_output(&mut self, ptr: TxPtr, output_index: u32) -> Option<Vec<u8>> {
@@ -200,15 +378,15 @@ mod tests {
         let mut store = ::store::Store::new(& test_cfg!());
 
         let ptr  = store.transactions.write(&tx1p);
-        let read = store.transactions.read(ptr);
+        let read = store.transactions.read(ptr).unwrap();
         assert_eq!(tx1, read.as_slice());
 
         let ptr = store.transactions.write(&tx2p);
-        let read = store.transactions.read(ptr);
+        let read = store.transactions.read(ptr).unwrap();
         assert_eq!(tx2, read.as_slice());
 
         let ptr = store.transactions.write(&tx3p);
-        let read = store.transactions.read(ptr);
+        let read = store.transactions.read(ptr).unwrap();
         assert_eq!(tx3, read.as_slice());
 
     }
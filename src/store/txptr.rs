@@ -72,7 +72,7 @@ impl TxPtr {
     }
 
     pub fn offset(self, offset: u32) -> TxPtr {
-        if self.file_offset + offset > super::MAX_CONTENT_SIZE as u32 {
+        if self.file_offset + offset > super::transactions::MAX_CONTENT_SIZE as u32 {
             println!("Next file!");
             TxPtr {
                 file_number: self.file_number + 1,
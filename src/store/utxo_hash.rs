@@ -0,0 +1,225 @@
+//! Index of the MuHash of the unspent outputs as of each block
+//!
+//! The index is only kept if `utxo_hash` is enabled in the config. For each connected block, the numerator and denominator of the MuHash are stored as a fixed
+//! size record, together with the height and the block it belongs to, and can be found by the
+//! hash of the block through a hash-index. As branches don't share their state, a record is never
+//! changed; when a block is connected again, its record is replaced.
+//!
+//! Removing a spent output requires the height of the block that contains its transaction, which
+//! the spend-tree doesn't keep. The heights are stored in a second hash-index by transaction
+//! hash. Only if a transaction is found in blocks of different branches at different heights,
+//! it is marked `HEIGHT_CONFLICT` and the chain is searched.
+
+
+use config;
+use hash::*;
+
+use muhash::{MuHash3072, LIMBS};
+use store::BlockPtr;
+use store::flatfileset::{FlatFilePtr, FlatFileSet};
+use store::hash_index::{HashIndex, HashIndexGuard};
+
+
+const MB:                 u64 = 1024 * 1024;
+const FILE_SIZE:          u64 = 1024 * MB;
+const MAX_CONTENT_SIZE:   u64 = FILE_SIZE - 10 * MB;
+
+const HEIGHTS_DIR: &str = "tx-height-index";
+
+/// The height stored for a transaction that is in blocks at different heights
+pub const HEIGHT_CONFLICT: u64 = u64::MAX;
+
+
+/// A pointer to a MuHash record
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct UtxoHashPtr {
+    file_offset: u32,
+    file_number: i16,
+    zero:        u16
+}
+
+impl FlatFilePtr for UtxoHashPtr {
+
+    fn new(file_number: i16, file_offset: u64) -> Self {
+        UtxoHashPtr {
+            file_offset: file_offset as u32,
+            file_number,
+            zero:        0
+        }
+    }
+
+    fn get_file_number(self) -> i16 { self.file_number }
+
+    fn get_file_offset(self) -> u64 { self.file_offset as u64 }
+}
+
+impl HashIndexGuard for UtxoHashPtr {
+    fn is_guard(self) -> bool { false }
+}
+
+
+/// The height of the block containing a transaction, as stored in the height index
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TxHeight(pub u64);
+
+impl HashIndexGuard for TxHeight {
+    fn is_guard(self) -> bool { false }
+}
+
+
+/// The MuHash of the unspent outputs as of a block
+#[derive(Clone, Copy)]
+pub struct UtxoHashRecord {
+
+    /// The index of the start-of-block record of the block
+    pub block:   u64,
+    pub height:  u64,
+
+    numerator:   [u64; LIMBS],
+    denominator: [u64; LIMBS]
+}
+
+impl UtxoHashRecord {
+
+    pub fn new(block: BlockPtr, height: u64, muhash: &MuHash3072) -> UtxoHashRecord {
+
+        let (numerator, denominator) = muhash.to_limbs();
+        UtxoHashRecord {
+            block: block.start.to_index(),
+            height,
+            numerator,
+            denominator
+        }
+    }
+
+    pub fn muhash(&self) -> MuHash3072 {
+        MuHash3072::from_limbs(self.numerator, self.denominator)
+    }
+}
+
+
+pub struct UtxoHashIndex {
+    fileset: FlatFileSet<UtxoHashPtr>,
+    index:   HashIndex<UtxoHashPtr>,
+    heights: HashIndex<TxHeight>
+}
+
+impl UtxoHashIndex {
+
+    /// Opens the index at the location given in the config
+    ///
+    /// Creates a new fileset if needed
+    pub fn new(cfg: &config::Config) -> UtxoHashIndex {
+
+        let dir = &cfg.root.clone().join("utxo-hash");
+
+        UtxoHashIndex {
+            fileset: FlatFileSet::open(dir, "uh-", FILE_SIZE, MAX_CONTENT_SIZE, cfg.read_only),
+            index:   HashIndex::new(cfg, "utxo-hash-index"),
+            heights: HashIndex::new(cfg, HEIGHTS_DIR)
+        }
+    }
+
+    /// Returns true if the store at the location of the config has the index
+    pub fn exists(cfg: &config::Config) -> bool {
+        cfg.root.join(HEIGHTS_DIR).is_dir()
+    }
+
+    fn get_ptr(&mut self, block_hash: Hash32, block: BlockPtr) -> Option<UtxoHashPtr> {
+
+        let ptr = self.index.get(block_hash).into_iter().next()?;
+        if self.fileset.read_fixed::<UtxoHashRecord>(ptr).block == block.start.to_index() {
+            Some(ptr)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the record of the given block
+    pub fn get(&mut self, block_hash: Hash32, block: BlockPtr) -> Option<UtxoHashRecord> {
+
+        let ptr = self.get_ptr(block_hash, block)?;
+        Some(*self.fileset.read_fixed::<UtxoHashRecord>(ptr))
+    }
+
+    /// Stores the record of a block, replacing a previous one with the same hash
+    pub fn add(&mut self, block_hash: Hash32, record: &UtxoHashRecord) {
+
+        let ptr = self.fileset.write_fixed(record);
+        self.index.set(block_hash, ptr, &[], true);
+    }
+
+    /// Removes the record of a block that is disconnected; does nothing if it has none
+    pub fn remove(&mut self, block_hash: Hash32, block: BlockPtr) {
+
+        if let Some(ptr) = self.get_ptr(block_hash, block) {
+            self.index.remove(block_hash, ptr);
+        }
+    }
+
+    /// Returns the height of the block containing the transaction, which may be
+    /// `HEIGHT_CONFLICT`
+    ///
+    /// Returns None if the transaction is not found
+    pub fn get_height(&mut self, tx_hash: Hash32) -> Option<u64> {
+
+        self.heights.get(tx_hash).into_iter().next().map(|height| height.0)
+    }
+
+    /// Stores the height of a block containing the transaction
+    pub fn add_height(&mut self, tx_hash: Hash32, height: u64) {
+
+        let heights = &mut self.heights;
+        if heights.set(tx_hash, TxHeight(height), &[], false) {
+            return;
+        }
+        let stored = heights.get(tx_hash).into_iter().next().map(|height| height.0);
+        if stored.is_some_and(|stored| stored != height) {
+            heights.set(tx_hash, TxHeight(HEIGHT_CONFLICT), &[], true);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use store::RecordPtr;
+
+    fn block(start: u64) -> BlockPtr {
+        BlockPtr { start: RecordPtr::new(start), length: 2, is_guard: false }
+    }
+
+    #[test]
+    fn test_utxo_hash_index() {
+
+        let mut index = UtxoHashIndex::new(& test_cfg!().with_utxo_hash(true));
+        let hash      = Hash32Buf::double_sha256(b"block");
+
+        let mut muhash = MuHash3072::new();
+        muhash.insert(b"output");
+        index.add(hash.as_ref(), &UtxoHashRecord::new(block(10), 1, &muhash));
+
+        let record = index.get(hash.as_ref(), block(10)).unwrap();
+        assert_eq!(record.height, 1);
+        assert!(record.muhash() == muhash);
+
+        // a record of the same hash for another block is not returned nor removed
+        assert!(index.get(hash.as_ref(), block(20)).is_none());
+        index.remove(hash.as_ref(), block(20));
+        assert!(index.get(hash.as_ref(), block(10)).is_some());
+
+        index.remove(hash.as_ref(), block(10));
+        assert!(index.get(hash.as_ref(), block(10)).is_none());
+
+        // heights
+        let tx = Hash32Buf::double_sha256(b"tx");
+        assert_eq!(index.get_height(tx.as_ref()), None);
+        index.add_height(tx.as_ref(), 5);
+        index.add_height(tx.as_ref(), 5);
+        assert_eq!(index.get_height(tx.as_ref()), Some(5));
+        index.add_height(tx.as_ref(), 6);
+        assert_eq!(index.get_height(tx.as_ref()), Some(HEIGHT_CONFLICT));
+    }
+}
@@ -0,0 +1,508 @@
+//! Builder for chains of blocks used in tests
+//!
+//! Unlike the macros in `builders`, `TestChain` creates blocks that are valid: coinbases pay
+//! the subsidy plus the fees of the block, merkle roots are correct and the headers meet the
+//! trivial regtest target. Blocks can be built on any block to create forks, and can be made
+//! invalid on purpose with a `Violation`.
+//!
+//! All outputs are locked with OP_TRUE, such that they can be spent with an empty script.
+//!
+//! ```no_run
+//! use bitcrust_lib::test_chain::TestChain;
+//!
+//! let mut chain = TestChain::new();
+//!
+//! // let the coinbase of block 1 mature
+//! let genesis = chain.genesis();
+//! let block1  = chain.add_block(genesis, vec![]);
+//! let tip     = chain.extend(block1, 100);
+//!
+//! let coinbase = chain.block(block1).coinbase.as_ref().unwrap().output(0);
+//! let tx       = chain.spend(&[coinbase], &[10_0000_0000, 39_0000_0000]);
+//! let tip      = chain.add_block(tip, vec![tx]);
+//!
+//! let mut store = bitcrust_lib::init();
+//! for block in chain.chain(tip) {
+//!     bitcrust_lib::add_block(&mut store, &block.raw);
+//! }
+//! ```
+
+
+use hash::*;
+use merkle_tree;
+use pow;
+use util::{from_hex, write_compact_size};
+
+
+pub const COIN: u64 = 100_000_000;
+
+const INITIAL_SUBSIDY:          u64 = 50 * COIN;
+const SUBSIDY_HALVING_INTERVAL: u64 = 210_000;
+
+/// The target used for all blocks after genesis
+pub const REGTEST_BITS: u32 = 0x207F_FFFF;
+
+const OP_TRUE: u8 = 0x51;
+
+// time between blocks
+const BLOCK_INTERVAL: u32 = 600;
+
+const GENESIS: &str = "0100000000000000000000000000000000000000000000000000000000000000\
+    000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa\
+    4b1e5e4a29ab5f49ffff001d1dac2b7c01010000000100000000000000000000\
+    00000000000000000000000000000000000000000000ffffffff4d04ffff001d\
+    0104455468652054696d65732030332f4a616e2f32303039204368616e63656c\
+    6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f75742066\
+    6f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe554827\
+    1967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4\
+    f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+
+/// A deliberate rule violation in a block
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Violation {
+    /// The merkle root in the header doesn't match the transactions
+    BadMerkleRoot,
+
+    /// The header hash doesn't meet the target
+    BadProofOfWork,
+
+    /// The coinbase pays one satoshi more than the subsidy plus fees
+    ExcessCoinbaseAmount,
+
+    /// The block has no coinbase
+    NoCoinbase,
+
+    /// A second coinbase follows the transactions
+    DoubleCoinbase
+}
+
+
+/// A spendable output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutPoint {
+    pub txid:  [u8; 32],
+    pub index: u32,
+    pub value: u64
+}
+
+#[derive(Debug, Clone)]
+pub struct TestTransaction {
+    pub raw:     Vec<u8>,
+    pub txid:    [u8; 32],
+    pub outputs: Vec<OutPoint>,
+
+    /// The input value minus the output value
+    pub fee:     u64
+}
+
+impl TestTransaction {
+
+    fn new(raw: Vec<u8>, values: &[u64], fee: u64) -> TestTransaction {
+
+        let txid = *Hash32Buf::double_sha256(&raw).as_ref().0;
+        let outputs = values.iter().enumerate()
+            .map(|(index, &value)| OutPoint { txid, index: index as u32, value })
+            .collect();
+
+        TestTransaction { raw, txid, outputs, fee }
+    }
+
+    pub fn output(&self, index: usize) -> OutPoint {
+        self.outputs[index]
+    }
+
+    /// Returns this transaction as version 2, which enables relative locks, with the given
+    /// sequence on each input and the given lock_time
+    ///
+    /// The inputs must have empty scripts, as those created by `TestChain::spend`
+    pub fn with_lock_times(&self, sequence: u32, lock_time: u32) -> TestTransaction {
+
+        let mut raw = self.raw.clone();
+        raw[0] = 2;
+
+        // the version, the input count, and per input the outpoint and the script length
+        for input in 0..raw[4] as usize {
+            let pos = 5 + input * 41 + 37;
+            raw[pos..pos + 4].copy_from_slice(&sequence.to_le_bytes());
+        }
+        let len = raw.len();
+        raw[len - 4..].copy_from_slice(&lock_time.to_le_bytes());
+
+        let values: Vec<u64> = self.outputs.iter().map(|output| output.value).collect();
+        TestTransaction::new(raw, &values, self.fee)
+    }
+}
+
+
+/// Index of a block in a `TestChain`
+pub type BlockId = usize;
+
+#[derive(Debug, Clone)]
+pub struct TestBlock {
+    pub raw:      Vec<u8>,
+    pub hash:     [u8; 32],
+    pub height:   u64,
+    pub parent:   Option<BlockId>,
+
+    /// The first coinbase of the block, if any
+    pub coinbase: Option<TestTransaction>
+}
+
+impl TestBlock {
+    pub fn header(&self) -> &[u8] {
+        &self.raw[0..80]
+    }
+
+    fn time(&self) -> u32 {
+        let t = &self.raw[68..72];
+        t[0] as u32 | (t[1] as u32) << 8 | (t[2] as u32) << 16 | (t[3] as u32) << 24
+    }
+}
+
+
+/// A tree of blocks starting at the bitcoin genesis block
+pub struct TestChain {
+    blocks: Vec<TestBlock>
+}
+
+impl TestChain {
+
+    pub fn new() -> TestChain {
+
+        let raw      = from_hex(GENESIS);
+        let coinbase = TestTransaction::new(raw[81..].to_vec(), &[INITIAL_SUBSIDY], 0);
+
+        TestChain {
+            blocks: vec![TestBlock {
+                hash:     *Hash32Buf::double_sha256(&raw[0..80]).as_ref().0,
+                raw,
+                height:   0,
+                parent:   None,
+                coinbase: Some(coinbase)
+            }]
+        }
+    }
+
+    pub fn genesis(&self) -> BlockId {
+        0
+    }
+
+    pub fn block(&self, id: BlockId) -> &TestBlock {
+        &self.blocks[id]
+    }
+
+    /// Returns the blocks from genesis up to and including `tip`
+    pub fn chain(&self, tip: BlockId) -> Vec<&TestBlock> {
+
+        let mut result = vec![&self.blocks[tip]];
+        while let Some(parent) = result[result.len() - 1].parent {
+            result.push(&self.blocks[parent]);
+        }
+        result.reverse();
+        result
+    }
+
+    /// Creates a transaction spending `inputs` to OP_TRUE outputs with the given values
+    pub fn spend(&self, inputs: &[OutPoint], outputs: &[u64]) -> TestTransaction {
+        self.spend_with_script(inputs, outputs, &[OP_TRUE])
+    }
+
+    /// Creates a transaction spending `inputs` to outputs with the given values and script
+    ///
+    /// The script must leave true on the stack when it is spent with an empty script
+    pub fn spend_with_script(&self, inputs: &[OutPoint], outputs: &[u64], pk_script: &[u8])
+        -> TestTransaction {
+
+        let mut raw = vec![1, 0, 0, 0];
+
+        write_compact_size(&mut raw, inputs.len());
+        for input in inputs {
+            raw.extend_from_slice(&input.txid);
+            raw.extend_from_slice(&input.index.to_le_bytes());
+            raw.push(0);                          // empty script
+            raw.extend_from_slice(&[0xFF; 4]);    // sequence
+        }
+        write_outputs(&mut raw, outputs, pk_script);
+        raw.extend_from_slice(&[0; 4]);           // lock_time
+
+        let input_value:  u64 = inputs.iter().map(|input| input.value).sum();
+        let output_value: u64 = outputs.iter().sum();
+
+        TestTransaction::new(raw, outputs, input_value.saturating_sub(output_value))
+    }
+
+    /// Starts building a block on top of `parent`
+    pub fn build_block(&mut self, parent: BlockId) -> BlockBuilder<'_> {
+        BlockBuilder {
+            chain:        self,
+            parent,
+            transactions: vec![],
+            violations:   vec![]
+        }
+    }
+
+    /// Adds a valid block with the given transactions on top of `parent`
+    pub fn add_block(&mut self, parent: BlockId, transactions: Vec<TestTransaction>) -> BlockId {
+
+        let mut builder = self.build_block(parent);
+        for tx in transactions {
+            builder = builder.transaction(tx);
+        }
+        builder.add()
+    }
+
+    /// Adds `count` blocks that only contain a coinbase on top of `parent`; returns the last
+    pub fn extend(&mut self, parent: BlockId, count: usize) -> BlockId {
+
+        (0..count).fold(parent, |tip, _| self.add_block(tip, vec![]))
+    }
+
+    // Creates a coinbase that is unique in this chain
+    fn coinbase(&self, height: u64, value: u64) -> TestTransaction {
+
+        let mut raw = vec![1, 0, 0, 0, 1];
+        raw.extend_from_slice(&[0; 32]);
+        raw.extend_from_slice(&[0xFF; 4]);
+
+        // push the height and the block number as extra-nonce
+        raw.push(10);
+        raw.push(4);
+        raw.extend_from_slice(&(height as u32).to_le_bytes());
+        raw.push(4);
+        raw.extend_from_slice(&(self.blocks.len() as u32).to_le_bytes());
+        raw.extend_from_slice(&[0xFF; 4]);
+
+        write_outputs(&mut raw, &[value], &[OP_TRUE]);
+        raw.extend_from_slice(&[0; 4]);
+
+        TestTransaction::new(raw, &[value], 0)
+    }
+}
+
+impl Default for TestChain {
+    fn default() -> TestChain {
+        TestChain::new()
+    }
+}
+
+
+/// Builds a block on top of a block of the `TestChain`
+pub struct BlockBuilder<'a> {
+    chain:        &'a mut TestChain,
+    parent:       BlockId,
+    transactions: Vec<TestTransaction>,
+    violations:   Vec<Violation>
+}
+
+impl<'a> BlockBuilder<'a> {
+
+    pub fn transaction(mut self, tx: TestTransaction) -> BlockBuilder<'a> {
+        self.transactions.push(tx);
+        self
+    }
+
+    pub fn violate(mut self, violation: Violation) -> BlockBuilder<'a> {
+        self.violations.push(violation);
+        self
+    }
+
+    /// Creates the block and adds it to the chain
+    pub fn add(self) -> BlockId {
+
+        let BlockBuilder { chain, parent: parent_id, transactions: txs, violations } = self;
+
+        let parent = &chain.blocks[parent_id];
+        let height = parent.height + 1;
+        let time   = parent.time() + BLOCK_INTERVAL;
+
+        let violated = |v| violations.contains(&v);
+
+        let fees: u64 = txs.iter().map(|tx| tx.fee).sum();
+        let value     = subsidy(height) + fees
+            + if violated(Violation::ExcessCoinbaseAmount) { 1 } else { 0 };
+
+        let coinbase = if violated(Violation::NoCoinbase) {
+            None
+        } else {
+            Some(chain.coinbase(height, value))
+        };
+
+        let mut transactions: Vec<&TestTransaction> = coinbase.iter()
+            .chain(txs.iter())
+            .collect();
+
+        let second_coinbase = chain.coinbase(height, value);
+        if violated(Violation::DoubleCoinbase) {
+            transactions.push(&second_coinbase);
+        }
+
+        let mut merkle_root = if transactions.is_empty() {
+            Hash32Buf::from_slice(&[0; 32])
+        } else {
+            merkle_tree::get_merkle_root(transactions.iter()
+                .map(|tx| Hash32Buf::from_slice(&tx.txid))
+                .collect())
+        };
+        if violated(Violation::BadMerkleRoot) {
+            let mut root = *merkle_root.as_ref().0;
+            root[0] ^= 1;
+            merkle_root = Hash32Buf::from_slice(&root);
+        }
+
+        let mut raw = vec![1, 0, 0, 0];
+        raw.extend_from_slice(&parent.hash);
+        raw.extend_from_slice(merkle_root.as_ref().0);
+        raw.extend_from_slice(&time.to_le_bytes());
+        raw.extend_from_slice(&REGTEST_BITS.to_le_bytes());
+        raw.extend_from_slice(&[0; 4]);
+
+        let hash = mine(&mut raw, !violated(Violation::BadProofOfWork));
+
+        write_compact_size(&mut raw, transactions.len());
+        for tx in transactions {
+            raw.extend_from_slice(&tx.raw);
+        }
+
+        chain.blocks.push(TestBlock {
+            raw,
+            hash,
+            height,
+            parent: Some(parent_id),
+            coinbase
+        });
+        chain.blocks.len() - 1
+    }
+}
+
+
+fn subsidy(height: u64) -> u64 {
+    let halvings = height / SUBSIDY_HALVING_INTERVAL;
+    if halvings >= 64 {
+        0
+    } else {
+        INITIAL_SUBSIDY >> halvings
+    }
+}
+
+fn write_outputs(raw: &mut Vec<u8>, values: &[u64], pk_script: &[u8]) {
+    write_compact_size(raw, values.len());
+    for value in values {
+        raw.extend_from_slice(&value.to_le_bytes());
+        write_compact_size(raw, pk_script.len());
+        raw.extend_from_slice(pk_script);
+    }
+}
+
+// Grinds the nonce until the proof of work is valid, or invalid if `valid` is false
+fn mine(header: &mut [u8], valid: bool) -> [u8; 32] {
+
+    for nonce in 0u32.. {
+        header[76..80].copy_from_slice(&nonce.to_le_bytes());
+
+        let hash = Hash32Buf::double_sha256(&header[0..80]);
+        if pow::verify_proof_of_work(hash.as_ref(), REGTEST_BITS) == valid {
+            return *hash.as_ref().0;
+        }
+    }
+    unreachable!()
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use block::{Block, BlockError};
+    use block_add;
+    use buffer::*;
+    use header_add;
+    use scheduler::BlockProgress;
+    use store;
+    use transaction::Transaction;
+
+    fn validate(store: &mut store::Store, block: &TestBlock) -> Result<(), BlockError> {
+        block_add::validate_block(store, &block.raw, &BlockProgress::new())
+    }
+
+    #[test]
+    fn test_chain_with_spends_and_fork() {
+
+        let mut store = store::Store::new(&test_cfg!().with_network(pow::Network::Regtest));
+        let mut chain = TestChain::new();
+
+        let genesis  = chain.genesis();
+        let block1   = chain.add_block(genesis, vec![]);
+        let matured  = chain.extend(block1, 99);
+
+        let coinbase = chain.block(block1).coinbase.as_ref().unwrap().output(0);
+        let tx1      = chain.spend(&[coinbase], &[10 * COIN, 39 * COIN]);
+        let tx2      = chain.spend(&[tx1.output(0)], &[9 * COIN]);
+        let tip      = chain.add_block(matured, vec![tx1.clone(), tx2]);
+
+        // the coinbase collects the fees
+        let block = chain.block(tip);
+        assert_eq!(block.height, 101);
+        assert_eq!(block.coinbase.as_ref().unwrap().output(0).value, 50 * COIN + 2 * COIN);
+
+        let parsed = Block::new(&block.raw).unwrap();
+        assert_eq!(parsed.txs.len(), 3);
+
+        // a fork on the matured block that spends the same coinbase
+        let fork_tx = chain.spend(&[coinbase], &[50 * COIN]);
+        let fork    = chain.add_block(matured, vec![fork_tx]);
+        assert_eq!(chain.block(fork).parent, Some(matured));
+
+        for block in chain.chain(tip) {
+            header_add::add_header(&mut store, block.header()).unwrap();
+            validate(&mut store, block).unwrap();
+        }
+        validate(&mut store, chain.block(fork)).unwrap();
+
+        let hash = Hash32Buf::from_slice(&chain.block(tip).hash);
+        assert_eq!(store.headers.get_best().unwrap().hash(), hash);
+    }
+
+    #[test]
+    fn test_violations() {
+
+        let mut store = store::Store::new(&test_cfg!().with_network(pow::Network::Regtest));
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+
+        validate(&mut store, chain.block(genesis)).unwrap();
+
+        let bad_merkle = chain.build_block(genesis).violate(Violation::BadMerkleRoot).add();
+        assert_eq!(validate(&mut store, chain.block(bad_merkle)), Err(BlockError::IncorrectMerkleRoot));
+
+        let bad_pow = chain.build_block(genesis).violate(Violation::BadProofOfWork).add();
+        header_add::add_header(&mut store, chain.block(genesis).header()).unwrap();
+        assert_eq!(header_add::add_header(&mut store, chain.block(bad_pow).header()),
+            Err(header_add::HeaderError::InvalidProofOfWork));
+
+        let excess = chain.build_block(genesis).violate(Violation::ExcessCoinbaseAmount).add();
+        assert_eq!(chain.block(excess).coinbase.as_ref().unwrap().output(0).value, 50 * COIN + 1);
+
+        let no_coinbase = chain.build_block(genesis).violate(Violation::NoCoinbase).add();
+        assert_eq!(Block::new(&chain.block(no_coinbase).raw).unwrap().txs.len(), 0);
+
+        let double = chain.build_block(genesis).violate(Violation::DoubleCoinbase).add();
+        let block  = Block::new(&chain.block(double).raw).unwrap();
+        assert!(block.txs.iter().all(|tx| tx.is_coinbase()));
+        assert_eq!(block.txs.len(), 2);
+    }
+
+    #[test]
+    fn test_spend() {
+
+        let chain    = TestChain::new();
+        let coinbase = chain.block(chain.genesis()).coinbase.as_ref().unwrap().output(0);
+        let tx       = chain.spend(&[coinbase], &[20 * COIN, 30 * COIN]);
+
+        let parsed = Transaction::parse(&mut Buffer::new(&tx.raw)).unwrap();
+        parsed.verify_syntax().unwrap();
+        assert_eq!(parsed.txs_in[0].prev_tx_out.0, &coinbase.txid);
+        assert_eq!(tx.fee, 0);
+        assert_eq!(tx.output(1).value, 30 * COIN);
+    }
+}
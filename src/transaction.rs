@@ -21,9 +21,21 @@ use store::TxIndex;
 
 const MAX_TRANSACTION_SIZE: usize = 1_000_000;
 
-#[derive(Debug)]
+// lock_time values below this are interpreted as height, otherwise as unix time
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+// An input with this sequence number opts out of lock_time
+const SEQUENCE_FINAL: u32 = 0xFFFF_FFFF;
+
+// BIP68 relative lock-time encoding in the sequence number
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+const SEQUENCE_LOCKTIME_TYPE_FLAG:    u32 = 1 << 22;
+const SEQUENCE_LOCKTIME_MASK:         u32 = 0x0000_FFFF;
+const SEQUENCE_LOCKTIME_GRANULARITY:  u32 = 9;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum TransactionError {
-    UnexpectedEndOfData,
+    ParseError(ParseError),
     TransactionTooLarge,
     NoInputs,
     NoOutputs,
@@ -32,8 +44,27 @@ pub enum TransactionError {
     OutputTransactionNotFound,
     OutputIndexNotFound,
 
-    ScriptError(i32)
+    ScriptError(i32),
+
+    /// The absolute lock_time (by height or median time past) is not yet reached
+    LockTimeNotFinal,
+
+    /// A BIP68 relative lock by height is not yet reached
+    SequenceLockHeightNotMet,
+
+    /// A BIP68 relative lock by time is not yet reached
+    SequenceLockTimeNotMet,
+}
+
+/// A BIP68 relative lock-time as encoded in the sequence number of an input
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelativeLock {
+    /// The spent output must be confirmed at least this number of blocks
+    Blocks(u32),
 
+    /// The median time past must have advanced this number of seconds since
+    /// the block before the one confirming the spent output
+    Seconds(u32)
 }
 
 #[derive(Debug)]
@@ -51,10 +82,10 @@ pub enum TransactionOk {
 
 type TransactionResult<T> = Result<T, TransactionError>;
 
-impl From<EndOfBufferError> for TransactionError {
-    fn from(_: EndOfBufferError) -> TransactionError {
+impl From<ParseError> for TransactionError {
+    fn from(err: ParseError) -> TransactionError {
 
-        TransactionError::UnexpectedEndOfData
+        TransactionError::ParseError(err)
 
     }
 }
@@ -81,16 +112,23 @@ pub struct Transaction<'a> {
 
 impl<'a> Parse<'a> for Transaction<'a> {
 
+    // version, an input, an output and lock_time
+    const MIN_SIZE: usize = 4 + 1 + TxInput::MIN_SIZE + 1 + TxOutput::MIN_SIZE + 4;
+
     /// Parses the raw bytes into individual fields
     /// and perform basic syntax checks
-    fn parse(buffer: &mut Buffer<'a>) -> Result<Transaction<'a>, EndOfBufferError> {
+    fn parse(buffer: &mut Buffer<'a>) -> Result<Transaction<'a>, ParseError> {
 
         let org_buffer = *buffer;
 
-        let version         = i32::parse(buffer)?;
-        let txs_in          = Vec::parse(buffer)?;
-        let (txs_out,idxs)  = buffer.parse_vec_with_indices(org_buffer)?;
-        let lock_time       = u32::parse(buffer)?;
+        let (version, txs_in, txs_out, idxs, lock_time) = buffer.parse_structure("transaction", |buffer| {
+            let version         = i32::parse(buffer)?;
+            let txs_in          = Vec::parse(buffer)?;
+            let (txs_out,idxs)  = buffer.parse_vec_with_indices(org_buffer)?;
+            let lock_time       = u32::parse(buffer)?;
+
+            Ok((version, txs_in, txs_out, idxs, lock_time))
+        })?;
 
         Ok(Transaction {
             version:   version,
@@ -143,6 +181,55 @@ impl<'a> Transaction<'a> {
         self.txs_in.len() == 1 && self.txs_in[0].prev_tx_out.is_null()
     }
 
+    /// Checks the absolute lock_time of the transaction against the block it is included in
+    ///
+    /// `height` is the height of that block and `median_time_past` is the median time past
+    /// of its previous block (BIP113)
+    pub fn verify_lock_time(&self, height: u64, median_time_past: u32) -> TransactionResult<()> {
+
+        if self.lock_time == 0 {
+            return Ok(());
+        }
+
+        let reached = if self.lock_time < LOCKTIME_THRESHOLD {
+            (self.lock_time as u64) < height
+        } else {
+            self.lock_time < median_time_past
+        };
+
+        // lock_time is ignored if all inputs are final
+        if reached || self.txs_in.iter().all(|tx_in| tx_in.sequence == SEQUENCE_FINAL) {
+            Ok(())
+        }
+        else {
+            Err(TransactionError::LockTimeNotFinal)
+        }
+    }
+
+    /// Returns the BIP68 relative locks of the inputs as (input-index, lock) pairs
+    ///
+    /// Relative locks only apply to transactions of version 2 and up
+    pub fn get_relative_locks(&self) -> Vec<(usize, RelativeLock)> {
+
+        if self.version < 2 || self.is_coinbase() {
+            return vec![];
+        }
+
+        self.txs_in.iter()
+            .enumerate()
+            .filter(|&(_, tx_in)| tx_in.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG == 0)
+            .map(|(idx, tx_in)| {
+                let value = tx_in.sequence & SEQUENCE_LOCKTIME_MASK;
+
+                (idx, if tx_in.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+                    RelativeLock::Seconds(value << SEQUENCE_LOCKTIME_GRANULARITY)
+                } else {
+                    RelativeLock::Blocks(value)
+                })
+            })
+            .collect()
+    }
+
 
 
     /// Reverse script validation
@@ -157,7 +244,8 @@ impl<'a> Transaction<'a> {
             debug_assert!(input_ptr.is_guard());
 
             // read tx from disk
-            let tx_raw_vec   = tx_store.read(*input_ptr);
+            let tx_raw_vec   = tx_store.read(*input_ptr)
+                    .expect("Transaction waiting for its input is pruned");
             let mut tx_raw   = Buffer::new(tx_raw_vec.as_slice());
 
             let tx           = Transaction::parse(&mut tx_raw).
@@ -343,14 +431,18 @@ pub struct TxInput<'a> {
 
 
 impl<'a> Parse<'a> for TxInput<'a> {
-    fn parse(buffer: &mut Buffer<'a>) -> Result<TxInput<'a>, EndOfBufferError> {
 
-        Ok(TxInput {
+    // prev_tx_out, index, script length and sequence
+    const MIN_SIZE: usize = 32 + 4 + 1 + 4;
+
+    fn parse(buffer: &mut Buffer<'a>) -> Result<TxInput<'a>, ParseError> {
+
+        buffer.parse_structure("tx-input", |buffer| Ok(TxInput {
             prev_tx_out: Hash32::parse(buffer)?,
             prev_tx_out_idx: u32::parse(buffer)?,
             script: buffer.parse_compact_size_bytes()?,
             sequence: u32::parse(buffer)?
-        })
+        }))
 
     }
 
@@ -362,15 +454,34 @@ pub struct TxOutput<'a> {
     pk_script: &'a[u8]
 }
 
+impl<'a> TxOutput<'a> {
+
+    /// Returns true if the output can never be spent, as its script starts with OP_RETURN
+    pub fn is_unspendable(&self) -> bool {
+        self.pk_script.first() == Some(&0x6a)
+    }
+
+    pub fn value(&self) -> i64 {
+        self.value
+    }
+
+    pub fn pk_script(&self) -> &'a [u8] {
+        self.pk_script
+    }
+}
+
 impl<'a> Parse<'a> for TxOutput<'a> {
 
-    fn parse(buffer: &mut Buffer<'a>) -> Result<TxOutput<'a>, EndOfBufferError> {
+    // value and script length
+    const MIN_SIZE: usize = 8 + 1;
+
+    fn parse(buffer: &mut Buffer<'a>) -> Result<TxOutput<'a>, ParseError> {
 
-        Ok(TxOutput {
+        buffer.parse_structure("tx-output", |buffer| Ok(TxOutput {
             value:      i64::parse(buffer)?,
             pk_script:  buffer.parse_compact_size_bytes()?
 
-        })
+        }))
     }
 }
 
@@ -496,4 +607,76 @@ mod tests {
 
         let _ = format!("{:?}", tx);
     }
+
+    #[test]
+    fn test_parse_errors() {
+        tx_builder!(bld);
+
+        let tx  = tx!(bld; coinbase => a);
+        let len = tx.len();
+
+        // truncated in the pk_script of the output, before the lock_time
+        let err = Transaction::parse(&mut Buffer::new(&tx[0..len-5])).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::EndOfBuffer);
+        assert_eq!(err.structure, Some("tx-output"));
+
+        // an input count that can't fit in the transaction
+        let mut invalid = tx.clone();
+        invalid[4] = 2;
+        let err = Transaction::parse(&mut Buffer::new(&invalid)).unwrap_err();
+        assert_eq!(err, ParseError {
+            kind:      ParseErrorKind::CountTooLarge(2),
+            offset:    4,
+            structure: Some("transaction")
+        });
+    }
+
+    #[test]
+    fn test_lock_time() {
+        tx_builder!(bld);
+
+        let _  = tx!(bld; coinbase => a);
+        let tx = tx!(bld; a => b);
+
+        // set lock_time to height 100
+        let mut locked = tx.clone();
+        let len = locked.len();
+        locked[len-4] = 100;
+
+        let tx = Transaction::parse(&mut Buffer::new(&tx)).unwrap();
+        let locked = Transaction::parse(&mut Buffer::new(&locked)).unwrap();
+
+        assert!(tx.verify_lock_time(1, 0).is_ok());
+        assert!(locked.verify_lock_time(101, 0).is_ok());
+
+        match locked.verify_lock_time(100, 0) {
+            Err(TransactionError::LockTimeNotFinal) => {},
+            r => panic!("Expected LockTimeNotFinal, got {:?}", r)
+        }
+    }
+
+    #[test]
+    fn test_relative_locks() {
+        tx_builder!(bld);
+
+        let _ = tx!(bld; coinbase => a, b, c);
+
+        // a: 10 blocks, b: 2*512 seconds, c: disabled
+        bld.get_mut("a").unwrap()[37..41].copy_from_slice(&[10, 0, 0, 0]);
+        bld.get_mut("b").unwrap()[37..41].copy_from_slice(&[2, 0, 0x40, 0]);
+        bld.get_mut("c").unwrap()[37..41].copy_from_slice(&[2, 0, 0, 0x80]);
+
+        let mut tx = tx!(bld; a,b,c => d);
+
+        // relative locks are ignored for version 1
+        let v1 = Transaction::parse(&mut Buffer::new(&tx)).unwrap();
+        assert!(v1.get_relative_locks().is_empty());
+
+        tx[0] = 2;
+        let v2 = Transaction::parse(&mut Buffer::new(&tx)).unwrap();
+        assert_eq!(v2.get_relative_locks(), vec![
+            (0, RelativeLock::Blocks(10)),
+            (1, RelativeLock::Seconds(1024))
+        ]);
+    }
 }
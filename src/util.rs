@@ -36,6 +36,23 @@ pub fn from_hex(str: &str) -> Vec<u8> {
     b.into_iter().collect()
 }
 
+/// Appends the size in bitcoin's variable length encoding
+pub fn write_compact_size(buf: &mut Vec<u8>, size: usize) {
+    let size = size as u64;
+    if size < 0xfd {
+        buf.push(size as u8);
+    } else if size <= 0xffff {
+        buf.push(0xfd);
+        buf.extend_from_slice(&(size as u16).to_le_bytes());
+    } else if size <= 0xffff_ffff {
+        buf.push(0xfe);
+        buf.extend_from_slice(&(size as u32).to_le_bytes());
+    } else {
+        buf.push(0xff);
+        buf.extend_from_slice(&size.to_le_bytes());
+    }
+}
+
 /// Useful to keep hashes in the same format as usually printed
 pub fn from_hex_rev(str: &str) -> Vec<u8> {
     let mut v = from_hex(str);
@@ -0,0 +1,861 @@
+//! The set of unspent outputs as of a block
+//!
+//! The spend-tree stores spends instead of unspent outputs, so the unspent outputs of the chain
+//! ending at a block are derived: the transactions of the chain are walked and each output is
+//! looked up in the spend-index.
+//!
+//! The spend-index is shared by all branches, and the spends of a block are only added once a
+//! block on top of it is connected. To restrict it to the chain, the spend-tree is scanned once
+//! for the blocks that are not in the chain; the outputs these spend are in the spend-index,
+//! and for those the chain itself is searched. The spends of the block itself are added as well.
+//!
+//! Transactions that are removed by pruning have all their outputs spent, and are skipped. The
+//! outputs are returned in the order of the chain; outputs that can never be spent are not
+//! part of the set, and neither is the coinbase of the genesis block, as in Bitcoin Core.
+//!
+//! To compare the set with Bitcoin Core without walking it, the MuHash of the set can be kept up
+//! to date in the `utxo_hashes` of the store: when a block is connected, the outputs it creates are
+//! added to the MuHash of the previous block, and the outputs it spends are removed.
+//!
+//! The set can be written in a simple export format:
+//!
+//! * the magic `bcutxo` followed by the version byte 1
+//! * the hash of the block and its height (u64)
+//! * for each output: the byte 1, the txid, the output index (u32), the height times two plus
+//!   one for a coinbase (u64), the value (i64) and the script with its compact-size length
+//! * the byte 0, the number of outputs (u64) and the sum of their values (u64)
+//!
+//! All integers are little-endian.
+
+
+use std::collections::{HashSet, VecDeque};
+use std::io;
+use std::io::{Read, Write};
+
+use buffer::*;
+use hash::*;
+use block_add;
+use muhash::MuHash3072;
+use util::write_compact_size;
+use store::{BlockPtr, Pruned, Record, RecordPtr, Store, TxPtr};
+use store::utxo_hash::{UtxoHashRecord, HEIGHT_CONFLICT};
+use transaction::{Transaction, TxOutput};
+
+
+const MAGIC: &[u8; 7] = b"bcutxo\x01";
+
+
+/// An unspent output
+#[derive(Debug, Clone, PartialEq)]
+pub struct Utxo {
+    pub txid:        [u8; 32],
+    pub index:       u32,
+
+    /// The height of the block containing the transaction
+    pub height:      u64,
+    pub is_coinbase: bool,
+
+    pub value:       i64,
+    pub pk_script:   Vec<u8>
+}
+
+impl Utxo {
+
+    fn new(txid: Hash32Buf, index: usize, height: u64, is_coinbase: bool, output: &TxOutput) -> Utxo {
+        Utxo {
+            txid:      *txid.as_ref().0,
+            index:     index as u32,
+            height,
+            is_coinbase,
+            value:     output.value(),
+            pk_script: output.pk_script().to_vec()
+        }
+    }
+
+    /// Serializes the output as Bitcoin Core does to hash its UTXO set
+    pub fn serialize(&self) -> Vec<u8> {
+
+        let mut result = self.txid.to_vec();
+        result.extend_from_slice(&self.index.to_le_bytes());
+        result.extend_from_slice(&((self.height as u32) << 1 | self.is_coinbase as u32).to_le_bytes());
+        result.extend_from_slice(&self.value.to_le_bytes());
+        write_compact_size(&mut result, self.pk_script.len());
+        result.extend_from_slice(&self.pk_script);
+        result
+    }
+}
+
+
+/// Totals of the unspent outputs as of a block, as reported by `gettxoutsetinfo`
+#[derive(Debug, Clone, PartialEq)]
+pub struct UtxoStats {
+    pub block_hash:   [u8; 32],
+    pub height:       u64,
+
+    /// Number of transactions with unspent outputs
+    pub transactions: u64,
+
+    /// Number of unspent outputs
+    pub txouts:       u64,
+
+    /// Sum of the values of the unspent outputs
+    pub total_amount: u64,
+
+    /// The MuHash of the unspent outputs, as `muhash` of `gettxoutsetinfo` in reverse
+    pub muhash:       [u8; 32]
+}
+
+impl UtxoStats {
+
+    fn new(block_hash: [u8; 32], height: u64) -> UtxoStats {
+        UtxoStats {
+            block_hash,
+            height,
+            transactions: 0,
+            txouts:       0,
+            total_amount: 0,
+            muhash:       MuHash3072::new().finalize()
+        }
+    }
+
+    // Adds an output; the outputs of a transaction must be added together
+    fn add(&mut self, utxo: &Utxo, previous: Option<&Utxo>) {
+
+        if previous.is_none_or(|prev| prev.txid != utxo.txid || prev.height != utxo.height) {
+            self.transactions += 1;
+        }
+        self.txouts       += 1;
+        self.total_amount += utxo.value as u64;
+    }
+}
+
+
+// The outputs spent in the chain, as far as they can't be taken from the spend-index
+struct ChainSpends {
+
+    // outputs spent in blocks that are not in the chain
+    other: HashSet<u64>,
+
+    // outputs of `other` that are spent in the chain as well, and the spends of the tip
+    own:   HashSet<u64>
+}
+
+impl ChainSpends {
+
+    fn new(store: &mut Store, chain: &[BlockPtr]) -> ChainSpends {
+
+        let in_chain: HashSet<u64> = chain.iter().map(|block| block.start.to_index()).collect();
+
+        let mut other = HashSet::new();
+        for block in store.spend_tree.get_blocks_from(RecordPtr::new(0)).0 {
+            if !in_chain.contains(&block.start.to_index()) {
+                other.extend(get_spends(store, block));
+            }
+        }
+
+        let mut own = HashSet::new();
+        if !other.is_empty() {
+            for &block in chain {
+                own.extend(get_spends(store, block).into_iter().filter(|hash| other.contains(hash)));
+            }
+        }
+        if let Some(&tip) = chain.last() {
+            own.extend(get_spends(store, tip));
+        }
+
+        ChainSpends { other, own }
+    }
+
+    fn is_spent(&self, store: &mut Store, hash: u64) -> bool {
+
+        if self.own.contains(&hash) {
+            return true;
+        }
+
+        // the segment may have been added by another store
+        store.spend_index.reserve(hash);
+        !self.other.contains(&hash) && store.spend_index.exists(hash)
+    }
+}
+
+
+/// Iterator over the unspent outputs as of a block
+pub struct UtxoIter<'a> {
+    store:   &'a mut Store,
+    chain:   Vec<BlockPtr>,
+    next:    usize,
+    spends:  ChainSpends,
+    pending: VecDeque<Utxo>
+}
+
+impl<'a> UtxoIter<'a> {
+
+    /// Collects the spends that are needed to iterate the unspent outputs as of `block`
+    ///
+    /// This scans the entire spend-tree
+    pub fn new(store: &'a mut Store, block: BlockPtr) -> UtxoIter<'a> {
+
+        let mut chain = vec![block];
+        while let Some(prev) = store.spend_tree.get_previous_block(chain[chain.len() - 1]) {
+            chain.push(prev);
+        }
+        chain.reverse();
+
+        let spends = ChainSpends::new(store, &chain);
+
+        UtxoIter {
+            store,
+            chain,
+            next:    0,
+            spends,
+            pending: VecDeque::new()
+        }
+    }
+
+    /// Returns the height of the block
+    pub fn height(&self) -> u64 {
+        self.chain.len() as u64 - 1
+    }
+
+    // Adds the unspent outputs of the transactions in the block at `height` to `pending`
+    fn read_block(&mut self, height: usize) {
+
+        let block = self.chain[height];
+        if height == 0 && block_add::is_genesis_block(self.store.get_block_hash(block).as_ref()) {
+            return;
+        }
+
+        let records = self.store.spend_tree.get_block_mut(block).to_vec();
+
+        for rec in records[1..records.len() - 1].iter()
+            .filter(|rec| rec.is_transaction() && !rec.is_unmatched_input()) {
+
+            self.read_transaction(rec.get_transaction_ptr(), height as u64);
+        }
+    }
+
+    fn read_transaction(&mut self, tx_ptr: TxPtr, height: u64) {
+
+        // the outputs of a pruned transaction are all spent
+        let raw = match self.store.transactions.read(tx_ptr) {
+            Ok(raw) => raw,
+            Err(_)  => return
+        };
+        let tx = Transaction::parse(&mut Buffer::new(&raw))
+            .expect("Stored transaction can be parsed");
+
+        let txid = Hash32Buf::double_sha256(&raw);
+        for (index, output) in tx.txs_out.iter().enumerate() {
+
+            // outputs with a higher index can't be stored in the spend-tree
+            if output.is_unspendable() || index > 0x3FFF {
+                continue;
+            }
+            if self.spends.is_spent(self.store, Record::new_output(tx_ptr, index as u32).hash()) {
+                continue;
+            }
+
+            self.pending.push_back(Utxo::new(txid, index, height, tx.is_coinbase(), output));
+        }
+    }
+}
+
+impl<'a> Iterator for UtxoIter<'a> {
+    type Item = Utxo;
+
+    fn next(&mut self) -> Option<Utxo> {
+
+        while self.pending.is_empty() && self.next < self.chain.len() {
+            let height = self.next;
+            self.read_block(height);
+            self.next += 1;
+        }
+        self.pending.pop_front()
+    }
+}
+
+
+// Returns the hashes of the outputs spent in a block
+fn get_spends(store: &mut Store, block: BlockPtr) -> Vec<u64> {
+
+    store.spend_tree.get_block_mut(block).iter()
+        .filter(|rec| rec.is_output())
+        .map(|rec| rec.hash())
+        .collect()
+}
+
+
+// The reasons the MuHash of a block can't be updated
+#[derive(Debug)]
+enum UtxoHashError {
+
+    // a transaction of the block, or spent by it, has been pruned
+    Pruned,
+
+    // the block containing a spent transaction is not found
+    UnknownHeight
+}
+
+impl From<Pruned> for UtxoHashError {
+    fn from(_: Pruned) -> UtxoHashError {
+        UtxoHashError::Pruned
+    }
+}
+
+/// Stores the MuHash of the unspent outputs as of a block that was just connected, if the
+/// store keeps these
+///
+/// This must be done before the block is added to the block-index, such that it is there when
+/// the next block is connected. The MuHash is updated from that of the previous block; if the
+/// previous block has none, or a spent transaction is pruned or can't be found, it is computed
+/// from all unspent outputs instead. As transactions are only pruned once all their outputs are
+/// spent deep in the best chain, a block only spends a pruned transaction after a
+/// reorganisation deeper than the prune depth
+pub fn connect_block(store: &mut Store, block: BlockPtr, block_hash: Hash32) {
+
+    if store.utxo_hashes.is_none() {
+        return;
+    }
+
+    let previous = match store.spend_tree.get_previous_block(block) {
+        None           => Some((MuHash3072::new(), 0)),
+        Some(previous) => {
+            let previous_hash = store.get_block_hash(previous);
+            store.utxo_hashes.as_mut().unwrap().get(previous_hash.as_ref(), previous)
+                .map(|record| (record.muhash(), record.height + 1))
+        }
+    };
+
+    let updated = previous.and_then(|(mut muhash, height)| {
+        match update_muhash(store, block, block_hash, height, &mut muhash) {
+            Ok(())   => Some((muhash, height)),
+            Err(err) => {
+                warn!(store.logger, "cannot update utxo hash; rebuilding";
+                    "block" => format!("{:?}", block_hash),
+                    "error" => format!("{:?}", err));
+                None
+            }
+        }
+    });
+
+    let (muhash, height) = updated.unwrap_or_else(|| rebuild_muhash(store, block));
+    store.utxo_hashes.as_mut().unwrap().add(block_hash, &UtxoHashRecord::new(block, height, &muhash));
+}
+
+// Returns the MuHash of all unspent outputs as of `block`, and its height
+//
+// This walks the entire chain. The heights of the transactions with unspent outputs and of the
+// transactions of the block are stored as well, such that the next blocks can be updated
+fn rebuild_muhash(store: &mut Store, block: BlockPtr) -> (MuHash3072, u64) {
+
+    let mut muhash  = MuHash3072::new();
+    let mut heights = vec![];
+    let height = {
+        let mut utxos = UtxoIter::new(store, block);
+        for utxo in &mut utxos {
+            muhash.insert(&utxo.serialize());
+            heights.push((utxo.txid, utxo.height));
+        }
+        utxos.height()
+    };
+
+    heights.dedup();
+    for (txid, height) in heights {
+        store.utxo_hashes.as_mut().unwrap().add_height(Hash32(&txid), height);
+    }
+
+    let tx_ptrs: Vec<TxPtr> = store.spend_tree.get_block_mut(block).iter()
+        .filter(|rec| rec.is_transaction() && !rec.is_unmatched_input())
+        .map(|rec| rec.get_transaction_ptr())
+        .collect();
+
+    for tx_ptr in tx_ptrs {
+        if let Ok(raw) = store.transactions.read(tx_ptr) {
+            store.utxo_hashes.as_mut().unwrap().add_height(Hash32Buf::double_sha256(&raw).as_ref(), height);
+        }
+    }
+    (muhash, height)
+}
+
+// Adds the outputs created by the block at `height` to the MuHash and removes those it spends
+fn update_muhash(store: &mut Store, block: BlockPtr, block_hash: Hash32, height: u64, muhash: &mut MuHash3072)
+    -> Result<(), UtxoHashError>
+{
+    let records = store.spend_tree.get_block_mut(block).to_vec();
+    let genesis = height == 0 && block_add::is_genesis_block(block_hash);
+
+    let mut in_block = HashSet::new();
+    for rec in &records[1..records.len() - 1] {
+
+        if rec.is_transaction() && !rec.is_unmatched_input() {
+            let tx_ptr = rec.get_transaction_ptr();
+            let raw    = store.transactions.read(tx_ptr)?;
+            let tx     = Transaction::parse(&mut Buffer::new(&raw))
+                .expect("Stored transaction can be parsed");
+            let txid   = Hash32Buf::double_sha256(&raw);
+
+            in_block.insert(tx_ptr);
+            store.utxo_hashes.as_mut().unwrap().add_height(txid.as_ref(), height);
+
+            if genesis {
+                continue;
+            }
+            for (index, output) in tx.txs_out.iter().enumerate() {
+                if !output.is_unspendable() && index <= 0x3FFF {
+                    muhash.insert(&Utxo::new(txid, index, height, tx.is_coinbase(), output).serialize());
+                }
+            }
+        }
+        else if rec.is_output() {
+            let tx_ptr = rec.get_transaction_ptr();
+            let raw    = store.transactions.read(tx_ptr)?;
+            let tx     = Transaction::parse(&mut Buffer::new(&raw))
+                .expect("Stored transaction can be parsed");
+            let txid   = Hash32Buf::double_sha256(&raw);
+            let index  = rec.get_output_index() as usize;
+
+            let spent_height = if in_block.contains(&tx_ptr) {
+                height
+            } else {
+                get_tx_height(store, block, height, txid.as_ref(), tx_ptr)
+                    .ok_or(UtxoHashError::UnknownHeight)?
+            };
+            let utxo = Utxo::new(txid, index, spent_height, tx.is_coinbase(), &tx.txs_out[index]);
+            muhash.remove(&utxo.serialize());
+        }
+    }
+    Ok(())
+}
+
+// Returns the height of the block before `block` that contains the transaction
+//
+// If the transaction is at different heights in different branches, the chain is searched
+fn get_tx_height(store: &mut Store, block: BlockPtr, height: u64, txid: Hash32, tx_ptr: TxPtr) -> Option<u64> {
+
+    match store.utxo_hashes.as_mut().unwrap().get_height(txid) {
+        Some(HEIGHT_CONFLICT) => {},
+        Some(height)          => return Some(height),
+        None                  => {}
+    }
+
+    let mut next   = store.spend_tree.get_previous_block(block);
+    let mut height = height;
+    while let Some(block) = next {
+        height -= 1;
+        let found = store.spend_tree.get_block_mut(block).iter()
+            .any(|rec| rec.is_transaction() && !rec.is_unmatched_input() && rec.get_transaction_ptr() == tx_ptr);
+
+        if found {
+            return Some(height);
+        }
+        next = store.spend_tree.get_previous_block(block);
+    }
+    None
+}
+
+/// Returns the MuHash of the unspent outputs as of `block`, as kept up to date when blocks are
+/// connected
+pub fn get_muhash(store: &mut Store, block: BlockPtr) -> Option<[u8; 32]> {
+
+    let hash = store.get_block_hash(block);
+    store.utxo_hashes.as_mut()?.get(hash.as_ref(), block).map(|record| record.muhash().finalize())
+}
+
+/// Returns the totals of the unspent outputs as of `block`
+pub fn get_stats(store: &mut Store, block: BlockPtr) -> UtxoStats {
+
+    // a sink can't fail
+    export(store, block, &mut io::sink()).unwrap()
+}
+
+/// Writes the unspent outputs as of `block` in the export format
+pub fn export<W: Write>(store: &mut Store, block: BlockPtr, writer: &mut W) -> io::Result<UtxoStats> {
+
+    let hash      = store.get_block_hash(block);
+    let mut utxos = UtxoIter::new(store, block);
+    let mut stats = UtxoStats::new(*hash.as_ref().0, utxos.height());
+
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&stats.block_hash);
+    header.extend_from_slice(&stats.height.to_le_bytes());
+    writer.write_all(&header)?;
+
+    let mut muhash = MuHash3072::new();
+    let mut previous: Option<Utxo> = None;
+    for utxo in &mut utxos {
+        writer.write_all(&write_utxo(&utxo))?;
+        stats.add(&utxo, previous.as_ref());
+        muhash.insert(&utxo.serialize());
+        previous = Some(utxo);
+    }
+    stats.muhash = muhash.finalize();
+
+    let mut trailer = vec![0];
+    trailer.extend_from_slice(&stats.txouts.to_le_bytes());
+    trailer.extend_from_slice(&stats.total_amount.to_le_bytes());
+    writer.write_all(&trailer)?;
+
+    Ok(stats)
+}
+
+fn write_utxo(utxo: &Utxo) -> Vec<u8> {
+
+    let mut result = vec![1];
+    result.extend_from_slice(&utxo.txid);
+    result.extend_from_slice(&utxo.index.to_le_bytes());
+    result.extend_from_slice(&(utxo.height * 2 + utxo.is_coinbase as u64).to_le_bytes());
+    result.extend_from_slice(&utxo.value.to_le_bytes());
+    write_compact_size(&mut result, utxo.pk_script.len());
+    result.extend_from_slice(&utxo.pk_script);
+    result
+}
+
+
+/// Reads the unspent outputs from the export format
+///
+/// The totals are checked against the trailer when the end is reached
+pub struct UtxoReader<R: Read> {
+    reader:   R,
+    stats:    UtxoStats,
+    muhash:   MuHash3072,
+    previous: Option<Utxo>,
+    done:     bool
+}
+
+impl<R: Read> UtxoReader<R> {
+
+    /// Reads the header; fails if it is not in the export format
+    pub fn new(mut reader: R) -> io::Result<UtxoReader<R>> {
+
+        let mut magic = [0; 7];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("Not a utxo export"));
+        }
+
+        let mut block_hash = [0; 32];
+        reader.read_exact(&mut block_hash)?;
+        let height = read_u64(&mut reader)?;
+
+        Ok(UtxoReader {
+            reader,
+            stats:    UtxoStats::new(block_hash, height),
+            muhash:   MuHash3072::new(),
+            previous: None,
+            done:     false
+        })
+    }
+
+    /// Returns the totals of the outputs read so far; the MuHash is set when the end is reached
+    pub fn stats(&self) -> &UtxoStats {
+        &self.stats
+    }
+
+    fn read_utxo(&mut self) -> io::Result<Option<Utxo>> {
+
+        let mut tag = [0];
+        self.reader.read_exact(&mut tag)?;
+
+        match tag[0] {
+            0 => {
+                let txouts = read_u64(&mut self.reader)?;
+                let total  = read_u64(&mut self.reader)?;
+                if txouts != self.stats.txouts || total != self.stats.total_amount {
+                    return Err(invalid_data("Utxo export doesn't match its totals"));
+                }
+                Ok(None)
+            },
+            1 => {
+                let mut txid = [0; 32];
+                self.reader.read_exact(&mut txid)?;
+                let index  = read_u32(&mut self.reader)?;
+                let code   = read_u64(&mut self.reader)?;
+                let value  = read_u64(&mut self.reader)? as i64;
+                let len    = read_compact_size(&mut self.reader)?;
+
+                let mut pk_script = vec![];
+                (&mut self.reader).take(len).read_to_end(&mut pk_script)?;
+                if pk_script.len() as u64 != len {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+
+                Ok(Some(Utxo {
+                    txid,
+                    index,
+                    height:      code / 2,
+                    is_coinbase: code % 2 == 1,
+                    value,
+                    pk_script
+                }))
+            },
+            _ => Err(invalid_data("Invalid utxo record"))
+        }
+    }
+}
+
+impl<R: Read> Iterator for UtxoReader<R> {
+    type Item = io::Result<Utxo>;
+
+    fn next(&mut self) -> Option<io::Result<Utxo>> {
+
+        if self.done {
+            return None;
+        }
+        match self.read_utxo() {
+            Ok(Some(utxo)) => {
+                self.stats.add(&utxo, self.previous.as_ref());
+                self.muhash.insert(&utxo.serialize());
+                self.previous = Some(utxo.clone());
+                Some(Ok(utxo))
+            },
+            Ok(None) => {
+                self.stats.muhash = self.muhash.finalize();
+                self.done = true;
+                None
+            },
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_compact_size<R: Read>(reader: &mut R) -> io::Result<u64> {
+
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    Ok(match byte[0] {
+        0xff => read_u64(reader)?,
+        0xfe => read_u32(reader)? as u64,
+        0xfd => {
+            let mut buf = [0; 2];
+            reader.read_exact(&mut buf)?;
+            u16::from_le_bytes(buf) as u64
+        },
+        n => n as u64
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use block_add;
+    use export;
+    use store::utxo_hash::UtxoHashIndex;
+    use test_chain::TestChain;
+
+    fn get_utxos(store: &mut Store, hash: &[u8; 32]) -> Vec<(u64, u32, i64)> {
+
+        let block = export::get_block(store, Hash32(hash)).unwrap();
+        UtxoIter::new(store, block)
+            .map(|utxo| (utxo.height, utxo.index, utxo.value))
+            .collect()
+    }
+
+    #[test]
+    fn test_utxos() {
+
+        let mut store = Store::new(& test_cfg!());
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let base      = chain.extend(genesis, 101);
+        let coinbase1 = chain.chain(base)[1].coinbase.clone().unwrap();
+        let coinbase2 = chain.chain(base)[2].coinbase.clone().unwrap();
+        let value     = coinbase1.output(0).value;
+
+        // the main chain spends the first coinbase, a fork the second
+        let tx1       = chain.spend(&[coinbase1.output(0)], &[value / 2, value / 2]);
+        let tx2       = chain.spend(&[tx1.output(1)], &[value / 4]);
+        let tx3       = chain.spend(&[tx1.output(0)], &[value / 8]);
+        let burn      = chain.spend_with_script(&[coinbase2.output(0)], &[value], &[0x6a]);
+        let main      = chain.add_block(base, vec![tx1, tx2]);
+        let main_tip  = chain.add_block(main, vec![tx3]);
+        let fork      = chain.add_block(base, vec![burn]);
+        let fork_tip  = chain.extend(fork, 1);
+
+        for block in chain.chain(main_tip) {
+            block_add::add_block(&mut store, &block.raw);
+        }
+        block_add::add_block(&mut store, &chain.block(fork).raw);
+        block_add::add_block(&mut store, &chain.block(fork_tip).raw);
+
+        let value = value as i64;
+
+        // every coinbase of the chain up to and including the block, except that of genesis
+        let base_utxos = get_utxos(&mut store, &chain.block(base).hash);
+        assert_eq!(base_utxos.len(), 101);
+        assert!(base_utxos.iter().enumerate().all(|(n, &utxo)| utxo == (n as u64 + 1, 0, value)));
+
+        // a spend within the block
+        let main_utxos = get_utxos(&mut store, &chain.block(main).hash);
+        assert_eq!(main_utxos.len(), 103);
+        assert!(!main_utxos.contains(&(1, 0, value)));
+        assert!(main_utxos.contains(&(2, 0, value)));
+        assert!(main_utxos.contains(&(102, 0, value / 2)));
+        assert!(!main_utxos.contains(&(102, 1, value / 2)));
+        assert!(main_utxos.contains(&(102, 0, value / 4)));
+
+        // the spends of the tip are not yet in the spend-index
+        let tip_utxos = get_utxos(&mut store, &chain.block(main_tip).hash);
+        assert_eq!(tip_utxos.len(), 104);
+        assert!(!tip_utxos.contains(&(102, 0, value / 2)));
+        assert!(tip_utxos.contains(&(103, 0, value / 8)));
+
+        // the spends of the main chain are in the spend-index, but not in this chain; the
+        // output that is burnt is not part of the set
+        let fork_utxos = get_utxos(&mut store, &chain.block(fork_tip).hash);
+        assert_eq!(fork_utxos.len(), 102);
+        assert!(fork_utxos.contains(&(1, 0, value)));
+        assert!(!fork_utxos.contains(&(2, 0, value)));
+    }
+
+    #[test]
+    fn test_muhash() {
+
+        let mut store = Store::new(& test_cfg!().with_utxo_hash(true));
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let base      = chain.extend(genesis, 101);
+        let coinbase  = chain.chain(base)[1].coinbase.clone().unwrap();
+        let value     = coinbase.output(0).value;
+
+        // the transaction is in a fork at height 102 and in the main chain at height 103, where
+        // it is spent in the same block and in the next
+        let tx1       = chain.spend(&[coinbase.output(0)], &[value / 2, value / 2]);
+        let tx2       = chain.spend(&[tx1.output(0)], &[value / 2]);
+        let tx3       = chain.spend(&[tx1.output(1)], &[value / 2]);
+        let fork      = chain.add_block(base, vec![tx1.clone()]);
+        let main      = chain.extend(base, 1);
+        let main2     = chain.add_block(main, vec![tx1.clone(), tx2]);
+        let main3     = chain.add_block(main2, vec![tx3]);
+
+        for block in chain.chain(base) {
+            block_add::add_block(&mut store, &block.raw);
+        }
+        for &id in &[fork, main, main2, main3] {
+            block_add::add_block(&mut store, &chain.block(id).raw);
+        }
+        assert_eq!(store.utxo_hashes.as_mut().unwrap().get_height(Hash32(&tx1.txid)), Some(HEIGHT_CONFLICT));
+
+        // the MuHash kept per block is the MuHash of the full set
+        let mut hashes = vec![];
+        for &id in &[base, fork, main, main2, main3] {
+            let block  = export::get_block(&mut store, Hash32(&chain.block(id).hash)).unwrap();
+            let muhash = get_muhash(&mut store, block).unwrap();
+
+            assert_eq!(muhash, get_stats(&mut store, block).muhash);
+            hashes.push(muhash);
+        }
+        assert_eq!(hashes.iter().collect::<HashSet<_>>().len(), 5);
+    }
+
+    #[test]
+    fn test_muhash_rebuilt() {
+
+        let cfg       = test_cfg!();
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let base      = chain.extend(genesis, 101);
+        let coinbase  = chain.chain(base)[1].coinbase.clone().unwrap();
+        let tx        = chain.spend(&[coinbase.output(0)], &[coinbase.output(0).value]);
+        let main      = chain.add_block(base, vec![tx]);
+        let tip       = chain.extend(main, 1);
+
+        // the store doesn't keep the MuHash until it is enabled
+        {
+            let mut store = Store::new(&cfg);
+            for block in chain.chain(main) {
+                block_add::add_block(&mut store, &block.raw);
+            }
+            let main_block = export::get_block(&mut store, Hash32(&chain.block(main).hash)).unwrap();
+            assert!(get_muhash(&mut store, main_block).is_none());
+            assert!(!UtxoHashIndex::exists(&cfg));
+        }
+
+        // as the previous block has no MuHash, it is computed from all unspent outputs, and the
+        // heights of their transactions are stored
+        let mut store = Store::new(&cfg.clone().with_utxo_hash(true));
+        block_add::add_block(&mut store, &chain.block(tip).raw);
+
+        let tip_block = export::get_block(&mut store, Hash32(&chain.block(tip).hash)).unwrap();
+        assert_eq!(get_muhash(&mut store, tip_block).unwrap(), get_stats(&mut store, tip_block).muhash);
+
+        let base_coinbase = chain.block(base).coinbase.clone().unwrap();
+        let utxo_hashes   = store.utxo_hashes.as_mut().unwrap();
+        assert_eq!(utxo_hashes.get(Hash32(&chain.block(tip).hash), tip_block).unwrap().height, 103);
+        assert_eq!(utxo_hashes.get_height(Hash32(&base_coinbase.txid)), Some(101));
+
+        // the next block is updated from it
+        let coinbase2 = chain.chain(base)[2].coinbase.clone().unwrap();
+        let tx2       = chain.spend(&[coinbase2.output(0)], &[coinbase2.output(0).value]);
+        let next      = chain.add_block(tip, vec![tx2]);
+        block_add::add_block(&mut store, &chain.block(next).raw);
+
+        let next_block = export::get_block(&mut store, Hash32(&chain.block(next).hash)).unwrap();
+        assert_eq!(get_muhash(&mut store, next_block).unwrap(), get_stats(&mut store, next_block).muhash);
+    }
+
+    #[test]
+    fn test_export() {
+
+        let mut store = Store::new(& test_cfg!());
+
+        let mut chain = TestChain::new();
+        let genesis   = chain.genesis();
+        let base      = chain.extend(genesis, 101);
+        let coinbase  = chain.chain(base)[1].coinbase.clone().unwrap();
+        let value     = coinbase.output(0).value;
+        let tx        = chain.spend_with_script(&[coinbase.output(0)], &[1, value - 1], &[0x51; 300]);
+        let tip       = chain.add_block(base, vec![tx.clone()]);
+
+        for block in chain.chain(tip) {
+            block_add::add_block(&mut store, &block.raw);
+        }
+
+        let block    = export::get_block(&mut store, Hash32(&chain.block(tip).hash)).unwrap();
+        let mut data = vec![];
+        let stats    = export(&mut store, block, &mut data).unwrap();
+
+        assert_eq!(stats.block_hash, chain.block(tip).hash);
+        assert_eq!(stats.height, 102);
+        assert_eq!(stats.transactions, 102);
+        assert_eq!(stats.txouts, 103);
+        assert_eq!(stats.total_amount, value * 102);
+        assert_eq!(get_stats(&mut store, block), stats);
+
+        let mut reader = UtxoReader::new(&data[..]).unwrap();
+        let utxos: Vec<Utxo> = (&mut reader).map(|utxo| utxo.unwrap()).collect();
+        assert_eq!(reader.stats(), &stats);
+        assert_eq!(utxos, UtxoIter::new(&mut store, block).collect::<Vec<_>>());
+
+        let last = &utxos[utxos.len() - 1];
+        assert_eq!(last.txid, tx.txid);
+        assert_eq!((last.index, last.height, last.is_coinbase), (1, 102, false));
+        assert_eq!(last.pk_script, vec![0x51; 300]);
+
+        // a truncated export fails
+        let mut reader = UtxoReader::new(&data[..data.len() - 1]).unwrap();
+        assert!(reader.any(|utxo| utxo.is_err()));
+    }
+}
@@ -0,0 +1,97 @@
+
+use std::collections::HashMap;
+
+use blkfile::BlkBlock;
+pub use blkfile::{Importer, ImportError, ImportProgress};
+
+use db::*;
+use hash::*;
+use Header;
+use api::block::*;
+
+
+/// The reason a block read from the blk files could not be added
+#[derive(Debug)]
+pub enum ImportBlockError {
+    Db(DbError),
+
+    /// The block with the given hash is invalid
+    Invalid(Hash)
+}
+
+impl From<DbError> for ImportBlockError {
+    fn from(err: DbError) -> ImportBlockError {
+        ImportBlockError::Db(err)
+    }
+}
+
+
+/// Imports the headers and blocks from bitcoin-core blk*.dat files
+///
+/// Blocks of which the parent is not yet known are kept in memory until the parent comes in.
+/// The checkpoint is not advanced past such a block, so it is read again after resuming.
+///
+/// The import stops at an invalid block, which is returned as `ImportBlockError::Invalid`.
+///
+/// `progress` is called after each block read; returns the number of blocks imported,
+/// including those that were already in the store
+pub fn import_blk_files<F>(db: &mut Db, importer: &mut Importer, mut progress: F)
+    -> Result<u64, ImportError<ImportBlockError>>
+
+    where F: FnMut(&ImportProgress)
+{
+    // blocks waiting for their parent, keyed by the parent hash
+    let mut orphans: HashMap<Hash, Vec<BlkBlock>> = HashMap::new();
+    let mut count = 0;
+
+    while let Some(block) = importer.next_block()? {
+
+        let mut todo = vec![block];
+        while let Some(block) = todo.pop() {
+
+            let hash = double_sha256(&block.data[0..80]);
+
+            match import_block(db, &hash, &block.data).map_err(ImportError::Block)? {
+                Some(parent) => {
+                    orphans.entry(parent).or_insert_with(Vec::new).push(block);
+                },
+                None => {
+                    count += 1;
+                    if let Some(children) = orphans.remove(&hash) {
+                        todo.extend(children);
+                    }
+                }
+            }
+        }
+
+        let checkpoint = orphans.values()
+            .flat_map(|blocks| blocks.iter())
+            .map(|block| block.start)
+            .min()
+            .unwrap_or(importer.position());
+
+        importer.commit(checkpoint)?;
+        progress(&importer.progress());
+    }
+
+    importer.flush()?;
+    Ok(count)
+}
+
+// Adds a single block; returns the hash of the parent if it is not yet known
+fn import_block(db: &mut Db, hash: &Hash, data: &[u8]) -> Result<Option<Hash>, ImportBlockError> {
+
+    let header = Header::new(&data[0..80]).map_err(DbError::from)?;
+
+    match header_add(db, hash, header)? {
+        HeaderAddResult::Orphan(parent) => Ok(Some(parent)),
+        HeaderAddResult::AlreadyExists  => Ok(None),
+
+        HeaderAddResult::Invalid        => Err(ImportBlockError::Invalid(*hash)),
+
+        HeaderAddResult::Ok => {
+            block_add_transactions(db, data, true)?;
+            Ok(None)
+        }
+    }
+}
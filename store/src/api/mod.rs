@@ -2,5 +2,6 @@
 
 pub mod transaction;
 pub mod block;
+pub mod import;
 
 
@@ -1,6 +1,7 @@
 
 extern crate itertools;
 extern crate hashstore;
+extern crate blkfile;
 
 extern crate serde;
 #[macro_use]
@@ -28,6 +29,7 @@ use hashstore::ValuePtr;
 
 pub use api::transaction::*;
 pub use api::block::*;
+pub use api::import::*;
 
 pub use db::{Db, DbError, init, init_empty};
 
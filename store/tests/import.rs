@@ -1,12 +1,18 @@
 
 
 extern crate store;
+extern crate byteorder;
 
-
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
 use std::time::Instant;
 
+use byteorder::{WriteBytesExt, LittleEndian};
+
 mod util;
-mod blk_file;
 
 
 
@@ -14,48 +20,19 @@ mod blk_file;
 #[ignore]
 fn test_import() {
 
-    let mut db = store::init_empty("tst-import").unwrap();
-    let mut orphans = std::collections::HashMap::new();
+    // Set BITCRUST_BLOCKS or use 'ln -s ~/.bitcoin/blocks ./core-blocks' to link the directory
+    let blocks_dir = env::var_os("BITCRUST_BLOCKS").map_or_else(|| PathBuf::from("../core-blocks"), PathBuf::from);
+    let db_dir = util::temp_dir("tst-import");
+    let mut db = store::init_empty(&db_dir).unwrap();
+    let mut importer = store::Importer::new(blocks_dir);
     let now = Instant::now();
-    let mut blocks = 0;
-
-    for blk in blk_file::read_blocks() {
-
-        // we only use the header
-        let mut raw_hdr = blk;
-        let mut hash = store::double_sha256(&raw_hdr[0..80]);
-
-        let header = store::Header::new(&raw_hdr[0..80]).unwrap();
-        let prev_hash = header.prev_hash;
-
-
-        let add_result = store::header_add(&mut db, &hash, header).unwrap();
-        if let store::HeaderAddResult::Orphan(parent) = add_result {
-
-            let header = store::Header::new(&raw_hdr[0..80]).unwrap();
-
-
-            //println!("Marking orphan {} waiting for {}", util::to_hex_rev(&hash[..]), util::to_hex_rev(&parent[..]));
-            assert!(orphans.insert(parent, (hash,header)).is_none());
-
-
-        } else {
-            //println!("Added header {} with prev {}", util::to_hex_rev(&hash[..]), util::to_hex_rev(&prev_hash[..]));
-
 
-            while let Some(&(ref orphan_hash, ref orphan_header)) = orphans.get(&hash) {
-                //println!("Adding decendent {} of {}", util::to_hex_rev(&orphan_hash[..]), util::to_hex_rev(&hash[..]));
-                let add_result = store::header_add(&mut db, &orphan_hash, orphan_header.clone()).unwrap();
-
-                if let store::HeaderAddResult::Orphan(_) = add_result {
-                    panic!("{} should not be orphan anymore", util::to_hex_rev(&hash[..]));
-                }
-                hash = *orphan_hash;
-            }
+    let blocks = store::import_blk_files(&mut db, &mut importer, |progress| {
+        if progress.blocks % 10_000 == 0 {
+            println!("Imported {} blocks; {} of {} bytes",
+                     progress.blocks, progress.bytes_done, progress.bytes_total);
         }
-
-        blocks += 1;
-    }
+    }).unwrap();
 
     let elapsed = now.elapsed().as_secs() * 1000 + now.elapsed().subsec_nanos() as u64 / 1000_000;
     let ms_block = elapsed / blocks;
@@ -65,6 +42,63 @@ fn test_import() {
                                 &util::hash_from_hex("000000000000034a7dedef4a161fa058a2d67a173a90155f3a2fe6fc132e0ebf"))
         .unwrap().unwrap();
 
+    drop(db);
+    let _ = fs::remove_dir_all(&db_dir);
+}
 
+// Creates a block with an empty transaction list on top of prev
+fn create_block(prev: &[u8], nonce: u8) -> Vec<u8> {
+    let mut block = vec![1, 0, 0, 0];
+    block.extend_from_slice(&store::double_sha256(&prev[0..80]));
+    block.extend_from_slice(&[0; 32]);               // merkle root
+    block.extend_from_slice(&[0x29, 0xab, 0x5f, 0x49]); // time
+    block.extend_from_slice(&[0xff, 0xff, 0x00, 0x1d]); // bits
+    block.extend_from_slice(&[nonce, 0, 0, 0]);
+    block.push(0);
+    block
 }
 
+#[test]
+fn test_import_out_of_order() {
+
+    let db_dir = util::temp_dir("tst-import-blk");
+    let mut db = store::init_empty(&db_dir).unwrap();
+
+    let genesis = util::from_hex("0100000000000000000000000000000000000000000000000000000000000000\
+           000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa\
+           4b1e5e4a29ab5f49ffff001d1dac2b7c");
+    let block1 = create_block(&genesis, 1);
+    let block2 = create_block(&block1, 2);
+    let block3 = create_block(&block2, 3);
+
+    let dir = util::temp_dir("tst-import-blk-files");
+    {
+        let mut file = File::create(dir.join("blk00000.dat")).unwrap();
+        for block in [&block3, &block1, &block2].iter() {
+            file.write_u32::<LittleEndian>(0xD9B4BEF9).unwrap();
+            file.write_u32::<LittleEndian>(block.len() as u32).unwrap();
+            file.write_all(block).unwrap();
+        }
+    }
+
+    let checkpoint = dir.join("checkpoint");
+    let mut importer = store::Importer::with_checkpoint(&dir, &checkpoint).unwrap();
+    let mut reported = 0;
+
+    let count = store::import_blk_files(&mut db, &mut importer, |_| reported += 1).unwrap();
+    assert_eq!(count, 3);
+    assert_eq!(reported, 3);
+
+    let hash3 = store::double_sha256(&block3[0..80]);
+    let hdr = store::header_get(&mut db, &hash3).unwrap().unwrap();
+    assert_eq!(hdr.height, 3);
+    assert_eq!(store::header_get_best(&mut db).unwrap(), hash3);
+
+    // resuming finds nothing new
+    let mut importer = store::Importer::with_checkpoint(&dir, &checkpoint).unwrap();
+    assert_eq!(store::import_blk_files(&mut db, &mut importer, |_| {}).unwrap(), 0);
+
+    drop(db);
+    let _ = fs::remove_dir_all(&db_dir);
+    let _ = fs::remove_dir_all(&dir);
+}
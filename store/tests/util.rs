@@ -73,3 +73,12 @@ pub fn from_hex_rev(str: &str) -> Vec<u8> {
     v.reverse();
     v
 }
+
+/// Returns an empty directory for a test; the test removes it when done
+#[allow(dead_code)]
+pub fn temp_dir(name: &str) -> ::std::path::PathBuf {
+    let dir = ::std::env::temp_dir().join(format!("{}-{}", name, ::std::process::id()));
+    let _ = ::std::fs::remove_dir_all(&dir);
+    ::std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
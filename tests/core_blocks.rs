@@ -0,0 +1,17 @@
+//!
+//! Location of the bitcoin-core blk files used by the tests that load the real chain
+//!
+//! The directory is taken from `BITCRUST_BLOCKS`, and defaults to `./core-blocks`.
+//! Use 'ln -s ~/.bitcoin/blocks ./core-blocks' to link the directory
+
+
+use std::env;
+use std::path::PathBuf;
+
+
+/// Returns the directory holding blk00000.dat and onwards
+pub fn blocks_dir() -> PathBuf {
+
+    env::var_os("BITCRUST_BLOCKS")
+        .map_or_else(|| PathBuf::from("./core-blocks"), PathBuf::from)
+}
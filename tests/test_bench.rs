@@ -1,43 +1,31 @@
-
 extern crate bitcrust_lib;
+extern crate blkfile;
 
-extern crate byteorder;
-
-
-use std::io::BufReader;
-use std::fs::File;
-
-mod blk_file;
 
 use std::time::{Instant};
-extern crate rayon;
 
+use blkfile::{BlkPosition, Importer};
+
+mod core_blocks;
 
 
 #[test]
 #[ignore]
 fn load_bench_init() {
 
-    let mut store = bitcrust_lib::init();
-
-    for fileno in 0..750 {
-        let name = format!("./core-blocks/blk{:05}.dat", fileno);
-        println!("Processing {}", name);
-        let f = File::open(name).unwrap();
-        let mut rdr = BufReader::new(f);
+    let mut store    = bitcrust_lib::init();
+    let mut importer = Importer::new(core_blocks::blocks_dir());
 
-        let mut blocks = 0;
-        loop {
-            let blk = blk_file::read_block(&mut rdr).unwrap();
+    while let Some(block) = importer.next_block().unwrap() {
 
-            if blk.is_none() {
-                break;
-            }
-
-            bitcrust_lib::add_block(&mut store, &blk.unwrap());
+        if block.start.file_number >= 750 {
+            break;
+        }
+        if block.start.file_offset == 0 {
+            println!("Processing file {}", block.start.file_number);
+        }
 
-            blocks += 1;
-       }
+        bitcrust_lib::add_block(&mut store, &block.data);
     }
 
 }
@@ -50,29 +38,24 @@ fn load_bench_next() {
 
     let fileno = 750;
 
-    let name = format!("./core-blocks/blk{:05}.dat", fileno);
-    println!("Processing {}", name);
-    let f = File::open(name).unwrap();
-
-    let mut rdr = BufReader::new(f);
+    let mut importer = Importer::new(core_blocks::blocks_dir());
+    importer.seek(BlkPosition { file_number: fileno, file_offset: 0 });
+    println!("Processing file {}", fileno);
 
     let mut blocks = 0;
     let start = Instant::now();
-    loop {
-        let blk = blk_file::read_block(&mut rdr).unwrap();
+    while let Some(block) = importer.next_block().unwrap() {
 
-        if blk.is_none() {
+        if block.start.file_number != fileno {
             break;
         }
 
-        bitcrust_lib::add_block(&mut store, &blk.unwrap());
+        bitcrust_lib::add_block(&mut store, &block.data);
 
         blocks += 1;
     }
 
-    let elapsed = Instant::now() - start;
-    let elapsed = elapsed.as_secs() as u64 * 1000 + elapsed.subsec_nanos() as u64 / 1000_000;
+    let elapsed = start.elapsed().as_millis() as u64;
     println!("Processes {} blocks in {} ms ({} ms/block)", blocks, elapsed, elapsed / blocks )
 
 }
-
@@ -1,60 +1,30 @@
-
 extern crate bitcrust_lib;
-
-extern crate byteorder;
+extern crate blkfile;
 
 
-use std::io::BufReader;
-use std::fs::File;
+use std::thread;
+use std::time::{Instant};
 
-mod blk_file;
+use blkfile::{BlkPosition, Importer};
 
-use std::time::{Instant};
-extern crate rayon;
+mod core_blocks;
 
 
 #[test]
 #[ignore]
 fn load_file1() {
 
-    let mut store = bitcrust_lib::init();
-
-    let fileno = 0;
-    let name = format!("./data/blk{:05}.dat", fileno);
-    println!("Processing {}", name);
-    let f = File::open(name).unwrap();
-    let mut rdr = BufReader::new(f);
-
-    let mut blocks = 0;
-    loop {
-        let blk = blk_file::read_block(&mut rdr).unwrap();
-
-        if blk.is_none() {
-            break;
-        }
-
-        bitcrust_lib::add_block(&mut store, &blk.unwrap());
-
-        blocks += 1;
-
-
-
-
-        if blocks == 2 {
-            break;
-        }
+    let mut store    = bitcrust_lib::init();
+    let mut importer = Importer::new(core_blocks::blocks_dir());
 
+    // the first two blocks
+    for _ in 0..2 {
+        let block = importer.next_block().unwrap().unwrap();
+        bitcrust_lib::add_block(&mut store, &block.data);
     }
-
-
-
-
 }
 
 
-
-
-
 #[test]
 #[ignore]
 fn load_file_large() {
@@ -63,35 +33,19 @@ fn load_file_large() {
 
     let mut blocks = 0;
 
-    let mut store = bitcrust_lib::init();
+    let mut store    = bitcrust_lib::init();
+    let mut importer = Importer::new(core_blocks::blocks_dir());
 
     store.initial_sync = true;
-    for fileno in 0..999 {
-        let name = format!("./core-blocks/blk{:05}.dat", fileno);
-        println!("Processing {}", name);
-        let f = File::open(name).unwrap();
-        let mut rdr = BufReader::new(f);
+    while let Some(block) = importer.next_block().unwrap() {
 
-        loop {
-            let blk = blk_file::read_block(&mut rdr).unwrap();
+        bitcrust_lib::add_block(&mut store, &block.data);
 
-            if blk.is_none() {
-                break;
-            }
-
-            bitcrust_lib::add_block(&mut store, &blk.unwrap());
-
-
-            blocks += 1;
-
-            if blocks % 100 == 0 {
-                println!("Processed {} blocks in {} sec at {}/s", blocks, start.elapsed().as_secs(),
-                         blocks / (start.elapsed().as_secs() + 1));
-            }
+        blocks += 1;
 
-            if blocks >= BLOCK_COUNT {
-                break;
-            }
+        if blocks % 100 == 0 {
+            println!("Processed {} blocks in {} sec at {}/s", blocks, start.elapsed().as_secs(),
+                     blocks / (start.elapsed().as_secs() + 1));
         }
 
         if blocks >= BLOCK_COUNT {
@@ -103,38 +57,33 @@ fn load_file_large() {
              blocks / (start.elapsed().as_secs() + 1));
 }
 
-use std::thread;
 #[test]
 #[ignore]
 fn load_large_concurrent() {
-    const THREADS: usize = 5;
+    const THREADS: u32 = 5;
     const BLOCK_COUNT: u64 = 400000;
 
-
-
     let handles: Vec<_> = (0..THREADS).map(|n| {
-        //let mut store = store_b.clone();
         thread::spawn(move || {
-            let mut store = bitcrust_lib::init_prs();
+            let mut store    = bitcrust_lib::init_prs();
+            let mut importer = Importer::new(core_blocks::blocks_dir());
 
             let start = Instant::now();
             let mut blocks = 0;
-            for fileno_b in 0..999 {
+
+            // each thread takes every THREADS-th file
+            'files: for fileno_b in 0..999 {
                 let fileno = fileno_b * THREADS + n;
-                let name = format!("./data/blk{:05}.dat", fileno);
-                println!("Processing {}", name);
-                let f = File::open(name).unwrap();
-                let mut rdr = BufReader::new(f);
+                importer.seek(BlkPosition { file_number: fileno, file_offset: 0 });
+                println!("Processing file {}", fileno);
 
-                loop {
-                    let blk = blk_file::read_block(&mut rdr).unwrap();
+                while let Some(block) = importer.next_block().unwrap() {
 
-                    if blk.is_none() {
+                    if block.start.file_number != fileno {
                         break;
                     }
 
-                    bitcrust_lib::add_block(&mut store, &blk.unwrap());
-
+                    bitcrust_lib::add_block(&mut store, &block.data);
 
                     blocks += 1;
 
@@ -144,13 +93,9 @@ fn load_large_concurrent() {
                     }
 
                     if blocks >= BLOCK_COUNT {
-                        break;
+                        break 'files;
                     }
                 }
-
-                if blocks >= BLOCK_COUNT {
-                    break;
-                }
             }
         })
     }).collect();
@@ -158,4 +103,4 @@ fn load_large_concurrent() {
     for h in handles {
         h.join().unwrap();
     }
-}
\ No newline at end of file
+}